@@ -0,0 +1,40 @@
+#!/bin/bash
+
+# Saves or compares against the criterion baselines committed in benches/baselines
+#
+#   ./bench_baseline.sh save <name>     runs the benches and commits the results as <name>
+#   ./bench_baseline.sh compare <name>  runs the benches against the committed <name> baseline
+
+action=$1
+name=${2:-main}
+
+baselines_dir=benches/baselines
+criterion_dir=target/criterion
+
+if [ "$action" == "save" ]; then
+    cargo bench --bench '*' -- --save-baseline "$name" || exit 1
+
+    # Keep only the estimates and samples of the named baseline for every benchmark
+    rm -rf "$baselines_dir/$name"
+    cd $criterion_dir
+    find . -type d -name "$name" | while read -r dir; do
+        mkdir -p "../../$baselines_dir/$name/$(dirname "$dir")"
+        cp -r "$dir" "../../$baselines_dir/$name/$(dirname "$dir")/"
+    done
+    cd ../..
+
+    echo "Baseline '$name' saved to $baselines_dir/$name"
+elif [ "$action" == "compare" ]; then
+    if [ ! -d "$baselines_dir/$name" ]; then
+        echo "No baseline named '$name' in $baselines_dir"
+        exit 1
+    fi
+
+    mkdir -p $criterion_dir
+    cp -r "$baselines_dir/$name/." $criterion_dir/
+
+    cargo bench --bench '*' -- --baseline "$name"
+else
+    echo "Usage: ./bench_baseline.sh save|compare [baseline_name]"
+    exit 1
+fi
@@ -0,0 +1,36 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use num_bigint::BigUint;
+
+use invisible_backend::utils::crypto_utils::{hash, hash_many, pedersen, pedersen_on_vec};
+
+/// The BigUint wrappers convert through decimal strings, so these include the conversion
+/// overhead on top of the raw starknet-crypto benches.
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let inputs = (1..=16u64)
+        .map(|i| BigUint::from(i) * BigUint::from(0x1234_5678_9abc_def0_u64).pow(3))
+        .collect::<Vec<BigUint>>();
+
+    c.bench_function("poseidon_hash", |b| {
+        b.iter(|| black_box(hash(&inputs[0], &inputs[1])));
+    });
+
+    c.bench_function("pedersen_hash", |b| {
+        b.iter(|| black_box(pedersen(&inputs[0], &inputs[1])));
+    });
+
+    let mut group = c.benchmark_group("hash_many");
+    for n in [2, 4, 8, 16] {
+        let arr = inputs.iter().take(n).collect::<Vec<&BigUint>>();
+
+        group.bench_with_input(BenchmarkId::new("poseidon", n), &arr, |b, arr| {
+            b.iter(|| black_box(hash_many(arr)));
+        });
+        group.bench_with_input(BenchmarkId::new("pedersen", n), &arr, |b, arr| {
+            b.iter(|| black_box(pedersen_on_vec(arr)));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,129 @@
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::time::SystemTime;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use num_bigint::BigUint;
+
+use common::{signed_open_order, TestUser, BTC, USDC};
+use invisible_backend::{
+    matching_engine::{
+        domain::{Order, OrderSide},
+        orderbook::{OrderBook, Success},
+        orders::{limit_order_cancel_request, new_limit_order_request, OrderRequest},
+    },
+    perpetual::{get_collateral_amount, OrderSide as PerpOrderSide},
+    server::server_helpers::PERP_MARKET_IDS,
+    utils::notes::Note,
+};
+
+const PRICE_LEVELS: u64 = 100;
+const LOT_SIZE: u64 = 1_000_000; // 0.01 BTC
+
+fn limit_order(user: &TestUser, side: OrderSide, price: u64) -> OrderRequest {
+    let margin_note = Note::new(
+        user.user_id,
+        user.address.clone(),
+        USDC,
+        10_000 * 10u64.pow(6),
+        BigUint::from(user.user_id),
+    );
+    let order_side = match side {
+        OrderSide::Bid => PerpOrderSide::Long,
+        OrderSide::Ask => PerpOrderSide::Short,
+    };
+    let price = price * 10u64.pow(6);
+
+    let (order, signature) = signed_open_order(
+        user,
+        order_side,
+        BTC,
+        LOT_SIZE,
+        get_collateral_amount(BTC, LOT_SIZE, price),
+        &margin_note,
+    );
+
+    new_limit_order_request(
+        side,
+        Order::Perp(order),
+        signature,
+        SystemTime::now(),
+        false,
+        user.user_id,
+        0,
+    )
+}
+
+fn accepted_id(book: &mut OrderBook, request: OrderRequest) -> u64 {
+    let results = book.process_order(request);
+
+    match &results[0] {
+        Ok(Success::Accepted { id, .. }) => *id,
+        res => panic!("order not accepted: {:?}", res),
+    }
+}
+
+/// Builds a book with `depth` resting asks spread over PRICE_LEVELS levels (30k USD and up)
+/// and as many resting bids below 29k USD.
+fn deep_book(
+    depth: u64,
+    ask_levels: &Vec<OrderRequest>,
+    bid_levels: &Vec<OrderRequest>,
+) -> OrderBook {
+    let mut book = OrderBook::new(BTC, USDC, PERP_MARKET_IDS[&BTC.to_string()]);
+
+    for i in 0..depth {
+        let level = (i % PRICE_LEVELS) as usize;
+        book.process_order(ask_levels[level].clone());
+        book.process_order(bid_levels[level].clone());
+    }
+
+    book
+}
+
+/// Every request goes through the order validation as well, so the signature verification
+/// is part of the measured time (as it is in the engine).
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let maker = TestUser::new(1);
+    let taker = TestUser::new(2);
+
+    let ask_levels = (0..PRICE_LEVELS)
+        .map(|i| limit_order(&maker, OrderSide::Ask, 30_000 + i))
+        .collect::<Vec<OrderRequest>>();
+    let bid_levels = (0..PRICE_LEVELS)
+        .map(|i| limit_order(&maker, OrderSide::Bid, 29_000 - i))
+        .collect::<Vec<OrderRequest>>();
+
+    let passive_bid = limit_order(&taker, OrderSide::Bid, 28_000);
+    let crossing_bid = limit_order(&taker, OrderSide::Bid, 30_000);
+
+    let mut group = c.benchmark_group("process_order");
+    for depth in [100, 1_000, 10_000] {
+        let mut book = deep_book(depth, &ask_levels, &bid_levels);
+
+        // ? A bid that rests in the book, followed by its cancellation
+        group.bench_function(BenchmarkId::new("insert_and_cancel", depth), |b| {
+            b.iter(|| {
+                let id = accepted_id(&mut book, passive_bid.clone());
+                black_box(book.process_order(limit_order_cancel_request(
+                    id,
+                    OrderSide::Bid,
+                    taker.user_id,
+                )));
+            });
+        });
+
+        // ? A bid filling the best ask, after which the ask is replenished to keep the depth
+        group.bench_function(BenchmarkId::new("match_best_ask", depth), |b| {
+            b.iter(|| {
+                black_box(book.process_order(crossing_bid.clone()));
+                black_box(book.process_order(ask_levels[0].clone()));
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,111 @@
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::{collections::HashMap, sync::Arc};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use num_bigint::BigUint;
+use parking_lot::Mutex;
+
+use common::{signed_open_order, TestUser, BTC, USDC};
+use invisible_backend::{
+    perpetual::{get_collateral_amount, order_execution::execute_perp_swap_transaction, OrderSide},
+    transaction_batch::{
+        batch_functions::batch_transition::TREE_DEPTH, tx_batch_structs::SwapFundingInfo,
+    },
+    trees::superficial_tree::SuperficialTree,
+    utils::notes::Note,
+};
+
+const SYNTHETIC_AMOUNT: u64 = 10_000_000; // 0.1 BTC
+const PRICE: u64 = 30_000 * 1_000_000;
+
+fn margin_note(user: &TestUser, index: u64) -> Note {
+    Note::new(
+        index,
+        user.address.clone(),
+        USDC,
+        1_000 * 1_000_000,
+        BigUint::from(user.user_id),
+    )
+}
+
+/// Executes a swap between two orders opening new positions (both filled in full).
+/// The state and fill trackers are reset for every iteration since the swap blocks the order ids.
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+
+    let collateral_amount = get_collateral_amount(BTC, SYNTHETIC_AMOUNT, PRICE);
+
+    let (mut order_a, signature_a) = signed_open_order(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        SYNTHETIC_AMOUNT,
+        collateral_amount,
+        &margin_note(&alice, 0),
+    );
+    let (mut order_b, signature_b) = signed_open_order(
+        &bob,
+        OrderSide::Short,
+        BTC,
+        SYNTHETIC_AMOUNT,
+        collateral_amount,
+        &margin_note(&bob, 1),
+    );
+    order_a.order_id = 1;
+    order_b.order_id = 2;
+
+    let signature_a = Some(signature_a);
+    let signature_b = Some(signature_b);
+    let fee = collateral_amount / 1000;
+
+    c.bench_function("execute_perp_swap_transaction/open_open", |b| {
+        b.iter_batched(
+            || {
+                (
+                    Arc::new(Mutex::new(SuperficialTree::new(TREE_DEPTH))),
+                    Arc::new(Mutex::new(HashMap::new())),
+                    Arc::new(Mutex::new(HashMap::new())),
+                    Arc::new(Mutex::new(HashMap::new())),
+                )
+            },
+            |(
+                state_tree,
+                blocked_perp_order_ids,
+                perpetual_partial_fill_tracker,
+                partialy_filled_positions,
+            )| {
+                let result = execute_perp_swap_transaction(
+                    &state_tree,
+                    &blocked_perp_order_ids,
+                    &perpetual_partial_fill_tracker,
+                    &partialy_filled_positions,
+                    PRICE,
+                    SwapFundingInfo {
+                        current_funding_idx: 0,
+                        swap_funding_rates: vec![],
+                        swap_funding_prices: vec![],
+                        min_swap_funding_idx: 0,
+                    },
+                    &order_a,
+                    &order_b,
+                    &signature_a,
+                    &signature_b,
+                    SYNTHETIC_AMOUNT,
+                    collateral_amount,
+                    fee,
+                    fee,
+                );
+                assert!(result.is_ok(), "{:?}", result.err());
+
+                black_box(result)
+            },
+            BatchSize::SmallInput,
+        );
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,120 @@
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::{collections::HashMap, sync::Arc};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use num_bigint::BigUint;
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+use common::{TestExchange, TestUser, BTC, USDC};
+use invisible_backend::{
+    perpetual::OrderSide,
+    transaction_batch::{
+        batch_functions::batch_transition::TREE_DEPTH, restore_state::_restore_state_inner,
+    },
+    trees::{superficial_tree::SuperficialTree, Tree},
+};
+
+const BTC_PRICE: u64 = 30_000 * 1_000_000;
+
+/// Records a batch with `n_swaps` perpetual swaps opening new positions (and the deposits
+/// funding them) through the in-process exchange, and returns its transactions.
+fn record_batch(n_swaps: u64) -> Vec<Map<String, Value>> {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    runtime.block_on(async {
+        let mut exchange = TestExchange::builder()
+            .with_index_price(BTC, BTC_PRICE)
+            .build()
+            .await;
+
+        for i in 0..n_swaps {
+            for (user_id, order_side) in
+                [(2 * i + 1, OrderSide::Long), (2 * i + 2, OrderSide::Short)]
+            {
+                let user = TestUser::new(user_id);
+                let note = exchange.deposit(&user, USDC, 2_000 * 1_000_000).await;
+
+                let order = exchange.open_order_message(
+                    &user,
+                    order_side,
+                    BTC,
+                    10_000_000,
+                    3_000 * 1_000_000,
+                    1_000 * 1_000_000,
+                    &note,
+                );
+                let response = exchange.submit_perp_order(&user, order).await;
+                assert!(response.successful, "{}", response.error_message);
+            }
+        }
+
+        exchange.finalize_batch().await.transactions
+    })
+}
+
+/// `n` leaf updates spread over the first 3n leaves of the tree.
+fn leaf_updates(n: u64) -> HashMap<u64, BigUint> {
+    (0..n)
+        .map(|i| (i * 3, BigUint::from(i + 1) * BigUint::from(u64::MAX).pow(2)))
+        .collect()
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    // ? Tree updates are parallelized in chunks of STRIDE leaves (see trees/mod.rs)
+    let mut group = c.benchmark_group("batch_transition_updates");
+    group.sample_size(10);
+    for n in [100, 1_000, 10_000] {
+        let updates = leaf_updates(n);
+
+        group.bench_with_input(BenchmarkId::from_parameter(n), &updates, |b, updates| {
+            b.iter_batched(
+                || (Tree::new(TREE_DEPTH, 0), Map::new()),
+                |(mut tree, mut preimage)| {
+                    tree.batch_transition_updates(updates, &mut preimage);
+                    black_box(tree.root)
+                },
+                BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("restore_state");
+    for n_swaps in [10, 50] {
+        let transactions = record_batch(n_swaps);
+
+        group.bench_with_input(
+            BenchmarkId::new("perp_swaps", n_swaps),
+            &transactions,
+            |b, transactions| {
+                b.iter_batched(
+                    || {
+                        (
+                            Arc::new(Mutex::new(SuperficialTree::new(TREE_DEPTH))),
+                            Arc::new(Mutex::new(HashMap::new())),
+                            Arc::new(Mutex::new(HashMap::new())),
+                            transactions.clone(),
+                        )
+                    },
+                    |(state_tree, updated_state_hashes, perpetual_partial_fill_tracker, txs)| {
+                        _restore_state_inner(
+                            &state_tree,
+                            &updated_state_hashes,
+                            &perpetual_partial_fill_tracker,
+                            txs,
+                        );
+                        black_box(updated_state_hashes)
+                    },
+                    BatchSize::SmallInput,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,111 @@
+use std::{fs, path::Path, path::PathBuf, str::FromStr};
+
+use num_bigint::BigUint;
+
+use invisible_backend::utils::{
+    cairo_output::{format_cairo_ouput, parse_cairo_output},
+    state_reconstruction::{BatchStateUpdates, StateReconstructor},
+};
+
+const USAGE: &str =
+    "Usage: reconstruct_state <program_outputs_dir> [--da-dir <da_output_dir>] [--out <state.json>]";
+
+/// Replays the program outputs of all batches (files named by batch index) and
+/// verifies every state root along the way.
+///
+/// If a DA directory is given the leaves are taken from the DA data of each batch
+/// (the json arrays stored under da_output/N) instead of the program output.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut program_outputs_dir: Option<PathBuf> = None;
+    let mut da_dir: Option<PathBuf> = None;
+    let mut out_path: Option<PathBuf> = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--da-dir" => {
+                da_dir = Some(PathBuf::from(args.get(i + 1).ok_or(USAGE)?));
+                i += 1;
+            }
+            "--out" => {
+                out_path = Some(PathBuf::from(args.get(i + 1).ok_or(USAGE)?));
+                i += 1;
+            }
+            arg => program_outputs_dir = Some(PathBuf::from(arg)),
+        }
+        i += 1;
+    }
+    let program_outputs_dir = program_outputs_dir.ok_or(USAGE)?;
+
+    let batch_files = get_sorted_batch_files(&program_outputs_dir)?;
+
+    let mut reconstructor = StateReconstructor::new();
+    for (batch_index, path) in batch_files {
+        let raw_output = fs::read_to_string(&path)?;
+        let program_output = parse_cairo_output(format_cairo_ouput(&raw_output));
+
+        let new_root = match &da_dir {
+            Some(da_dir) => {
+                let da_path = da_dir.join(batch_index.to_string());
+                let da_output: Vec<String> = serde_json::from_slice(&fs::read(&da_path)?)?;
+                let da_output = da_output
+                    .iter()
+                    .map(|x| BigUint::from_str(x))
+                    .collect::<Result<Vec<BigUint>, _>>()?;
+
+                let updates = BatchStateUpdates::from_da_output(
+                    &da_output,
+                    &program_output.dex_state.program_input_counts,
+                )
+                .map_err(|e| e.current_context().err_msg.clone())?;
+
+                reconstructor.apply_batch(&program_output.dex_state, updates)
+            }
+            None => reconstructor.apply_program_output(&program_output),
+        }
+        .map_err(|e| e.current_context().err_msg.clone())?;
+
+        println!("batch {}: state root verified {}", batch_index, new_root);
+    }
+
+    println!(
+        "\nreconstructed {} notes, {} positions and {} order tabs",
+        reconstructor.notes.len(),
+        reconstructor.positions.len(),
+        reconstructor.order_tabs.len()
+    );
+    println!("final state root: {}", reconstructor.state_root());
+
+    if let Some(out_path) = out_path {
+        fs::write(
+            out_path,
+            serde_json::to_string_pretty(&reconstructor.to_json())?,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Returns the files in the directory whose names are batch indexes, sorted by index.
+fn get_sorted_batch_files(dir: &Path) -> Result<Vec<(u32, PathBuf)>, Box<dyn std::error::Error>> {
+    let mut batch_files: Vec<(u32, PathBuf)> = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        let batch_index = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u32>().ok());
+
+        if let Some(batch_index) = batch_index {
+            batch_files.push((batch_index, path));
+        }
+    }
+
+    batch_files.sort_by_key(|(batch_index, _)| *batch_index);
+
+    Ok(batch_files)
+}
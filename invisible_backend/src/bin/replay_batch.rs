@@ -0,0 +1,223 @@
+use std::path::PathBuf;
+
+use num_bigint::BigUint;
+use serde_json::{Map, Value};
+use sled::{Config, Db};
+
+use invisible_backend::{
+    transaction_batch::{
+        batch_functions::batch_transition::{BatchTransitionInfo, TREE_DEPTH},
+        restore_state::replay::{replay_batch, ReplayDiff},
+    },
+    trees::superficial_tree::SuperficialTree,
+};
+
+const USAGE: &str = "Usage: replay_batch <batch_index> [--storage <storage_dir>] [--json]";
+
+/// Re-executes the transactions stored under storage/transaction_data/N offline and reports
+/// any difference in leaf hashes, funding indexes or insurance fund with the recorded batch.
+///
+/// The replay runs with an offline session, so nothing is written to the exchange database.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut batch_index: Option<u32> = None;
+    let mut storage_dir = PathBuf::from("./storage");
+    let mut print_json = false;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--storage" => {
+                storage_dir = PathBuf::from(args.get(i + 1).ok_or(USAGE)?);
+                i += 1;
+            }
+            "--json" => print_json = true,
+            arg => batch_index = Some(arg.parse::<u32>()?),
+        }
+        i += 1;
+    }
+    let batch_index = batch_index.ok_or(USAGE)?;
+
+    let transactions = read_batch_transactions(&storage_dir, batch_index)?;
+
+    // ? Opened once, as the info of every previous batch is read to rebuild the state
+    let info_db = Config::new()
+        .path(storage_dir.join("batch_transition_info"))
+        .open()?;
+
+    let batch_transition_info = read_batch_transition_info(&info_db, batch_index)?.ok_or(
+        format!("batch transition info for batch {} not found", batch_index),
+    )?;
+
+    // ? The insurance fund at the start of the batch is the one recorded at the end of the previous batch
+    let prev_insurance_fund = if batch_index == 0 {
+        0
+    } else {
+        read_batch_transition_info(&info_db, batch_index - 1)?
+            .and_then(|info| {
+                info.exchange_state_storage
+                    .get("insurance_fund")
+                    .and_then(|v| v.as_i64())
+            })
+            .unwrap_or_default()
+    };
+
+    // ? The state at the start of the batch is the one left by the updates of all the previous batches
+    let mut prev_state = SuperficialTree::new(TREE_DEPTH);
+    for prev_batch_index in 0..batch_index {
+        let prev_batch_info =
+            read_batch_transition_info(&info_db, prev_batch_index)?.ok_or(format!(
+                "batch transition info for batch {} not found",
+                prev_batch_index
+            ))?;
+
+        let mut leaf_updates: Vec<(&u64, &BigUint)> = prev_batch_info
+            .updated_state_hashes
+            .iter()
+            .map(|(idx, (_, hash))| (idx, hash))
+            .collect();
+        leaf_updates.sort_unstable_by_key(|(idx, _)| **idx);
+        for (idx, hash) in leaf_updates {
+            prev_state.update_leaf_node(hash, *idx);
+        }
+    }
+
+    let report = replay_batch(
+        batch_index,
+        prev_state,
+        transactions,
+        &batch_transition_info,
+        prev_insurance_fund,
+    );
+
+    if print_json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!(
+            "batch {}: replayed {} transactions, {} differences found",
+            report.batch_index,
+            report.n_transactions,
+            report.diffs.len()
+        );
+
+        for diff in report.diffs.iter() {
+            print_diff(diff);
+        }
+
+        if !report.restored.is_empty() {
+            println!(
+                "  transactions restored from the recorded output (not re-executed): {:?}",
+                report.restored
+            );
+        }
+    }
+
+    if !report.is_consistent() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn print_diff(diff: &ReplayDiff) {
+    match diff {
+        ReplayDiff::LeafHash {
+            index,
+            recorded,
+            replayed,
+        } => println!(
+            "  leaf {}: recorded {:?}, replayed {:?}",
+            index, recorded, replayed
+        ),
+        ReplayDiff::PositionHash {
+            tx_index,
+            position_index,
+            recorded,
+            replayed,
+        } => println!(
+            "  tx {}: position {} hash recorded {:?}, replayed {:?}",
+            tx_index, position_index, recorded, replayed
+        ),
+        ReplayDiff::FundingIdx {
+            tx_index,
+            position_index,
+            recorded,
+            replayed,
+        } => println!(
+            "  tx {}: position {} funding idx recorded {}, replayed {}",
+            tx_index, position_index, recorded, replayed
+        ),
+        ReplayDiff::MinFundingIdx {
+            token,
+            recorded,
+            replayed,
+        } => println!(
+            "  token {}: min funding idx recorded {:?}, replayed {:?}",
+            token, recorded, replayed
+        ),
+        ReplayDiff::NewIndexes {
+            tx_index,
+            recorded,
+            replayed,
+        } => println!(
+            "  tx {}: new indexes recorded {:?}, replayed {:?}",
+            tx_index, recorded, replayed
+        ),
+        ReplayDiff::InsuranceFund { recorded, replayed } => println!(
+            "  insurance fund recorded {}, replayed {}",
+            recorded, replayed
+        ),
+        ReplayDiff::ExecutionFailed {
+            tx_index,
+            transaction_type,
+            err_msg,
+        } => println!(
+            "  tx {}: failed to re-execute {} transaction: {}",
+            tx_index, transaction_type, err_msg
+        ),
+    }
+}
+
+/// Reads all the micro-batches of a batch (same layout as MainStorage::read_storage).
+fn read_batch_transactions(
+    storage_dir: &PathBuf,
+    batch_index: u32,
+) -> Result<Vec<Map<String, Value>>, Box<dyn std::error::Error>> {
+    let path = storage_dir
+        .join("transaction_data")
+        .join(batch_index.to_string());
+    if !path.exists() {
+        return Err(format!("batch directory {:?} does not exist", path).into());
+    }
+    let tx_db = Config::new().path(path).open()?;
+
+    let count: u64 = match tx_db.get("count")? {
+        Some(count) => serde_json::from_slice(&count.to_vec())?,
+        None => 0,
+    };
+
+    let mut transactions = Vec::new();
+    for i in 0..count {
+        let value = tx_db
+            .get(&i.to_string())?
+            .ok_or(format!("micro batch {} not found", i))?;
+        let micro_batch: Vec<Map<String, Value>> = serde_json::from_slice(&value.to_vec())?;
+
+        transactions.extend(micro_batch);
+    }
+
+    Ok(transactions)
+}
+
+fn read_batch_transition_info(
+    db: &Db,
+    batch_index: u32,
+) -> Result<Option<BatchTransitionInfo>, Box<dyn std::error::Error>> {
+    let batch_transition_info = match db.get(&batch_index.to_string())? {
+        Some(info) => Some(serde_json::from_slice(&info.to_vec())?),
+        None => None,
+    };
+
+    Ok(batch_transition_info)
+}
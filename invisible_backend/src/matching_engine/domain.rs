@@ -36,7 +36,8 @@ use std::{fmt::Debug, time::SystemTime};
 
 use crate::{
     perpetual::{
-        get_cross_price, perp_order::PerpOrder, OrderSide as PerpOrderSide, COLLATERAL_TOKEN,
+        get_cross_price, perp_order::PerpOrder, perp_position::PerpPosition,
+        OrderSide as PerpOrderSide, COLLATERAL_TOKEN,
     },
     transactions::limit_order::LimitOrder,
 };
@@ -69,6 +70,17 @@ pub struct OrderWrapper {
     pub order_side: OrderSide, // The side of the order
     pub qty_left: u64,         // The amount left to be executed
     pub user_id: u64,
+    pub client_order_id: u64, // The id the user assigned to the order (0 if none)
+}
+
+impl OrderWrapper {
+    /// The position a reduce only perp order decreases (None for all other orders)
+    pub fn reduce_only_position(&self) -> Option<&PerpPosition> {
+        match &self.order {
+            Order::Perp(ord) if ord.reduce_only => ord.position.as_ref(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,194 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use super::domain::OrderSide;
+
+/// Market maker protection rule of a user in one market.
+///
+/// When the quantity a user gets filled for (or the net delta of those fills) within `window_ms`
+/// exceeds a limit, all their resting orders in the market are pulled and their new limit orders
+/// are rejected until they reset the protection. A limit of 0 disables that check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MmpConfig {
+    pub window_ms: u64,
+    pub qty_limit: u64,   // in base asset units
+    pub delta_limit: u64, // |bought - sold| in base asset units
+}
+
+/// Orders that were pulled because a user's protection was triggered
+#[derive(Debug, Clone, PartialEq)]
+pub struct MmpTrigger {
+    pub user_id: u64,
+    pub cancelled_order_ids: Vec<u64>,
+}
+
+struct MmpState {
+    config: MmpConfig,
+    fills: VecDeque<(SystemTime, OrderSide, u64)>, // (ts, side, qty) of the fills in the window
+    frozen: bool,
+}
+
+#[derive(Default)]
+pub struct MmProtection {
+    users: HashMap<u64, MmpState>,
+    triggers: Vec<MmpTrigger>,
+}
+
+impl MmProtection {
+    pub fn new() -> Self {
+        MmProtection::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Sets the protection rule of user_id (replacing the previous one and unfreezing them).
+    /// A window of 0 removes the protection.
+    pub fn set_config(&mut self, user_id: u64, config: MmpConfig) {
+        if config.window_ms == 0 {
+            self.users.remove(&user_id);
+            return;
+        }
+
+        self.users.insert(
+            user_id,
+            MmpState {
+                config,
+                fills: VecDeque::new(),
+                frozen: false,
+            },
+        );
+    }
+
+    /// Unfreezes user_id and forgets their previous fills. Returns false if they have no protection.
+    pub fn reset(&mut self, user_id: u64) -> bool {
+        if let Some(state) = self.users.get_mut(&user_id) {
+            state.fills.clear();
+            state.frozen = false;
+
+            return true;
+        }
+
+        false
+    }
+
+    pub fn is_frozen(&self, user_id: u64) -> bool {
+        self.users.get(&user_id).map_or(false, |state| state.frozen)
+    }
+
+    /// Records a fill of user_id and returns true if it just triggered their protection.
+    pub fn record_fill(&mut self, user_id: u64, side: OrderSide, qty: u64, ts: SystemTime) -> bool {
+        let state = self.users.get_mut(&user_id);
+        if state.is_none() {
+            return false;
+        }
+        let state = state.unwrap();
+
+        if state.frozen {
+            return false;
+        }
+
+        // ? Drop the fills that are no longer in the window
+        let window_start = ts
+            .checked_sub(Duration::from_millis(state.config.window_ms))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        while let Some((fill_ts, _, _)) = state.fills.front() {
+            if *fill_ts >= window_start {
+                break;
+            }
+            state.fills.pop_front();
+        }
+
+        state.fills.push_back((ts, side, qty));
+
+        let mut filled_qty: u64 = 0;
+        let mut delta: i128 = 0;
+        for (_, side, qty) in state.fills.iter() {
+            filled_qty += qty;
+            match side {
+                OrderSide::Bid => delta += *qty as i128,
+                OrderSide::Ask => delta -= *qty as i128,
+            }
+        }
+
+        let config = &state.config;
+        if (config.qty_limit > 0 && filled_qty > config.qty_limit)
+            || (config.delta_limit > 0 && delta.unsigned_abs() > config.delta_limit as u128)
+        {
+            state.frozen = true;
+            state.fills.clear();
+
+            return true;
+        }
+
+        false
+    }
+
+    pub fn push_trigger(&mut self, trigger: MmpTrigger) {
+        self.triggers.push(trigger);
+    }
+
+    /// Returns the triggers that haven't been reported to the users yet
+    pub fn take_triggers(&mut self) -> Vec<MmpTrigger> {
+        std::mem::take(&mut self.triggers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(window_ms: u64, qty_limit: u64, delta_limit: u64) -> MmpConfig {
+        MmpConfig {
+            window_ms,
+            qty_limit,
+            delta_limit,
+        }
+    }
+
+    #[test]
+    fn qty_limit_triggers_within_the_window() {
+        let mut mmp = MmProtection::new();
+        mmp.set_config(1, config(1_000, 100, 0));
+
+        let t0 = SystemTime::now();
+        assert!(!mmp.record_fill(1, OrderSide::Bid, 60, t0));
+
+        // ? The first fill is out of the window
+        let t1 = t0 + Duration::from_millis(1_500);
+        assert!(!mmp.record_fill(1, OrderSide::Ask, 60, t1));
+        assert!(!mmp.is_frozen(1));
+
+        assert!(mmp.record_fill(1, OrderSide::Bid, 50, t1));
+        assert!(mmp.is_frozen(1));
+
+        // ? Frozen users don't trigger again until they reset
+        assert!(!mmp.record_fill(1, OrderSide::Bid, 500, t1));
+        assert!(mmp.reset(1));
+        assert!(!mmp.is_frozen(1));
+    }
+
+    #[test]
+    fn delta_limit_nets_out_both_sides() {
+        let mut mmp = MmProtection::new();
+        mmp.set_config(1, config(1_000, 0, 100));
+
+        let t0 = SystemTime::now();
+        assert!(!mmp.record_fill(1, OrderSide::Bid, 80, t0));
+        assert!(!mmp.record_fill(1, OrderSide::Ask, 80, t0));
+        assert!(!mmp.record_fill(1, OrderSide::Ask, 90, t0));
+        assert!(mmp.record_fill(1, OrderSide::Ask, 20, t0));
+    }
+
+    #[test]
+    fn users_without_protection_are_ignored() {
+        let mut mmp = MmProtection::new();
+        mmp.set_config(1, config(1_000, 10, 0));
+        mmp.set_config(1, config(0, 10, 0));
+
+        assert!(!mmp.record_fill(1, OrderSide::Bid, 1_000, SystemTime::now()));
+        assert!(!mmp.reset(1));
+        assert!(mmp.is_empty());
+    }
+}
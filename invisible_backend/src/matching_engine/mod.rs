@@ -3,6 +3,7 @@ use crate::perpetual::DECIMALS_PER_ASSET;
 use self::domain::OrderSide;
 
 pub mod domain;
+pub mod mm_protection;
 pub mod order_queues;
 pub mod orderbook;
 pub mod orders;
@@ -1,5 +1,5 @@
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
 use std::sync::Arc;
 use std::time::{self, SystemTime};
 
@@ -13,6 +13,14 @@ use crate::utils::crypto_utils::Signature;
 use super::domain::{Order, OrderSide, OrderWrapper};
 use super::orders::amend_inner;
 
+/// A reduce only order that was resized or cancelled after its position decreased
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReduceOnlyUpdate {
+    pub user_id: u64,
+    pub order_id: u64,
+    pub qty_left: u64, // 0 if the order was cancelled
+}
+
 #[derive(Clone, Debug)]
 struct OrderIndex {
     id: u64,
@@ -59,6 +67,21 @@ impl PartialEq for OrderIndex {
 
 impl Eq for OrderIndex {}
 
+/// Prices are grouped into levels with this precision (prices closer than 1e-8 are the same level)
+const PRICE_LEVEL_PRECISION: f64 = 1e8;
+
+/// The total quantity and the number of orders resting at a price
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PriceLevel {
+    pub price: f64,
+    pub qty: u64,
+    pub order_count: u32,
+}
+
+fn price_level_key(price: f64) -> u64 {
+    (price * PRICE_LEVEL_PRECISION).round() as u64
+}
+
 #[derive(Debug)]
 /// Public methods
 pub struct OrderQueue {
@@ -68,7 +91,10 @@ pub struct OrderQueue {
     op_counter: u64,
     max_stalled: u64,
     queue_side: OrderSide,
-    pending_orders: HashMap<u64, (Signature, OrderSide, u64, u64)>, // order_id => (signature, order_side, qty_left, user_id)
+    pending_orders: HashMap<u64, (Signature, OrderSide, u64, u64, u64)>, // order_id => (signature, order_side, qty_left, user_id, client_order_id)
+    levels: BTreeMap<u64, PriceLevel>, // price level key => aggregated orders at that price
+    order_levels: HashMap<u64, (u64, u64)>, // order_id => (price level key, qty counted in the level)
+    client_order_ids: HashMap<(u64, u64), u64>, // (user_id, client_order_id) => order_id
 }
 
 impl OrderQueue {
@@ -83,6 +109,9 @@ impl OrderQueue {
             max_stalled,
             queue_side: side,
             pending_orders: HashMap::with_capacity(capacity),
+            levels: BTreeMap::new(),
+            order_levels: HashMap::with_capacity(capacity),
+            client_order_ids: HashMap::with_capacity(capacity),
         }
     }
 
@@ -107,7 +136,7 @@ impl OrderQueue {
         let ts = index_item.timestamp;
 
         if self.orders.contains_key(&order_id) {
-            if let Some(ord) = self.orders.remove(&order_id) {
+            if let Some(ord) = self.remove_from_orders(order_id) {
                 Some((ord, ts))
             } else {
                 None
@@ -151,7 +180,13 @@ impl OrderQueue {
         order.order.set_id(id);
         order.order_id = id;
 
+        if order.client_order_id != 0 {
+            self.client_order_ids
+                .insert((order.user_id, order.client_order_id), id);
+        }
+
         self.orders.insert(id, order);
+        self.add_to_level(id, price);
 
         true
     }
@@ -173,10 +208,11 @@ impl OrderQueue {
                 return false;
             };
 
-            amend_inner(&mut wrapper, price, new_expiration, signature);
+            amend_inner(&mut wrapper, price, new_expiration, 0, signature);
 
             // store new order data
             self.rebuild_idx(id, price, ts);
+            self.add_to_level(id, price);
 
             true
         } else {
@@ -184,15 +220,41 @@ impl OrderQueue {
         }
     }
 
+    /// Amends an order without changing its place in the queue, so it keeps its time priority. \
+    /// Used when the quantity of an order is reduced at the same price.
+    pub fn amend_in_place(
+        &mut self,
+        id: u64,
+        user_id: u64,
+        price: f64,
+        new_expiration: u64,
+        new_qty: u64,
+        qty_left: u64,
+        signature: Signature,
+    ) -> bool {
+        match self.orders.get_mut(&id) {
+            Some(wrapper) if wrapper.user_id == user_id => {
+                amend_inner(wrapper, price, new_expiration, new_qty, signature);
+                wrapper.qty_left = qty_left;
+
+                self.update_level_qty(id);
+
+                true
+            }
+            _ => false,
+        }
+    }
+
     /// This cancels an order with order_id. \
     /// If force is true, then order will be cancelled even if it's not owned by user_id
     pub fn cancel(&mut self, order_id: u64, user_id: u64, force: bool) -> bool {
-        match self.orders.remove(&order_id) {
+        match self.orders.get(&order_id) {
             Some(wrapper) => {
                 // Only a user can cancel
                 if wrapper.user_id != user_id && !force {
                     return false;
                 }
+                self.remove_from_orders(order_id);
                 self.clean_check();
                 true
             }
@@ -212,7 +274,7 @@ impl OrderQueue {
             return None;
         }
 
-        let wrapper = self.orders.remove(&order_id)?;
+        let wrapper = self.remove_from_orders(order_id)?;
 
         self.remove_stalled();
 
@@ -224,6 +286,25 @@ impl OrderQueue {
         self.orders.get(&id)
     }
 
+    /// Returns the order of user_id with the given client_order_id
+    pub fn get_client_order(&self, user_id: u64, client_order_id: u64) -> Option<&OrderWrapper> {
+        let order_id = self.client_order_ids.get(&(user_id, client_order_id))?;
+        self.orders.get(order_id)
+    }
+
+    /// Returns the ids of all the open orders of user_id
+    pub fn get_user_order_ids(&self, user_id: u64) -> Vec<u64> {
+        let mut order_ids: Vec<u64> = self
+            .orders
+            .values()
+            .filter(|wrapper| wrapper.user_id == user_id)
+            .map(|wrapper| wrapper.order_id)
+            .collect();
+        order_ids.sort_unstable();
+
+        order_ids
+    }
+
     // *-----------------------------------------------------------------------------
     pub fn get_tab_mutex(&self, tab_hash: &BigUint) -> Option<Arc<Mutex<OrderTab>>> {
         for (_, ord_) in self.orders.iter() {
@@ -251,6 +332,8 @@ impl OrderQueue {
         let order = self.orders.get_mut(&id).unwrap();
 
         order.qty_left += increase_qty;
+
+        self.update_level_qty(id);
     }
 
     /* Internal methods */
@@ -262,12 +345,26 @@ impl OrderQueue {
         if let Some(order_id) = self.get_current_order_id() {
             if self.orders.contains_key(&order_id) {
                 self.orders.insert(order_id, new_order);
+                self.update_level_qty(order_id);
                 return true;
             }
         }
         false
     }
 
+    /// Removes the order with id along with its price level and client order id entries
+    fn remove_from_orders(&mut self, id: u64) -> Option<OrderWrapper> {
+        let wrapper = self.orders.remove(&id)?;
+        self.remove_from_level(id);
+
+        let client_key = (wrapper.user_id, wrapper.client_order_id);
+        if self.client_order_ids.get(&client_key) == Some(&id) {
+            self.client_order_ids.remove(&client_key);
+        }
+
+        Some(wrapper)
+    }
+
     /// Verify if queue should be cleaned
     fn clean_check(&mut self) {
         if self.op_counter > self.max_stalled {
@@ -307,6 +404,59 @@ impl OrderQueue {
         }
     }
 
+    /// Counts the order with id in the level of price (moving it there if it was in another one)
+    fn add_to_level(&mut self, id: u64, price: f64) {
+        self.remove_from_level(id);
+
+        let qty = match self.orders.get(&id) {
+            Some(wrapper) => wrapper.qty_left,
+            None => return,
+        };
+
+        let key = price_level_key(price);
+        let level = self.levels.entry(key).or_insert(PriceLevel {
+            price,
+            qty: 0,
+            order_count: 0,
+        });
+        level.qty += qty;
+        level.order_count += 1;
+
+        self.order_levels.insert(id, (key, qty));
+    }
+
+    /// Removes the order with id from its price level
+    fn remove_from_level(&mut self, id: u64) {
+        let (key, qty) = match self.order_levels.remove(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        if let Some(level) = self.levels.get_mut(&key) {
+            level.qty -= qty;
+            level.order_count -= 1;
+
+            if level.order_count == 0 {
+                self.levels.remove(&key);
+            }
+        }
+    }
+
+    /// Updates the price level of the order with id after its qty_left changed
+    fn update_level_qty(&mut self, id: u64) {
+        let qty = match self.orders.get(&id) {
+            Some(wrapper) => wrapper.qty_left,
+            None => return,
+        };
+
+        if let Some((key, counted_qty)) = self.order_levels.get_mut(&id) {
+            if let Some(level) = self.levels.get_mut(key) {
+                level.qty = level.qty - *counted_qty + qty;
+            }
+            *counted_qty = qty;
+        }
+    }
+
     /// Return ID of current order in queue
     fn get_current_order_id(&self) -> Option<u64> {
         let order_id = self.idx_queue.as_ref()?.peek()?;
@@ -324,7 +474,7 @@ impl OrderQueue {
         }
 
         for id in expired_order_ids {
-            self.orders.remove(&id);
+            self.remove_from_orders(id);
             self.op_counter += 1;
         }
 
@@ -367,6 +517,66 @@ impl OrderQueue {
         return book;
     }
 
+    /// Returns the resting orders in the order they would be matched (best price first and FIFO
+    /// within a price)
+    pub fn resting_orders(&self) -> Vec<&OrderWrapper> {
+        let mut idx_queue = self.idx_queue.as_ref().unwrap().clone().into_vec();
+        idx_queue.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        return idx_queue
+            .iter()
+            .rev()
+            .filter_map(|idx| self.orders.get(&idx.id))
+            .filter(|ord| ord.qty_left > 0)
+            .collect();
+    }
+
+    /// Returns the aggregated liquidity of the best `max_levels` price levels, starting from
+    /// the best price.
+    ///
+    /// If grouping is positive the prices are bucketed into multiples of grouping (bids are
+    /// rounded down and asks up). Only the price levels that end up in the result are visited.
+    pub fn depth(&self, max_levels: usize, grouping: f64) -> Vec<PriceLevel> {
+        let levels: Box<dyn Iterator<Item = &PriceLevel>> = match self.queue_side {
+            OrderSide::Bid => Box::new(self.levels.values().rev()),
+            OrderSide::Ask => Box::new(self.levels.values()),
+        };
+
+        let mut depth: Vec<PriceLevel> = Vec::with_capacity(max_levels.min(self.levels.len()));
+        for level in levels.filter(|level| level.qty > 0) {
+            let price = if grouping > 0.0 {
+                // ? Prices already on a bucket boundary shouldn't move because of rounding errors
+                let buckets = level.price / grouping;
+                match self.queue_side {
+                    OrderSide::Bid => (buckets + 1e-9).floor() * grouping,
+                    OrderSide::Ask => (buckets - 1e-9).ceil() * grouping,
+                }
+            } else {
+                level.price
+            };
+
+            match depth.last_mut() {
+                Some(last) if price_level_key(last.price) == price_level_key(price) => {
+                    last.qty += level.qty;
+                    last.order_count += level.order_count;
+                }
+                _ => {
+                    if depth.len() == max_levels {
+                        break;
+                    }
+
+                    depth.push(PriceLevel {
+                        price,
+                        qty: level.qty,
+                        order_count: level.order_count,
+                    });
+                }
+            }
+        }
+
+        return depth;
+    }
+
     // *-----------------------------------------------------------------------------
 
     /// Update the order position
@@ -398,6 +608,60 @@ impl OrderQueue {
         return;
     }
 
+    /// Resizes the reduce only orders on position_address to the current size of the position
+    ///
+    /// Orders that can't decrease the position anymore (it was closed, liquidated or flipped)
+    /// are cancelled, returns the resized and cancelled orders sorted by id
+    pub fn update_reduce_only_orders(
+        &mut self,
+        position_address: &BigUint,
+        new_position: &Option<PerpPosition>,
+    ) -> Vec<ReduceOnlyUpdate> {
+        let mut updates = vec![];
+        for (id, wrapper) in self.orders.iter_mut() {
+            let order_side = match &wrapper.order {
+                Order::Perp(ord) => ord.order_side.clone(),
+                _ => continue,
+            };
+            match wrapper.reduce_only_position() {
+                Some(pos) if pos.position_header.position_address == *position_address => {}
+                _ => continue,
+            }
+
+            match new_position {
+                Some(pos) if pos.order_side != order_side && pos.position_size > 0 => {
+                    if wrapper.qty_left > pos.position_size {
+                        wrapper.qty_left = pos.position_size;
+                        updates.push(ReduceOnlyUpdate {
+                            user_id: wrapper.user_id,
+                            order_id: *id,
+                            qty_left: pos.position_size,
+                        });
+                    }
+                }
+                _ => updates.push(ReduceOnlyUpdate {
+                    user_id: wrapper.user_id,
+                    order_id: *id,
+                    qty_left: 0,
+                }),
+            }
+        }
+
+        for update in updates.iter() {
+            if update.qty_left > 0 {
+                self.update_level_qty(update.order_id);
+            } else {
+                self.remove_from_orders(update.order_id);
+            }
+        }
+        if updates.iter().any(|update| update.qty_left == 0) {
+            self.clean_check();
+        }
+
+        updates.sort_unstable_by_key(|update| update.order_id);
+        updates
+    }
+
     /// Gets the impact price from the impact notional value
     pub fn get_impact_price(&self, impact_notional: u64) -> f64 {
         let mut sum = 0;
@@ -436,10 +700,11 @@ impl OrderQueue {
         side: OrderSide,
         qty: u64,
         user_id: u64,
+        client_order_id: u64,
     ) {
         if !self.pending_orders.contains_key(&order_id) {
             self.pending_orders
-                .insert(order_id, (sig, side, qty, user_id));
+                .insert(order_id, (sig, side, qty, user_id, client_order_id));
         }
     }
 
@@ -452,7 +717,8 @@ impl OrderQueue {
         if !self.pending_orders.contains_key(&order_id) {
             return;
         }
-        let (sig, side, qty, user_id) = self.pending_orders.remove(&order_id).unwrap();
+        let (sig, side, qty, user_id, client_order_id) =
+            self.pending_orders.remove(&order_id).unwrap();
 
         if qty <= reduce_qty || force {
             self.pending_orders.remove(&order_id);
@@ -460,7 +726,7 @@ impl OrderQueue {
             let new_qty = qty - reduce_qty;
 
             self.pending_orders
-                .insert(order_id, (sig, side, new_qty, user_id));
+                .insert(order_id, (sig, side, new_qty, user_id, client_order_id));
         }
     }
 
@@ -479,7 +745,7 @@ impl OrderQueue {
                 if !self.pending_orders.contains_key(&limit_order.order_id) {
                     return;
                 }
-                let (sig, side, _, user_id) =
+                let (sig, side, _, user_id, client_order_id) =
                     self.pending_orders.remove(&limit_order.order_id).unwrap();
 
                 let order_id = limit_order.order_id;
@@ -494,6 +760,7 @@ impl OrderQueue {
                     signature: sig,
                     order: order__,
                     user_id,
+                    client_order_id,
                 };
 
                 self.insert(order_id, price, ts, wrapper);
@@ -507,7 +774,7 @@ impl OrderQueue {
                 if !self.pending_orders.contains_key(&perp_order.order_id) {
                     return;
                 }
-                let (sig, side, _, user_id) =
+                let (sig, side, _, user_id, client_order_id) =
                     self.pending_orders.remove(&perp_order.order_id).unwrap();
 
                 let order_id = perp_order.order_id;
@@ -522,6 +789,7 @@ impl OrderQueue {
                     signature: sig,
                     order: order__,
                     user_id,
+                    client_order_id,
                 };
 
                 self.insert(order_id, price, ts, wrapper);
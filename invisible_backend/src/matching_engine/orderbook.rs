@@ -1,8 +1,10 @@
 use std::fmt::Debug;
 use std::time::{Duration, SystemTime};
 
+use num_bigint::BigUint;
+
 use crate::matching_engine::get_qty_from_quote;
-use crate::matching_engine::orders::amend_inner;
+use crate::matching_engine::orders::{amend_inner, amended_qty_left};
 use crate::perpetual::perp_order::PerpOrder;
 use crate::perpetual::perp_position::PerpPosition;
 use crate::perpetual::{DUST_AMOUNT_PER_ASSET, PRICE_DECIMALS_PER_ASSET};
@@ -13,13 +15,14 @@ use crate::transactions::limit_order::LimitOrder;
 use crate::utils::crypto_utils::Signature;
 
 use super::domain::{Order, OrderSide, OrderType, OrderWrapper};
-use super::order_queues::OrderQueue;
+use super::mm_protection::{MmProtection, MmpTrigger};
+use super::order_queues::{OrderQueue, ReduceOnlyUpdate};
 use super::orders::{link_order_tab, OrderRequest};
-use super::validation::OrderRequestValidator;
+use super::validation::{MarketParams, OrderRequestValidator};
 use super::{get_quote_qty, sequence};
 
 const MIN_SEQUENCE_ID: u64 = 1;
-const MAX_SEQUENCE_ID: u64 = 100_000;
+const MAX_SEQUENCE_ID: u64 = u64::MAX >> sequence::MARKET_ID_BITS;
 const MAX_STALLED_INDICES_IN_QUEUE: u64 = 10;
 const ORDER_QUEUE_INIT_CAPACITY: usize = 500;
 
@@ -69,11 +72,22 @@ pub enum Success {
 pub enum Failed {
     ValidationFailed(String),
     DuplicateOrderID(u64),
+    DuplicateClientOrderID(u64),
     NoMatch(u64),
     OrderNotFound(u64),
     TooMuchSlippage(u64),
 }
 
+/// A fill a new order would get against a resting order, without changing the book
+/// (see `simulate_perp_order`)
+#[derive(Debug, Clone)]
+pub struct SimulatedFill {
+    pub maker_order: Order,
+    pub maker_user_id: u64,
+    pub price: f64,
+    pub qty: u64,
+}
+
 pub struct OrderBook {
     pub order_asset: u32,
     pub price_asset: u32,
@@ -82,6 +96,8 @@ pub struct OrderBook {
     seq: sequence::TradeSequence,
     order_validator: OrderRequestValidator,
     pub market_id: u16, // This is used to prepend the order id with a unique number for each orderbook
+    pub mm_protection: MmProtection,
+    reduce_only_updates: Vec<ReduceOnlyUpdate>, // resized and cancelled reduce only orders that haven't been reported yet
 }
 
 impl OrderBook {
@@ -107,16 +123,60 @@ impl OrderBook {
                 MAX_SEQUENCE_ID,
             ),
             market_id,
+            mm_protection: MmProtection::new(),
+            reduce_only_updates: Vec::new(),
         }
     }
 
+    /// The tick size, lot size, minimum notional and maximum order size of the market
+    pub fn market_params(&self) -> MarketParams {
+        self.order_validator.market_params
+    }
+
+    /// Runs the checks of process_order without placing, amending or cancelling anything
+    ///
+    /// # Arguments
+    /// * `cancelled_ids` - Orders that are cancelled before the request is processed (their
+    ///   client order ids are free to be reused)
+    pub fn check_order_request(
+        &self,
+        order: &OrderRequest,
+        cancelled_ids: &[u64],
+    ) -> Result<(), Failed> {
+        if let Err(reason) = self.order_validator.validate(order) {
+            return Err(Failed::ValidationFailed(String::from(reason)));
+        }
+
+        if let OrderRequest::NewLimitOrder {
+            order, is_market, ..
+        } = order
+        {
+            // ? Client order ids must be unique among the user's open orders
+            if order.client_order_id != 0 {
+                let duplicate = self.get_order_by_client_id(order.user_id, order.client_order_id);
+                if duplicate.is_some_and(|o| !cancelled_ids.contains(&o.order_id)) {
+                    return Err(Failed::DuplicateClientOrderID(order.client_order_id));
+                }
+            }
+
+            // ? Users whose market maker protection was triggered can only take liquidity
+            if !is_market && self.mm_protection.is_frozen(order.user_id) {
+                return Err(Failed::ValidationFailed(String::from(
+                    "Market maker protection was triggered, reset it to place new orders",
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn process_order(&mut self, order: OrderRequest) -> OrderProcessingResult {
         // processing result accumulator
         let mut proc_result: OrderProcessingResult = vec![];
 
         // validate request
-        if let Err(reason) = self.order_validator.validate(&order) {
-            proc_result.push(Err(Failed::ValidationFailed(String::from(reason))));
+        if let Err(failed) = self.check_order_request(&order, &[]) {
+            proc_result.push(Err(failed));
             return proc_result;
         }
 
@@ -132,9 +192,27 @@ impl OrderBook {
                 ts,
                 is_market,
             } => {
-                let seq_id = self.seq.next_id();
+                // ? Reduce only orders can't be larger than the position they decrease
+                let (qty, quote_qty) = match order.reduce_only_position().map(|p| p.position_size) {
+                    Some(position_size) if position_size < qty => {
+                        order.qty_left = position_size;
+                        let quote_qty =
+                            (quote_qty as u128 * position_size as u128 / qty as u128) as u64;
+
+                        (position_size, quote_qty)
+                    }
+                    _ => (qty, quote_qty),
+                };
 
-                let order_id = (seq_id as u64) * 2_u64.pow(16) + self.market_id as u64;
+                let seq_id = match self.seq.next_id(sequence::clock_id()) {
+                    Ok(seq_id) => seq_id,
+                    Err(reason) => {
+                        proc_result.push(Err(Failed::ValidationFailed(String::from(reason))));
+                        return proc_result;
+                    }
+                };
+
+                let order_id = sequence::order_id_from_seq(seq_id, self.market_id);
 
                 proc_result.push(Ok(Success::Accepted {
                     id: order_id,
@@ -173,6 +251,7 @@ impl OrderBook {
                 side,
                 new_price,
                 new_expiration,
+                new_qty,
                 signature,
                 user_id,
                 match_only,
@@ -183,6 +262,7 @@ impl OrderBook {
                     side,
                     new_price,
                     new_expiration,
+                    new_qty,
                     signature,
                     user_id,
                     match_only,
@@ -190,6 +270,8 @@ impl OrderBook {
             }
         }
 
+        self.apply_mm_protection(&proc_result);
+
         // return collected processing results
         proc_result
     }
@@ -555,6 +637,7 @@ impl OrderBook {
         side: OrderSide,
         new_price: f64,
         new_expiration: u64,
+        new_qty: u64,
         signature: Signature,
         user_id: u64,
         match_only: bool, // if true then only match the order and don't amend it
@@ -564,15 +647,74 @@ impl OrderBook {
             OrderSide::Ask => &mut self.ask_queue,
         };
 
+        let (prev_price, prev_qty, prev_qty_left) = match order_queue.get_order(order_id) {
+            Some(wrapper) if wrapper.user_id == user_id => {
+                let prev_price = wrapper.order.get_price(side, None);
+                let (prev_qty, _) = wrapper.order.get_base_and_quote_qty(side, prev_price);
+
+                (prev_price, prev_qty, wrapper.qty_left)
+            }
+            _ => {
+                results.push(Err(Failed::OrderNotFound(order_id)));
+                return;
+            }
+        };
+
+        let new_price = if new_price == 0.0 {
+            prev_price
+        } else {
+            new_price
+        };
+
+        let new_qty_left = if new_qty == 0 {
+            prev_qty_left
+        } else if match_only {
+            results.push(Err(Failed::ValidationFailed(
+                "The quantity can't be amended for match only amends".to_string(),
+            )));
+            return;
+        } else {
+            match amended_qty_left(prev_qty, prev_qty_left, new_qty) {
+                Some(qty_left) => qty_left,
+                None => {
+                    results.push(Err(Failed::ValidationFailed(format!(
+                        "New quantity {} must exceed the filled quantity {}",
+                        new_qty,
+                        prev_qty - prev_qty_left
+                    ))));
+                    return;
+                }
+            }
+        };
+
+        // ? Reducing the quantity at the same price keeps the order's time priority
+        if new_price == prev_price && new_qty > 0 && new_qty < prev_qty {
+            order_queue.amend_in_place(
+                order_id,
+                user_id,
+                new_price,
+                new_expiration,
+                new_qty,
+                new_qty_left,
+                signature,
+            );
+
+            results.push(Ok(Success::Amended {
+                id: order_id,
+                new_price,
+                ts: SystemTime::now(),
+            }));
+            return;
+        }
+
         let qty_left: u64;
-        let prev_price: f64;
         let prev_signature: Signature;
         let mut order_wrapper: OrderWrapper;
         if let Some(mut wrapper) = order_queue.remove_order(order_id, user_id, false) {
-            prev_price = wrapper.order.get_price(side, None);
             prev_signature = wrapper.signature.clone();
 
-            amend_inner(&mut wrapper, new_price, new_expiration, signature);
+            amend_inner(&mut wrapper, new_price, new_expiration, new_qty, signature);
+            wrapper.qty_left = new_qty_left;
 
             let ts = SystemTime::now();
 
@@ -620,6 +762,7 @@ impl OrderBook {
                     &mut order_wrapper,
                     prev_price,
                     new_expiration,
+                    0,
                     prev_signature,
                 );
             }
@@ -648,7 +791,166 @@ impl OrderBook {
         }
     }
 
+    /// Finds an open order by the id the user assigned to it
+    pub fn get_order_by_client_id(
+        &self,
+        user_id: u64,
+        client_order_id: u64,
+    ) -> Option<OrderWrapper> {
+        if let Some(wrapper) = self.bid_queue.get_client_order(user_id, client_order_id) {
+            Some(wrapper.clone())
+        } else if let Some(wrapper) = self.ask_queue.get_client_order(user_id, client_order_id) {
+            Some(wrapper.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the ids and sides of all the open orders of user_id
+    pub fn get_user_orders(&self, user_id: u64) -> Vec<(u64, OrderSide)> {
+        let bids = self.bid_queue.get_user_order_ids(user_id);
+        let asks = self.ask_queue.get_user_order_ids(user_id);
+
+        bids.into_iter()
+            .map(|id| (id, OrderSide::Bid))
+            .chain(asks.into_iter().map(|id| (id, OrderSide::Ask)))
+            .collect()
+    }
+
+    /// Walks the opposite side of the book like `process_order_internal` would for a new perp
+    /// order, but only reads the book. The order doesn't have to be signed yet.
+    ///
+    /// ### Returns:
+    /// * the fills the order would get and the qty that would be left unfilled
+    pub fn simulate_perp_order(
+        &self,
+        perp_order: &PerpOrder,
+        is_market: bool,
+    ) -> Result<(Vec<SimulatedFill>, u64), String> {
+        let side: OrderSide = perp_order.order_side.clone().into();
+        let order = Order::Perp(perp_order.clone());
+
+        self.order_validator
+            .validate_unsigned_perp_order(side, &order, is_market)
+            .map_err(|e| e.to_string())?;
+
+        let price = order.get_price(side, Some(side == OrderSide::Ask));
+
+        // ? Reduce only orders can't be larger than the position they decrease
+        let mut qty_left = perp_order.synthetic_amount;
+        if perp_order.reduce_only {
+            if let Some(position) = &perp_order.position {
+                qty_left = std::cmp::min(qty_left, position.position_size);
+            }
+        }
+
+        let opposite_queue = match side {
+            OrderSide::Bid => &self.ask_queue,
+            OrderSide::Ask => &self.bid_queue,
+        };
+
+        let mut fills: Vec<SimulatedFill> = Vec::new();
+        for opposite_order in opposite_queue.resting_orders() {
+            if qty_left == 0 {
+                break;
+            }
+
+            // ? Expired orders would be cancelled when they are matched
+            if opposite_order.order.has_expired() {
+                continue;
+            }
+
+            // ? Matching stops at an order updating the same position
+            if let Order::Perp(opposite_perp_order) = &opposite_order.order {
+                if perp_order.position.is_some() && opposite_perp_order.position.is_some() {
+                    if perp_order.position.as_ref().unwrap().hash
+                        == opposite_perp_order.position.as_ref().unwrap().hash
+                    {
+                        break;
+                    }
+                }
+            }
+
+            let could_be_matched = match side {
+                OrderSide::Bid => {
+                    price
+                        >= opposite_order
+                            .order
+                            .get_price(opposite_order.order_side, Some(true))
+                }
+                OrderSide::Ask => {
+                    price
+                        <= opposite_order
+                            .order
+                            .get_price(opposite_order.order_side, Some(false))
+                }
+            };
+            if !could_be_matched {
+                break;
+            }
+
+            let qty = std::cmp::min(qty_left, opposite_order.qty_left);
+            qty_left -= qty;
+
+            fills.push(SimulatedFill {
+                maker_order: opposite_order.order.clone(),
+                maker_user_id: opposite_order.user_id,
+                price: opposite_order
+                    .order
+                    .get_price(opposite_order.order_side, Some(side == OrderSide::Bid)),
+                qty,
+            });
+        }
+
+        return Ok((fills, qty_left));
+    }
+
     /* Helpers */
+
+    /// Records the fills of the users with market maker protection and pulls all the open orders
+    /// of the ones that went over their limits.
+    ///
+    /// Fills of retried orders are not recorded again, as they replace fills that were already
+    /// counted when the order was first matched.
+    fn apply_mm_protection(&mut self, results: &OrderProcessingResult) {
+        if self.mm_protection.is_empty() {
+            return;
+        }
+
+        let mut triggered_users: Vec<u64> = Vec::new();
+        for res in results.iter() {
+            if let Ok(Success::Filled {
+                side,
+                qty,
+                ts,
+                user_id,
+                ..
+            }) = res
+            {
+                if self.mm_protection.record_fill(*user_id, *side, *qty, *ts) {
+                    triggered_users.push(*user_id);
+                }
+            }
+        }
+
+        for user_id in triggered_users {
+            let mut cancelled_order_ids = Vec::new();
+            for (order_id, side) in self.get_user_orders(user_id) {
+                let mut cancel_results: OrderProcessingResult = vec![];
+                self.process_order_cancel(&mut cancel_results, order_id, side, user_id);
+
+                if let Some(Ok(Success::Cancelled { .. })) = cancel_results.first() {
+                    cancelled_order_ids.push(order_id);
+                }
+            }
+
+            self.mm_protection.push_trigger(MmpTrigger {
+                user_id,
+                cancelled_order_ids,
+            });
+        }
+    }
+
     fn store_new_limit_order(
         &mut self,
         results: &mut OrderProcessingResult,
@@ -755,6 +1057,7 @@ impl OrderBook {
                 order: opposite_order.order,
                 signature: opposite_order.signature.clone(),
                 user_id: opposite_order.user_id,
+                client_order_id: opposite_order.client_order_id,
             };
 
             opposite_queue.modify_current_order(modified_order);
@@ -766,6 +1069,7 @@ impl OrderBook {
                 opposite_order.order_side,
                 opposite_order.qty_left,
                 opposite_order.user_id,
+                opposite_order.client_order_id,
             );
             same_queue.store_pending_order(
                 order_id,
@@ -773,6 +1077,7 @@ impl OrderBook {
                 side,
                 qty,
                 order.user_id,
+                order.client_order_id,
             );
 
             return true;
@@ -844,6 +1149,7 @@ impl OrderBook {
                 opposite_order.order_side,
                 opposite_order.qty_left,
                 opposite_order.user_id,
+                opposite_order.client_order_id,
             );
             same_queue.store_pending_order(
                 order_id,
@@ -851,6 +1157,7 @@ impl OrderBook {
                 side,
                 qty,
                 order.user_id,
+                order.client_order_id,
             );
 
             // matching incomplete
@@ -919,8 +1226,16 @@ impl OrderBook {
                 opposite_order.order_side,
                 opposite_order.qty_left,
                 opposite_order.user_id,
+                opposite_order.client_order_id,
+            );
+            same_queue.store_pending_order(
+                order_id,
+                order.signature.clone(),
+                side,
+                qty,
+                order.user_id,
+                order.client_order_id,
             );
-            same_queue.store_pending_order(order_id, order.signature.clone(), side, qty, order_id);
         }
 
         // complete matching
@@ -1001,6 +1316,7 @@ impl OrderBook {
                 opposite_order.order_side,
                 opposite_order.qty_left,
                 opposite_order.user_id,
+                opposite_order.client_order_id,
             );
 
             // matching incomplete
@@ -1066,6 +1382,7 @@ impl OrderBook {
                 order: opposite_order.order,
                 signature: opposite_order.signature.clone(),
                 user_id: opposite_order.user_id,
+                client_order_id: opposite_order.client_order_id,
             };
 
             opposite_queue.modify_current_order(modified_order);
@@ -1077,6 +1394,7 @@ impl OrderBook {
                 opposite_order.order_side,
                 opposite_order.qty_left,
                 opposite_order.user_id,
+                opposite_order.client_order_id,
             );
 
             return true;
@@ -1141,6 +1459,7 @@ impl OrderBook {
                 opposite_order.order_side,
                 opposite_order.qty_left,
                 opposite_order.user_id,
+                opposite_order.client_order_id,
             );
             return true;
         }
@@ -1154,6 +1473,29 @@ impl OrderBook {
         self.ask_queue.update_order_position(user_id, new_position);
     }
 
+    /// Resizes the reduce only orders on position_address after the position decreased and cancels
+    /// the ones that can't decrease it anymore, the changes are kept until they are reported
+    pub fn update_reduce_only_orders(
+        &mut self,
+        position_address: &BigUint,
+        new_position: &Option<PerpPosition>,
+    ) {
+        let bid_updates = self
+            .bid_queue
+            .update_reduce_only_orders(position_address, new_position);
+        let ask_updates = self
+            .ask_queue
+            .update_reduce_only_orders(position_address, new_position);
+
+        self.reduce_only_updates.extend(bid_updates);
+        self.reduce_only_updates.extend(ask_updates);
+    }
+
+    /// Returns the reduce only updates that haven't been reported to the users yet
+    pub fn take_reduce_only_updates(&mut self) -> Vec<ReduceOnlyUpdate> {
+        std::mem::take(&mut self.reduce_only_updates)
+    }
+
     /// * Restore the orderbook (in case of server restarts)
     pub fn restore_spot_order_book(
         &mut self,
@@ -1205,7 +1547,7 @@ impl OrderBook {
             self._restore_spot_inner(order, OrderSide::Ask);
         }
 
-        let max_seq_id = max_order_id / 2_u64.pow(16);
+        let max_seq_id = sequence::seq_id_from_order_id(max_order_id);
 
         self.seq.set_id(max_seq_id + 1);
     }
@@ -1253,7 +1595,7 @@ impl OrderBook {
             self._restore_perp_inner(order, OrderSide::Ask);
         }
 
-        let max_seq_id = max_order_id / 2_u64.pow(16);
+        let max_seq_id = sequence::seq_id_from_order_id(max_order_id);
 
         self.seq.set_id(max_seq_id + 1);
     }
@@ -1275,6 +1617,7 @@ impl OrderBook {
         )
         .unwrap();
         let user_id = order.order.as_ref().unwrap().user_id;
+        let client_order_id = order.order.as_ref().unwrap().client_order_id;
 
         if let Ok(mut limit_order) = LimitOrder::try_from(order.order.unwrap()) {
             let order_id = order.order_id;
@@ -1298,6 +1641,7 @@ impl OrderBook {
                 qty_left: amount,
                 signature,
                 user_id,
+                client_order_id,
             };
 
             if order_side == OrderSide::Bid {
@@ -1325,6 +1669,7 @@ impl OrderBook {
         )
         .unwrap();
         let user_id = order.order.as_ref().unwrap().user_id;
+        let client_order_id = order.order.as_ref().unwrap().client_order_id;
 
         if let Ok(perp_order) = PerpOrder::try_from(order.order.unwrap()) {
             let order_id = order.order_id;
@@ -1339,6 +1684,7 @@ impl OrderBook {
                 qty_left: amount,
                 signature,
                 user_id,
+                client_order_id,
             };
 
             if order_side == OrderSide::Bid {
@@ -28,8 +28,9 @@ pub enum OrderRequest {
     AmendOrder {
         id: u64,
         side: OrderSide,
-        new_price: f64,
+        new_price: f64, // 0 keeps the current price
         new_expiration: u64,
+        new_qty: u64, // new total quantity in the order asset, 0 keeps the current quantity
         signature: Signature,
         user_id: u64,
         match_only: bool,
@@ -50,6 +51,7 @@ pub fn new_limit_order_request(
     ts: SystemTime,
     is_market: bool,
     user_id: u64,
+    client_order_id: u64,
 ) -> OrderRequest {
     let (order_asset, price_asset) = order.get_order_and_price_assets(side);
 
@@ -64,6 +66,7 @@ pub fn new_limit_order_request(
         order_id: 0,
         order_side: side,
         user_id,
+        client_order_id,
     };
 
     OrderRequest::NewLimitOrder {
@@ -95,6 +98,7 @@ pub fn new_amend_order(
     user_id: u64,
     new_price: f64,
     new_expiration: u64,
+    new_qty: u64,
     signature: Signature,
     match_only: bool,
 ) -> OrderRequest {
@@ -103,6 +107,7 @@ pub fn new_amend_order(
         side,
         new_price,
         new_expiration,
+        new_qty,
         signature,
         user_id,
         match_only,
@@ -110,12 +115,31 @@ pub fn new_amend_order(
 }
 
 /// Amend an order
+///
+/// If new_qty is not 0 the size of the order is set to new_qty (in the order asset) before
+/// the amounts are recomputed at the new price.
 pub fn amend_inner(
     wrapper: &mut OrderWrapper,
     price: f64,
     new_expiration: u64,
+    new_qty: u64,
     signature: Signature,
 ) {
+    if new_qty > 0 {
+        match &mut wrapper.order {
+            Order::Spot(ord) => {
+                ord.amount_spent = if wrapper.order_side == OrderSide::Bid {
+                    get_quote_qty(new_qty, price, ord.token_received, ord.token_spent, None)
+                } else {
+                    new_qty
+                };
+            }
+            Order::Perp(ord) => {
+                ord.synthetic_amount = new_qty;
+            }
+        }
+    }
+
     if wrapper.order_side == OrderSide::Bid {
         match &mut wrapper.order {
             Order::Spot(ord) => {
@@ -171,6 +195,15 @@ pub fn amend_inner(
     wrapper.signature = signature;
 }
 
+/// Returns the quantity left of an order after its total quantity is amended from prev_qty
+/// to new_qty, keeping what has already been filled. \
+/// Returns None if the new quantity doesn't exceed the filled quantity.
+pub fn amended_qty_left(prev_qty: u64, qty_left: u64, new_qty: u64) -> Option<u64> {
+    let filled_qty = prev_qty.saturating_sub(qty_left);
+
+    new_qty.checked_sub(filled_qty).filter(|qty| *qty > 0)
+}
+
 // * =======================================================================================
 
 /// If multiple orders are using the same order tab link them with a mutex object
@@ -1,4 +1,13 @@
-// Rotating index sequence
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of low bits of an order id reserved for the market id
+pub const MARKET_ID_BITS: u32 = 16;
+
+// Monotonic index sequence
+//
+// The sequence never goes below the current unix time in milliseconds, so ids handed out after
+// a restart are always greater than the ones handed out before it (as long as the previous run
+// didn't average more than one id per millisecond since it started).
 pub struct TradeSequence {
     min_id: u64,
     max_id: u64,
@@ -6,24 +15,24 @@ pub struct TradeSequence {
 }
 
 impl TradeSequence {
-    pub fn next_id(&mut self) -> u64 {
-        let next_id = self.current_idx;
-
-        // update index
-        if (next_id + 1) <= self.max_id {
-            self.current_idx += 1;
-        } else {
-            self.current_idx = self.min_id;
+    /// Returns the next id, which is at least `clock_id` (the current unix time in milliseconds)
+    pub fn next_id(&mut self, clock_id: u64) -> Result<u64, &'static str> {
+        let next_id = std::cmp::max(self.current_idx, clock_id).max(self.min_id);
+
+        // ? 48 bits of milliseconds last until the year 10889, so this is never expected to happen
+        if next_id > self.max_id {
+            return Err("Order id sequence exhausted");
         }
 
-        next_id
+        self.current_idx = next_id + 1;
+
+        Ok(next_id)
     }
 
+    /// Makes sure the next id is at least `id` (the sequence never moves backwards)
     pub fn set_id(&mut self, id: u64) {
-        if id <= self.max_id {
+        if id > self.current_idx {
             self.current_idx = id;
-        } else {
-            self.current_idx = self.min_id;
         }
     }
 }
@@ -36,28 +45,80 @@ pub fn new_sequence_gen(min: u64, max: u64) -> TradeSequence {
     }
 }
 
+/// order_id = seq_id << 16 | market_id
+pub fn order_id_from_seq(seq_id: u64, market_id: u16) -> u64 {
+    (seq_id << MARKET_ID_BITS) | market_id as u64
+}
+
+pub fn seq_id_from_order_id(order_id: u64) -> u64 {
+    order_id >> MARKET_ID_BITS
+}
+
+pub fn market_id_from_order_id(order_id: u64) -> u16 {
+    (order_id & ((1 << MARKET_ID_BITS) - 1)) as u16
+}
+
+pub fn clock_id() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const CLOCK_ID: u64 = 1_700_000_000_000;
+
     #[test]
-    fn seq_from_zero() {
-        let mut seq_gen = new_sequence_gen(0, 2);
-
-        assert_eq!(seq_gen.current_idx, 0);
-        assert_eq!(seq_gen.next_id(), 0);
-        assert_eq!(seq_gen.next_id(), 1);
-        assert_eq!(seq_gen.next_id(), 2);
-        assert_eq!(seq_gen.next_id(), 0);
+    fn seq_is_strictly_increasing() {
+        let mut seq_gen = new_sequence_gen(1, u64::MAX >> MARKET_ID_BITS);
+
+        let first = seq_gen.next_id(CLOCK_ID).unwrap();
+        assert_eq!(first, CLOCK_ID);
+
+        // ? Ids keep increasing when more than one is handed out per millisecond
+        let mut prev = first;
+        for i in 0..100_000 {
+            let id = seq_gen.next_id(CLOCK_ID + i / 10).unwrap();
+            assert!(id > prev);
+            prev = id;
+        }
+
+        // ? and jump ahead with the clock
+        assert_eq!(
+            seq_gen.next_id(CLOCK_ID + 1_000_000).unwrap(),
+            CLOCK_ID + 1_000_000
+        );
+    }
+
+    #[test]
+    fn seq_never_moves_backwards() {
+        let mut seq_gen = new_sequence_gen(1, u64::MAX >> MARKET_ID_BITS);
+
+        // ? Ids restored from before a restart that are ahead of the clock
+        let restored_id = CLOCK_ID + 1_000_000;
+        seq_gen.set_id(restored_id + 1);
+        assert_eq!(seq_gen.next_id(CLOCK_ID).unwrap(), restored_id + 1);
+
+        seq_gen.set_id(5);
+        assert_eq!(seq_gen.next_id(CLOCK_ID).unwrap(), restored_id + 2);
+    }
+
+    #[test]
+    fn exhausted_seq_returns_an_error() {
+        let mut seq_gen = new_sequence_gen(1, CLOCK_ID);
+
+        assert_eq!(seq_gen.next_id(CLOCK_ID), Ok(CLOCK_ID));
+        assert!(seq_gen.next_id(CLOCK_ID).is_err());
     }
 
     #[test]
-    fn seq_from_positive() {
-        let mut seq_gen = new_sequence_gen(1, 2);
+    fn order_id_layout() {
+        let order_id = order_id_from_seq(1_700_000_000_000, 21);
 
-        assert_eq!(seq_gen.current_idx, 1);
-        assert_eq!(seq_gen.next_id(), 1);
-        assert_eq!(seq_gen.next_id(), 2);
-        assert_eq!(seq_gen.next_id(), 1);
+        assert_eq!(seq_id_from_order_id(order_id), 1_700_000_000_000);
+        assert_eq!(market_id_from_order_id(order_id), 21);
     }
 }
@@ -1,9 +1,18 @@
+use crate::perpetual::open_interest::{max_position_size, owner_position_size, size_after_order};
+use crate::perpetual::perp_order::PerpOrder;
+use crate::perpetual::perp_position::PerpPosition;
 use crate::perpetual::{
-    PositionEffectType, ASSETS, COLLATERAL_TOKEN, DUST_AMOUNT_PER_ASSET, SYNTHETIC_ASSETS,
+    get_collateral_amount, get_price, PositionEffectType, ASSETS, COLLATERAL_TOKEN,
+    DUST_AMOUNT_PER_ASSET, LOT_SIZE_PER_ASSET, MAX_ORDER_SIZE_PER_ASSET, MIN_NOTIONAL_PER_ASSET,
+    PRICE_DECIMALS_PER_ASSET, SYNTHETIC_ASSETS, TICK_SIZE_PER_ASSET,
 };
 use crate::utils::crypto_utils::Signature;
 
-use super::{domain::Order, orders::OrderRequest};
+use super::{
+    domain::{Order, OrderSide},
+    orders::OrderRequest,
+    sequence::seq_id_from_order_id,
+};
 
 /// Validation errors
 const ERR_BAD_ORDER_ASSET: &str = "bad order asset";
@@ -11,6 +20,82 @@ const ERR_BAD_PRICE_ASSET: &str = "bad price asset";
 const ERR_BAD_PRICE_VALUE: &str = "price must be non-negative";
 const ERR_EXPIRED_ORDER: &str = "order has expired";
 const ERR_BAD_SEQ_ID: &str = "order ID out of range";
+const ERR_BAD_TICK: &str = "price must be a multiple of the tick size";
+const ERR_BAD_LOT: &str = "order size must be a multiple of the lot size";
+const ERR_MIN_NOTIONAL: &str = "order value is below the minimum notional";
+const ERR_MAX_ORDER_SIZE: &str = "order size is above the maximum order size";
+const ERR_MAX_POSITION_SIZE: &str = "position size would be above the maximum position size";
+const ERR_REDUCE_ONLY_POSITION: &str = "reduce only orders must update an existing position";
+const ERR_REDUCE_ONLY_SIDE: &str =
+    "reduce only orders must be on the opposite side of the position";
+
+/// Trading parameters of a market (the same for the spot and perp markets of a base asset)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarketParams {
+    pub tick_size: u64,         // in price units (with price_decimals decimals)
+    pub price_decimals: u8,     // PRICE_DECIMALS_PER_ASSET of the base asset
+    pub lot_size: u64,          // in base asset units
+    pub min_notional: u64,      // in quote asset units
+    pub max_order_size: u64,    // in base asset units
+    pub max_position_size: u64, // in base asset units (perp markets only)
+}
+
+impl MarketParams {
+    /// The parameters of the markets of base_asset (assets without any are not restricted)
+    pub fn for_asset(base_asset: u32) -> Self {
+        let asset = base_asset.to_string();
+
+        MarketParams {
+            tick_size: *TICK_SIZE_PER_ASSET.get(&asset).unwrap_or(&1),
+            price_decimals: *PRICE_DECIMALS_PER_ASSET.get(&asset).unwrap_or(&0),
+            lot_size: *LOT_SIZE_PER_ASSET.get(&asset).unwrap_or(&1),
+            min_notional: *MIN_NOTIONAL_PER_ASSET.get(&asset).unwrap_or(&0),
+            max_order_size: *MAX_ORDER_SIZE_PER_ASSET.get(&asset).unwrap_or(&u64::MAX),
+            max_position_size: max_position_size(base_asset),
+        }
+    }
+
+    /// Checks that a price (as a float) is on a tick
+    pub fn is_on_tick(&self, price: f64) -> bool {
+        let tick_size = self.tick_size as f64 / 10_f64.powi(self.price_decimals as i32);
+        let ticks = price / tick_size;
+
+        (ticks - ticks.round()).abs() < 1e-6
+    }
+
+    /// Checks that the positions of the order's owner in the market stay within the max position
+    /// size once the order is fully filled (closing orders are always accepted)
+    ///
+    /// # Arguments
+    /// * `owner_positions` - The positions at the owner's address (the order book only knows
+    ///   the position updated by the order and passes none)
+    pub fn validate_owner_position_size(
+        &self,
+        owner_positions: &[PerpPosition],
+        perp_order: &PerpOrder,
+    ) -> Result<(), &'static str> {
+        if perp_order.position_effect_type == PositionEffectType::Close {
+            return Ok(());
+        }
+
+        let position_size = size_after_order(
+            perp_order.position.as_ref(),
+            &perp_order.order_side,
+            perp_order.synthetic_amount,
+        );
+        let total_size = owner_position_size(
+            owner_positions,
+            perp_order.synthetic_token,
+            perp_order.position.as_ref().map(|pos| pos.index),
+            position_size,
+        );
+        if total_size > self.max_position_size {
+            return Err(ERR_MAX_POSITION_SIZE);
+        }
+
+        Ok(())
+    }
+}
 
 /* Validators */
 
@@ -19,6 +104,7 @@ pub struct OrderRequestValidator {
     orderbook_price_asset: u32,
     min_sequence_id: u64,
     max_sequence_id: u64,
+    pub market_params: MarketParams,
 }
 
 impl OrderRequestValidator {
@@ -33,6 +119,7 @@ impl OrderRequestValidator {
             orderbook_price_asset,
             min_sequence_id,
             max_sequence_id,
+            market_params: MarketParams::for_asset(orderbook_order_asset),
         }
     }
 
@@ -41,30 +128,69 @@ impl OrderRequestValidator {
             OrderRequest::NewLimitOrder {
                 order_asset,
                 price_asset,
-                side: _side,
+                side,
                 price,
                 qty,
                 order,
+                is_market,
                 ..
             } => {
                 if *price <= 0.0 {
                     return Err(ERR_BAD_PRICE_VALUE);
                 };
-                return self.validate_order(
+                self.validate_order(
                     *order_asset,
                     *price_asset,
                     *qty,
                     &order.order,
-                    &order.signature,
-                );
+                    Some(&order.signature),
+                )?;
+
+                return self.validate_market_params(*side, &order.order, *is_market);
             }
 
             OrderRequest::CancelOrder { id, .. } => self.validate_cancel(*id),
 
-            OrderRequest::AmendOrder { id, .. } => self.validate_cancel(*id),
+            OrderRequest::AmendOrder {
+                id,
+                new_price,
+                new_qty,
+                ..
+            } => {
+                self.validate_cancel(*id)?;
+
+                let params = &self.market_params;
+                if *new_price != 0.0 && !params.is_on_tick(*new_price) {
+                    return Err(ERR_BAD_TICK);
+                }
+                if *new_qty % params.lot_size != 0 {
+                    return Err(ERR_BAD_LOT);
+                }
+                if *new_qty > params.max_order_size {
+                    return Err(ERR_MAX_ORDER_SIZE);
+                }
+
+                Ok(())
+            }
         }
     }
 
+    /// Validates a new perp order that hasn't been signed yet, with the same checks as `validate`
+    /// except for the signature (used to simulate orders before they are submitted)
+    pub fn validate_unsigned_perp_order(
+        &self,
+        side: OrderSide,
+        order: &Order,
+        is_market: bool,
+    ) -> Result<(), &str> {
+        let (order_asset, price_asset) = order.get_order_and_price_assets(side);
+        let (qty, _) = order.get_base_and_quote_qty(side, 0.0);
+
+        self.validate_order(order_asset, price_asset, qty, order, None)?;
+
+        return self.validate_market_params(side, order, is_market);
+    }
+
     /* Internal validators */
 
     fn validate_order(
@@ -73,7 +199,7 @@ impl OrderRequestValidator {
         price_asset: u32,
         _qty: u64,
         order: &Order,
-        signature: &Signature,
+        signature: Option<&Signature>,
     ) -> Result<(), &str> {
         if self.orderbook_order_asset != order_asset {
             return Err(ERR_BAD_ORDER_ASSET);
@@ -107,15 +233,18 @@ impl OrderRequestValidator {
                 }
 
                 // ? Check that the signature is valid
-                let order_tab_lock = if limit_order.order_tab.is_some() {
-                    Some(limit_order.order_tab.as_ref().unwrap().lock().clone())
-                } else {
-                    None
-                };
-                if let Err(_e) = limit_order.verify_order_signature(&signature, &order_tab_lock) {
-                    return Err("Invalid signature");
+                if let Some(signature) = signature {
+                    let order_tab_lock = if limit_order.order_tab.is_some() {
+                        Some(limit_order.order_tab.as_ref().unwrap().lock().clone())
+                    } else {
+                        None
+                    };
+                    if let Err(_e) = limit_order.verify_order_signature(signature, &order_tab_lock)
+                    {
+                        return Err("Invalid signature");
+                    }
+                    drop(order_tab_lock);
                 }
-                drop(order_tab_lock);
 
                 if limit_order.spot_note_info.is_some() {
                     let note_info = limit_order.spot_note_info.as_ref().unwrap();
@@ -174,6 +303,17 @@ impl OrderRequestValidator {
                     return Err("Order amount is too small");
                 }
 
+                // ? Reduce only orders can only decrease the position they reference
+                if perp_order.reduce_only {
+                    match &perp_order.position {
+                        Some(pos) if pos.order_side == perp_order.order_side => {
+                            return Err(ERR_REDUCE_ONLY_SIDE);
+                        }
+                        Some(_) => {}
+                        None => return Err(ERR_REDUCE_ONLY_POSITION),
+                    }
+                }
+
                 match perp_order.position_effect_type {
                     PositionEffectType::Open => {
                         if COLLATERAL_TOKEN
@@ -187,8 +327,10 @@ impl OrderRequestValidator {
                         }
 
                         // ? Verify order signature
-                        if let Err(_) = perp_order.verify_order_signature(signature, None) {
-                            return Err("Invalid signature");
+                        if let Some(signature) = signature {
+                            if let Err(_) = perp_order.verify_order_signature(signature, None) {
+                                return Err("Invalid signature");
+                            }
                         }
 
                         let mut spent_indexes: Vec<u64> = Vec::new();
@@ -223,11 +365,13 @@ impl OrderRequestValidator {
                     PositionEffectType::Modify => {
                         if let Some(pos) = &perp_order.position {
                             // ? Verify order signature
-                            if let Err(_) = perp_order.verify_order_signature(
-                                signature,
-                                Some(&pos.position_header.position_address),
-                            ) {
-                                return Err("Invalid signature");
+                            if let Some(signature) = signature {
+                                if let Err(_) = perp_order.verify_order_signature(
+                                    signature,
+                                    Some(&pos.position_header.position_address),
+                                ) {
+                                    return Err("Invalid signature");
+                                }
                             }
                         } else {
                             return Err("Position to update is undefined");
@@ -255,11 +399,13 @@ impl OrderRequestValidator {
                     PositionEffectType::Close => {
                         if let Some(pos) = &perp_order.position {
                             // ? Verify order signature
-                            if let Err(_) = perp_order.verify_order_signature(
-                                signature,
-                                Some(&pos.position_header.position_address),
-                            ) {
-                                return Err("Invalid signature");
+                            if let Some(signature) = signature {
+                                if let Err(_) = perp_order.verify_order_signature(
+                                    signature,
+                                    Some(&pos.position_header.position_address),
+                                ) {
+                                    return Err("Invalid signature");
+                                }
                             }
                         } else {
                             return Err("Position to update is undefined");
@@ -291,8 +437,64 @@ impl OrderRequestValidator {
         Ok(())
     }
 
+    /// Checks the order against the tick size, lot size, minimum notional and maximum order size,
+    /// and perp orders against the maximum size of the position they open or update.
+    ///
+    /// The checks use the amounts signed by the user, since the order qty of spot bids is derived
+    /// from the quote amount. Market orders can have any price (it only caps the slippage) and
+    /// orders closing a position can have any size (so whole positions can always be closed).
+    fn validate_market_params(
+        &self,
+        side: OrderSide,
+        order: &Order,
+        is_market: bool,
+    ) -> Result<(), &str> {
+        let params = &self.market_params;
+
+        let (base_amount, quote_amount, is_close) = match order {
+            Order::Spot(limit_order) => match side {
+                OrderSide::Bid => (limit_order.amount_received, limit_order.amount_spent, false),
+                OrderSide::Ask => (limit_order.amount_spent, limit_order.amount_received, false),
+            },
+            Order::Perp(perp_order) => (
+                perp_order.synthetic_amount,
+                perp_order.collateral_amount,
+                perp_order.position_effect_type == PositionEffectType::Close,
+            ),
+        };
+
+        if !is_close && base_amount % params.lot_size != 0 {
+            return Err(ERR_BAD_LOT);
+        }
+        if base_amount > params.max_order_size {
+            return Err(ERR_MAX_ORDER_SIZE);
+        }
+        if quote_amount < params.min_notional {
+            return Err(ERR_MIN_NOTIONAL);
+        }
+
+        if let Order::Perp(perp_order) = order {
+            params.validate_owner_position_size(&[], perp_order)?;
+        }
+
+        if !is_market
+            && PRICE_DECIMALS_PER_ASSET.contains_key(&self.orderbook_order_asset.to_string())
+        {
+            // ? The signed amounts have to correspond to a price that is exactly on a tick
+            let price = get_price(self.orderbook_order_asset, quote_amount, base_amount);
+            if price % params.tick_size != 0
+                || get_collateral_amount(self.orderbook_order_asset, base_amount, price)
+                    != quote_amount
+            {
+                return Err(ERR_BAD_TICK);
+            }
+        }
+
+        Ok(())
+    }
+
     fn validate_cancel(&self, id: u64) -> Result<(), &str> {
-        let seq_id = id % 2_u64.pow(16);
+        let seq_id = seq_id_from_order_id(id);
 
         if self.min_sequence_id > seq_id || self.max_sequence_id < seq_id {
             return Err(ERR_BAD_SEQ_ID);
@@ -4,10 +4,10 @@ use num_bigint::BigUint;
 use num_traits::FromPrimitive;
 use parking_lot::Mutex;
 
-use firestore_db_and_auth::ServiceSession;
 use serde_json::Value;
 
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     perpetual::{perp_order::CloseOrderFields, DUST_AMOUNT_PER_ASSET},
     server::grpc::engine_proto::CloseOrderTabReq,
@@ -24,7 +24,7 @@ use super::{
 };
 
 pub fn close_order_tab(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     state_tree: &Arc<Mutex<SuperficialTree>>,
     updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
@@ -2,9 +2,8 @@ use std::sync::Arc;
 
 use parking_lot::Mutex;
 
-use firestore_db_and_auth::ServiceSession;
-
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::utils::{
     notes::Note,
     storage::firestore::{
@@ -17,7 +16,7 @@ use super::OrderTab;
 
 /// Update the database after a new order tab has been opened.
 pub fn open_tab_db_updates(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     order_tab: OrderTab,
     base_notes_in: &Vec<Note>,
@@ -54,7 +53,7 @@ pub fn open_tab_db_updates(
 
 /// Update the database after an order tab has been closed.
 pub fn close_tab_db_updates(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     order_tab: &OrderTab,
     updated_order_tab: &Option<OrderTab>,
@@ -6,9 +6,8 @@ use parking_lot::Mutex;
 use serde_json::Value;
 use starknet::curve::AffinePoint;
 
-use firestore_db_and_auth::ServiceSession;
-
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     server::grpc::engine_proto::OpenOrderTabReq,
     transaction_batch::LeafNodeType,
@@ -26,7 +25,7 @@ use super::{
 // TODO: Check that the notes exist just before you update the state tree not in the beginning
 
 pub fn open_order_tab(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     open_order_tab_req: OpenOrderTabReq,
     state_tree: &Arc<Mutex<SuperficialTree>>,
@@ -0,0 +1,947 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use num_bigint::BigUint;
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::crypto_utils::hash_many;
+
+use super::{
+    get_risk_tier, OrderSide, RiskTier, COLLATERAL_ASSETS, COLLATERAL_HAIRCUT_PER_ASSET,
+    COLLATERAL_LIQUIDATION_DISCOUNT, COLLATERAL_TOKEN, COLLATERAL_TOKEN_DECIMALS,
+    DECIMALS_PER_ASSET, PRICE_DECIMALS_PER_ASSET, SYNTHETIC_ASSETS,
+};
+
+/// Fee paid to the liquidator on the notional of each liquidated position (per mille)
+pub const CROSS_LIQUIDATOR_FEE_RATE: u64 = 5; // 0.5 %
+
+/// A position inside a cross margin account.
+///
+/// Unlike a `PerpPosition` it has no margin of its own, so it also has no liquidation price:
+/// whether it can be liquidated depends on every other position of the account.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrossPosition {
+    pub synthetic_token: u32,
+    pub order_side: OrderSide,
+    pub position_size: u64,
+    pub entry_price: u64,
+    pub last_funding_idx: u32,
+}
+
+/// A non-USD collateral balance of a cross margin account
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollateralAsset {
+    pub token: u32,
+    pub amount: u64,
+}
+
+/// Result of selling one collateral asset of a liquidatable account to the liquidator
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollateralLiquidation {
+    pub token: u32,
+    pub amount: u64,
+    pub collateral_received: u64, // in collateral token units, at the discounted index price
+}
+
+/// Result of liquidating one position of a cross margin account
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrossLiquidation {
+    pub synthetic_token: u32,
+    pub liquidated_size: u64,
+    pub liquidator_fee: u64,
+    pub insurance_fund_delta: i64, // negative if the insurance fund had to cover a deficit
+}
+
+/// A cross margin account pools its collateral across up to one position per market.
+///
+/// Pnl, fees and funding are settled in the collateral token balance, which is signed: realizing
+/// a loss on one position while another one is in profit can leave it negative, as long as the
+/// account value stays above the maintenance margin of all its positions. Other collateral assets
+/// count towards the account value at their index price minus a haircut.
+#[derive(Debug, Clone)]
+pub struct CrossMarginAccount {
+    pub account_address: BigUint,
+    pub header_hash: BigUint,
+    // ? Mutable fields
+    pub collateral: i64,                         // in collateral token units
+    pub collateral_assets: Vec<CollateralAsset>, // non-USD collateral, sorted by token
+    pub positions: Vec<CrossPosition>,           // sorted by synthetic token
+    //
+    pub hash: BigUint,
+}
+
+impl CrossMarginAccount {
+    pub fn new(account_address: BigUint, collateral: u64) -> CrossMarginAccount {
+        let header_hash = hash_cross_margin_header(&account_address);
+
+        let mut account = CrossMarginAccount {
+            account_address,
+            header_hash,
+            collateral: collateral as i64,
+            collateral_assets: Vec::new(),
+            positions: Vec::new(),
+            hash: BigUint::default(),
+        };
+        account.update_hash();
+
+        return account;
+    }
+
+    pub fn get_position(&self, synthetic_token: u32) -> Option<&CrossPosition> {
+        self.positions
+            .iter()
+            .find(|pos| pos.synthetic_token == synthetic_token)
+    }
+
+    // * MARGIN ------------------------------------------------------------------------------
+
+    /// collateral + the value of the collateral assets after haircuts + the unrealized pnl of all
+    /// positions, at the index prices
+    pub fn get_account_value(&self, index_prices: &HashMap<u32, u64>) -> Result<i64, String> {
+        let mut value =
+            self.collateral as i128 + self.get_collateral_assets_value(index_prices)? as i128;
+        for position in self.positions.iter() {
+            let price = get_index_price(index_prices, position.synthetic_token)?;
+            value += get_pnl(position, price);
+        }
+
+        return Ok(value as i64);
+    }
+
+    /// Margin the account needs to open or increase positions
+    pub fn get_initial_margin(&self, index_prices: &HashMap<u32, u64>) -> Result<u64, String> {
+        self.get_margin_requirement(index_prices, |tier| tier.initial_margin_fraction)
+    }
+
+    /// Margin below which the account can be liquidated
+    pub fn get_maintenance_margin(&self, index_prices: &HashMap<u32, u64>) -> Result<u64, String> {
+        self.get_margin_requirement(index_prices, |tier| tier.maintenance_margin_fraction)
+    }
+
+    /// Account value left after the initial margin (negative if the account can't add risk)
+    pub fn get_free_collateral(&self, index_prices: &HashMap<u32, u64>) -> Result<i64, String> {
+        let value = self.get_account_value(index_prices)?;
+        let initial_margin = self.get_initial_margin(index_prices)?;
+
+        return Ok(value - initial_margin as i64);
+    }
+
+    /// Value of the non-USD collateral after haircuts
+    pub fn get_collateral_assets_value(
+        &self,
+        index_prices: &HashMap<u32, u64>,
+    ) -> Result<u64, String> {
+        let mut value: u128 = 0;
+        for asset in self.collateral_assets.iter() {
+            let price = get_index_price(index_prices, asset.token)?;
+            let haircut = get_haircut(asset.token)?;
+
+            value += get_value(asset.token, asset.amount, price) as u128 * (1000 - haircut) as u128
+                / 1000;
+        }
+
+        return Ok(value as u64);
+    }
+
+    pub fn is_liquidatable(&self, index_prices: &HashMap<u32, u64>) -> Result<bool, String> {
+        if self.positions.is_empty() {
+            return Ok(false);
+        }
+
+        let value = self.get_account_value(index_prices)?;
+        let maintenance_margin = self.get_maintenance_margin(index_prices)?;
+
+        return Ok(value < maintenance_margin as i64);
+    }
+
+    /// Sum of the margin fractions of each position's risk tier times its notional
+    fn get_margin_requirement(
+        &self,
+        index_prices: &HashMap<u32, u64>,
+        fraction: fn(&RiskTier) -> u64,
+    ) -> Result<u64, String> {
+        let mut requirement: u128 = 0;
+        for position in self.positions.iter() {
+            let price = get_index_price(index_prices, position.synthetic_token)?;
+            let notional = get_notional(position, price);
+            let tier = get_risk_tier(position.synthetic_token, notional);
+
+            requirement += notional as u128 * fraction(tier) as u128 / 1000;
+        }
+
+        return Ok(requirement as u64);
+    }
+
+    // * COLLATERAL ---------------------------------------------------------------------------
+
+    pub fn add_collateral(&mut self, token: u32, amount: u64) -> Result<(), String> {
+        if !COLLATERAL_ASSETS.contains(&token) {
+            return Err("Invalid collateral token".to_string());
+        }
+
+        if token == COLLATERAL_TOKEN {
+            self.collateral += amount as i64;
+        } else {
+            match self.collateral_assets.iter_mut().find(|a| a.token == token) {
+                Some(asset) => asset.amount += amount,
+                None => {
+                    self.collateral_assets
+                        .push(CollateralAsset { token, amount });
+                    self.collateral_assets.sort_by_key(|a| a.token);
+                }
+            }
+        }
+
+        self.update_hash();
+
+        Ok(())
+    }
+
+    /// Removes collateral from the account, as long as what's left still covers the initial margin
+    pub fn remove_collateral(
+        &mut self,
+        token: u32,
+        amount: u64,
+        index_prices: &HashMap<u32, u64>,
+    ) -> Result<(), String> {
+        let free_collateral = self.get_free_collateral(index_prices)?;
+
+        if token == COLLATERAL_TOKEN {
+            if amount as i64 > free_collateral {
+                return Err("Not enough free collateral".to_string());
+            }
+            if amount as i64 > self.collateral {
+                return Err("Unrealized profits can't be withdrawn".to_string());
+            }
+
+            self.collateral -= amount as i64;
+        } else {
+            let asset_idx = self
+                .collateral_assets
+                .iter()
+                .position(|a| a.token == token && a.amount >= amount);
+            let asset_idx = match asset_idx {
+                Some(i) => i,
+                None => return Err("Not enough collateral".to_string()),
+            };
+
+            // ? The account value only drops by the haircut value of the asset
+            let price = get_index_price(index_prices, token)?;
+            let haircut = get_haircut(token)?;
+            let removed_value =
+                get_value(token, amount, price) as u128 * (1000 - haircut) as u128 / 1000;
+            if removed_value as i64 > free_collateral {
+                return Err("Not enough free collateral".to_string());
+            }
+
+            self.collateral_assets[asset_idx].amount -= amount;
+            if self.collateral_assets[asset_idx].amount == 0 {
+                self.collateral_assets.remove(asset_idx);
+            }
+        }
+
+        self.update_hash();
+
+        Ok(())
+    }
+
+    // * TRADING ------------------------------------------------------------------------------
+
+    /// Applies a fill of `size` at `price` to the position in synthetic_token.\
+    /// The part of the fill that reduces the position realizes its pnl into the collateral,
+    /// the rest increases (or flips) it at the fill price. The fee is taken from the collateral.
+    ///
+    /// Funding has to be applied before the fill (positions opened here start at funding_idx).
+    pub fn apply_fill(
+        &mut self,
+        synthetic_token: u32,
+        order_side: OrderSide,
+        size: u64,
+        price: u64,
+        fee_taken: u64,
+        funding_idx: u32,
+    ) -> Result<(), String> {
+        if !SYNTHETIC_ASSETS.contains(&synthetic_token) {
+            return Err("Invalid synthetic token".to_string());
+        }
+        if size == 0 || price == 0 {
+            return Err("Invalid fill".to_string());
+        }
+
+        let pos_idx = self
+            .positions
+            .iter()
+            .position(|pos| pos.synthetic_token == synthetic_token);
+
+        match pos_idx {
+            None => {
+                self.positions.push(CrossPosition {
+                    synthetic_token,
+                    order_side,
+                    position_size: size,
+                    entry_price: price,
+                    last_funding_idx: funding_idx,
+                });
+                self.positions.sort_by_key(|pos| pos.synthetic_token);
+            }
+            Some(i) => {
+                let position = &mut self.positions[i];
+                if position.last_funding_idx < funding_idx {
+                    return Err("Funding has to be applied before the fill".to_string());
+                }
+
+                if position.order_side == order_side {
+                    // ? Average the entry price
+                    let new_size = position.position_size + size;
+                    position.entry_price = ((position.position_size as u128
+                        * position.entry_price as u128
+                        + size as u128 * price as u128)
+                        / new_size as u128) as u64;
+                    position.position_size = new_size;
+                } else {
+                    let closed_size = std::cmp::min(size, position.position_size);
+
+                    let realized_pnl = get_pnl(
+                        &CrossPosition {
+                            position_size: closed_size,
+                            ..position.clone()
+                        },
+                        price,
+                    );
+                    self.collateral += realized_pnl as i64;
+
+                    if size < position.position_size {
+                        position.position_size -= size;
+                    } else if size == position.position_size {
+                        self.positions.remove(i);
+                    } else {
+                        // ? Flip the position at the fill price
+                        position.order_side = order_side;
+                        position.position_size = size - closed_size;
+                        position.entry_price = price;
+                    }
+                }
+            }
+        }
+
+        self.collateral -= fee_taken as i64;
+        self.update_hash();
+
+        Ok(())
+    }
+
+    /// Applies the funding payments of the position in synthetic_token since its last funding index
+    pub fn apply_funding(
+        &mut self,
+        synthetic_token: u32,
+        funding_rates: &[i64],
+        funding_prices: &[u64],
+        funding_idx: u32,
+    ) {
+        let position = self
+            .positions
+            .iter_mut()
+            .find(|pos| pos.synthetic_token == synthetic_token);
+        let position = match position {
+            Some(position) if funding_idx > position.last_funding_idx => position,
+            _ => return,
+        };
+
+        let mut funding_sum: i128 = 0;
+        for (rate, price) in funding_rates.iter().zip(funding_prices.iter()) {
+            // funding rate has 5 decimal places
+            funding_sum += *rate as i128 * *price as i128 / 100_000;
+        }
+        let funding_usd =
+            position.position_size as i128 * funding_sum / get_multiplier(synthetic_token);
+
+        if position.order_side == OrderSide::Long {
+            self.collateral -= funding_usd as i64;
+        } else {
+            self.collateral += funding_usd as i64;
+        }
+        position.last_funding_idx = funding_idx;
+
+        self.update_hash();
+    }
+
+    // * LIQUIDATIONS -------------------------------------------------------------------------
+
+    /// The order in which the positions of a liquidatable account have to be closed.
+    ///
+    /// Positions with the largest maintenance margin go first, as closing them frees up the most
+    /// margin. Ties go to the position with the lowest unrealized pnl and then to the lowest
+    /// synthetic token, so the order is deterministic and can be checked by the prover.
+    pub fn get_liquidation_order(
+        &self,
+        index_prices: &HashMap<u32, u64>,
+    ) -> Result<Vec<u32>, String> {
+        let mut order: Vec<(u64, i128, u32)> = Vec::with_capacity(self.positions.len());
+        for position in self.positions.iter() {
+            let price = get_index_price(index_prices, position.synthetic_token)?;
+
+            let notional = get_notional(position, price);
+            let pnl = get_pnl(position, price);
+
+            order.push((notional, pnl, position.synthetic_token));
+        }
+
+        order.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+
+        return Ok(order.into_iter().map(|(_, _, token)| token).collect());
+    }
+
+    /// Sells a collateral asset of a liquidatable account to the liquidator at the index price
+    /// minus COLLATERAL_LIQUIDATION_DISCOUNT.
+    ///
+    /// Non-USD collateral is sold before any position is closed, the asset with the highest
+    /// value after haircuts first (then the lowest token).
+    pub fn liquidate_collateral(
+        &mut self,
+        token: u32,
+        index_prices: &HashMap<u32, u64>,
+    ) -> Result<CollateralLiquidation, String> {
+        if !self.is_liquidatable(index_prices)? {
+            return Err("Account is not liquidatable".to_string());
+        }
+
+        let mut order: Vec<(u64, u32)> = Vec::with_capacity(self.collateral_assets.len());
+        for asset in self.collateral_assets.iter() {
+            let price = get_index_price(index_prices, asset.token)?;
+            let haircut = get_haircut(asset.token)?;
+            let value = get_value(asset.token, asset.amount, price) as u128
+                * (1000 - haircut) as u128
+                / 1000;
+
+            order.push((value as u64, asset.token));
+        }
+        order.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        match order.first() {
+            Some((_, next_token)) if *next_token == token => {}
+            Some((_, next_token)) => {
+                return Err(format!(
+                    "Collateral has to be liquidated in order, next is {}",
+                    next_token
+                ))
+            }
+            None => return Err("Account has no collateral to liquidate".to_string()),
+        }
+
+        let asset_idx = self
+            .collateral_assets
+            .iter()
+            .position(|a| a.token == token)
+            .unwrap();
+        let asset = self.collateral_assets.remove(asset_idx);
+
+        let price = get_index_price(index_prices, token)?;
+        let collateral_received = (get_value(token, asset.amount, price) as u128
+            * (1000 - COLLATERAL_LIQUIDATION_DISCOUNT) as u128
+            / 1000) as u64;
+
+        self.collateral += collateral_received as i64;
+        self.update_hash();
+
+        return Ok(CollateralLiquidation {
+            token,
+            amount: asset.amount,
+            collateral_received,
+        });
+    }
+
+    /// Closes the next position in the liquidation order at the market price.
+    ///
+    /// All non-USD collateral has to be sold first. The liquidator fee is taken from the
+    /// collateral, and once the last position is closed any negative collateral left is covered
+    /// by the insurance fund.
+    pub fn liquidate_position(
+        &mut self,
+        synthetic_token: u32,
+        market_price: u64,
+        index_prices: &HashMap<u32, u64>,
+    ) -> Result<CrossLiquidation, String> {
+        if !self.is_liquidatable(index_prices)? {
+            return Err("Account is not liquidatable".to_string());
+        }
+
+        if !self.collateral_assets.is_empty() {
+            return Err("Collateral assets have to be liquidated first".to_string());
+        }
+
+        let next_token = self.get_liquidation_order(index_prices)?[0];
+        if next_token != synthetic_token {
+            return Err(format!(
+                "Positions have to be liquidated in order, next is {}",
+                next_token
+            ));
+        }
+
+        let pos_idx = self
+            .positions
+            .iter()
+            .position(|pos| pos.synthetic_token == synthetic_token)
+            .unwrap();
+        let position = self.positions.remove(pos_idx);
+
+        let liquidator_fee = (get_notional(&position, market_price) as u128
+            * CROSS_LIQUIDATOR_FEE_RATE as u128
+            / 1000) as u64;
+
+        self.collateral += get_pnl(&position, market_price) as i64 - liquidator_fee as i64;
+
+        let mut insurance_fund_delta = 0;
+        if self.positions.is_empty() && self.collateral < 0 {
+            insurance_fund_delta = self.collateral;
+            self.collateral = 0;
+        }
+
+        self.update_hash();
+
+        return Ok(CrossLiquidation {
+            synthetic_token,
+            liquidated_size: position.position_size,
+            liquidator_fee,
+            insurance_fund_delta,
+        });
+    }
+
+    // * HASHING ------------------------------------------------------------------------------
+
+    pub fn update_hash(&mut self) {
+        self.hash = hash_cross_margin_account(
+            &self.header_hash,
+            self.collateral,
+            &self.collateral_assets,
+            &self.positions,
+        );
+    }
+}
+
+// * HELPERS * //
+
+fn get_index_price(index_prices: &HashMap<u32, u64>, synthetic_token: u32) -> Result<u64, String> {
+    match index_prices.get(&synthetic_token) {
+        Some(price) if *price > 0 => Ok(*price),
+        _ => Err(format!("Missing index price for token {}", synthetic_token)),
+    }
+}
+
+/// Haircut (per mille) applied to the value of a collateral asset
+fn get_haircut(token: u32) -> Result<u64, String> {
+    match COLLATERAL_HAIRCUT_PER_ASSET.get(&token.to_string()) {
+        Some(haircut) => Ok(*haircut),
+        None => Err(format!("Missing haircut for collateral token {}", token)),
+    }
+}
+
+/// multiplier between (size * price) and collateral amounts
+fn get_multiplier(synthetic_token: u32) -> i128 {
+    let synthetic_decimals = DECIMALS_PER_ASSET[&synthetic_token.to_string()];
+    let synthetic_price_decimals = PRICE_DECIMALS_PER_ASSET[&synthetic_token.to_string()];
+
+    10_i128.pow((synthetic_decimals + synthetic_price_decimals - COLLATERAL_TOKEN_DECIMALS) as u32)
+}
+
+/// Value of `amount` of token at price, in collateral token units
+fn get_value(token: u32, amount: u64, price: u64) -> u64 {
+    (amount as i128 * price as i128 / get_multiplier(token)) as u64
+}
+
+fn get_notional(position: &CrossPosition, price: u64) -> u64 {
+    get_value(position.synthetic_token, position.position_size, price)
+}
+
+fn get_pnl(position: &CrossPosition, price: u64) -> i128 {
+    let price_delta = if position.order_side == OrderSide::Long {
+        price as i128 - position.entry_price as i128
+    } else {
+        position.entry_price as i128 - price as i128
+    };
+
+    position.position_size as i128 * price_delta / get_multiplier(position.synthetic_token)
+}
+
+// * HASH FUNCTIONS * //
+
+/// header_hash = H({account_address})
+pub fn hash_cross_margin_header(account_address: &BigUint) -> BigUint {
+    hash_many(&vec![account_address])
+}
+
+/// position_hash = H({synthetic_token, order_side, position_size, entry_price, last_funding_idx})
+pub fn hash_cross_position(position: &CrossPosition) -> BigUint {
+    let synthetic_token = BigUint::from_u32(position.synthetic_token).unwrap();
+    let order_side = BigUint::from_u8((position.order_side == OrderSide::Long) as u8).unwrap();
+    let position_size = BigUint::from_u64(position.position_size).unwrap();
+    let entry_price = BigUint::from_u64(position.entry_price).unwrap();
+    let last_funding_idx = BigUint::from_u32(position.last_funding_idx).unwrap();
+
+    hash_many(&vec![
+        &synthetic_token,
+        &order_side,
+        &position_size,
+        &entry_price,
+        &last_funding_idx,
+    ])
+}
+
+/// collateral_assets_hash = H({token_1, amount_1, ..., token_k, amount_k}) or 0 if there are none
+pub fn hash_collateral_assets(collateral_assets: &[CollateralAsset]) -> BigUint {
+    if collateral_assets.is_empty() {
+        return BigUint::default();
+    }
+
+    let mut inputs: Vec<BigUint> = Vec::with_capacity(2 * collateral_assets.len());
+    for asset in collateral_assets.iter() {
+        inputs.push(BigUint::from_u32(asset.token).unwrap());
+        inputs.push(BigUint::from_u64(asset.amount).unwrap());
+    }
+
+    hash_many(&inputs.iter().collect())
+}
+
+/// hash = H({header_hash, collateral, collateral_assets_hash, position_hash_1, ..., position_hash_n})
+///
+/// The collateral is hashed as |collateral| + 2^64 if it is negative, the collateral assets and
+/// positions in ascending token order.
+pub fn hash_cross_margin_account(
+    header_hash: &BigUint,
+    collateral: i64,
+    collateral_assets: &[CollateralAsset],
+    positions: &[CrossPosition],
+) -> BigUint {
+    let mut collateral_felt = BigUint::from_u64(collateral.unsigned_abs()).unwrap();
+    if collateral < 0 {
+        collateral_felt += BigUint::from_u8(1).unwrap() << 64;
+    }
+
+    let collateral_assets_hash = hash_collateral_assets(collateral_assets);
+    let position_hashes: Vec<BigUint> = positions.iter().map(hash_cross_position).collect();
+
+    let mut hash_inputs: Vec<&BigUint> =
+        vec![header_hash, &collateral_felt, &collateral_assets_hash];
+    hash_inputs.extend(position_hashes.iter());
+
+    hash_many(&hash_inputs)
+}
+
+// * =============================================================================================================
+
+use serde::ser::{SerializeStruct, Serializer};
+
+impl Serialize for CrossMarginAccount {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut account = serializer.serialize_struct("CrossMarginAccount", 6)?;
+
+        account.serialize_field("account_address", &self.account_address.to_string())?;
+        account.serialize_field("header_hash", &self.header_hash.to_string())?;
+        account.serialize_field("collateral", &self.collateral)?;
+        account.serialize_field("collateral_assets", &self.collateral_assets)?;
+        account.serialize_field("positions", &self.positions)?;
+        account.serialize_field("hash", &self.hash.to_string())?;
+
+        return account.end();
+    }
+}
+
+use serde::de::Deserializer;
+
+impl<'de> Deserialize<'de> for CrossMarginAccount {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Helper {
+            account_address: String,
+            header_hash: String,
+            collateral: i64,
+            collateral_assets: Vec<CollateralAsset>,
+            positions: Vec<CrossPosition>,
+            hash: String,
+        }
+
+        let helper = Helper::deserialize(deserializer)?;
+
+        Ok(CrossMarginAccount {
+            account_address: BigUint::from_str(&helper.account_address).unwrap(),
+            header_hash: BigUint::from_str(&helper.header_hash).unwrap(),
+            collateral: helper.collateral,
+            collateral_assets: helper.collateral_assets,
+            positions: helper.positions,
+            hash: BigUint::from_str(&helper.hash).unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BTC: u32 = 3592681469;
+    const ETH: u32 = 453755560;
+
+    fn prices(btc: u64, eth: u64) -> HashMap<u32, u64> {
+        HashMap::from([(BTC, btc * 10u64.pow(6)), (ETH, eth * 10u64.pow(6))])
+    }
+
+    fn account(collateral: u64) -> CrossMarginAccount {
+        CrossMarginAccount::new(BigUint::from_u64(1234).unwrap(), collateral * 10u64.pow(6))
+    }
+
+    #[test]
+    fn profit_on_one_position_backs_the_other() {
+        // ? 1 BTC long and 10 ETH short with 5k USD of collateral
+        let mut account = account(5_000);
+        account
+            .apply_fill(
+                BTC,
+                OrderSide::Long,
+                10u64.pow(8),
+                30_000 * 10u64.pow(6),
+                0,
+                0,
+            )
+            .unwrap();
+        account
+            .apply_fill(
+                ETH,
+                OrderSide::Short,
+                10 * 10u64.pow(8),
+                2_000 * 10u64.pow(6),
+                0,
+                0,
+            )
+            .unwrap();
+
+        // ? IM = 6.7% * (30k + 20k) = 3350 USD, MM = 3% * 50k = 1500 USD
+        let index_prices = prices(30_000, 2_000);
+        assert_eq!(
+            account.get_initial_margin(&index_prices),
+            Ok(3_350 * 10u64.pow(6))
+        );
+        assert_eq!(
+            account.get_maintenance_margin(&index_prices),
+            Ok(1_500 * 10u64.pow(6))
+        );
+
+        // ? ETH rallies 400 USD (-4k) but BTC gains 1k, so the account is still healthy
+        let index_prices = prices(31_000, 2_400);
+        assert_eq!(
+            account.get_account_value(&index_prices),
+            Ok(2_000 * 10u64.pow(6) as i64)
+        );
+        assert_eq!(account.is_liquidatable(&index_prices), Ok(false));
+
+        // ? Without the BTC profit it wouldn't be
+        let index_prices = prices(30_000, 2_400);
+        assert_eq!(account.is_liquidatable(&index_prices), Ok(true));
+    }
+
+    #[test]
+    fn closing_realizes_pnl_into_the_shared_collateral() {
+        let mut account = account(1_000);
+        account
+            .apply_fill(
+                BTC,
+                OrderSide::Long,
+                10u64.pow(8),
+                30_000 * 10u64.pow(6),
+                0,
+                0,
+            )
+            .unwrap();
+        account
+            .apply_fill(
+                ETH,
+                OrderSide::Long,
+                10u64.pow(8),
+                2_000 * 10u64.pow(6),
+                0,
+                0,
+            )
+            .unwrap();
+
+        // ? Closing BTC at a 1.5k loss leaves the collateral negative, ETH is still open
+        account
+            .apply_fill(
+                BTC,
+                OrderSide::Short,
+                10u64.pow(8),
+                28_500 * 10u64.pow(6),
+                0,
+                0,
+            )
+            .unwrap();
+        assert_eq!(account.collateral, -500 * 10i64.pow(6));
+        assert_eq!(account.positions.len(), 1);
+        assert!(account.get_position(BTC).is_none());
+
+        // ? Losses can't be withdrawn, and neither can profits that aren't realized yet
+        let index_prices = prices(30_000, 3_000);
+        assert!(account
+            .remove_collateral(COLLATERAL_TOKEN, 1, &index_prices)
+            .is_err());
+    }
+
+    #[test]
+    fn liquidations_follow_the_account_order() {
+        let mut account = account(2_000);
+        account
+            .apply_fill(
+                BTC,
+                OrderSide::Long,
+                10u64.pow(8),
+                30_000 * 10u64.pow(6),
+                0,
+                0,
+            )
+            .unwrap();
+        account
+            .apply_fill(
+                ETH,
+                OrderSide::Long,
+                5 * 10u64.pow(8),
+                2_000 * 10u64.pow(6),
+                0,
+                0,
+            )
+            .unwrap();
+
+        let index_prices = prices(28_500, 1_990);
+        assert_eq!(account.is_liquidatable(&index_prices), Ok(true));
+        assert_eq!(
+            account.get_liquidation_order(&index_prices),
+            Ok(vec![BTC, ETH])
+        );
+
+        // ? ETH can't be liquidated before BTC
+        assert!(account
+            .liquidate_position(ETH, 1_990 * 10u64.pow(6), &index_prices)
+            .is_err());
+
+        let liquidation = account
+            .liquidate_position(BTC, 28_500 * 10u64.pow(6), &index_prices)
+            .unwrap();
+        assert_eq!(liquidation.liquidated_size, 10u64.pow(8));
+        assert_eq!(liquidation.liquidator_fee, 142_500_000);
+        assert_eq!(liquidation.insurance_fund_delta, 0);
+
+        // ? 2k - 1.5k loss - 142.5 fee = 357.5 USD left, enough for the ETH position on its own
+        assert_eq!(account.collateral, 357_500_000);
+        assert_eq!(account.is_liquidatable(&index_prices), Ok(false));
+    }
+
+    #[test]
+    fn non_usd_collateral_is_haircut_and_sold_first() {
+        // ? 2 ETH of collateral at 2k count as 4k - 15% = 3.4k USD
+        let mut account = account(0);
+        account.add_collateral(ETH, 2 * 10u64.pow(8)).unwrap();
+        account
+            .apply_fill(
+                BTC,
+                OrderSide::Long,
+                10u64.pow(8),
+                30_000 * 10u64.pow(6),
+                0,
+                0,
+            )
+            .unwrap();
+
+        let index_prices = prices(30_000, 2_000);
+        assert_eq!(
+            account.get_account_value(&index_prices),
+            Ok(3_400 * 10i64.pow(6))
+        );
+
+        // ? Free collateral is 3.4k - 2.01k IM, one ETH (1.7k after the haircut) is too much
+        assert!(account
+            .remove_collateral(ETH, 10u64.pow(8), &index_prices)
+            .is_err());
+
+        // ? BTC drops 3k, leaving 400 USD of account value against an 810 USD MM
+        let index_prices = prices(27_000, 2_000);
+        assert_eq!(account.is_liquidatable(&index_prices), Ok(true));
+        assert!(account
+            .liquidate_position(BTC, 27_000 * 10u64.pow(6), &index_prices)
+            .is_err());
+
+        // ? The ETH is sold at a 2.5% discount, which is enough to cover the MM again
+        let liquidation = account.liquidate_collateral(ETH, &index_prices).unwrap();
+        assert_eq!(liquidation.amount, 2 * 10u64.pow(8));
+        assert_eq!(liquidation.collateral_received, 3_900 * 10u64.pow(6));
+        assert!(account.collateral_assets.is_empty());
+        assert_eq!(account.collateral, 3_900 * 10i64.pow(6));
+        assert_eq!(account.is_liquidatable(&index_prices), Ok(false));
+    }
+
+    #[test]
+    fn collateral_without_a_haircut_is_rejected() {
+        const SOL: u32 = 277158171;
+
+        let mut account = account(1_000);
+        assert!(account.add_collateral(SOL, 10u64.pow(9)).is_err());
+
+        // ? Valuing an account holding an asset without a haircut errors instead of panicking
+        account.collateral_assets.push(CollateralAsset {
+            token: SOL,
+            amount: 10u64.pow(9),
+        });
+        let index_prices = HashMap::from([(SOL, 20 * 10u64.pow(6))]);
+        assert!(account.get_account_value(&index_prices).is_err());
+        assert!(account.remove_collateral(SOL, 1, &index_prices).is_err());
+    }
+
+    #[test]
+    fn hash_covers_the_positions_in_token_order() {
+        let mut account = account(1_000);
+        account
+            .apply_fill(
+                BTC,
+                OrderSide::Long,
+                10u64.pow(8),
+                30_000 * 10u64.pow(6),
+                0,
+                3,
+            )
+            .unwrap();
+        account
+            .apply_fill(
+                ETH,
+                OrderSide::Short,
+                10u64.pow(8),
+                2_000 * 10u64.pow(6),
+                0,
+                3,
+            )
+            .unwrap();
+        account.add_collateral(ETH, 5 * 10u64.pow(7)).unwrap();
+
+        // ? Positions are kept in token order no matter the order they were opened in
+        let tokens: Vec<u32> = account
+            .positions
+            .iter()
+            .map(|p| p.synthetic_token)
+            .collect();
+        assert_eq!(tokens, vec![ETH, BTC]);
+
+        let expected_hash = hash_cross_margin_account(
+            &hash_cross_margin_header(&account.account_address),
+            account.collateral,
+            &account.collateral_assets,
+            &account.positions,
+        );
+        assert_eq!(account.hash, expected_hash);
+
+        // ? A negative collateral balance hashes differently from the positive one
+        let hash = account.hash.clone();
+        account.collateral = -account.collateral;
+        account.update_hash();
+        assert_ne!(account.hash, hash);
+    }
+}
@@ -0,0 +1,127 @@
+//! Funding parameters of the perp markets.
+//!
+//! They are read from the exchange config (shared with the frontend and the relay server) when
+//! the transaction batch is created, so the sample interval, the rate caps and the interest rates
+//! can be changed without a new build.
+
+use std::{collections::HashMap, fs};
+
+use serde::Deserialize;
+
+use super::SYNTHETIC_ASSETS;
+
+/// Path of the exchange config, relative to the backend's working directory
+pub const EXCHANGE_CONFIG_PATH: &str = "../exchange-config.json";
+
+/// The premium is sampled every sample_interval_secs and funding is settled every
+/// samples_per_settlement samples. Rates are quoted per realization period (realization_periods
+/// settlements) with 5 decimals (100_000 = 100 %).
+#[derive(Debug, Clone, Deserialize)]
+pub struct FundingConfig {
+    #[serde(rename = "FUNDING_SAMPLE_INTERVAL_SECS")]
+    pub sample_interval_secs: u64,
+    #[serde(rename = "FUNDING_SAMPLES_PER_SETTLEMENT")]
+    pub samples_per_settlement: u16,
+    #[serde(rename = "FUNDING_REALIZATION_PERIODS")]
+    pub realization_periods: i64,
+    // The interest component pulls the premium towards the interest rate by at most this much
+    #[serde(rename = "FUNDING_INTEREST_DAMPER")]
+    pub interest_damper: i64,
+    #[serde(rename = "FUNDING_INTEREST_RATE_PER_ASSET")]
+    pub interest_rate_per_asset: HashMap<u32, i64>,
+    #[serde(rename = "MAX_FUNDING_RATE_PER_ASSET")]
+    pub max_rate_per_asset: HashMap<u32, i64>,
+    #[serde(rename = "MIN_FUNDING_RATE_PER_ASSET")]
+    pub min_rate_per_asset: HashMap<u32, i64>,
+}
+
+impl FundingConfig {
+    /// Reads the funding config from the exchange config at EXCHANGE_CONFIG_PATH
+    pub fn load() -> FundingConfig {
+        match FundingConfig::from_file(EXCHANGE_CONFIG_PATH) {
+            Ok(config) => config,
+            Err(e) => panic!("Invalid funding config: {}", e),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<FundingConfig, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path, e))?;
+
+        return FundingConfig::from_json(&contents);
+    }
+
+    /// Parses the funding fields of an exchange config and checks every market has its rates
+    pub fn from_json(json: &str) -> Result<FundingConfig, String> {
+        let config: FundingConfig = serde_json::from_str(json).map_err(|e| e.to_string())?;
+
+        if config.sample_interval_secs == 0
+            || config.samples_per_settlement == 0
+            || config.realization_periods <= 0
+        {
+            return Err("The funding interval and periods must be positive".to_string());
+        }
+        if config.interest_damper < 0 {
+            return Err("The funding interest damper can't be negative".to_string());
+        }
+
+        for token in SYNTHETIC_ASSETS {
+            let rates = (
+                config.interest_rate_per_asset.get(&token),
+                config.min_rate_per_asset.get(&token),
+                config.max_rate_per_asset.get(&token),
+            );
+
+            match rates {
+                (Some(_), Some(min_rate), Some(max_rate)) if min_rate <= max_rate => {}
+                _ => return Err(format!("Missing or invalid funding rates for {}", token)),
+            }
+        }
+
+        return Ok(config);
+    }
+
+    pub fn interest_rate(&self, token: u32) -> i64 {
+        return self.interest_rate_per_asset[&token];
+    }
+
+    /// The (min, max) funding rate of the market per realization period
+    pub fn rate_bounds(&self, token: u32) -> (i64, i64) {
+        return (
+            self.min_rate_per_asset[&token],
+            self.max_rate_per_asset[&token],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BTC: u32 = 3592681469;
+
+    #[test]
+    fn the_exchange_config_has_the_funding_parameters_of_every_market() {
+        let config = FundingConfig::from_file(EXCHANGE_CONFIG_PATH).unwrap();
+
+        assert_eq!(config.sample_interval_secs, 60);
+        assert_eq!(config.samples_per_settlement, 60);
+        assert_eq!(config.rate_bounds(BTC), (-750, 750));
+        assert_eq!(config.interest_rate(BTC), 10);
+    }
+
+    #[test]
+    fn a_market_without_funding_rates_is_rejected() {
+        let json = fs::read_to_string(EXCHANGE_CONFIG_PATH).unwrap();
+        let mut config: serde_json::Value = serde_json::from_str(&json).unwrap();
+        config["MAX_FUNDING_RATE_PER_ASSET"]
+            .as_object_mut()
+            .unwrap()
+            .remove(&BTC.to_string());
+
+        assert!(FundingConfig::from_json(&config.to_string()).is_err());
+
+        config["MAX_FUNDING_RATE_PER_ASSET"][BTC.to_string()] = (-1000).into();
+        assert!(FundingConfig::from_json(&config.to_string()).is_err());
+    }
+}
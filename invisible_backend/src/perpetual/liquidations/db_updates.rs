@@ -1,9 +1,9 @@
 use std::sync::Arc;
 
-use firestore_db_and_auth::ServiceSession;
 use parking_lot::Mutex;
 
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     perpetual::perp_position::PerpPosition,
     transactions::transaction_helpers::db_updates::DbNoteUpdater,
@@ -16,7 +16,7 @@ use crate::{
 use super::liquidation_order::LiquidationOrder;
 
 pub fn update_db_after_liquidation_swap(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     liquidation_order: &LiquidationOrder,
     liquidated_position: &Option<PerpPosition>,
@@ -75,7 +75,8 @@ pub fn open_new_position_after_liquidation(
         / (init_margin as u128 * multiplier)) as u64;
 
     // ? Check that leverage is valid relative to the notional position size
-    let max_leverage = get_max_leverage(liquidation_order.synthetic_token, liquidated_size);
+    let notional = (liquidated_size as u128 * market_price as u128 / multiplier) as u64;
+    let max_leverage = get_max_leverage(liquidation_order.synthetic_token, notional);
     if max_leverage < leverage {
         return Err(send_perp_swap_error(
             "Leverage is too high".to_string(),
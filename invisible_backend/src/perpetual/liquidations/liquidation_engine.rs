@@ -1,4 +1,3 @@
-use firestore_db_and_auth::ServiceSession;
 use num_bigint::BigUint;
 use parking_lot::Mutex;
 use serde_json::Value;
@@ -16,13 +15,17 @@ use super::liquidation_output::{wrap_liquidation_output, LiquidationResponse};
 use super::state_updates::{
     update_perpetual_state_after_liquidation, update_state_after_liquidation,
 };
+use crate::perpetual::open_interest::{apply_open_interest_change, open_interest_change};
 use crate::perpetual::perp_position::PerpPosition;
+use crate::perpetual::position_ledger::{liquidation_ledger_entries, liquidator_ledger_entries};
 use crate::transaction_batch::tx_batch_structs::SwapFundingInfo;
 use crate::transaction_batch::LeafNodeType;
 use crate::trees::superficial_tree::SuperficialTree;
 use crate::utils::crypto_utils::Signature;
 use crate::utils::errors::{send_perp_swap_error, PerpSwapExecutionError};
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
+use crate::utils::storage::local_storage::MainStorage;
 
 use error_stack::{Report, Result};
 //
@@ -64,10 +67,12 @@ impl LiquidationSwap {
         //
         index_price: u64,
         min_funding_idxs: Arc<Mutex<HashMap<u32, u32>>>,
+        open_interest: Arc<Mutex<HashMap<u32, u64>>>,
         swap_funding_info: SwapFundingInfo,
         //
-        session: Arc<Mutex<ServiceSession>>,
+        session: Arc<Mutex<FirestoreSession>>,
         backup_storage: Arc<Mutex<BackupStorage>>,
+        main_storage: Arc<Mutex<MainStorage>>,
     ) -> Result<LiquidationResponse, PerpSwapExecutionError> {
         //
 
@@ -142,6 +147,43 @@ impl LiquidationSwap {
                 &new_position,
             )?;
 
+            // ? The liquidator takes over the liquidated size, liquidations are never rejected
+            let open_interest_delta = open_interest_change(&[
+                (
+                    Some(&self.liquidation_order.position),
+                    liquidated_position.as_ref(),
+                ),
+                (None, Some(&new_position)),
+            ]);
+            let _ = apply_open_interest_change(
+                &open_interest,
+                self.liquidation_order.synthetic_token,
+                open_interest_delta,
+                false,
+            );
+
+            // ? Append the liquidation to the ledgers of both positions
+            let ledger = liquidation_ledger_entries(
+                &self.liquidation_order.position,
+                &liquidated_position,
+                &swap_funding_info,
+                liquidated_size,
+                self.market_price,
+            );
+            let liquidator_ledger =
+                liquidator_ledger_entries(&new_position, liquidator_fee, self.market_price);
+
+            let main_storage_m = main_storage.lock();
+            main_storage_m.store_ledger_entries(&ledger.0, &ledger.1);
+            main_storage_m.store_ledger_entries(&liquidator_ledger.0, &liquidator_ledger.1);
+            main_storage_m.store_open_interest(&open_interest.lock());
+            match &liquidated_position {
+                Some(position) => main_storage_m.store_position(position),
+                None => main_storage_m.remove_position(self.liquidation_order.position.index),
+            }
+            main_storage_m.store_position(&new_position);
+            drop(main_storage_m);
+
             Ok((liquidated_position, new_position))
         })
         .or_else(|e| {
@@ -1,12 +1,17 @@
 use phf::phf_map;
 use serde::{Deserialize, Serialize};
 
+pub mod cross_margin;
+pub mod funding_config;
 pub mod liquidations;
+pub mod open_interest;
 pub mod order_execution;
 pub mod perp_helpers;
 pub mod perp_order;
 pub mod perp_position;
 pub mod perp_swap;
+pub mod position_ledger;
+pub mod position_risk;
 
 #[derive(PartialEq, Debug, Clone, Deserialize, Serialize)]
 pub enum OrderSide {
@@ -25,12 +30,12 @@ pub enum PositionEffectType {
     Modify,
 }
 
+// Superseded by RISK_TIERS_PER_ASSET, still part of the global config layout
 pub static LEVERAGE_BOUNDS_PER_ASSET: phf::Map<&'static str, [f32; 2]> = phf_map! {
 "3592681469" => [1.5, 30.0], // BTC
 "453755560" => [15.0, 150.0], // ETH
 "277158171" => [1000.0, 10_000.0], // SOL
 };
-pub const MAX_LEVERAGE: f64 = 15.0;
 
 // BTC - 3592681469
 // ETH - 453755560
@@ -53,6 +58,13 @@ pub static DUST_AMOUNT_PER_ASSET: phf::Map<&'static str, u64> = phf_map! {
 "2413654107" => 50_000, // USDC ~ 5c
 "277158171" => 250_000, // SOL ~ 5c
 };
+// Minimum fee for restructuring (splitting/merging) notes, so the state can't be spammed with notes
+pub static NOTE_RESTRUCTURE_FEE_PER_ASSET: phf::Map<&'static str, u64> = phf_map! {
+"3592681469" => 250, // BTC ~ 5c
+"453755560" => 2500, // ETH ~ 5c
+"2413654107" => 50_000, // USDC ~ 5c
+"277158171" => 250_000, // SOL ~ 5c
+};
 
 // ? ------------------  SYNTHETIC_ASSETS ------------------ //
 
@@ -76,23 +88,120 @@ pub static MIN_PARTIAL_LIQUIDATION_SIZE: phf::Map<&'static str, u64> = phf_map!
 "277158171" => 350_000_000, // 3.5 SOL
 };
 
+// ? ------------------  RISK TIERS ------------------ //
+
+/// Margin requirements of positions up to a notional size (in collateral token units).
+///
+/// Fractions are per mille of the notional. The max leverage of a tier is 1000 / initial_margin_fraction.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RiskTier {
+    pub max_notional: u64,
+    pub initial_margin_fraction: u64,
+    pub maintenance_margin_fraction: u64,
+}
+
+const fn tier(max_notional_usd: u64, initial: u64, maintenance: u64) -> RiskTier {
+    RiskTier {
+        max_notional: max_notional_usd.saturating_mul(10_u64.pow(COLLATERAL_TOKEN_DECIMALS as u32)),
+        initial_margin_fraction: initial,
+        maintenance_margin_fraction: maintenance,
+    }
+}
+
+// Sorted by max_notional, the last tier covers everything above the previous one
+pub static RISK_TIERS_PER_ASSET: phf::Map<&'static str, &'static [RiskTier]> = phf_map! {
+"3592681469" => &[
+    tier(500_000, 67, 30), // 15X
+    tier(2_000_000, 100, 50), // 10X
+    tier(5_000_000, 200, 80), // 5X
+    tier(10_000_000, 333, 120), // 3X
+    tier(u64::MAX, 500, 200), // 2X
+], // BTC
+"453755560" => &[
+    tier(250_000, 67, 30),
+    tier(1_000_000, 100, 50),
+    tier(2_500_000, 200, 80),
+    tier(5_000_000, 333, 120),
+    tier(u64::MAX, 500, 200),
+], // ETH
+"277158171" => &[
+    tier(100_000, 67, 30),
+    tier(500_000, 100, 50),
+    tier(1_000_000, 200, 80),
+    tier(2_500_000, 333, 120),
+    tier(u64::MAX, 500, 200),
+], // SOL
+};
+
+/// Returns the risk tier of a position in synthetic_token with the given notional
+pub fn get_risk_tier(synthetic_token: u32, notional: u64) -> &'static RiskTier {
+    let tiers = RISK_TIERS_PER_ASSET[&synthetic_token.to_string()];
+
+    tiers
+        .iter()
+        .find(|t| notional <= t.max_notional)
+        .unwrap_or(&tiers[tiers.len() - 1])
+}
+
+// ? ------------------  MARKET PARAMETERS ------------------ //
+// Shared by the spot and perp markets of the same base asset
+
+// Smallest price increment (with PRICE_DECIMALS_PER_ASSET decimals)
+pub static TICK_SIZE_PER_ASSET: phf::Map<&'static str, u64> = phf_map! {
+"3592681469" => 100_000, // BTC - 0.1 USD
+"453755560" => 10_000, // ETH - 0.01 USD
+"277158171" => 1_000, // SOL - 0.001 USD
+};
+// Order sizes must be a multiple of the lot size (in base asset units)
+pub static LOT_SIZE_PER_ASSET: phf::Map<&'static str, u64> = phf_map! {
+"3592681469" => 10_000, // 0.0001 BTC
+"453755560" => 100_000, // 0.001 ETH
+"277158171" => 1_000_000, // 0.01 SOL
+};
+// Minimum order value (in collateral token units)
+pub static MIN_NOTIONAL_PER_ASSET: phf::Map<&'static str, u64> = phf_map! {
+"3592681469" => 10_000_000, // 10 USD
+"453755560" => 10_000_000, // 10 USD
+"277158171" => 10_000_000, // 10 USD
+};
+// Maximum size of a single order (in base asset units)
+pub static MAX_ORDER_SIZE_PER_ASSET: phf::Map<&'static str, u64> = phf_map! {
+"3592681469" => 10_000_000_000, // 100 BTC
+"453755560" => 100_000_000_000, // 1000 ETH
+"277158171" => 10_000_000_000_000, // 100_000 SOL
+};
+
+// ? ------------------  COLLATERAL ASSETS ------------------ //
+// Assets that can back cross margin accounts, valued at the index price minus a haircut (per mille)
+pub static COLLATERAL_ASSETS: [u32; 3] = [2413654107, 3592681469, 453755560];
+pub static COLLATERAL_HAIRCUT_PER_ASSET: phf::Map<&'static str, u64> = phf_map! {
+"2413654107" => 0, // USDC
+"3592681469" => 100, // BTC - 10 %
+"453755560" => 150, // ETH - 15 %
+};
+// Discount (per mille) at which liquidators buy the non-USD collateral of liquidatable accounts.
+// It is below every haircut, so selling collateral never lowers the account value.
+pub const COLLATERAL_LIQUIDATION_DISCOUNT: u64 = 25; // 2.5 %
+
+// ? ------------------  OPEN INTEREST ------------------ //
+// Max open interest of a market (the total size of its long positions, in synthetic asset units)
+pub static MAX_OPEN_INTEREST_PER_ASSET: phf::Map<&'static str, u64> = phf_map! {
+"3592681469" => 100_000_000_000, // 1_000 BTC
+"453755560" => 1_000_000_000_000, // 10_000 ETH
+"277158171" => 100_000_000_000_000, // 1_000_000 SOL
+};
+// Max size of a single position (in synthetic asset units)
+pub static MAX_POSITION_SIZE_PER_ASSET: phf::Map<&'static str, u64> = phf_map! {
+"3592681469" => 15_000_000_000, // 150 BTC
+"453755560" => 150_000_000_000, // 1_500 ETH
+"277158171" => 15_000_000_000_000, // 150_000 SOL
+};
+
 pub const LEVERAGE_DECIMALS: u8 = 4; // 6 decimals for leverage
 pub const COLLATERAL_TOKEN_DECIMALS: u8 = 6; // 6 decimals for USDC/USDT...
 
 // impact Notional Amount = 500 USDC / Initial Margin Fraction
 
-// notional_size0 => 2 BTC
-// 3 BTC > 20X leverage > init_margin = 5%
-// 6 BTC > 10X leverage > init_margin = 10%
-// 9 BTC > 5X leverage > init_margin = 20%
-// 12 BTC > 4X leverage > init_margin = 25%
-// 16 BTC > 3X leverage > init_margin = 33.3%
-// 20 BTC > 2X leverage > init_margin = 50%
-// 25 BTC > 1.5X leverage > init_margin = 66.6%
-
-// 10 BTC min init_margin = 3BTC*5% + 6BTC*10% + 1BTC*20% = 0.95BTC
-// max leverage = 10BTC/0.95BTC = 10.5X
-
 // * Price functions * // ====================================================================
 pub fn get_price(synthetic_token: u32, collateral_amount: u64, synthetic_amount: u64) -> u64 {
     let synthetic_decimals: &u8 = DECIMALS_PER_ASSET
@@ -130,7 +239,7 @@ pub fn get_cross_price(
 
         return price;
 
-        // return round_price(price, round);
+        // return round_price(price, base_token, round);
     } else {
         return 0.0;
     }
@@ -174,16 +283,23 @@ pub fn get_collateral_amount(synthetic_token: u32, synthetic_amount: u64, price:
     return collateral_amount;
 }
 
-pub fn round_price(price: f64, round: Option<bool>) -> f64 {
-    if let Some(r) = round {
-        if r {
-            return (price * 100.0).ceil() / 100.0;
-        } else {
-            return (price * 100.0).floor() / 100.0;
-        }
-    }
+/// Rounds the price to the tick size of the token (up if round is Some(true), down otherwise)
+pub fn round_price(price: f64, token: u32, round: Option<bool>) -> f64 {
+    let price_decimals = PRICE_DECIMALS_PER_ASSET[&token.to_string()];
+    let tick_size =
+        TICK_SIZE_PER_ASSET[&token.to_string()] as f64 / 10_f64.powi(price_decimals as i32);
+
+    // ? Avoid moving prices that are already on a tick because of floating point errors
+    let ticks = price / tick_size;
+    let ticks = if (ticks - ticks.round()).abs() < 1e-9 * ticks.abs().max(1.0) {
+        ticks.round()
+    } else if round == Some(true) {
+        ticks.ceil()
+    } else {
+        ticks.floor()
+    };
 
-    return (price * 100.0).floor() / 100.0;
+    return ticks * tick_size;
 }
 
 pub fn scale_up_price(price: f64, token: u32) -> u64 {
@@ -0,0 +1,258 @@
+//! Open interest accounting and position limits per market.
+//!
+//! The open interest of a market is the total size of its long positions (which always equals
+//! the total size of its short positions). It is updated from the positions before and after
+//! every perp swap and liquidation, and capped at MAX_OPEN_INTEREST_PER_ASSET.
+//!
+//! Position sizes are capped at MAX_POSITION_SIZE_PER_ASSET over all the positions an owner
+//! (position address) has in a market.
+
+use std::collections::HashMap;
+
+use parking_lot::Mutex;
+
+use super::{
+    perp_position::PerpPosition, OrderSide, MAX_OPEN_INTEREST_PER_ASSET,
+    MAX_POSITION_SIZE_PER_ASSET,
+};
+
+/// The max open interest of a market (markets without one are not restricted)
+pub fn max_open_interest(synthetic_token: u32) -> u64 {
+    return *MAX_OPEN_INTEREST_PER_ASSET
+        .get(&synthetic_token.to_string())
+        .unwrap_or(&u64::MAX);
+}
+
+/// The max size of a single position in a market (markets without one are not restricted)
+pub fn max_position_size(synthetic_token: u32) -> u64 {
+    return *MAX_POSITION_SIZE_PER_ASSET
+        .get(&synthetic_token.to_string())
+        .unwrap_or(&u64::MAX);
+}
+
+/// The size of the position after an order is fully filled against it
+pub fn size_after_order(
+    position: Option<&PerpPosition>,
+    order_side: &OrderSide,
+    synthetic_amount: u64,
+) -> u64 {
+    match position {
+        None => synthetic_amount,
+        Some(pos) if pos.order_side == *order_side => pos.position_size + synthetic_amount,
+        Some(pos) if synthetic_amount > pos.position_size => synthetic_amount - pos.position_size,
+        Some(pos) => pos.position_size - synthetic_amount,
+    }
+}
+
+/// The total size of the positions of an owner in a market, with one of them (or a new one)
+/// at the given size
+///
+/// The max position size applies to this total, since an owner can open several positions in
+/// the same market.
+///
+/// # Arguments
+/// * `owner_positions` - All the positions stored at the owner's address (in any market)
+/// * `position_index` - The index of the position being updated (None for a new position)
+/// * `position_size` - The size of the position being updated after the update
+pub fn owner_position_size(
+    owner_positions: &[PerpPosition],
+    synthetic_token: u32,
+    position_index: Option<u64>,
+    position_size: u64,
+) -> u64 {
+    let other_positions_size: u64 = owner_positions
+        .iter()
+        .filter(|pos| {
+            pos.position_header.synthetic_token == synthetic_token
+                && Some(pos.index) != position_index
+        })
+        .map(|pos| pos.position_size)
+        .sum();
+
+    return other_positions_size + position_size;
+}
+
+/// The most an order can add to the open interest of its market when fully filled
+/// (if all of its counterparties open new positions)
+pub fn max_open_interest_increase(
+    position: Option<&PerpPosition>,
+    order_side: &OrderSide,
+    synthetic_amount: u64,
+) -> u64 {
+    match position {
+        Some(pos) if pos.order_side != *order_side => {
+            synthetic_amount.saturating_sub(pos.position_size)
+        }
+        _ => synthetic_amount,
+    }
+}
+
+/// The change of the open interest after positions were updated
+///
+/// # Arguments
+/// * `updates` - The (previous, new) state of every updated position (None if it didn't/doesn't exist)
+pub fn open_interest_change(updates: &[(Option<&PerpPosition>, Option<&PerpPosition>)]) -> i64 {
+    return updates
+        .iter()
+        .map(|(prev_position, position)| {
+            long_size(*position) as i64 - long_size(*prev_position) as i64
+        })
+        .sum();
+}
+
+/// Applies a change to the open interest of a market
+///
+/// An increase past the max open interest is rejected (and nothing is applied) unless
+/// `enforce_cap` is false, e.g. for liquidations which must never be rejected.
+///
+/// # Returns
+/// * `u64` - The new open interest of the market
+pub fn apply_open_interest_change(
+    open_interest: &Mutex<HashMap<u32, u64>>,
+    synthetic_token: u32,
+    change: i64,
+    enforce_cap: bool,
+) -> Result<u64, String> {
+    let mut open_interest_m = open_interest.lock();
+    let current = *open_interest_m.get(&synthetic_token).unwrap_or(&0);
+
+    let new_open_interest = if change >= 0 {
+        current.saturating_add(change as u64)
+    } else {
+        current.saturating_sub(change.unsigned_abs())
+    };
+
+    let max = max_open_interest(synthetic_token);
+    if enforce_cap && change > 0 && new_open_interest > max {
+        return Err(format!(
+            "Open interest would exceed the market limit: {} > {}",
+            new_open_interest, max
+        ));
+    }
+
+    open_interest_m.insert(synthetic_token, new_open_interest);
+
+    return Ok(new_open_interest);
+}
+
+// * HELPERS * //
+
+fn long_size(position: Option<&PerpPosition>) -> u64 {
+    match position {
+        Some(pos) if pos.order_side == OrderSide::Long => pos.position_size,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use num_bigint::BigUint;
+    use num_traits::FromPrimitive;
+
+    use crate::perpetual::COLLATERAL_TOKEN;
+
+    const BTC: u32 = 3592681469;
+
+    fn position(order_side: OrderSide, size: u64) -> PerpPosition {
+        position_at(BTC, 7, order_side, size)
+    }
+
+    fn position_at(
+        synthetic_token: u32,
+        index: u64,
+        order_side: OrderSide,
+        size: u64,
+    ) -> PerpPosition {
+        PerpPosition::new(
+            order_side,
+            size,
+            synthetic_token,
+            COLLATERAL_TOKEN,
+            1_000 * 10u64.pow(6),
+            10u64.pow(4),
+            false,
+            BigUint::from_u64(1234).unwrap(),
+            0,
+            index,
+            0,
+        )
+    }
+
+    #[test]
+    fn swaps_move_the_open_interest_by_the_long_exposure() {
+        let long = position(OrderSide::Long, 100);
+        let short = position(OrderSide::Short, 100);
+
+        // ? Two new positions open 100 of open interest
+        let opened = open_interest_change(&[(None, Some(&long)), (None, Some(&short))]);
+        assert_eq!(opened, 100);
+
+        // ? The long sells 40 to a new long, nothing changes
+        let reduced = position(OrderSide::Long, 60);
+        let new_long = position(OrderSide::Long, 40);
+        let transferred =
+            open_interest_change(&[(Some(&long), Some(&reduced)), (None, Some(&new_long))]);
+        assert_eq!(transferred, 0);
+
+        // ? Both sides close
+        let closed = open_interest_change(&[(Some(&long), None), (Some(&short), None)]);
+        assert_eq!(closed, -100);
+
+        // ? The short flips to a 50 long
+        let flipped = position(OrderSide::Long, 50);
+        assert_eq!(size_after_order(Some(&short), &OrderSide::Long, 150), 50);
+        assert_eq!(
+            max_open_interest_increase(Some(&short), &OrderSide::Long, 150),
+            50
+        );
+        assert_eq!(
+            max_open_interest_increase(Some(&short), &OrderSide::Long, 80),
+            0
+        );
+        assert_eq!(open_interest_change(&[(Some(&short), Some(&flipped))]), 50);
+    }
+
+    #[test]
+    fn position_sizes_add_up_over_the_positions_of_an_owner() {
+        const ETH: u32 = 453755560;
+
+        let owner_positions = vec![
+            position_at(BTC, 3, OrderSide::Long, 100),
+            position_at(BTC, 5, OrderSide::Short, 30),
+            position_at(ETH, 8, OrderSide::Long, 1_000),
+        ];
+
+        // ? A new position adds to the positions of the owner in its market only
+        assert_eq!(owner_position_size(&owner_positions, BTC, None, 20), 150);
+
+        // ? An updated position replaces its previous size
+        assert_eq!(owner_position_size(&owner_positions, BTC, Some(3), 40), 70);
+        assert_eq!(owner_position_size(&owner_positions, ETH, Some(8), 0), 0);
+
+        let max = max_position_size(BTC);
+        assert!(owner_position_size(&owner_positions, BTC, None, max - 100) > max);
+        assert_eq!(owner_position_size(&[], BTC, None, max), max);
+    }
+
+    #[test]
+    fn increases_past_the_cap_are_rejected() {
+        let max = max_open_interest(BTC);
+        let open_interest = Mutex::new(HashMap::from([(BTC, max - 10)]));
+
+        assert!(apply_open_interest_change(&open_interest, BTC, 11, true).is_err());
+        assert_eq!(open_interest.lock()[&BTC], max - 10);
+
+        // ? Decreases and unenforced changes always go through
+        assert_eq!(
+            apply_open_interest_change(&open_interest, BTC, 11, false),
+            Ok(max + 1)
+        );
+        assert_eq!(
+            apply_open_interest_change(&open_interest, BTC, -1, true),
+            Ok(max)
+        );
+        assert!(apply_open_interest_change(&open_interest, BTC, 1, true).is_err());
+    }
+}
@@ -1,6 +1,5 @@
 use std::{collections::HashMap, sync::Arc};
 
-use crate::utils::crypto_utils::Signature;
 use crate::{
     perpetual::{
         get_price, perp_order::PerpOrder, perp_position::PerpPosition, DUST_AMOUNT_PER_ASSET,
@@ -19,7 +18,6 @@ pub fn execute_close_order(
     swap_funding_info: &SwapFundingInfo,
     partialy_filled_positions_m: &Arc<Mutex<HashMap<String, (PerpPosition, u64)>>>,
     order: &PerpOrder,
-    signature: &Signature,
     fee_taken: u64,
     spent_collateral: u64,
     spent_synthetic: u64,
@@ -52,7 +50,6 @@ pub fn execute_close_order(
         swap_funding_info,
         order,
         prev_position,
-        signature,
         fee_taken,
         spent_collateral,
         spent_synthetic,
@@ -80,7 +77,6 @@ fn close_position(
     swap_funding_info: &SwapFundingInfo,
     order: &PerpOrder,
     prev_position: &PerpPosition,
-    signature: &Signature,
     fee_taken: u64,
     spent_collateral: u64,
     spent_synthetic: u64,
@@ -102,9 +98,6 @@ fn close_position(
         ));
     }
 
-    // ? Verify order signature
-    order.verify_order_signature(signature, Some(&position.position_header.position_address))?;
-
     if spent_synthetic > position.position_size {
         return Err(send_perp_swap_error(
             "over spending in position close".to_string(),
@@ -1,4 +1,3 @@
-use firestore_db_and_auth::ServiceSession;
 use std::{collections::HashMap, sync::Arc};
 
 use crossbeam::thread;
@@ -10,6 +9,7 @@ use serde_json::{Map, Value};
 use error_stack::{Report, Result};
 
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     transaction_batch::{tx_batch_structs::SwapFundingInfo, LeafNodeType},
     transactions::transaction_helpers::swap_helpers::unblock_order,
@@ -47,6 +47,7 @@ use crate::utils::crypto_utils::Signature;
 pub mod close_order;
 pub mod modify_order;
 pub mod open_order;
+pub mod simulate_order;
 
 type ExecutionResult = (TxExecutionThreadOutput, TxExecutionThreadOutput);
 pub fn execute_perp_swap_transaction(
@@ -144,6 +145,11 @@ pub fn execute_perp_swap_transaction(
                         order_a.order_id,
                     )?;
 
+                    order_a.verify_order_signature(
+                        &signature_a.as_ref().unwrap(),
+                        Some(&prev_position.position_header.position_address),
+                    )?;
+
                     let (
                         position,
                         new_pfr_info,
@@ -156,7 +162,6 @@ pub fn execute_perp_swap_transaction(
                         fee_taken_a,
                         &partialy_filled_positions__,
                         &order_a,
-                        &signature_a.as_ref().unwrap(),
                         spent_collateral,
                         spent_synthetic,
                         &prev_position,
@@ -187,6 +192,11 @@ pub fn execute_perp_swap_transaction(
                         order_a.order_id,
                     )?;
 
+                    order_a.verify_order_signature(
+                        &signature_a.as_ref().unwrap(),
+                        Some(&prev_position.position_header.position_address),
+                    )?;
+
                     let (
                         position_index,
                         position,
@@ -199,7 +209,6 @@ pub fn execute_perp_swap_transaction(
                         &swap_funding_info__,
                         &partialy_filled_positions__,
                         &order_a,
-                        &signature_a.as_ref().unwrap(),
                         fee_taken_a,
                         spent_collateral,
                         spent_synthetic,
@@ -302,6 +311,11 @@ pub fn execute_perp_swap_transaction(
                         order_b.order_id,
                     )?;
 
+                    order_b.verify_order_signature(
+                        &signature_b.as_ref().unwrap(),
+                        Some(&prev_position.position_header.position_address),
+                    )?;
+
                     let (
                         position,
                         new_pfr_info,
@@ -314,7 +328,6 @@ pub fn execute_perp_swap_transaction(
                         fee_taken_b,
                         &partialy_filled_positions__,
                         &order_b,
-                        &signature_b.as_ref().unwrap(),
                         spent_collateral,
                         spent_synthetic,
                         &prev_position,
@@ -345,6 +358,11 @@ pub fn execute_perp_swap_transaction(
                         order_b.order_id,
                     )?;
 
+                    order_b.verify_order_signature(
+                        &signature_b.as_ref().unwrap(),
+                        Some(&prev_position.position_header.position_address),
+                    )?;
+
                     let (
                         position_index,
                         position,
@@ -357,7 +375,6 @@ pub fn execute_perp_swap_transaction(
                         &swap_funding_info__,
                         &partialy_filled_positions__,
                         &order_b,
-                        &signature_b.as_ref().unwrap(),
                         fee_taken_b,
                         spent_collateral,
                         spent_synthetic,
@@ -446,7 +463,7 @@ pub fn update_state_and_finalize(
     blocked_perp_order_ids: &Arc<Mutex<HashMap<u64, bool>>>,
     perpetual_partial_fill_tracker: &Arc<Mutex<HashMap<u64, (Option<Note>, u64, u64)>>>, // (pfr_note, amount_filled, spent_margin)
     partialy_filled_positions: &Arc<Mutex<HashMap<String, (PerpPosition, u64)>>>, // (position, synthetic filled)
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     //
     execution_result: &mut ExecutionResult,
@@ -2,8 +2,11 @@ use std::{collections::HashMap, sync::Arc};
 
 use crate::{
     perpetual::{
-        get_price, perp_helpers::perp_swap_helpers::get_max_leverage, perp_order::PerpOrder,
-        perp_position::PerpPosition, DUST_AMOUNT_PER_ASSET,
+        get_collateral_amount, get_price,
+        perp_helpers::perp_swap_helpers::{check_max_position_size, get_max_leverage},
+        perp_order::PerpOrder,
+        perp_position::PerpPosition,
+        DUST_AMOUNT_PER_ASSET,
     },
     transaction_batch::tx_batch_structs::SwapFundingInfo,
     utils::{
@@ -14,15 +17,12 @@ use crate::{
 use error_stack::Result;
 use parking_lot::Mutex;
 
-use crate::utils::crypto_utils::Signature;
-
 pub fn execute_modify_order(
     swap_funding_info: &SwapFundingInfo,
     index_price: u64,
     fee_taken: u64,
     partialy_filled_positions_m: &Arc<Mutex<HashMap<String, (PerpPosition, u64)>>>,
     order: &PerpOrder,
-    signature: &Signature,
     spent_collateral: u64,
     spent_synthetic: u64,
     prev_position: &PerpPosition,
@@ -44,7 +44,6 @@ pub fn execute_modify_order(
         swap_funding_info,
         order,
         prev_position,
-        signature,
         fee_taken,
         spent_collateral,
         spent_synthetic,
@@ -72,7 +71,6 @@ fn modify_position(
     swap_funding_info: &SwapFundingInfo,
     order: &PerpOrder,
     prev_position: &PerpPosition,
-    signature: &Signature,
     fee_taken: u64,
     spent_collateral: u64,
     spent_synthetic: u64,
@@ -95,8 +93,6 @@ fn modify_position(
         ));
     }
 
-    order.verify_order_signature(signature, Some(&position.position_header.position_address))?;
-
     // ? Check that order token matches synthetic token
     if prev_position.position_header.synthetic_token != order.synthetic_token {
         return Err(send_perp_swap_error(
@@ -106,6 +102,20 @@ fn modify_position(
         ));
     }
 
+    // ? Reduce only orders can't increase or flip the position at its current size
+    if order.reduce_only
+        && (position.order_side == order.order_side || spent_synthetic > position.position_size)
+    {
+        return Err(send_perp_swap_error(
+            "Reduce only order would increase the position".to_string(),
+            Some(order.order_id),
+            Some(format!(
+                "spent_synthetic: {}, position_size: {}",
+                spent_synthetic, position.position_size
+            )),
+        ));
+    }
+
     let price: u64 = get_price(order.synthetic_token, spent_collateral, spent_synthetic);
 
     if position.order_side == order.order_side {
@@ -124,10 +134,14 @@ fn modify_position(
             swap_funding_info.current_funding_idx,
         );
 
+        check_max_position_size(&position, order.order_id)?;
+
         let leverage = position.get_current_leverage(index_price)?;
 
         // ? Check that leverage is valid relative to the notional position size after increasing size
-        if get_max_leverage(order.synthetic_token, position.position_size) * 103 / 100 < leverage {
+        let notional =
+            get_collateral_amount(order.synthetic_token, position.position_size, index_price);
+        if get_max_leverage(order.synthetic_token, notional) * 103 / 100 < leverage {
             return Err(send_perp_swap_error(
                 "Leverage would be too high".to_string(),
                 Some(order.order_id),
@@ -153,12 +167,14 @@ fn modify_position(
                 swap_funding_info.current_funding_idx,
             );
 
+            check_max_position_size(&position, order.order_id)?;
+
             let leverage = position.get_current_leverage(index_price)?;
 
             // ? Check that leverage is valid relative to the notional position size after increasing size
-            if get_max_leverage(order.synthetic_token, position.position_size) * 103 / 100
-                < leverage
-            {
+            let notional =
+                get_collateral_amount(order.synthetic_token, position.position_size, index_price);
+            if get_max_leverage(order.synthetic_token, notional) * 103 / 100 < leverage {
                 return Err(send_perp_swap_error(
                     "Leverage would be too high".to_string(),
                     Some(order.order_id),
@@ -7,8 +7,10 @@ use std::{collections::HashMap, sync::Arc};
 
 use crate::{
     perpetual::{
+        get_collateral_amount,
         perp_helpers::perp_swap_helpers::{
-            _check_note_sums, _check_prev_fill_consistencies, get_max_leverage, refund_partial_fill,
+            _check_note_sums, _check_prev_fill_consistencies, check_max_position_size,
+            get_max_leverage, refund_partial_fill,
         },
         perp_order::PerpOrder,
         perp_position::PerpPosition,
@@ -201,14 +203,14 @@ fn open_new_position(
         / (init_margin - fee_taken) as u128) as u64;
 
     // ? Check that leverage is valid relative to the notional position size
-    let max_leverage = get_max_leverage(order.synthetic_token, spent_synthetic);
+    let max_leverage = get_max_leverage(order.synthetic_token, spent_collateral);
     if max_leverage * 103 / 100 < leverage {
         return Err(send_perp_swap_error(
             "Leverage is too high".to_string(),
             Some(order.order_id),
             Some(format!(
-                "Max leverage for {} with size {} is {}",
-                order.synthetic_token, spent_synthetic, max_leverage
+                "Max leverage for {} with notional {} is {}",
+                order.synthetic_token, spent_collateral, max_leverage
             )),
         ));
     }
@@ -236,6 +238,8 @@ fn open_new_position(
         fee_taken,
     );
 
+    check_max_position_size(&position, order.order_id)?;
+
     return Ok(position);
 }
 
@@ -296,16 +300,22 @@ fn add_margin_to_position(
 
     position.add_margin_to_position(init_margin, spent_synthetic, leverage, fee_taken);
 
+    check_max_position_size(&position, order.order_id)?;
+
     // ? Check that leverage is valid relative to the notional position size
-    if get_max_leverage(order.synthetic_token, position.position_size) * 103 / 100 < leverage {
+    let notional = get_collateral_amount(
+        order.synthetic_token,
+        position.position_size,
+        position.entry_price,
+    );
+    let max_leverage = get_max_leverage(order.synthetic_token, notional);
+    if max_leverage * 103 / 100 < leverage {
         return Err(send_perp_swap_error(
             "Leverage is too high".to_string(),
             Some(order.order_id),
             Some(format!(
-                "Max leverage for {} with size {} is {}",
-                order.synthetic_token,
-                order.synthetic_amount,
-                get_max_leverage(order.synthetic_token, order.synthetic_amount)
+                "Max leverage for {} with notional {} is {}",
+                order.synthetic_token, notional, max_leverage
             )),
         ));
     }
@@ -317,6 +327,8 @@ fn add_margin_to_position(
 
 // * ======================================================================================================
 
+/// Positions are margined in COLLATERAL_TOKEN only (the haircut COLLATERAL_ASSETS are only
+/// valued by the cross margin account type, which no order can be placed against yet).
 pub fn check_valid_collateral_token(order: &PerpOrder) -> Result<(), PerpSwapExecutionError> {
     // ? Collateral token is invalid
     if COLLATERAL_TOKEN != order.open_order_fields.as_ref().unwrap().collateral_token {
@@ -0,0 +1,199 @@
+//! Dry runs of perp orders.
+//!
+//! The open, modify and close math of the order execution is run on the fills an order would get
+//! from the book, so users can check an order before signing it. Nothing in the state is updated:
+//! the partial fills are tracked in a scratch map and new positions don't take a state index.
+//!
+//! The fills are executed as a single swap at their average price, so the resulting position can
+//! differ slightly from the one of the real execution (where every fill is a separate swap).
+
+use std::{collections::HashMap, sync::Arc};
+
+use error_stack::Result;
+use parking_lot::Mutex;
+
+use crate::{
+    perpetual::{
+        perp_helpers::perp_swap_helpers::consistency_checks, perp_order::PerpOrder,
+        perp_position::PerpPosition, OrderSide, PositionEffectType,
+    },
+    transaction_batch::tx_batch_structs::SwapFundingInfo,
+    trees::superficial_tree::SuperficialTree,
+    utils::errors::PerpSwapExecutionError,
+};
+
+use super::{
+    close_order::execute_close_order,
+    modify_order::execute_modify_order,
+    open_order::{check_valid_collateral_token, execute_open_order, get_init_margin},
+    verify_position_existence,
+};
+
+/// Order id given to simulated orders (the real id is assigned when the order enters the book)
+pub const SIMULATED_ORDER_ID: u64 = u64::MAX;
+
+/// A swap between the simulated order (always the taker) and a resting order
+#[derive(Debug, Clone)]
+pub struct SimulatedPerpFill {
+    pub maker_order: PerpOrder,
+    pub spent_synthetic: u64,
+    pub spent_collateral: u64,
+    pub fee_taken: u64, // taker fee (makers pay no fees)
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulatedPerpOrder {
+    pub spent_synthetic: u64,
+    pub spent_collateral: u64,
+    pub fee_taken: u64,
+    pub prev_position: Option<PerpPosition>,
+    pub position: Option<PerpPosition>, // None if the position would be fully closed
+    pub collateral_returned: u64,
+}
+
+/// Runs the order execution for the fills of an order without updating the state
+///
+/// # Arguments
+/// * `state_tree` - Only read to check the position being updated exists
+/// * `fills` - The fills the order would get from the book (can be empty)
+pub fn simulate_perp_order(
+    state_tree: &Arc<Mutex<SuperficialTree>>,
+    order: &PerpOrder,
+    fills: &[SimulatedPerpFill],
+    index_price: u64,
+    swap_funding_info: &SwapFundingInfo,
+) -> Result<SimulatedPerpOrder, PerpSwapExecutionError> {
+    let mut order = order.clone();
+    if order.order_id == 0 {
+        order.order_id = SIMULATED_ORDER_ID;
+    }
+
+    // ? Every fill is a separate swap with order a being the long order
+    for fill in fills {
+        if order.order_side == OrderSide::Long {
+            consistency_checks(
+                &order,
+                &fill.maker_order,
+                fill.spent_collateral,
+                fill.spent_synthetic,
+                fill.fee_taken,
+                0,
+            )?;
+        } else {
+            consistency_checks(
+                &fill.maker_order,
+                &order,
+                fill.spent_collateral,
+                fill.spent_synthetic,
+                0,
+                fill.fee_taken,
+            )?;
+        }
+    }
+
+    let spent_synthetic: u64 = fills.iter().map(|f| f.spent_synthetic).sum();
+    let spent_collateral: u64 = fills.iter().map(|f| f.spent_collateral).sum();
+    let fee_taken: u64 = fills.iter().map(|f| f.fee_taken).sum();
+
+    let prev_position = match order.position_effect_type {
+        PositionEffectType::Open => None,
+        _ => order.position.clone(),
+    };
+
+    // ? An order that wouldn't be filled leaves the position as it is
+    if spent_synthetic == 0 {
+        return Ok(SimulatedPerpOrder {
+            spent_synthetic,
+            spent_collateral,
+            fee_taken,
+            position: prev_position.clone(),
+            prev_position,
+            collateral_returned: 0,
+        });
+    }
+
+    let partialy_filled_positions: Arc<Mutex<HashMap<String, (PerpPosition, u64)>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+
+    let position: Option<PerpPosition>;
+    let mut collateral_returned: u64 = 0;
+    match order.position_effect_type {
+        PositionEffectType::Open => {
+            check_valid_collateral_token(&order)?;
+
+            // ? A partial refund note would take an index in the state tree
+            let depth = state_tree.lock().depth;
+            let scratch_tree = Arc::new(Mutex::new(SuperficialTree::new(depth)));
+
+            let init_margin = get_init_margin(&order, spent_synthetic);
+
+            let (_, new_position, _, _, _, _, _) = execute_open_order(
+                &scratch_tree,
+                &partialy_filled_positions,
+                &order,
+                fee_taken,
+                0,
+                swap_funding_info.current_funding_idx,
+                spent_synthetic,
+                spent_collateral,
+                init_margin,
+                None,
+            )?;
+
+            position = Some(new_position);
+        }
+        PositionEffectType::Modify => {
+            let prev_position = verify_position_existence(
+                state_tree,
+                &partialy_filled_positions,
+                &order.position,
+                order.order_id,
+            )?;
+
+            let (new_position, _, _, _, _) = execute_modify_order(
+                swap_funding_info,
+                index_price,
+                fee_taken,
+                &partialy_filled_positions,
+                &order,
+                spent_collateral,
+                spent_synthetic,
+                &prev_position,
+                None,
+            )?;
+
+            position = Some(new_position);
+        }
+        PositionEffectType::Close => {
+            let prev_position = verify_position_existence(
+                state_tree,
+                &partialy_filled_positions,
+                &order.position,
+                order.order_id,
+            )?;
+
+            let (_, new_position, _, collateral_returned_, _, _, _) = execute_close_order(
+                swap_funding_info,
+                &partialy_filled_positions,
+                &order,
+                fee_taken,
+                spent_collateral,
+                spent_synthetic,
+                &prev_position,
+                None,
+            )?;
+
+            position = new_position;
+            collateral_returned = collateral_returned_;
+        }
+    }
+
+    return Ok(SimulatedPerpOrder {
+        spent_synthetic,
+        spent_collateral,
+        fee_taken,
+        prev_position,
+        position,
+        collateral_returned,
+    });
+}
@@ -1,9 +1,9 @@
 use std::sync::Arc;
 
-use firestore_db_and_auth::ServiceSession;
 use parking_lot::Mutex;
 
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     perpetual::{perp_order::PerpOrder, perp_position::PerpPosition, PositionEffectType},
     transactions::transaction_helpers::{
@@ -18,7 +18,7 @@ use crate::{
 };
 
 pub fn update_db_after_perp_swap(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     order: &PerpOrder,
     prev_pfr_note: &Option<Note>,
@@ -101,7 +101,7 @@ pub fn update_db_after_perp_swap(
 // Store perp fill
 
 pub fn store_perp_fill(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     amount: u64,
     price: u64,
@@ -6,15 +6,17 @@ use std::time::Duration;
 
 use num_bigint::BigUint;
 
+use crate::perpetual::open_interest::{max_position_size, owner_position_size};
 use crate::perpetual::perp_order::OpenOrderFields;
 use crate::perpetual::perp_position::PerpPosition;
 use crate::perpetual::{
-    OrderSide, PositionEffectType, COLLATERAL_TOKEN, DECIMALS_PER_ASSET, DUST_AMOUNT_PER_ASSET,
-    LEVERAGE_BOUNDS_PER_ASSET, LEVERAGE_DECIMALS, MAX_LEVERAGE, SYNTHETIC_ASSETS,
+    get_risk_tier, OrderSide, PositionEffectType, COLLATERAL_TOKEN, DUST_AMOUNT_PER_ASSET,
+    LEVERAGE_DECIMALS, SYNTHETIC_ASSETS,
 };
 use crate::trees::superficial_tree::SuperficialTree;
 use crate::utils::errors::{send_perp_swap_error, PerpSwapExecutionError};
 use crate::utils::notes::Note;
+use crate::utils::storage::local_storage::MainStorage;
 
 use crate::utils::crypto_utils::EcPoint;
 use error_stack::Result;
@@ -45,28 +47,70 @@ pub fn refund_partial_fill(
     return Some(new_partial_refund_note);
 }
 
-/// Gets the maximum leverage for a given token and amount
-pub fn get_max_leverage(token: u32, amount: u64) -> u64 {
-    let [min_bound, max_bound] = LEVERAGE_BOUNDS_PER_ASSET
-        .get(token.to_string().as_str())
-        .unwrap();
+/// Gets the maximum leverage for a position in token with the given notional (in collateral token units)
+pub fn get_max_leverage(token: u32, notional: u64) -> u64 {
+    let tier = get_risk_tier(token, notional);
 
-    let token_decimals = DECIMALS_PER_ASSET.get(token.to_string().as_str()).unwrap();
-    let decimal_amount: f64 = (amount as f64) / 10_f64.powf(*token_decimals as f64);
+    return 10_u64.pow(LEVERAGE_DECIMALS as u32) * 1000 / tier.initial_margin_fraction;
+}
 
-    let max_leverage: f64;
+/// Checks that a position didn't grow past the max position size of its market
+pub fn check_max_position_size(
+    position: &PerpPosition,
+    order_id: u64,
+) -> Result<(), PerpSwapExecutionError> {
+    let max_size = max_position_size(position.position_header.synthetic_token);
+    if position.position_size > max_size {
+        return Err(send_perp_swap_error(
+            "Position size would exceed the market limit".to_string(),
+            Some(order_id),
+            Some(format!(
+                "position_size: {}, max_position_size: {}",
+                position.position_size, max_size
+            )),
+        ));
+    }
 
-    // TODO: FIGURE OUT CORRECT MAX LEVERAGE FOR PEPE
+    Ok(())
+}
 
-    if decimal_amount < *min_bound as f64 {
-        max_leverage = MAX_LEVERAGE;
-    } else if decimal_amount < *max_bound as f64 {
-        max_leverage = MAX_LEVERAGE * (*min_bound as f64 / decimal_amount);
-    } else {
-        max_leverage = 1.0;
+/// Checks that a position growing in a swap didn't take the positions of its owner in the market
+/// past the max position size (the limit applies to the sum of all of the owner's positions)
+pub fn check_owner_position_size(
+    main_storage: &Arc<Mutex<MainStorage>>,
+    prev_position: Option<&PerpPosition>,
+    position: Option<&PerpPosition>,
+    order_id: u64,
+) -> Result<(), PerpSwapExecutionError> {
+    let position = match position {
+        Some(pos) if pos.position_size > prev_position.map_or(0, |p| p.position_size) => pos,
+        _ => return Ok(()),
+    };
+
+    let synthetic_token = position.position_header.synthetic_token;
+    let owner_positions = main_storage
+        .lock()
+        .read_positions_at_address(&position.position_header.position_address.to_string());
+    let total_size = owner_position_size(
+        &owner_positions,
+        synthetic_token,
+        Some(position.index),
+        position.position_size,
+    );
+
+    let max_size = max_position_size(synthetic_token);
+    if total_size > max_size {
+        return Err(send_perp_swap_error(
+            "Position size would exceed the market limit".to_string(),
+            Some(order_id),
+            Some(format!(
+                "owner_position_size: {}, max_position_size: {}",
+                total_size, max_size
+            )),
+        ));
     }
 
-    return (max_leverage * 10_f64.powf(LEVERAGE_DECIMALS as f64)) as u64;
+    Ok(())
 }
 
 // * ==============================================================================
@@ -27,6 +27,8 @@ pub struct PerpOrder {
     pub open_order_fields: Option<OpenOrderFields>,
     // * Specific to Close orders
     pub close_order_fields: Option<CloseOrderFields>,
+    // * Engine-side flag, not part of the signed order hash
+    pub reduce_only: bool,
     //
     pub hash: BigUint,
 }
@@ -71,6 +73,7 @@ impl PerpOrder {
             fee_limit,
             open_order_fields,
             close_order_fields: None,
+            reduce_only: false,
             hash,
         };
     }
@@ -112,6 +115,7 @@ impl PerpOrder {
             fee_limit,
             open_order_fields: None,
             close_order_fields: None,
+            reduce_only: false,
             hash,
         };
     }
@@ -156,6 +160,7 @@ impl PerpOrder {
             open_order_fields: None,
             close_order_fields,
 
+            reduce_only: false,
             hash,
         };
     }
@@ -8,11 +8,12 @@ use serde::Deserialize as DeserializeTrait;
 
 use crate::perpetual::OrderSide;
 use crate::perpetual::{
-    COLLATERAL_TOKEN_DECIMALS, DECIMALS_PER_ASSET, LEVERAGE_DECIMALS, MIN_PARTIAL_LIQUIDATION_SIZE,
-    PRICE_DECIMALS_PER_ASSET,
+    get_risk_tier, COLLATERAL_TOKEN_DECIMALS, DECIMALS_PER_ASSET, LEVERAGE_DECIMALS,
+    MIN_PARTIAL_LIQUIDATION_SIZE, PRICE_DECIMALS_PER_ASSET,
 };
 use crate::utils::errors::{send_perp_swap_error, PerpSwapExecutionError};
 
+use crate::utils::cairo_output::PerpPositionOutput;
 use crate::utils::crypto_utils::hash_many;
 
 // position should have address or something
@@ -40,7 +41,7 @@ impl PerpPosition {
         order_side: OrderSide,
         position_size: u64,
         synthetic_token: u32,
-        _collateral_token: u32, // TODO: What to do with this?
+        _collateral_token: u32, // isolated positions are only margined in COLLATERAL_TOKEN
         margin: u64,
         leverage: u64,
         allow_partial_liquidations: bool,
@@ -570,7 +571,10 @@ impl PerpPosition {
             market_price as u64 - self.entry_price as u64
         };
 
-        let im_rate = 67; // 6.7 %
+        // ? The position is brought back to the initial margin of its tier (by entry notional)
+        let notional = self.position_size as u128 * self.entry_price as u128 / multiplier1;
+        let im_rate = get_risk_tier(self.position_header.synthetic_token, notional as u64)
+            .initial_margin_fraction;
         let liquidator_fee_rate = 5; // 0.5 %
 
         let s1 = self.margin as u128 * multiplier1;
@@ -609,6 +613,11 @@ impl PerpPosition {
     //  -----------------------------------------------------------------------
 
     pub fn get_pnl(&self, index_price: u64) -> i64 {
+        return self.get_realized_pnl(self.position_size, index_price);
+    }
+
+    /// The profit/loss of closing `size` of the position at `price`
+    pub fn get_realized_pnl(&self, size: u64, price: u64) -> i64 {
         let synthetic_decimals: &u8 = DECIMALS_PER_ASSET
             .get(self.position_header.synthetic_token.to_string().as_str())
             .unwrap();
@@ -624,13 +633,11 @@ impl PerpPosition {
 
         let realized_pnl: i128;
         if self.order_side == OrderSide::Long {
-            realized_pnl = self.position_size as i128
-                * (index_price as i64 - self.entry_price as i64) as i128
-                / multiplier;
+            realized_pnl =
+                size as i128 * (price as i64 - self.entry_price as i64) as i128 / multiplier;
         } else {
-            realized_pnl = self.position_size as i128
-                * (self.entry_price as i64 - index_price as i64) as i128
-                / multiplier;
+            realized_pnl =
+                size as i128 * (self.entry_price as i64 - price as i64) as i128 / multiplier;
         }
 
         return realized_pnl as i64;
@@ -675,6 +682,51 @@ impl PerpPosition {
         return Ok(current_leverage);
     }
 
+    /// The margin the position needs to be at the given leverage at the index price
+    /// (rounded up, so the resulting leverage is never above the target)
+    pub fn get_margin_for_leverage(
+        &self,
+        index_price: u64,
+        leverage: u64,
+    ) -> Result<u64, PerpSwapExecutionError> {
+        if index_price == 0 || leverage == 0 {
+            return Err(send_perp_swap_error(
+                "Index price and leverage cannot be 0".to_string(),
+                None,
+                None,
+            ));
+        }
+
+        let pnl: i64 = self.get_pnl(index_price);
+
+        let synthetic_decimals: &u8 = DECIMALS_PER_ASSET
+            .get(self.position_header.synthetic_token.to_string().as_str())
+            .unwrap();
+
+        let synthetic_price_decimals: &u8 = PRICE_DECIMALS_PER_ASSET
+            .get(self.position_header.synthetic_token.to_string().as_str())
+            .unwrap();
+
+        let decimal_conversion = *synthetic_decimals + *synthetic_price_decimals
+            - (COLLATERAL_TOKEN_DECIMALS + LEVERAGE_DECIMALS);
+        let multiplier = 10_u128.pow(decimal_conversion as u32);
+
+        let notional = index_price as u128 * self.position_size as u128;
+        let divisor = leverage as u128 * multiplier;
+        let equity = ((notional + divisor - 1) / divisor) as i64;
+
+        // ? The position keeps some margin even if its unrealized profit covers the whole equity
+        if equity - pnl <= 0 {
+            return Err(send_perp_swap_error(
+                "Leverage is too high for the unrealized profit".to_string(),
+                None,
+                None,
+            ));
+        }
+
+        return Ok((equity - pnl) as u64);
+    }
+
     fn apply_funding(&mut self, funding_rates: Vec<i64>, prices: Vec<u64>, funding_idx: u32) {
         // & Funding rate are the funding rate percentages that keep the market price close to the index price
 
@@ -684,6 +736,25 @@ impl PerpPosition {
             return;
         }
 
+        let funding_payment = self.get_funding_payment(&funding_rates, &prices, funding_idx);
+
+        // ? Make updates to the position
+        self.margin = (self.margin as i128 + funding_payment as i128) as u64;
+        self.last_funding_idx = funding_idx;
+    }
+
+    /// The funding the position receives (positive) or pays (negative) when the funding rates
+    /// since its last funding index are applied
+    pub fn get_funding_payment(
+        &self,
+        funding_rates: &[i64],
+        prices: &[u64],
+        funding_idx: u32,
+    ) -> i64 {
+        if funding_idx <= self.last_funding_idx {
+            return 0;
+        }
+
         let synthetic_decimals: &u8 = DECIMALS_PER_ASSET
             .get(self.position_header.synthetic_token.to_string().as_str())
             .unwrap();
@@ -710,15 +781,11 @@ impl PerpPosition {
 
         let funding_sum_usd = self.position_size as i128 * funding_sum / multiplier as i128;
 
-        let margin_after_funding = if self.order_side == OrderSide::Long {
-            (self.margin as i128 - funding_sum_usd) as u128
+        if self.order_side == OrderSide::Long {
+            return -funding_sum_usd as i64;
         } else {
-            (self.margin as i128 + funding_sum_usd) as u128
-        };
-
-        // ? Make updates to the position
-        self.margin = margin_after_funding as u64;
-        self.last_funding_idx = funding_idx;
+            return funding_sum_usd as i64;
+        }
     }
 
     //  -----------------------------------------------------------------------
@@ -757,6 +824,50 @@ impl PerpPosition {
         self.hash = new_hash;
     }
 
+    /// Rebuilds a position from its state output (as stored with the state updates of a batch)
+    pub fn from_position_output(output: &PerpPositionOutput) -> PerpPosition {
+        let position_header = PositionHeader::new(
+            output.synthetic_token,
+            output.allow_partial_liquidations,
+            BigUint::from_str(&output.public_key).unwrap_or_default(),
+            output.vlp_token,
+        );
+
+        let bankruptcy_price: u64 = _get_bankruptcy_price(
+            output.entry_price,
+            output.margin,
+            output.position_size,
+            &output.order_side,
+            output.synthetic_token,
+        );
+
+        let liquidation_price: u64 = get_liquidation_price(
+            output.entry_price,
+            output.margin,
+            output.position_size,
+            &output.order_side,
+            output.synthetic_token,
+            output.allow_partial_liquidations,
+        );
+
+        let mut position = PerpPosition {
+            index: output.index,
+            position_header,
+            order_side: output.order_side.clone(),
+            position_size: output.position_size,
+            margin: output.margin,
+            entry_price: output.entry_price,
+            liquidation_price,
+            bankruptcy_price,
+            last_funding_idx: output.last_funding_idx,
+            vlp_supply: output.vlp_supply,
+            hash: BigUint::default(),
+        };
+        position.hash = position.hash_position();
+
+        return position;
+    }
+
     pub fn hash_position(&self) -> BigUint {
         let position_hash: BigUint = _hash_position(
             &self.position_header.hash,
@@ -1040,15 +1151,6 @@ pub fn get_liquidation_price(
     synthetic_token: u32,
     is_partial_liquidation: bool,
 ) -> u64 {
-    // maintenance margin
-    let mm_fraction = if is_partial_liquidation
-        && position_size > MIN_PARTIAL_LIQUIDATION_SIZE[synthetic_token.to_string().as_str()]
-    {
-        4 //%
-    } else {
-        3 //%
-    };
-
     let synthetic_decimals: &u8 = DECIMALS_PER_ASSET
         .get(synthetic_token.to_string().as_str())
         .unwrap();
@@ -1061,18 +1163,28 @@ pub fn get_liquidation_price(
         - COLLATERAL_TOKEN_DECIMALS as i8;
     let multiplier1 = 10_u128.pow(dec_conversion1 as u32);
 
+    // maintenance margin (per mille) of the position's tier, 1 % higher if it can be partially liquidated
+    let notional = (entry_price as u128 * position_size as u128 / multiplier1) as u64;
+    let mut mm_fraction = get_risk_tier(synthetic_token, notional).maintenance_margin_fraction;
+    if is_partial_liquidation
+        && position_size > MIN_PARTIAL_LIQUIDATION_SIZE[synthetic_token.to_string().as_str()]
+    {
+        mm_fraction += 10;
+    }
+
     // & price_delta = (margin - mm_fraction * entry_price * size) / ((1 -/+ mm_fraction)*size) ; - for long, + for short
 
     let d1 = margin as u128 * multiplier1 as u128;
-    let d2 = mm_fraction as u128 * entry_price as u128 * position_size as u128 / 100;
+    let d2 = mm_fraction as u128 * entry_price as u128 * position_size as u128 / 1000;
 
     if *order_side == OrderSide::Long {
         if position_size == 0 {
             return 0;
         }
 
-        let price_delta =
-            ((d1 - d2) * 100) / ((100_u128 - mm_fraction as u128) * position_size as u128);
+        // ? A position below the maintenance margin of its tier is liquidatable at the entry price
+        let price_delta = (d1.saturating_sub(d2) * 1000)
+            / ((1000_u128 - mm_fraction as u128) * position_size as u128);
 
         let liquidation_price = entry_price.checked_sub(price_delta as u64);
 
@@ -1082,8 +1194,8 @@ pub fn get_liquidation_price(
             return 1_000_000_000 * 10_u64.pow(*synthetic_price_decimals as u32);
         }
 
-        let price_delta =
-            ((d1 - d2) * 100) / ((100_u128 + mm_fraction as u128) * position_size as u128);
+        let price_delta = (d1.saturating_sub(d2) * 1000)
+            / ((1000_u128 + mm_fraction as u128) * position_size as u128);
 
         let liquidation_price = entry_price + price_delta as u64;
 
@@ -1,4 +1,3 @@
-use firestore_db_and_auth::ServiceSession;
 use num_bigint::BigUint;
 use parking_lot::Mutex;
 use serde_json::Value;
@@ -8,17 +7,25 @@ use std::sync::Arc;
 use super::order_execution::{
     execute_perp_swap_transaction, update_json_output, update_state_and_finalize,
 };
+use crate::utils::storage::firestore::FirestoreSession;
 
 //
-use super::perp_helpers::perp_swap_helpers::consistency_checks;
+use super::open_interest::{apply_open_interest_change, open_interest_change};
+use super::perp_helpers::perp_swap_helpers::{check_owner_position_size, consistency_checks};
 use super::perp_helpers::perp_swap_outptut::{PerpSwapOutput, PerpSwapResponse};
+use super::position_ledger::swap_ledger_entries;
 use super::{perp_order::PerpOrder, perp_position::PerpPosition, OrderSide};
 use crate::transaction_batch::tx_batch_structs::SwapFundingInfo;
 use crate::transaction_batch::LeafNodeType;
+use crate::transactions::transaction_helpers::swap_helpers::unblock_order;
 use crate::trees::superficial_tree::SuperficialTree;
 use crate::utils::crypto_utils::Signature;
 use crate::utils::storage::backup_storage::BackupStorage;
-use crate::utils::{errors::PerpSwapExecutionError, notes::Note};
+use crate::utils::storage::local_storage::MainStorage;
+use crate::utils::{
+    errors::{send_perp_swap_error, PerpSwapExecutionError},
+    notes::Note,
+};
 
 use error_stack::Result;
 //
@@ -75,10 +82,12 @@ impl PerpSwap {
         //
         index_price: u64,
         min_funding_idxs: Arc<Mutex<HashMap<u32, u32>>>,
+        open_interest: Arc<Mutex<HashMap<u32, u64>>>,
         swap_funding_info: SwapFundingInfo,
         //
-        session: Arc<Mutex<ServiceSession>>,
+        session: Arc<Mutex<FirestoreSession>>,
         backup_storage: Arc<Mutex<BackupStorage>>,
+        main_storage: Arc<Mutex<MainStorage>>,
     ) -> Result<PerpSwapResponse, PerpSwapExecutionError> {
         //
 
@@ -100,7 +109,7 @@ impl PerpSwap {
             &perpetual_partial_fill_tracker,
             &partialy_filled_positions,
             index_price,
-            swap_funding_info,
+            swap_funding_info.clone(),
             &self.order_a,
             &self.order_b,
             &self.signature_a,
@@ -111,12 +120,57 @@ impl PerpSwap {
             self.fee_taken_b,
         )?;
 
+        // * Check the positions of both owners stay within the max position size
+        for (execution_output, order) in [
+            (&execution_result.0, &self.order_a),
+            (&execution_result.1, &self.order_b),
+        ] {
+            if let Err(err) = check_owner_position_size(
+                &main_storage,
+                execution_output.prev_position.as_ref(),
+                execution_output.position.as_ref(),
+                order.order_id,
+            ) {
+                unblock_order(
+                    &blocked_perp_order_ids,
+                    self.order_a.order_id,
+                    self.order_b.order_id,
+                );
+
+                return Err(err);
+            }
+        }
+
+        // * Reserve the open interest added by the swap ==================
+        let synthetic_token = self.order_a.synthetic_token;
+        let open_interest_delta = open_interest_change(&[
+            (
+                execution_result.0.prev_position.as_ref(),
+                execution_result.0.position.as_ref(),
+            ),
+            (
+                execution_result.1.prev_position.as_ref(),
+                execution_result.1.position.as_ref(),
+            ),
+        ]);
+        if let Err(err) =
+            apply_open_interest_change(&open_interest, synthetic_token, open_interest_delta, true)
+        {
+            unblock_order(
+                &blocked_perp_order_ids,
+                self.order_a.order_id,
+                self.order_b.order_id,
+            );
+
+            return Err(send_perp_swap_error(err, None, None));
+        }
+
         // ? Lock the json output before updating the state to prevent another transaction from
         // ? squeezing in between and updating the json output before this transaction is done
         let mut swap_output_json_ = swap_output_json.lock();
 
         // * Update the state if transaction was successful ===============
-        update_state_and_finalize(
+        let finalize_result = update_state_and_finalize(
             &state_tree,
             &updated_state_hashes,
             &blocked_perp_order_ids,
@@ -127,7 +181,18 @@ impl PerpSwap {
             &mut execution_result,
             &self.order_a,
             &self.order_b,
-        )?;
+        );
+        if let Err(err) = finalize_result {
+            // ? Release the open interest reserved by the failed swap
+            let _ = apply_open_interest_change(
+                &open_interest,
+                synthetic_token,
+                -open_interest_delta,
+                false,
+            );
+
+            return Err(err);
+        }
 
         let execution_output_a = execution_result.0;
         let execution_output_b = execution_result.1;
@@ -149,6 +214,38 @@ impl PerpSwap {
 
         drop(swap_output_json_);
 
+        // * Append the funding, pnl and fees to the position ledgers ========
+        let ledger_a = swap_ledger_entries(
+            &self.order_a,
+            &execution_output_a,
+            &swap_funding_info,
+            self.spent_collateral,
+            self.spent_synthetic,
+            self.fee_taken_a,
+        );
+        let ledger_b = swap_ledger_entries(
+            &self.order_b,
+            &execution_output_b,
+            &swap_funding_info,
+            self.spent_collateral,
+            self.spent_synthetic,
+            self.fee_taken_b,
+        );
+
+        let main_storage_m = main_storage.lock();
+        main_storage_m.store_ledger_entries(&ledger_a.0, &ledger_a.1);
+        main_storage_m.store_ledger_entries(&ledger_b.0, &ledger_b.1);
+        main_storage_m.store_open_interest(&open_interest.lock());
+        main_storage_m.update_position(
+            &execution_output_a.prev_position,
+            &execution_output_a.position,
+        );
+        main_storage_m.update_position(
+            &execution_output_b.prev_position,
+            &execution_output_b.position,
+        );
+        drop(main_storage_m);
+
         // * Update min funding index if necessary ===========================
         let mut min_funding_idxs_m = min_funding_idxs.lock();
         let prev_min_funding_idx = min_funding_idxs_m
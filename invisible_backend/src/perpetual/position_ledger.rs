@@ -0,0 +1,260 @@
+//! Append-only history of everything that moves the margin of a position.
+//!
+//! Funding payments, realized pnl, fees, liquidations and margin changes are applied to the
+//! margin directly, so the position itself keeps no trace of them. Every execution that touches
+//! a position appends entries to the ledger (keyed by the position address) so that traders and
+//! accounting can reconcile the margin afterwards.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::transaction_batch::tx_batch_structs::SwapFundingInfo;
+
+use super::{
+    get_price, perp_helpers::perp_swap_outptut::TxExecutionThreadOutput, perp_order::PerpOrder,
+    perp_position::PerpPosition, PositionEffectType,
+};
+
+/// Max number of entries returned by a single ledger query
+pub const MAX_LEDGER_ENTRIES_PER_QUERY: u32 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LedgerEntryType {
+    Funding,
+    RealizedPnl,
+    Fee,
+    Liquidation,
+    MarginChange,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub entry_type: LedgerEntryType,
+    pub position_index: u64,
+    pub synthetic_token: u32,
+    pub amount: i64, // change of the position's collateral (negative if paid by the position)
+    pub size: u64,   // synthetic amount the entry applies to (0 if not applicable)
+    pub price: u64,  // fill/liquidation price (0 if not applicable)
+    pub funding_idx: u32,
+    pub timestamp: u64,
+}
+
+impl LedgerEntry {
+    pub fn new(
+        entry_type: LedgerEntryType,
+        position_index: u64,
+        synthetic_token: u32,
+        amount: i64,
+        size: u64,
+        price: u64,
+        funding_idx: u32,
+    ) -> LedgerEntry {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        LedgerEntry {
+            entry_type,
+            position_index,
+            synthetic_token,
+            amount,
+            size,
+            price,
+            funding_idx,
+            timestamp,
+        }
+    }
+}
+
+/// Builds the ledger entries of one side of a perp swap
+///
+/// # Returns
+/// * `(String, Vec<LedgerEntry>)` - The position address and its entries
+pub fn swap_ledger_entries(
+    order: &PerpOrder,
+    execution_output: &TxExecutionThreadOutput,
+    swap_funding_info: &SwapFundingInfo,
+    spent_collateral: u64,
+    spent_synthetic: u64,
+    fee_taken: u64,
+) -> (String, Vec<LedgerEntry>) {
+    let price = get_price(order.synthetic_token, spent_collateral, spent_synthetic);
+    let position_index = execution_output.position_index;
+    let funding_idx = swap_funding_info.current_funding_idx;
+
+    let mut entries: Vec<LedgerEntry> = Vec::new();
+
+    let position_address = if order.position_effect_type == PositionEffectType::Open {
+        order
+            .open_order_fields
+            .as_ref()
+            .unwrap()
+            .position_address
+            .to_string()
+    } else {
+        let prev_position = execution_output.prev_position.as_ref().unwrap();
+
+        let funding = applicable_funding_payment(prev_position, swap_funding_info);
+        if funding != 0 {
+            entries.push(LedgerEntry::new(
+                LedgerEntryType::Funding,
+                position_index,
+                order.synthetic_token,
+                funding,
+                prev_position.position_size,
+                0,
+                funding_idx,
+            ));
+        }
+
+        // ? Fills against the position side realize the pnl of the reduced size
+        if prev_position.order_side != order.order_side {
+            let reduced_size = std::cmp::min(spent_synthetic, prev_position.position_size);
+
+            entries.push(LedgerEntry::new(
+                LedgerEntryType::RealizedPnl,
+                position_index,
+                order.synthetic_token,
+                prev_position.get_realized_pnl(reduced_size, price),
+                reduced_size,
+                price,
+                funding_idx,
+            ));
+        }
+
+        prev_position.position_header.position_address.to_string()
+    };
+
+    if fee_taken > 0 {
+        entries.push(LedgerEntry::new(
+            LedgerEntryType::Fee,
+            position_index,
+            order.synthetic_token,
+            -(fee_taken as i64),
+            spent_synthetic,
+            price,
+            funding_idx,
+        ));
+    }
+
+    return (position_address, entries);
+}
+
+/// Builds the ledger entries of the liquidated position
+///
+/// # Arguments
+/// * `prev_position` - The position before the liquidation
+/// * `liquidated_position` - The position after the liquidation (None if fully liquidated)
+pub fn liquidation_ledger_entries(
+    prev_position: &PerpPosition,
+    liquidated_position: &Option<PerpPosition>,
+    swap_funding_info: &SwapFundingInfo,
+    liquidated_size: u64,
+    market_price: u64,
+) -> (String, Vec<LedgerEntry>) {
+    let funding_idx = swap_funding_info.current_funding_idx;
+    let synthetic_token = prev_position.position_header.synthetic_token;
+
+    let mut entries: Vec<LedgerEntry> = Vec::new();
+
+    let funding = applicable_funding_payment(prev_position, swap_funding_info);
+    if funding != 0 {
+        entries.push(LedgerEntry::new(
+            LedgerEntryType::Funding,
+            prev_position.index,
+            synthetic_token,
+            funding,
+            prev_position.position_size,
+            0,
+            funding_idx,
+        ));
+    }
+
+    // ? Everything the position lost in the liquidation besides the funding
+    let margin_after = liquidated_position.as_ref().map(|p| p.margin).unwrap_or(0);
+    let margin_lost = margin_after as i64 - (prev_position.margin as i64 + funding);
+
+    entries.push(LedgerEntry::new(
+        LedgerEntryType::Liquidation,
+        prev_position.index,
+        synthetic_token,
+        margin_lost,
+        liquidated_size,
+        market_price,
+        funding_idx,
+    ));
+
+    return (
+        prev_position.position_header.position_address.to_string(),
+        entries,
+    );
+}
+
+/// Builds the ledger entry of the position opened by the liquidator, who is credited the liquidator fee
+pub fn liquidator_ledger_entries(
+    new_position: &PerpPosition,
+    liquidator_fee: u64,
+    market_price: u64,
+) -> (String, Vec<LedgerEntry>) {
+    let entry = LedgerEntry::new(
+        LedgerEntryType::Liquidation,
+        new_position.index,
+        new_position.position_header.synthetic_token,
+        liquidator_fee as i64,
+        new_position.position_size,
+        market_price,
+        new_position.last_funding_idx,
+    );
+
+    return (
+        new_position.position_header.position_address.to_string(),
+        vec![entry],
+    );
+}
+
+/// Builds the ledger entry of a margin change (also used when setting the leverage)
+pub fn margin_change_ledger_entries(
+    position: &PerpPosition,
+    margin_change: i64,
+) -> (String, Vec<LedgerEntry>) {
+    let entry = LedgerEntry::new(
+        LedgerEntryType::MarginChange,
+        position.index,
+        position.position_header.synthetic_token,
+        margin_change,
+        0,
+        0,
+        position.last_funding_idx,
+    );
+
+    return (
+        position.position_header.position_address.to_string(),
+        vec![entry],
+    );
+}
+
+// * HELPERS * //
+
+/// The funding paid/received by the position when the swap funding rates are applied to it
+fn applicable_funding_payment(position: &PerpPosition, swap_funding_info: &SwapFundingInfo) -> i64 {
+    let idx_diff = position
+        .last_funding_idx
+        .saturating_sub(swap_funding_info.min_swap_funding_idx) as usize;
+
+    let funding_rates = swap_funding_info
+        .swap_funding_rates
+        .get(idx_diff..)
+        .unwrap_or(&[]);
+    let funding_prices = swap_funding_info
+        .swap_funding_prices
+        .get(idx_diff..)
+        .unwrap_or(&[]);
+
+    return position.get_funding_payment(
+        funding_rates,
+        funding_prices,
+        swap_funding_info.current_funding_idx,
+    );
+}
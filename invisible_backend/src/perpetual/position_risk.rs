@@ -0,0 +1,209 @@
+//! Margin health of isolated positions.
+//!
+//! The risk of a position is derived from the position itself and the market state: the index
+//! price, the mark price (the orderbook mid price) and the funding rates applied since the
+//! position was last updated. Nothing here changes the position, it is only used for queries.
+
+use super::{
+    get_collateral_amount, get_risk_tier, perp_helpers::perp_swap_helpers::get_max_leverage,
+    perp_position::PerpPosition, COLLATERAL_TOKEN, DUST_AMOUNT_PER_ASSET, LEVERAGE_DECIMALS,
+};
+
+/// Max number of positions in a single batch risk query
+pub const MAX_POSITIONS_PER_RISK_QUERY: usize = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionRisk {
+    pub index_price: u64,
+    pub mark_price: u64,
+    pub unrealized_pnl_index: i64, // pnl of closing the position at the index price
+    pub unrealized_pnl_mark: i64,  // pnl of closing the position at the mark price
+    pub pending_funding: i64,      // funding not yet applied to the margin (negative if owed)
+    pub equity: i64,               // margin + unrealized pnl at the index price + pending funding
+    pub margin_ratio: u64,         // equity / notional (with LEVERAGE_DECIMALS decimals)
+    pub maintenance_margin_ratio: u64, // margin ratio of the risk tier (with LEVERAGE_DECIMALS decimals)
+    pub liquidation_price: u64,
+    pub bankruptcy_price: u64,
+    pub max_withdrawable_margin: u64,
+    pub is_liquidatable: bool,
+}
+
+/// Computes the margin health of a position
+///
+/// # Arguments
+/// * `funding_rates` - All the funding rates of the position's market (from funding index 0)
+/// * `funding_prices` - The prices the funding rates were applied at
+pub fn get_position_risk(
+    position: &PerpPosition,
+    index_price: u64,
+    mark_price: u64,
+    funding_rates: &[i64],
+    funding_prices: &[u64],
+) -> PositionRisk {
+    let synthetic_token = position.position_header.synthetic_token;
+
+    let unrealized_pnl_index = position.get_pnl(index_price);
+    let unrealized_pnl_mark = position.get_pnl(mark_price);
+
+    let last_funding_idx = position.last_funding_idx as usize;
+    let pending_funding = position.get_funding_payment(
+        funding_rates.get(last_funding_idx..).unwrap_or(&[]),
+        funding_prices.get(last_funding_idx..).unwrap_or(&[]),
+        funding_rates.len() as u32,
+    );
+
+    let equity = position.margin as i64 + unrealized_pnl_index + pending_funding;
+
+    let notional = get_collateral_amount(synthetic_token, position.position_size, index_price);
+    let margin_ratio = if notional == 0 || equity <= 0 {
+        0
+    } else {
+        (equity as u128 * 10_u128.pow(LEVERAGE_DECIMALS as u32) / notional as u128) as u64
+    };
+
+    let maintenance_margin_fraction =
+        get_risk_tier(synthetic_token, notional).maintenance_margin_fraction;
+    let maintenance_margin_ratio =
+        maintenance_margin_fraction * 10_u64.pow(LEVERAGE_DECIMALS as u32) / 1000;
+
+    PositionRisk {
+        index_price,
+        mark_price,
+        unrealized_pnl_index,
+        unrealized_pnl_mark,
+        pending_funding,
+        equity,
+        margin_ratio,
+        maintenance_margin_ratio,
+        liquidation_price: position.liquidation_price,
+        bankruptcy_price: position.bankruptcy_price,
+        max_withdrawable_margin: get_max_withdrawable_margin(position, index_price, notional),
+        is_liquidatable: position.is_position_liquidatable(mark_price, index_price).0,
+    }
+}
+
+// * HELPERS * //
+
+/// The most margin that can be removed from the position by a margin change,
+/// i.e. without going over the max leverage of its risk tier at the index price
+/// (rounded down, so removing it is always accepted)
+fn get_max_withdrawable_margin(position: &PerpPosition, index_price: u64, notional: u64) -> u64 {
+    if index_price == 0 {
+        return 0;
+    }
+
+    // ? A margin change can't leave the position with dust margin
+    let dust_amount = DUST_AMOUNT_PER_ASSET[&COLLATERAL_TOKEN.to_string()];
+    let removable_margin = position.margin.saturating_sub(dust_amount + 1);
+
+    let max_leverage = get_max_leverage(position.position_header.synthetic_token, notional);
+
+    // ? An error means the unrealized profit alone keeps the position under the max leverage
+    let required_margin = position
+        .get_margin_for_leverage(index_price, max_leverage)
+        .unwrap_or(0);
+
+    return std::cmp::min(
+        removable_margin,
+        position.margin.saturating_sub(required_margin),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use num_bigint::BigUint;
+    use num_traits::FromPrimitive;
+
+    use crate::perpetual::OrderSide;
+
+    const BTC: u32 = 3592681469;
+
+    // ? 1 BTC long at 30_000 with 10x leverage
+    fn position() -> PerpPosition {
+        PerpPosition::new(
+            OrderSide::Long,
+            100_000_000,
+            BTC,
+            COLLATERAL_TOKEN,
+            3_000 * 10u64.pow(6),
+            10 * 10u64.pow(4),
+            false,
+            BigUint::from_u64(1234).unwrap(),
+            0,
+            7,
+            0,
+        )
+    }
+
+    #[test]
+    fn risk_includes_the_pnl_and_the_pending_funding() {
+        let position = position();
+
+        // ? A 0.1% funding rate at 30_000 costs the long 30 USD
+        let risk = get_position_risk(
+            &position,
+            33_000 * 10u64.pow(6),
+            32_000 * 10u64.pow(6),
+            &[100],
+            &[30_000 * 10u64.pow(6)],
+        );
+
+        assert_eq!(risk.unrealized_pnl_index, 3_000 * 10i64.pow(6));
+        assert_eq!(risk.unrealized_pnl_mark, 2_000 * 10i64.pow(6));
+        assert_eq!(risk.pending_funding, -30 * 10i64.pow(6));
+        assert_eq!(risk.equity, 5_970 * 10i64.pow(6));
+        // ? 5_970 / 33_000 = 18.09%
+        assert_eq!(risk.margin_ratio, 1809);
+        assert_eq!(risk.maintenance_margin_ratio, 300);
+        assert!(!risk.is_liquidatable);
+
+        // ? Funding already applied to the position is not pending
+        let mut updated_position = position.clone();
+        updated_position.last_funding_idx = 1;
+        let risk = get_position_risk(
+            &updated_position,
+            33_000 * 10u64.pow(6),
+            32_000 * 10u64.pow(6),
+            &[100],
+            &[30_000 * 10u64.pow(6)],
+        );
+        assert_eq!(risk.pending_funding, 0);
+    }
+
+    #[test]
+    fn max_withdrawable_margin_keeps_the_position_under_the_max_leverage() {
+        let position = position();
+        let index_price = 30_500 * 10u64.pow(6);
+
+        let risk = get_position_risk(&position, index_price, index_price, &[], &[]);
+        let withdrawable = risk.max_withdrawable_margin;
+        assert!(withdrawable > 0);
+
+        let notional = get_collateral_amount(BTC, position.position_size, index_price);
+        let max_leverage = get_max_leverage(BTC, notional);
+
+        let mut reduced = position.clone();
+        reduced.modify_margin(-(withdrawable as i64)).unwrap();
+        assert!(reduced.get_current_leverage(index_price).unwrap() <= max_leverage);
+
+        // ? The leverage is checked with 4 decimals, so the bound is only exact to about a dollar
+        let mut over_reduced = position.clone();
+        over_reduced
+            .modify_margin(-(withdrawable as i64 + 10i64.pow(6)))
+            .unwrap();
+        assert!(over_reduced.get_current_leverage(index_price).unwrap() > max_leverage);
+
+        // ? Below the liquidation price nothing can be withdrawn
+        let risk = get_position_risk(
+            &position,
+            position.liquidation_price - 1,
+            position.liquidation_price - 1,
+            &[],
+            &[],
+        );
+        assert_eq!(risk.max_withdrawable_margin, 0);
+        assert!(risk.is_liquidatable);
+    }
+}
@@ -34,12 +34,10 @@ pub async fn finalize_batch_inner(
     let success = tx_batch_m.finalize_batch().is_ok();
     drop(tx_batch_m);
 
-    println!("time: {:?}", now.elapsed());
-
     if success {
-        println!("batch finalized sucessfuly");
+        println!("batch finalized sucessfuly in {:?}", now.elapsed());
     } else {
-        println!("batch finalization failed");
+        println!("batch finalization failed after {:?}", now.elapsed());
     }
 
     drop(lock);
@@ -0,0 +1,549 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::super::{
+    grpc::engine_proto::{
+        CancelAllRequest, CancelAllResponse, CancelOrderMessage, CancelOrderResponse,
+        CancelOrdersBatchRequest, CancelOrdersBatchResponse, OrderResponse, OrdersBatchRequest,
+        OrdersBatchResponse,
+    },
+    server_helpers::{
+        engine_helpers::{cancel_user_orders, handle_cancel_order_repsonse, store_output_json},
+        WsConnectionsMap,
+    },
+};
+use super::order_executions::{
+    execute_limit_order_matches, execute_perp_order_matches, existance_checks, limit_order_checks,
+    open_interest_checks, order_format_checks, position_size_checks,
+};
+use super::order_interactions::resolve_order_id;
+use crate::matching_engine::{
+    domain::{Order, OrderSide as OBOrderSide},
+    orderbook::{Failed, OrderBook},
+    orders::{limit_order_cancel_request, new_limit_order_request, OrderRequest},
+};
+use crate::transaction_batch::TransactionBatch;
+use crate::utils::crypto_utils::Signature;
+use crate::utils::errors::{
+    send_cancel_all_error_reply, send_cancel_order_error_reply,
+    send_cancel_orders_batch_error_reply, send_order_error_reply, send_orders_batch_error_reply,
+};
+use crate::utils::notes::Note;
+
+use parking_lot::Mutex;
+use tokio::sync::{Mutex as TokioMutex, Semaphore};
+use tonic::{Request, Response, Status};
+
+/// Maximum number of orders and cancels in a single batch request
+pub const MAX_BATCH_SIZE: usize = 50;
+
+/// A new order of the batch that passed the format and existence checks
+struct BatchOrder {
+    idx: usize,
+    order: Order,
+    side: OBOrderSide,
+    signature: Signature,
+    user_id: u64,
+    client_order_id: u64,
+    is_market: bool,
+}
+
+impl BatchOrder {
+    fn order_request(&self) -> OrderRequest {
+        return new_limit_order_request(
+            self.side,
+            self.order.clone(),
+            self.signature.clone(),
+            SystemTime::now(),
+            self.is_market,
+            self.user_id,
+            self.client_order_id,
+        );
+    }
+}
+
+/// The requests of a batch that touch the same orderbook
+#[derive(Default)]
+struct BookBatch {
+    cancels: Vec<(usize, CancelOrderMessage)>,
+    orders: Vec<BatchOrder>,
+}
+
+// * ===================================================================================================================================
+//
+
+pub async fn submit_orders_batch_inner(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
+    privileged_ws_connections: &Arc<TokioMutex<Vec<u64>>>,
+    semaphore: &Semaphore,
+    is_paused: &Arc<TokioMutex<bool>>,
+    request: Request<OrdersBatchRequest>,
+) -> Result<Response<OrdersBatchResponse>, Status> {
+    let _permit = semaphore.acquire().await.unwrap();
+
+    let lock = is_paused.lock().await;
+    drop(lock);
+
+    tokio::task::yield_now().await;
+
+    let req: OrdersBatchRequest = request.into_inner();
+    let all_or_nothing = req.all_or_nothing;
+
+    let batch_size = req.limit_orders.len() + req.perp_orders.len() + req.cancel_orders.len();
+    if batch_size == 0 || batch_size > MAX_BATCH_SIZE {
+        return send_orders_batch_error_reply(format!(
+            "Batch must contain between 1 and {} orders and cancels",
+            MAX_BATCH_SIZE
+        ));
+    }
+
+    let tx_batch_m = tx_batch.lock().await;
+    let state_tree = Arc::clone(&tx_batch_m.state_tree);
+    let open_interest = Arc::clone(&tx_batch_m.open_interest);
+    let swap_output_json = Arc::clone(&tx_batch_m.swap_output_json);
+    let main_storage = Arc::clone(&tx_batch_m.main_storage);
+    let partial_fill_tracker = Arc::clone(&tx_batch_m.partial_fill_tracker);
+    let perpetual_partial_fill_tracker = Arc::clone(&tx_batch_m.perpetual_partial_fill_tracker);
+    drop(tx_batch_m);
+
+    let mut limit_order_responses = vec![OrderResponse::default(); req.limit_orders.len()];
+    let mut perp_order_responses = vec![OrderResponse::default(); req.perp_orders.len()];
+    let mut cancel_responses = vec![CancelOrderResponse::default(); req.cancel_orders.len()];
+
+    // ? Group the requests by orderbook (is_perp, market_id)
+    let mut book_batches: BTreeMap<(bool, u16), BookBatch> = BTreeMap::new();
+
+    for (idx, cancel_req) in req.cancel_orders.into_iter().enumerate() {
+        let market_id = cancel_req.market_id as u16;
+
+        let order_books_ = if cancel_req.is_perp {
+            perp_order_books
+        } else {
+            order_books
+        };
+        if !order_books_.contains_key(&market_id) {
+            cancel_responses[idx] = send_cancel_order_error_reply("Market not found".to_string())
+                .unwrap()
+                .into_inner();
+            continue;
+        }
+
+        book_batches
+            .entry((cancel_req.is_perp, market_id))
+            .or_default()
+            .cancels
+            .push((idx, cancel_req));
+    }
+
+    for (idx, order_req) in req.limit_orders.into_iter().enumerate() {
+        let user_id = order_req.user_id;
+        let client_order_id = order_req.client_order_id;
+        let is_market = order_req.is_market;
+
+        match limit_order_checks(&state_tree, order_req) {
+            Ok((signature, limit_order, market_id, side)) => {
+                book_batches
+                    .entry((false, market_id))
+                    .or_default()
+                    .orders
+                    .push(BatchOrder {
+                        idx,
+                        order: Order::Spot(limit_order),
+                        side,
+                        signature,
+                        user_id,
+                        client_order_id,
+                        is_market,
+                    });
+            }
+            Err(err) => {
+                limit_order_responses[idx] = send_order_error_reply(err).unwrap().into_inner();
+            }
+        }
+    }
+
+    for (idx, order_req) in req.perp_orders.into_iter().enumerate() {
+        let user_id = order_req.user_id;
+        let client_order_id = order_req.client_order_id;
+        let is_market = order_req.is_market;
+
+        let res = order_format_checks(order_req).and_then(|(signature, perp_order, market)| {
+            existance_checks(&state_tree, &perp_order)?;
+            open_interest_checks(&open_interest, &perp_order)?;
+            position_size_checks(&main_storage, &perp_order)?;
+
+            Ok((signature, perp_order, market))
+        });
+
+        match res {
+            Ok((signature, perp_order, market_id)) => {
+                let side: OBOrderSide = perp_order.order_side.clone().into();
+
+                book_batches
+                    .entry((true, market_id))
+                    .or_default()
+                    .orders
+                    .push(BatchOrder {
+                        idx,
+                        order: Order::Perp(perp_order),
+                        side,
+                        signature,
+                        user_id,
+                        client_order_id,
+                        is_market,
+                    });
+            }
+            Err(err) => {
+                perp_order_responses[idx] = send_order_error_reply(err).unwrap().into_inner();
+            }
+        }
+    }
+
+    // ? In an all or nothing batch a request that failed its checks rejects the whole batch
+    if all_or_nothing {
+        let rejection = limit_order_responses
+            .iter()
+            .chain(perp_order_responses.iter())
+            .map(|res| &res.error_message)
+            .chain(cancel_responses.iter().map(|res| &res.error_message))
+            .find(|err_msg| !err_msg.is_empty());
+        if let Some(err_msg) = rejection {
+            return send_orders_batch_error_reply(format!(
+                "Batch rejected, nothing was cancelled or placed: {}",
+                err_msg
+            ));
+        }
+    }
+
+    // ? ------------------------------------------------------------------------------------
+    // ? Lock the books of the batch (in the same order for every batch) for the rest of the
+    // ? processing, so all or nothing batches are checked and applied under the same locks
+    let mut locked_books = Vec::new();
+    for ((is_perp, market_id), book_batch) in book_batches {
+        let order_book_m = if is_perp {
+            perp_order_books.get(&market_id).unwrap()
+        } else {
+            order_books.get(&market_id).unwrap()
+        };
+
+        let order_book = order_book_m.lock().await;
+        locked_books.push((order_book_m, order_book, book_batch));
+    }
+
+    if all_or_nothing {
+        for (_, order_book, book_batch) in locked_books.iter() {
+            if let Err(err_msg) = check_book_batch(order_book, book_batch) {
+                return send_orders_batch_error_reply(format!(
+                    "Batch rejected, nothing was cancelled or placed: {}",
+                    err_msg
+                ));
+            }
+        }
+    }
+
+    // ? Process the cancels and then the new orders of each book
+    let mut matched_orders = Vec::new();
+    for (order_book_m, mut order_book, book_batch) in locked_books {
+        for (idx, cancel_req) in book_batch.cancels {
+            cancel_responses[idx] = cancel_order_in_book(
+                &mut order_book,
+                &cancel_req,
+                &partial_fill_tracker,
+                &perpetual_partial_fill_tracker,
+            );
+        }
+
+        for batch_order in book_batch.orders {
+            let processed_res = order_book.process_order(batch_order.order_request());
+            matched_orders.push((order_book_m, batch_order, processed_res));
+        }
+
+        drop(order_book);
+    }
+
+    // ? ------------------------------------------------------------------------------------
+    // ? Execute the swaps of the orders that were matched
+    for (order_book_m, batch_order, processed_res) in matched_orders {
+        let BatchOrder {
+            idx,
+            order,
+            side,
+            signature,
+            user_id,
+            client_order_id,
+            is_market,
+        } = batch_order;
+
+        let (res, responses) = match order {
+            Order::Spot(limit_order) => (
+                execute_limit_order_matches(
+                    tx_batch,
+                    order_book_m,
+                    ws_connections,
+                    privileged_ws_connections,
+                    processed_res,
+                    limit_order,
+                    side,
+                    signature,
+                    user_id,
+                    client_order_id,
+                    is_market,
+                )
+                .await,
+                &mut limit_order_responses,
+            ),
+            Order::Perp(perp_order) => (
+                execute_perp_order_matches(
+                    tx_batch,
+                    order_book_m,
+                    ws_connections,
+                    privileged_ws_connections,
+                    None,
+                    processed_res,
+                    perp_order,
+                    side,
+                    signature,
+                    user_id,
+                    client_order_id,
+                    is_market,
+                )
+                .await,
+                &mut perp_order_responses,
+            ),
+        };
+
+        responses[idx] = match res {
+            Ok(order_id) => OrderResponse {
+                successful: true,
+                error_message: "".to_string(),
+                order_id,
+            },
+            Err(err) => send_order_error_reply(err).unwrap().into_inner(),
+        };
+    }
+
+    store_output_json(&swap_output_json, &main_storage);
+
+    let reply = OrdersBatchResponse {
+        successful: true,
+        error_message: "".to_string(),
+        limit_order_responses,
+        perp_order_responses,
+        cancel_responses,
+    };
+
+    return Ok(Response::new(reply));
+}
+
+//
+// * ===================================================================================================================================
+//
+
+pub async fn cancel_orders_batch_inner(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    request: Request<CancelOrdersBatchRequest>,
+) -> Result<Response<CancelOrdersBatchResponse>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: CancelOrdersBatchRequest = request.into_inner();
+
+    if req.cancel_orders.is_empty() || req.cancel_orders.len() > MAX_BATCH_SIZE {
+        return send_cancel_orders_batch_error_reply(format!(
+            "Batch must contain between 1 and {} cancels",
+            MAX_BATCH_SIZE
+        ));
+    }
+
+    let tx_batch_m = tx_batch.lock().await;
+    let partial_fill_tracker = Arc::clone(&tx_batch_m.partial_fill_tracker);
+    let perpetual_partial_fill_tracker = Arc::clone(&tx_batch_m.perpetual_partial_fill_tracker);
+    drop(tx_batch_m);
+
+    let mut cancel_responses = vec![CancelOrderResponse::default(); req.cancel_orders.len()];
+
+    let mut book_cancels: BTreeMap<(bool, u16), Vec<(usize, CancelOrderMessage)>> = BTreeMap::new();
+    for (idx, cancel_req) in req.cancel_orders.into_iter().enumerate() {
+        book_cancels
+            .entry((cancel_req.is_perp, cancel_req.market_id as u16))
+            .or_default()
+            .push((idx, cancel_req));
+    }
+
+    for ((is_perp, market_id), cancels) in book_cancels {
+        let order_book_m = if is_perp {
+            perp_order_books.get(&market_id)
+        } else {
+            order_books.get(&market_id)
+        };
+        if order_book_m.is_none() {
+            for (idx, _) in cancels {
+                cancel_responses[idx] =
+                    send_cancel_order_error_reply("Market not found".to_string())
+                        .unwrap()
+                        .into_inner();
+            }
+            continue;
+        }
+
+        let mut order_book = order_book_m.unwrap().lock().await;
+        for (idx, cancel_req) in cancels {
+            cancel_responses[idx] = cancel_order_in_book(
+                &mut order_book,
+                &cancel_req,
+                &partial_fill_tracker,
+                &perpetual_partial_fill_tracker,
+            );
+        }
+        drop(order_book);
+    }
+
+    let reply = CancelOrdersBatchResponse {
+        successful: true,
+        error_message: "".to_string(),
+        cancel_responses,
+    };
+
+    return Ok(Response::new(reply));
+}
+
+//
+// * ===================================================================================================================================
+//
+
+pub async fn cancel_all_inner(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    request: Request<CancelAllRequest>,
+) -> Result<Response<CancelAllResponse>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: CancelAllRequest = request.into_inner();
+
+    let market_id = req.market_id as u16;
+
+    // ? market_id 0 cancels the user's orders in every market
+    let mut books: Vec<(bool, &Arc<TokioMutex<OrderBook>>)> = Vec::new();
+    if market_id == 0 {
+        books.extend(order_books.values().map(|book| (false, book)));
+        books.extend(perp_order_books.values().map(|book| (true, book)));
+    } else {
+        let order_book_m = if req.is_perp {
+            perp_order_books.get(&market_id)
+        } else {
+            order_books.get(&market_id)
+        };
+        if order_book_m.is_none() {
+            return send_cancel_all_error_reply("Market not found".to_string());
+        }
+
+        books.push((req.is_perp, order_book_m.unwrap()));
+    }
+
+    let (cancelled_order_ids, pfr_notes) = cancel_user_orders(tx_batch, books, req.user_id).await;
+
+    let reply = CancelAllResponse {
+        successful: true,
+        error_message: "".to_string(),
+        cancelled_order_ids,
+        pfr_notes,
+    };
+
+    return Ok(Response::new(reply));
+}
+
+// * HELPERS * //
+
+/// Checks that every cancel and new order of a book batch would go through, without applying any
+///
+/// The cancelled orders have to be open orders of the user, and the new orders are checked as if
+/// the cancels were already applied (so a replacement can reuse the client order id it replaces).
+fn check_book_batch(order_book: &OrderBook, book_batch: &BookBatch) -> Result<(), String> {
+    let mut cancelled_ids: Vec<u64> = Vec::new();
+    for (_, cancel_req) in book_batch.cancels.iter() {
+        let order_side: OBOrderSide = if cancel_req.order_side {
+            OBOrderSide::Bid
+        } else {
+            OBOrderSide::Ask
+        };
+
+        let order = resolve_order_id(
+            order_book,
+            cancel_req.order_id,
+            cancel_req.client_order_id,
+            order_side,
+            cancel_req.user_id,
+        )
+        .and_then(|(order_id, _)| order_book.get_order(order_id))
+        .filter(|order| order.user_id == cancel_req.user_id);
+
+        match order {
+            Some(order) => cancelled_ids.push(order.order_id),
+            None => return Err("Order not found".to_string()),
+        }
+    }
+
+    for batch_order in book_batch.orders.iter() {
+        let order_request = batch_order.order_request();
+
+        if let Err(failed) = order_book.check_order_request(&order_request, &cancelled_ids) {
+            return Err(match failed {
+                Failed::ValidationFailed(reason) => reason,
+                Failed::DuplicateClientOrderID(client_order_id) => {
+                    format!("Duplicate client order id: {}", client_order_id)
+                }
+                failed => format!("{:?}", failed),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Cancels an order in an orderbook the caller has already locked
+fn cancel_order_in_book(
+    order_book: &mut OrderBook,
+    req: &CancelOrderMessage,
+    partial_fill_tracker: &Arc<Mutex<HashMap<u64, (Option<Note>, u64)>>>,
+    perpetual_partial_fill_tracker: &Arc<Mutex<HashMap<u64, (Option<Note>, u64, u64)>>>,
+) -> CancelOrderResponse {
+    let order_side: OBOrderSide = if req.order_side {
+        OBOrderSide::Bid
+    } else {
+        OBOrderSide::Ask
+    };
+
+    let (order_id, order_side) = match resolve_order_id(
+        order_book,
+        req.order_id,
+        req.client_order_id,
+        order_side,
+        req.user_id,
+    ) {
+        Some(res) => res,
+        None => {
+            return send_cancel_order_error_reply("Order not found".to_string())
+                .unwrap()
+                .into_inner()
+        }
+    };
+
+    let cancel_request = limit_order_cancel_request(order_id, order_side, req.user_id);
+    let res = order_book.process_order(cancel_request);
+
+    return handle_cancel_order_repsonse(
+        &res[0],
+        req.is_perp,
+        order_id,
+        partial_fill_tracker,
+        perpetual_partial_fill_tracker,
+    )
+    .unwrap()
+    .into_inner();
+}
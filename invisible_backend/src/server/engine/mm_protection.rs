@@ -0,0 +1,92 @@
+use std::{collections::HashMap, sync::Arc};
+
+use super::super::grpc::engine_proto::{MmProtectionReq, MmProtectionResetReq, SuccessResponse};
+use crate::matching_engine::{mm_protection::MmpConfig, orderbook::OrderBook};
+use crate::utils::errors::send_mm_protection_error_reply;
+
+use tokio::sync::Mutex as TokioMutex;
+use tonic::{Request, Response, Status};
+
+// * ===================================================================================================================================
+//
+
+pub async fn set_mm_protection_inner(
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    request: Request<MmProtectionReq>,
+) -> Result<Response<SuccessResponse>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: MmProtectionReq = request.into_inner();
+
+    let order_book_m = if req.is_perp {
+        perp_order_books.get(&(req.market_id as u16))
+    } else {
+        order_books.get(&(req.market_id as u16))
+    };
+    if order_book_m.is_none() {
+        return send_mm_protection_error_reply("Market not found".to_string());
+    }
+
+    if req.window_ms > 0 && req.qty_limit == 0 && req.delta_limit == 0 {
+        return send_mm_protection_error_reply(
+            "At least one of qty_limit and delta_limit must be set".to_string(),
+        );
+    }
+
+    let config = MmpConfig {
+        window_ms: req.window_ms,
+        qty_limit: req.qty_limit,
+        delta_limit: req.delta_limit,
+    };
+
+    let mut order_book = order_book_m.unwrap().lock().await;
+    order_book.mm_protection.set_config(req.user_id, config);
+    drop(order_book);
+
+    let reply = SuccessResponse {
+        successful: true,
+        error_message: "".to_string(),
+    };
+
+    return Ok(Response::new(reply));
+}
+
+// * ===================================================================================================================================
+//
+
+pub async fn reset_mm_protection_inner(
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    request: Request<MmProtectionResetReq>,
+) -> Result<Response<SuccessResponse>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: MmProtectionResetReq = request.into_inner();
+
+    let order_book_m = if req.is_perp {
+        perp_order_books.get(&(req.market_id as u16))
+    } else {
+        order_books.get(&(req.market_id as u16))
+    };
+    if order_book_m.is_none() {
+        return send_mm_protection_error_reply("Market not found".to_string());
+    }
+
+    let mut order_book = order_book_m.unwrap().lock().await;
+    let is_protected = order_book.mm_protection.reset(req.user_id);
+    drop(order_book);
+
+    if !is_protected {
+        return send_mm_protection_error_reply(
+            "Market maker protection is not set for this market".to_string(),
+        );
+    }
+
+    let reply = SuccessResponse {
+        successful: true,
+        error_message: "".to_string(),
+    };
+
+    return Ok(Response::new(reply));
+}
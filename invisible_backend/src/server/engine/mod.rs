@@ -1,8 +1,13 @@
-use std::{collections::HashMap, str::FromStr, sync::Arc};
+use std::{collections::HashMap, net::IpAddr, str::FromStr, sync::Arc};
 
 use self::{
     admin::{finalize_batch_inner, restore_orderbook_inner, update_index_price_inner},
-    note_position_helpers::{change_position_margin_inner, split_notes_inner},
+    batch_orders::{cancel_all_inner, cancel_orders_batch_inner, submit_orders_batch_inner},
+    mm_protection::{reset_mm_protection_inner, set_mm_protection_inner},
+    note_position_helpers::{
+        change_position_margin_inner, execute_transfer_inner, restructure_notes_inner,
+        set_position_leverage_inner,
+    },
     onchain_interaction::{execute_deposit_inner, execute_escape_inner, execute_withdrawal_inner},
     onchain_mms::{
         add_liquidity_mm_inner, close_onchain_mm_inner, register_onchain_mm_inner,
@@ -14,40 +19,57 @@ use self::{
     order_interactions::{amend_order_inner, cancel_order_inner},
     order_tabs::{close_order_tab_inner, open_order_tab_inner},
     queries::{
-        get_funding_info_inner, get_index_prices_inner, get_liquidity_inner, get_orders_inner,
-        get_state_info_inner,
+        get_depth_inner, get_funding_info_inner, get_index_prices_inner, get_l3_orders_inner,
+        get_liquidity_inner, get_market_info_inner, get_orders_inner, get_position_ledger_inner,
+        get_position_risk_inner, get_positions_risk_batch_inner, get_predicted_funding_inner,
+        get_state_info_inner, simulate_perp_order_inner, MAX_DEPTH_LEVELS,
     },
 };
 
 use super::grpc::engine_proto::{
-    AmendOrderRequest, AmendOrderResponse, CancelOrderMessage, CancelOrderResponse,
-    CloseOrderTabReq, DepositMessage, DepositResponse, EmptyReq, EscapeMessage,
-    FinalizeBatchResponse, FundingReq, FundingRes, IndexPriceRes, LimitOrderMessage,
-    LiquidationOrderMessage, LiquidationOrderResponse, LiquidityReq, LiquidityRes, MarginChangeReq,
-    MarginChangeRes, OnChainAddLiqReq, OnChainCloseMmReq, OnChainRegisterMmReq,
-    OnChainRemoveLiqReq, OnChainScmmRes, OpenOrderTabReq, OracleUpdateReq, OrderResponse,
-    OrdersReq, OrdersRes, PerpOrderMessage, RegisterOnchainActionRequest, RestoreOrderBookMessage,
-    SplitNotesReq, SplitNotesRes, StateInfoReq, StateInfoRes, SuccessResponse, UpdateDbIndexesReq,
-    WithdrawalMessage,
+    AmendOrderRequest, AmendOrderResponse, CancelAllRequest, CancelAllResponse, CancelOrderMessage,
+    CancelOrderResponse, CancelOrdersBatchRequest, CancelOrdersBatchResponse, CloseOrderTabReq,
+    DeadMansSwitchReq, DeadMansSwitchRes, DepositMessage, DepositResponse, DepthReq, DepthRes,
+    EmptyReq, EscapeMessage, FinalizeBatchResponse, FundingReq, FundingRes, IndexPriceRes,
+    L3OrdersReq, L3OrdersRes, LimitOrderMessage, LiquidationOrderMessage, LiquidationOrderResponse,
+    LiquidityReq, LiquidityRes, MarginChangeReq, MarginChangeRes, MarketInfoReq, MarketInfoRes,
+    MmProtectionReq, MmProtectionResetReq, NoteRestructureMessage, NoteRestructureResponse,
+    OnChainAddLiqReq, OnChainCloseMmReq, OnChainRegisterMmReq, OnChainRemoveLiqReq, OnChainScmmRes,
+    OpenOrderTabReq, OracleUpdateReq, OrderResponse, OrdersBatchRequest, OrdersBatchResponse,
+    OrdersReq, OrdersRes, PerpOrderMessage, PositionLedgerReq, PositionLedgerRes, PositionRiskReq,
+    PositionRiskRes, PositionsRiskBatchReq, PositionsRiskBatchRes, PredictedFundingRes,
+    RegisterOnchainActionRequest, RestoreOrderBookMessage, SetAccountTierReq, SetLeverageReq,
+    SetLeverageRes, SimulatePerpOrderRes, StateInfoReq, StateInfoRes, SuccessResponse,
+    TransferMessage, TransferResponse, UpdateDbIndexesReq, WithdrawalMessage,
 };
 use super::{
     grpc::engine_proto::{engine_server::Engine, CloseOrderTabRes, OpenOrderTabRes},
-    server_helpers::WsConnectionsMap,
+    server_helpers::{
+        dead_mans_switch::{set_dead_mans_switch_inner, DeadMansSwitches},
+        rate_limiter::{AccountTier, RateLimitKeys, RateLimiter, RequestKind, RequestOrigin},
+        WsConnectionsMap,
+    },
 };
 use crate::{
     matching_engine::orderbook::OrderBook,
+    perpetual::perp_position::PerpPosition,
     utils::{
-        errors::send_deposit_error_reply,
+        errors::{
+            send_account_tier_error_reply, send_deposit_error_reply, send_l3_orders_error_reply,
+        },
         storage::{local_storage::OnchainActionType, update_invalid::update_invalid_state},
     },
 };
 use crate::{transaction_batch::TransactionBatch, utils::errors::send_oracle_update_error_reply};
 
 use num_bigint::BigUint;
-use tokio::sync::{Mutex as TokioMutex, Semaphore};
+use parking_lot::Mutex;
+use tokio::sync::{oneshot, Mutex as TokioMutex, Semaphore};
 use tonic::{Request, Response, Status};
 
 mod admin;
+mod batch_orders;
+mod mm_protection;
 mod note_position_helpers;
 mod onchain_interaction;
 mod onchain_mms;
@@ -67,6 +89,8 @@ pub struct EngineService {
     //
     pub ws_connections: Arc<TokioMutex<WsConnectionsMap>>,
     pub privileged_ws_connections: Arc<TokioMutex<Vec<u64>>>,
+    pub dead_mans_switches: Arc<TokioMutex<DeadMansSwitches>>,
+    pub rate_limiter: Arc<Mutex<RateLimiter>>,
     //
     pub semaphore: Semaphore,
     pub is_paused: Arc<TokioMutex<bool>>,
@@ -79,6 +103,8 @@ impl Engine for EngineService {
         &self,
         request: Request<LimitOrderMessage>,
     ) -> Result<Response<OrderResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Order.weight())?;
+
         return submit_limit_order_inner(
             &self.transaction_batch,
             &self.order_books,
@@ -99,6 +125,8 @@ impl Engine for EngineService {
         &self,
         request: Request<PerpOrderMessage>,
     ) -> Result<Response<OrderResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Order.weight())?;
+
         let request: PerpOrderMessage = request.into_inner();
 
         return submit_perpetual_order_inner(
@@ -122,9 +150,12 @@ impl Engine for EngineService {
         &self,
         request: Request<LiquidationOrderMessage>,
     ) -> Result<Response<LiquidationOrderResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Order.weight())?;
+
         return submit_liquidation_order_inner(
             &self.transaction_batch,
             &self.perp_order_books,
+            &self.ws_connections,
             &self.semaphore,
             &self.is_paused,
             request,
@@ -140,6 +171,8 @@ impl Engine for EngineService {
         &self,
         request: Request<CancelOrderMessage>,
     ) -> Result<Response<CancelOrderResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Cancel.weight())?;
+
         return cancel_order_inner(
             &self.transaction_batch,
             &self.order_books,
@@ -157,6 +190,8 @@ impl Engine for EngineService {
         &self,
         request: Request<AmendOrderRequest>,
     ) -> Result<Response<AmendOrderResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Order.weight())?;
+
         return amend_order_inner(
             &self.transaction_batch,
             &self.order_books,
@@ -172,10 +207,107 @@ impl Engine for EngineService {
     // * ===================================================================================================================================
     //
 
+    async fn submit_orders_batch(
+        &self,
+        request: Request<OrdersBatchRequest>,
+    ) -> Result<Response<OrdersBatchResponse>, Status> {
+        let batch = request.get_ref();
+        let order_count = (batch.limit_orders.len() + batch.perp_orders.len()) as u32;
+        let cancel_count = batch.cancel_orders.len() as u32;
+        self.check_rate_limit(
+            &request,
+            RequestKind::Order.weight() * order_count + RequestKind::Cancel.weight() * cancel_count,
+        )?;
+
+        return submit_orders_batch_inner(
+            &self.transaction_batch,
+            &self.order_books,
+            &self.perp_order_books,
+            &self.ws_connections,
+            &self.privileged_ws_connections,
+            &self.semaphore,
+            &self.is_paused,
+            request,
+        )
+        .await;
+    }
+
+    //
+    // * ===================================================================================================================================
+    //
+
+    async fn cancel_orders_batch(
+        &self,
+        request: Request<CancelOrdersBatchRequest>,
+    ) -> Result<Response<CancelOrdersBatchResponse>, Status> {
+        let cancel_count = request.get_ref().cancel_orders.len() as u32;
+        self.check_rate_limit(&request, RequestKind::Cancel.weight() * cancel_count)?;
+
+        return cancel_orders_batch_inner(
+            &self.transaction_batch,
+            &self.order_books,
+            &self.perp_order_books,
+            request,
+        )
+        .await;
+    }
+
+    //
+    // * ===================================================================================================================================
+    //
+
+    async fn cancel_all(
+        &self,
+        request: Request<CancelAllRequest>,
+    ) -> Result<Response<CancelAllResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Cancel.weight())?;
+
+        return cancel_all_inner(
+            &self.transaction_batch,
+            &self.order_books,
+            &self.perp_order_books,
+            request,
+        )
+        .await;
+    }
+
+    async fn set_dead_mans_switch(
+        &self,
+        request: Request<DeadMansSwitchReq>,
+    ) -> Result<Response<DeadMansSwitchRes>, Status> {
+        self.check_rate_limit(&request, RequestKind::Cancel.weight())?;
+
+        return set_dead_mans_switch_inner(&self.dead_mans_switches, request).await;
+    }
+
+    async fn set_mm_protection(
+        &self,
+        request: Request<MmProtectionReq>,
+    ) -> Result<Response<SuccessResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Cancel.weight())?;
+
+        return set_mm_protection_inner(&self.order_books, &self.perp_order_books, request).await;
+    }
+
+    async fn reset_mm_protection(
+        &self,
+        request: Request<MmProtectionResetReq>,
+    ) -> Result<Response<SuccessResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Cancel.weight())?;
+
+        return reset_mm_protection_inner(&self.order_books, &self.perp_order_books, request).await;
+    }
+
+    //
+    // * ===================================================================================================================================
+    //
+
     async fn execute_deposit(
         &self,
         request: Request<DepositMessage>,
     ) -> Result<Response<DepositResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Admin.weight())?;
+
         // ? Only call the server from the same network (onyl as fallback)
         if !is_local_address(&request) {
             return send_deposit_error_reply(
@@ -200,6 +332,8 @@ impl Engine for EngineService {
         &self,
         request: Request<WithdrawalMessage>,
     ) -> Result<Response<SuccessResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Order.weight())?;
+
         return execute_withdrawal_inner(
             &self.transaction_batch,
             &self.semaphore,
@@ -217,6 +351,8 @@ impl Engine for EngineService {
         &self,
         request: Request<EscapeMessage>,
     ) -> Result<Response<SuccessResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Order.weight())?;
+
         return execute_escape_inner(
             &self.transaction_batch,
             &self.semaphore,
@@ -230,11 +366,32 @@ impl Engine for EngineService {
     // * ===================================================================================================================================
     //
 
-    async fn split_notes(
+    async fn restructure_notes(
         &self,
-        req: Request<SplitNotesReq>,
-    ) -> Result<Response<SplitNotesRes>, Status> {
-        return split_notes_inner(
+        req: Request<NoteRestructureMessage>,
+    ) -> Result<Response<NoteRestructureResponse>, Status> {
+        self.check_rate_limit(&req, RequestKind::Order.weight())?;
+
+        return restructure_notes_inner(
+            &self.transaction_batch,
+            &self.semaphore,
+            &self.is_paused,
+            req,
+        )
+        .await;
+    }
+
+    //
+    // * ===================================================================================================================================
+    //
+
+    async fn execute_transfer(
+        &self,
+        req: Request<TransferMessage>,
+    ) -> Result<Response<TransferResponse>, Status> {
+        self.check_rate_limit(&req, RequestKind::Order.weight())?;
+
+        return execute_transfer_inner(
             &self.transaction_batch,
             &self.semaphore,
             &self.is_paused,
@@ -251,6 +408,8 @@ impl Engine for EngineService {
         &self,
         req: Request<MarginChangeReq>,
     ) -> Result<Response<MarginChangeRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Order.weight())?;
+
         return change_position_margin_inner(
             &self.transaction_batch,
             &self.perp_order_books,
@@ -262,6 +421,28 @@ impl Engine for EngineService {
         .await;
     }
 
+    async fn set_leverage(
+        &self,
+        req: Request<SetLeverageReq>,
+    ) -> Result<Response<SetLeverageRes>, Status> {
+        let kind = if req.get_ref().dry_run {
+            RequestKind::Query
+        } else {
+            RequestKind::Order
+        };
+        self.check_rate_limit(&req, kind.weight())?;
+
+        return set_position_leverage_inner(
+            &self.transaction_batch,
+            &self.perp_order_books,
+            &self.ws_connections,
+            &self.semaphore,
+            &self.is_paused,
+            req,
+        )
+        .await;
+    }
+
     //
     // * ===================================================================================================================================
     //
@@ -270,6 +451,8 @@ impl Engine for EngineService {
         &self,
         req: Request<OpenOrderTabReq>,
     ) -> Result<Response<OpenOrderTabRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Order.weight())?;
+
         return open_order_tab_inner(
             &self.transaction_batch,
             &self.order_books,
@@ -288,6 +471,8 @@ impl Engine for EngineService {
         &self,
         req: Request<CloseOrderTabReq>,
     ) -> Result<Response<CloseOrderTabRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Order.weight())?;
+
         let req: CloseOrderTabReq = req.into_inner();
 
         return close_order_tab_inner(
@@ -308,6 +493,8 @@ impl Engine for EngineService {
         //
         req: Request<OnChainRegisterMmReq>,
     ) -> Result<Response<OnChainScmmRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Order.weight())?;
+
         let req = req.into_inner();
 
         return register_onchain_mm_inner(
@@ -328,6 +515,8 @@ impl Engine for EngineService {
         &self,
         req: Request<OnChainAddLiqReq>,
     ) -> Result<Response<OnChainScmmRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Order.weight())?;
+
         let req = req.into_inner();
 
         return add_liquidity_mm_inner(
@@ -348,6 +537,8 @@ impl Engine for EngineService {
         &self,
         req: Request<OnChainRemoveLiqReq>,
     ) -> Result<Response<OnChainScmmRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Order.weight())?;
+
         let req = req.into_inner();
 
         return remove_liquidity_mm_inner(
@@ -368,6 +559,8 @@ impl Engine for EngineService {
         &self,
         req: Request<OnChainCloseMmReq>,
     ) -> Result<Response<OnChainScmmRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Order.weight())?;
+
         let req = req.into_inner();
 
         return close_onchain_mm_inner(
@@ -388,6 +581,8 @@ impl Engine for EngineService {
         &self,
         request: Request<EmptyReq>,
     ) -> Result<Response<FinalizeBatchResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Admin.weight())?;
+
         // ? Only call the server from the same network (onyl as fallback)
         if !is_local_address(&request) {
             return Ok(Response::new(FinalizeBatchResponse {}));
@@ -410,6 +605,8 @@ impl Engine for EngineService {
         &self,
         request: Request<OracleUpdateReq>,
     ) -> Result<Response<SuccessResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Admin.weight())?;
+
         // ? Only call the server from the same network (onyl as fallback)
         if !is_local_address(&request) {
             return send_oracle_update_error_reply(format!(
@@ -428,6 +625,8 @@ impl Engine for EngineService {
         &self,
         request: Request<RestoreOrderBookMessage>,
     ) -> Result<Response<SuccessResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Admin.weight())?;
+
         // ? Only call the server from the same network (onyl as fallback)
         if !is_local_address(&request) {
             let reply = SuccessResponse {
@@ -450,6 +649,8 @@ impl Engine for EngineService {
         &self,
         request: Request<RegisterOnchainActionRequest>,
     ) -> Result<Response<SuccessResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Admin.weight())?;
+
         // ? Only call the server from the same network (onyl as fallback)
         if !is_local_address(&request) {
             let reply = SuccessResponse {
@@ -491,10 +692,45 @@ impl Engine for EngineService {
         }));
     }
 
+    async fn set_account_tier(
+        &self,
+        request: Request<SetAccountTierReq>,
+    ) -> Result<Response<SuccessResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Admin.weight())?;
+
+        // ? Only call the server from the same network (onyl as fallback)
+        if !is_local_address(&request) {
+            return send_account_tier_error_reply(
+                "set_account_tier can only be called from the same network".to_string(),
+            );
+        }
+
+        let request = request.into_inner();
+
+        let tier = match AccountTier::try_from(request.tier) {
+            Ok(tier) => tier,
+            Err(err) => return send_account_tier_error_reply(err),
+        };
+        if request.user_id == 0 {
+            return send_account_tier_error_reply("Invalid user id".to_string());
+        }
+
+        self.rate_limiter
+            .lock()
+            .set_user_tier(request.user_id, tier);
+
+        return Ok(Response::new(SuccessResponse {
+            successful: true,
+            error_message: "".to_string(),
+        }));
+    }
+
     async fn update_invalid_state_indexes(
         &self,
         request: Request<UpdateDbIndexesReq>,
     ) -> Result<Response<SuccessResponse>, Status> {
+        self.check_rate_limit(&request, RequestKind::Admin.weight())?;
+
         // ? Only call the server from the same network (onyl as fallback)
         if !is_local_address(&request) {
             let reply = SuccessResponse {
@@ -531,10 +767,38 @@ impl Engine for EngineService {
         &self,
         request: Request<LiquidityReq>,
     ) -> Result<Response<LiquidityRes>, Status> {
+        self.check_rate_limit(&request, RequestKind::Query.weight())?;
+
         return get_liquidity_inner(&self.order_books, &self.perp_order_books, request).await;
     }
 
+    async fn get_depth(&self, request: Request<DepthReq>) -> Result<Response<DepthRes>, Status> {
+        // ? Deeper queries cost more
+        let levels = request.get_ref().levels.min(MAX_DEPTH_LEVELS);
+        self.check_rate_limit(&request, RequestKind::Query.weight() * (1 + levels / 100))?;
+
+        return get_depth_inner(&self.order_books, &self.perp_order_books, request).await;
+    }
+
+    async fn get_l3_orders(
+        &self,
+        request: Request<L3OrdersReq>,
+    ) -> Result<Response<L3OrdersRes>, Status> {
+        self.check_rate_limit(&request, RequestKind::Admin.weight())?;
+
+        // ? Only call the server from the same network (onyl as fallback)
+        if !is_local_address(&request) {
+            return send_l3_orders_error_reply(
+                "get_l3_orders can only be called from the same network".to_string(),
+            );
+        }
+
+        return get_l3_orders_inner(&self.order_books, &self.perp_order_books, request).await;
+    }
+
     async fn get_orders(&self, request: Request<OrdersReq>) -> Result<Response<OrdersRes>, Status> {
+        self.check_rate_limit(&request, RequestKind::Query.weight())?;
+
         return get_orders_inner(
             &self.transaction_batch,
             &self.order_books,
@@ -549,6 +813,8 @@ impl Engine for EngineService {
         &self,
         req: Request<EmptyReq>,
     ) -> Result<Response<IndexPriceRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Query.weight())?;
+
         return get_index_prices_inner(&self.transaction_batch, req).await;
     }
 
@@ -556,26 +822,175 @@ impl Engine for EngineService {
         &self,
         req: Request<StateInfoReq>,
     ) -> Result<Response<StateInfoRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Query.weight())?;
+
         return get_state_info_inner(&self.transaction_batch, req).await;
     }
 
+    async fn get_market_info(
+        &self,
+        req: Request<MarketInfoReq>,
+    ) -> Result<Response<MarketInfoRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Query.weight())?;
+
+        return get_market_info_inner(&self.order_books, &self.perp_order_books, req).await;
+    }
+
     async fn get_funding_info(
         &self,
         req: Request<FundingReq>,
     ) -> Result<Response<FundingRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Query.weight())?;
+
         return get_funding_info_inner(&self.transaction_batch, req).await;
     }
 
+    async fn get_predicted_funding(
+        &self,
+        req: Request<FundingReq>,
+    ) -> Result<Response<PredictedFundingRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Query.weight())?;
+
+        return get_predicted_funding_inner(&self.transaction_batch, req).await;
+    }
+
+    async fn get_position_ledger(
+        &self,
+        req: Request<PositionLedgerReq>,
+    ) -> Result<Response<PositionLedgerRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Query.weight())?;
+
+        return get_position_ledger_inner(&self.transaction_batch, req).await;
+    }
+
+    async fn get_position_risk(
+        &self,
+        req: Request<PositionRiskReq>,
+    ) -> Result<Response<PositionRiskRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Query.weight())?;
+
+        return get_position_risk_inner(&self.transaction_batch, &self.perp_order_books, req).await;
+    }
+
+    async fn get_positions_risk_batch(
+        &self,
+        req: Request<PositionsRiskBatchReq>,
+    ) -> Result<Response<PositionsRiskBatchRes>, Status> {
+        let position_count = req.get_ref().positions.len() as u32;
+        self.check_rate_limit(
+            &req,
+            RequestKind::Query.weight() * (1 + position_count / 50),
+        )?;
+
+        return get_positions_risk_batch_inner(
+            &self.transaction_batch,
+            &self.perp_order_books,
+            req,
+        )
+        .await;
+    }
+
+    async fn simulate_perp_order(
+        &self,
+        req: Request<PerpOrderMessage>,
+    ) -> Result<Response<SimulatePerpOrderRes>, Status> {
+        self.check_rate_limit(&req, RequestKind::Query.weight())?;
+
+        return simulate_perp_order_inner(&self.transaction_batch, &self.perp_order_books, req)
+            .await;
+    }
+
     //
     // * ===================================================================================================================================
     //
 }
 
+impl EngineService {
+    /// Submits a perpetual order like submit_perpetual_order, but also returns the positions
+    /// updated by the swaps the order was matched in (for callers without a ws connection).
+    pub async fn submit_perpetual_order_with_positions(
+        &self,
+        request: PerpOrderMessage,
+    ) -> (
+        Result<Response<OrderResponse>, Status>,
+        Vec<(Option<PerpPosition>, Option<PerpPosition>)>,
+    ) {
+        let (sender, receiver) = oneshot::channel();
+
+        let response = submit_perpetual_order_inner(
+            &self.transaction_batch,
+            &self.perp_order_books,
+            &self.ws_connections,
+            &self.privileged_ws_connections,
+            Some(sender),
+            &self.semaphore,
+            &self.is_paused,
+            request,
+        )
+        .await;
+
+        // ? The sender is dropped without sending if the order wasn't matched
+        let updated_positions = receiver.await.unwrap_or_default();
+
+        return (response, updated_positions);
+    }
+
+    /// Charges the request to the rate limits of its user, stark key and peer address
+    fn check_rate_limit<T: RateLimitKeys>(
+        &self,
+        request: &Request<T>,
+        cost: u32,
+    ) -> Result<(), Status> {
+        let (peer, is_internal_peer) = peer_address(request);
+
+        let message = request.get_ref();
+        let origin = RequestOrigin {
+            user_id: message.user_id(),
+            stark_key: message.stark_key(),
+            peer,
+            is_internal_peer,
+        };
+
+        return self
+            .rate_limiter
+            .lock()
+            .check(&origin, cost)
+            .map_err(|err| Status::resource_exhausted(err));
+    }
+}
+
 fn is_local_address<T>(request: &Request<T>) -> bool {
     let [a, b, c, d] = SERVER_URL;
 
-    return request.remote_addr().unwrap().ip()
-        == std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))
-        || request.remote_addr().unwrap().ip()
-            == std::net::IpAddr::V4(std::net::Ipv4Addr::new(a, b, c, d));
+    let remote_addr = match request.remote_addr() {
+        Some(addr) => addr,
+        None => return false,
+    };
+
+    return remote_addr.ip() == std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))
+        || remote_addr.ip() == std::net::IpAddr::V4(std::net::Ipv4Addr::new(a, b, c, d));
+}
+
+/// The address of the client that made the request, and whether it is one of our own servers.
+///
+/// Requests relayed from the same network are attributed to the address in their
+/// `x-forwarded-for` header (if any), other peers can't choose the address they are limited by.
+fn peer_address<T>(request: &Request<T>) -> (Option<IpAddr>, bool) {
+    let remote_addr = request.remote_addr().map(|addr| addr.ip());
+
+    if !is_local_address(request) {
+        return (remote_addr, false);
+    }
+
+    let forwarded_addr = request
+        .metadata()
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .and_then(|addr| addr.trim().parse::<IpAddr>().ok());
+
+    return match forwarded_addr {
+        Some(addr) => (Some(addr), false),
+        None => (remote_addr, true),
+    };
 }
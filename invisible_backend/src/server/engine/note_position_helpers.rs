@@ -3,15 +3,23 @@ use std::{collections::HashMap, sync::Arc};
 use super::super::grpc::ChangeMarginMessage;
 use super::super::server_helpers::WsConnectionsMap;
 use super::super::{
-    grpc::engine_proto::{MarginChangeReq, MarginChangeRes, SplitNotesReq, SplitNotesRes},
-    server_helpers::engine_helpers::{handle_margin_change_repsonse, handle_split_notes_repsonse},
+    grpc::engine_proto::{
+        GrpcPerpPosition, MarginChangeReq, MarginChangeRes, NoteRestructureMessage,
+        NoteRestructureResponse, SetLeverageReq, SetLeverageRes, TransferMessage, TransferResponse,
+    },
+    server_helpers::engine_helpers::{
+        handle_margin_change_repsonse, handle_note_restructure_repsonse, handle_transfer_repsonse,
+    },
 };
 use crate::matching_engine::orderbook::OrderBook;
+use crate::perpetual::perp_position::PerpPosition;
+use crate::transaction_batch::batch_functions::state_modifications::get_set_leverage_margin_change;
 use crate::transaction_batch::TransactionBatch;
+use crate::transactions::{note_restructure::NoteRestructure, transfer::Transfer};
 
-use crate::utils::{
-    errors::{send_margin_change_error_reply, send_split_notes_error_reply},
-    notes::Note,
+use crate::utils::errors::{
+    send_margin_change_error_reply, send_note_restructure_error_reply,
+    send_set_leverage_error_reply, send_transfer_error_reply,
 };
 
 use tokio::sync::{Mutex as TokioMutex, Semaphore};
@@ -19,15 +27,15 @@ use tonic::{Request, Response, Status};
 
 //
 // * ===================================================================================================================================
-// * SPLIT NOTES
+// * RESTRUCTURE NOTES
 
-pub async fn split_notes_inner(
+pub async fn restructure_notes_inner(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     semaphore: &Semaphore,
     is_paused: &Arc<TokioMutex<bool>>,
     //
-    req: Request<SplitNotesReq>,
-) -> Result<Response<SplitNotesRes>, Status> {
+    req: Request<NoteRestructureMessage>,
+) -> Result<Response<NoteRestructureResponse>, Status> {
     let _permit = semaphore.acquire().await.unwrap();
 
     let lock = is_paused.lock().await;
@@ -40,46 +48,88 @@ pub async fn split_notes_inner(
     let main_storage = Arc::clone(&tx_batch_m.main_storage);
     drop(tx_batch_m);
 
-    let req: SplitNotesReq = req.into_inner();
+    let req: NoteRestructureMessage = req.into_inner();
 
-    let mut notes_in: Vec<Note> = Vec::new();
-    for n in req.notes_in.iter() {
-        let note = Note::try_from(n.clone());
-
-        if let Ok(n) = note {
-            notes_in.push(n);
-        } else {
-            return send_split_notes_error_reply("Invalid note".to_string());
-        }
-    }
-    let new_note: Note;
-    let mut refund_note: Option<Note> = None;
-    if req.note_out.is_some() {
-        let note_out = Note::try_from(req.note_out.unwrap());
-
-        if let Ok(n) = note_out {
-            new_note = n;
-        } else {
-            return send_split_notes_error_reply("Invalid note".to_string());
+    let restructure: NoteRestructure;
+    match NoteRestructure::try_from(req) {
+        Ok(r) => restructure = r,
+        Err(_e) => {
+            return send_note_restructure_error_reply(
+                "Erroc unpacking the restructure message (verify the format is correct)"
+                    .to_string(),
+            );
         }
-    } else {
-        return send_split_notes_error_reply("Invalid note".to_string());
+    };
+
+    let mut tx_batch_m = tx_batch.lock().await;
+    let restructure_handle = tx_batch_m.execute_transaction(restructure);
+    drop(tx_batch_m);
+
+    let restructure_response = restructure_handle.join();
+
+    if let Err(_e) = restructure_response {
+        return send_note_restructure_error_reply(
+            "Unknown Error occured in the note restructure execution".to_string(),
+        );
     }
-    if req.refund_note.is_some() {
-        let refund_note_ = Note::try_from(req.refund_note.unwrap());
 
-        if let Ok(n) = refund_note_ {
-            refund_note = Some(n);
-        } else {
-            return send_split_notes_error_reply("Invalid note".to_string());
+    return handle_note_restructure_repsonse(
+        restructure_response.unwrap(),
+        &swap_output_json,
+        &main_storage,
+    )
+    .await;
+}
+
+//
+// * ===================================================================================================================================
+// * EXECUTE TRANSFER
+
+pub async fn execute_transfer_inner(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    semaphore: &Semaphore,
+    is_paused: &Arc<TokioMutex<bool>>,
+    //
+    req: Request<TransferMessage>,
+) -> Result<Response<TransferResponse>, Status> {
+    let _permit = semaphore.acquire().await.unwrap();
+
+    let lock = is_paused.lock().await;
+    drop(lock);
+
+    tokio::task::yield_now().await;
+
+    let tx_batch_m = tx_batch.lock().await;
+    let swap_output_json = Arc::clone(&tx_batch_m.swap_output_json);
+    let main_storage = Arc::clone(&tx_batch_m.main_storage);
+    drop(tx_batch_m);
+
+    let req: TransferMessage = req.into_inner();
+
+    let transfer: Transfer;
+    match Transfer::try_from(req) {
+        Ok(t) => transfer = t,
+        Err(_e) => {
+            return send_transfer_error_reply(
+                "Erroc unpacking the transfer message (verify the format is correct)".to_string(),
+            );
         }
-    }
+    };
 
     let mut tx_batch_m = tx_batch.lock().await;
-    let zero_idxs = tx_batch_m.split_notes(notes_in, new_note, refund_note);
+    let transfer_handle = tx_batch_m.execute_transaction(transfer);
     drop(tx_batch_m);
 
-    return handle_split_notes_repsonse(zero_idxs, &swap_output_json, &main_storage).await;
+    let transfer_response = transfer_handle.join();
+
+    if let Err(_e) = transfer_response {
+        return send_transfer_error_reply(
+            "Unknown Error occured in the transfer execution".to_string(),
+        );
+    }
+
+    return handle_transfer_repsonse(transfer_response.unwrap(), &swap_output_json, &main_storage)
+        .await;
 }
 
 //
@@ -140,3 +190,104 @@ pub async fn change_position_margin_inner(
     )
     .await;
 }
+
+//
+// * ===================================================================================================================================
+// * SET LEVERAGE
+
+pub async fn set_position_leverage_inner(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
+    semaphore: &Semaphore,
+    is_paused: &Arc<TokioMutex<bool>>,
+    //
+    req: Request<SetLeverageReq>,
+) -> Result<Response<SetLeverageRes>, Status> {
+    let _permit = semaphore.acquire().await.unwrap();
+
+    let lock = is_paused.lock().await;
+    drop(lock);
+
+    tokio::task::yield_now().await;
+
+    let req: SetLeverageReq = req.into_inner();
+    let leverage = req.leverage;
+
+    let position = match req.position.clone().map(PerpPosition::try_from) {
+        Some(Ok(position)) => position,
+        _ => return send_set_leverage_error_reply("Invalid position".to_string()),
+    };
+    let synthetic_token = position.position_header.synthetic_token;
+
+    let tx_batch_m = tx_batch.lock().await;
+    let swap_output_json = Arc::clone(&tx_batch_m.swap_output_json);
+    let main_storage = Arc::clone(&tx_batch_m.main_storage);
+    let index_price = tx_batch_m
+        .latest_index_price
+        .get(&synthetic_token)
+        .copied()
+        .unwrap_or(0);
+    drop(tx_batch_m);
+
+    // ? A dry run only previews the margin change and the resulting position
+    if req.dry_run {
+        return match get_set_leverage_margin_change(&position, leverage, index_price) {
+            Ok((margin_change, new_position)) => {
+                let reply = SetLeverageRes {
+                    successful: true,
+                    error_message: "".to_string(),
+                    margin_change,
+                    leverage: new_position.get_current_leverage(index_price).unwrap_or(0),
+                    liquidation_price: new_position.liquidation_price,
+                    return_collateral_index: 0,
+                    position: Some(GrpcPerpPosition::from(new_position)),
+                };
+
+                Ok(Response::new(reply))
+            }
+            Err(e) => send_set_leverage_error_reply(e),
+        };
+    }
+
+    let change_margin_message = ChangeMarginMessage::try_from(req).ok();
+
+    if change_margin_message.is_none() {
+        return send_set_leverage_error_reply("Invalid set leverage message".to_string());
+    }
+
+    let user_id = change_margin_message.as_ref().unwrap().user_id;
+
+    let tx_batch_m = tx_batch.lock().await;
+    let result = tx_batch_m.set_position_leverage(change_margin_message.unwrap());
+    drop(tx_batch_m);
+
+    let (return_collateral_index, new_position) = match result {
+        Ok(res) => res,
+        Err(e) => return send_set_leverage_error_reply(e),
+    };
+
+    let margin_change = new_position.margin as i64 - position.margin as i64;
+    let reply = SetLeverageRes {
+        successful: true,
+        error_message: "".to_string(),
+        margin_change,
+        leverage: new_position.get_current_leverage(index_price).unwrap_or(0),
+        liquidation_price: new_position.liquidation_price,
+        return_collateral_index,
+        position: Some(GrpcPerpPosition::from(new_position.clone())),
+    };
+
+    // ? Update the resting orders, store the transaction and notify the relay like a margin change
+    handle_margin_change_repsonse(
+        (return_collateral_index, new_position),
+        user_id,
+        &swap_output_json,
+        &main_storage,
+        perp_order_books,
+        ws_connections,
+    )
+    .await?;
+
+    return Ok(Response::new(reply));
+}
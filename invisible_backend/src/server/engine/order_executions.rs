@@ -9,7 +9,9 @@ use super::super::{
         OrderResponse, PerpOrderMessage,
     },
     server_helpers::{
-        engine_helpers::store_output_json,
+        engine_helpers::{
+            report_mm_protection_triggers, report_reduce_only_updates, store_output_json,
+        },
         get_market_id_and_order_side,
         perp_swap_execution::{
             process_and_execute_perp_swaps, process_perp_order_request, retry_failed_perp_swaps,
@@ -22,6 +24,9 @@ use super::super::{
     },
 };
 
+use crate::matching_engine::orderbook::{Failed, Success};
+use crate::matching_engine::validation::MarketParams;
+use crate::perpetual::open_interest::{max_open_interest, max_open_interest_increase};
 use crate::perpetual::perp_order::PerpOrder;
 use crate::perpetual::perp_position::PerpPosition;
 use crate::server::server_helpers::engine_helpers::verify_tab_existence;
@@ -39,6 +44,7 @@ use crate::{
 use crate::transactions::limit_order::LimitOrder;
 use crate::utils::crypto_utils::Signature;
 use crate::utils::errors::send_order_error_reply;
+use crate::utils::storage::local_storage::MainStorage;
 
 use parking_lot::Mutex;
 use tokio::sync::oneshot::Sender;
@@ -68,22 +74,81 @@ pub async fn submit_limit_order_inner(
     let tx_batch_m = tx_batch.lock().await;
     let state_tree = Arc::clone(&tx_batch_m.state_tree);
     let swap_output_json = Arc::clone(&tx_batch_m.swap_output_json);
-    let firebase_session = Arc::clone(&tx_batch_m.firebase_session);
     let main_storage = Arc::clone(&tx_batch_m.main_storage);
-    let backup_storage = Arc::clone(&tx_batch_m.backup_storage);
     drop(tx_batch_m);
 
     let req: LimitOrderMessage = request.into_inner();
 
     let user_id = req.user_id;
+    let client_order_id = req.client_order_id;
     let is_market: bool = req.is_market;
 
+    let res = limit_order_checks(&state_tree, req);
+    if let Err(e) = res {
+        return send_order_error_reply(e);
+    }
+    let (signature, limit_order, market_id, side) = res.unwrap();
+
+    // ? ------------------------------------------------------------------------------------
+    // ? Insert the order into the orderbook and see if there is a hit
+    let processed_res = process_limit_order_request(
+        order_books.get(&market_id).clone().unwrap(),
+        limit_order.clone(),
+        side,
+        signature.clone(),
+        user_id,
+        client_order_id,
+        is_market,
+        false,
+        0,
+        0,
+        None,
+    )
+    .await;
+
+    let new_order_id = match execute_limit_order_matches(
+        tx_batch,
+        order_books.get(&market_id).clone().unwrap(),
+        ws_connections,
+        privileged_ws_connections,
+        processed_res,
+        limit_order,
+        side,
+        signature,
+        user_id,
+        client_order_id,
+        is_market,
+    )
+    .await
+    {
+        Ok(oid) => oid,
+        Err(e) => return send_order_error_reply(e),
+    };
+
+    store_output_json(&swap_output_json, &main_storage);
+
+    // Send a successul reply to the caller
+    let reply = OrderResponse {
+        successful: true,
+        error_message: "".to_string(),
+        order_id: new_order_id,
+    };
+
+    return Ok(Response::new(reply));
+}
+
+/// Verifies the format of the limit order message and that the notes/order tab it spends exist. \
+/// Returns the signature, the parsed order and the market and side of the order.
+pub fn limit_order_checks(
+    state_tree: &Arc<Mutex<SuperficialTree>>,
+    req: LimitOrderMessage,
+) -> std::result::Result<(Signature, LimitOrder, u16, OBOrderSide), String> {
     // ? Verify the signature is defined and has a valid format
     let signature: Signature;
     match verify_signature_format(&req.signature) {
         Ok(sig) => signature = sig,
         Err(err) => {
-            return send_order_error_reply(err);
+            return Err(err);
         }
     }
 
@@ -92,7 +157,7 @@ pub async fn submit_limit_order_inner(
     match LimitOrder::try_from(req) {
         Ok(lo) => limit_order = lo,
         Err(_e) => {
-            return send_order_error_reply(
+            return Err(
                 "Error unpacking the limit order (verify the format is correct)".to_string(),
             );
         }
@@ -101,122 +166,94 @@ pub async fn submit_limit_order_inner(
     // ? Try to get the market_id and order_side from the limit_order
     let res = get_market_id_and_order_side(limit_order.token_spent, limit_order.token_received);
     if res.is_none() {
-        return send_order_error_reply("Market (token pair) not found".to_string());
+        return Err("Market (token pair) not found".to_string());
     }
     let (market_id, side) = res.unwrap();
 
     if limit_order.spot_note_info.is_some() {
         // ? Verify the notes spent exist in the state tree
-        if let Err(err_msg) = verify_notes_existence(
+        verify_notes_existence(
             &limit_order.spot_note_info.as_ref().unwrap().notes_in,
             &state_tree,
-        ) {
-            return send_order_error_reply(err_msg);
-        }
+        )?;
     } else {
         if limit_order.order_tab.is_none() {
-            return send_order_error_reply(
-                "Order tab is not defined for this limit order".to_string(),
-            );
+            return Err("Order tab is not defined for this limit order".to_string());
         }
 
         // ? Verify the order tab exist in the state tree
-        if let Err(err_msg) =
-            verify_tab_existence(&limit_order.order_tab.as_ref().unwrap(), &state_tree)
-        {
-            return send_order_error_reply(err_msg);
-        }
+        verify_tab_existence(&limit_order.order_tab.as_ref().unwrap(), &state_tree)?;
     }
 
-    // ? ------------------------------------------------------------------------------------
-    // ? Insert the order into the orderbook and see if there is a hit
-    let mut processed_res = process_limit_order_request(
-        order_books.get(&market_id).clone().unwrap(),
-        limit_order.clone(),
-        side,
-        signature.clone(),
-        user_id,
-        is_market,
-        false,
-        0,
-        0,
-        None,
-    )
-    .await;
+    return Ok((signature, limit_order, market_id, side));
+}
+
+/// Executes the swaps from the orderbook matches of a new limit order (retrying the failed ones)
+/// and returns the id of the new order.
+pub async fn execute_limit_order_matches(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    order_book: &Arc<TokioMutex<OrderBook>>,
+    ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
+    privileged_ws_connections: &Arc<TokioMutex<Vec<u64>>>,
+    mut processed_res: Vec<std::result::Result<Success, Failed>>,
+    limit_order: LimitOrder,
+    side: OBOrderSide,
+    signature: Signature,
+    user_id: u64,
+    client_order_id: u64,
+    is_market: bool,
+) -> std::result::Result<u64, String> {
+    let tx_batch_m = tx_batch.lock().await;
+    let firebase_session = Arc::clone(&tx_batch_m.firebase_session);
+    let backup_storage = Arc::clone(&tx_batch_m.backup_storage);
+    drop(tx_batch_m);
 
     // ? ------------------------------------------------------------------------------------
     // ? If there are any hits, process and execute the swaps
-    let reults;
-    let new_order_id;
-    match process_and_execute_spot_swaps(
+    let (results, new_order_id) = process_and_execute_spot_swaps(
         &tx_batch,
-        order_books.get(&market_id).clone().unwrap(),
+        order_book,
         &firebase_session,
         &backup_storage,
         &mut processed_res,
     )
-    .await
-    {
-        Ok((h, oid)) => {
-            reults = h;
-            new_order_id = oid;
-        }
-        Err(err) => {
-            return send_order_error_reply(err);
-        }
-    };
+    .await?;
 
     // ? ------------------------------------------------------------------------------------
     // ? Handle the result of the swap executions
-    let retry_messages;
-    match handle_swap_execution_results(
+    let retry_messages = handle_swap_execution_results(
         &ws_connections,
         &privileged_ws_connections,
-        reults,
+        results,
         user_id,
     )
-    .await
-    {
-        Ok(rm) => retry_messages = rm,
-        Err(e) => {
-            return send_order_error_reply(e);
-        }
-    };
+    .await?;
 
     // ? ------------------------------------------------------------------------------------
     // ? Retry the order in case it fails
     if retry_messages.len() > 0 {
-        if let Err(e) = retry_failed_swaps(
+        retry_failed_swaps(
             &tx_batch,
-            order_books.get(&market_id).clone().unwrap(),
+            order_book,
             &firebase_session,
             &backup_storage,
             limit_order,
             side,
             signature,
             user_id,
+            client_order_id,
             is_market,
             &ws_connections,
             &privileged_ws_connections,
             retry_messages,
             None,
         )
-        .await
-        {
-            return send_order_error_reply(e);
-        }
+        .await?;
     }
 
-    store_output_json(&swap_output_json, &main_storage);
+    report_mm_protection_triggers(tx_batch, order_book, false, ws_connections).await;
 
-    // Send a successul reply to the caller
-    let reply = OrderResponse {
-        successful: true,
-        error_message: "".to_string(),
-        order_id: new_order_id,
-    };
-
-    return Ok(Response::new(reply));
+    return Ok(new_order_id);
 }
 
 //
@@ -242,9 +279,12 @@ pub async fn submit_perpetual_order_inner(
 
     let tx_batch_m = tx_batch.lock().await;
     let state_tree = Arc::clone(&tx_batch_m.state_tree);
+    let open_interest = Arc::clone(&tx_batch_m.open_interest);
+    let main_storage = Arc::clone(&tx_batch_m.main_storage);
     drop(tx_batch_m);
 
     let user_id = req.user_id;
+    let client_order_id = req.client_order_id;
     let is_market: bool = req.is_market;
 
     let res = order_format_checks(req);
@@ -256,6 +296,12 @@ pub async fn submit_perpetual_order_inner(
     if let Err(err) = existance_checks(&state_tree, &perp_order) {
         return send_order_error_reply(err);
     }
+    if let Err(err) = open_interest_checks(&open_interest, &perp_order) {
+        return send_order_error_reply(err);
+    }
+    if let Err(err) = position_size_checks(&main_storage, &perp_order) {
+        return send_order_error_reply(err);
+    }
 
     return match_and_execute_perp_order(
         tx_batch,
@@ -266,6 +312,7 @@ pub async fn submit_perpetual_order_inner(
         perp_order,
         signature,
         user_id,
+        client_order_id,
         is_market,
         market,
     )
@@ -329,6 +376,70 @@ pub fn existance_checks(
     Ok(())
 }
 
+/// Rejects orders that could push the open interest of the market past its max
+/// (closing and reducing orders are always accepted)
+pub fn open_interest_checks(
+    open_interest: &Arc<Mutex<HashMap<u32, u64>>>,
+    perp_order: &PerpOrder,
+) -> std::result::Result<(), String> {
+    if perp_order.position_effect_type == PositionEffectType::Close {
+        return Ok(());
+    }
+
+    let increase = max_open_interest_increase(
+        perp_order.position.as_ref(),
+        &perp_order.order_side,
+        perp_order.synthetic_amount,
+    );
+    if increase == 0 {
+        return Ok(());
+    }
+
+    let current = *open_interest
+        .lock()
+        .get(&perp_order.synthetic_token)
+        .unwrap_or(&0);
+    if current + increase > max_open_interest(perp_order.synthetic_token) {
+        return Err("Order would exceed the open interest limit of the market".to_string());
+    }
+
+    Ok(())
+}
+
+/// Rejects orders that could grow the positions of their owner in the market past the max
+/// position size (the limit applies to the sum of all of the owner's positions in the market)
+pub fn position_size_checks(
+    main_storage: &Arc<Mutex<MainStorage>>,
+    perp_order: &PerpOrder,
+) -> std::result::Result<(), String> {
+    let position_address = match perp_order.position_effect_type {
+        PositionEffectType::Open => {
+            &perp_order
+                .open_order_fields
+                .as_ref()
+                .unwrap()
+                .position_address
+        }
+        PositionEffectType::Modify => {
+            &perp_order
+                .position
+                .as_ref()
+                .unwrap()
+                .position_header
+                .position_address
+        }
+        PositionEffectType::Close => return Ok(()),
+    };
+
+    let owner_positions = main_storage
+        .lock()
+        .read_positions_at_address(&position_address.to_string());
+
+    return MarketParams::for_asset(perp_order.synthetic_token)
+        .validate_owner_position_size(&owner_positions, perp_order)
+        .map_err(|err| err.to_string());
+}
+
 pub async fn match_and_execute_perp_order(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
@@ -338,24 +449,24 @@ pub async fn match_and_execute_perp_order(
     perp_order: PerpOrder,
     signature: Signature,
     user_id: u64,
+    client_order_id: u64,
     is_market: bool,
     market: u16,
 ) -> Result<Response<OrderResponse>, Status> {
     let tx_batch_m = tx_batch.lock().await;
     let swap_output_json = Arc::clone(&tx_batch_m.swap_output_json);
-    let firebase_session = Arc::clone(&tx_batch_m.firebase_session);
     let main_storage = Arc::clone(&tx_batch_m.main_storage);
-    let backup_storage = Arc::clone(&tx_batch_m.backup_storage);
     drop(tx_batch_m);
 
     let side: OBOrderSide = perp_order.order_side.clone().into();
 
-    let mut processed_res = process_perp_order_request(
+    let processed_res = process_perp_order_request(
         perp_order_books.get(&market).clone().unwrap(),
         perp_order.clone(),
         side,
         signature.clone(),
         user_id,
+        client_order_id,
         is_market,
         false,
         0,
@@ -364,12 +475,63 @@ pub async fn match_and_execute_perp_order(
     )
     .await;
 
+    let new_order_id = match execute_perp_order_matches(
+        tx_batch,
+        perp_order_books.get(&market).clone().unwrap(),
+        ws_connections,
+        privileged_ws_connections,
+        response_sender,
+        processed_res,
+        perp_order,
+        side,
+        signature,
+        user_id,
+        client_order_id,
+        is_market,
+    )
+    .await
+    {
+        Ok(oid) => oid,
+        Err(e) => return send_order_error_reply(e),
+    };
+
+    store_output_json(&swap_output_json, &main_storage);
+
+    // Send a successful reply to the caller
+    let reply = OrderResponse {
+        successful: true,
+        error_message: "".to_string(),
+        order_id: new_order_id,
+    };
+
+    return Ok(Response::new(reply));
+}
+
+/// Executes the perp swaps from the orderbook matches of a new perp order (retrying the failed ones)
+/// and returns the id of the new order.
+pub async fn execute_perp_order_matches(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    perp_order_book: &Arc<TokioMutex<OrderBook>>,
+    ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
+    privileged_ws_connections: &Arc<TokioMutex<Vec<u64>>>,
+    response_sender: Option<Sender<Vec<(Option<PerpPosition>, Option<PerpPosition>)>>>,
+    mut processed_res: Vec<std::result::Result<Success, Failed>>,
+    perp_order: PerpOrder,
+    side: OBOrderSide,
+    signature: Signature,
+    user_id: u64,
+    client_order_id: u64,
+    is_market: bool,
+) -> std::result::Result<u64, String> {
+    let tx_batch_m = tx_batch.lock().await;
+    let firebase_session = Arc::clone(&tx_batch_m.firebase_session);
+    let backup_storage = Arc::clone(&tx_batch_m.backup_storage);
+    drop(tx_batch_m);
+
     // This matches the orders and creates the swaps that can be executed
-    let retry_messages;
-    let new_order_id;
-    match process_and_execute_perp_swaps(
+    let (retry_messages, new_order_id) = process_and_execute_perp_swaps(
         &tx_batch,
-        perp_order_books.get(&market).clone().unwrap(),
+        perp_order_book,
         &firebase_session,
         &backup_storage,
         &ws_connections,
@@ -378,47 +540,30 @@ pub async fn match_and_execute_perp_order(
         &mut processed_res,
         user_id,
     )
-    .await
-    {
-        Ok((h, oid)) => {
-            retry_messages = h;
-            new_order_id = oid;
-        }
-        Err(err) => {
-            return send_order_error_reply(err);
-        }
-    };
+    .await?;
 
-    if let Err(e) = retry_failed_perp_swaps(
+    retry_failed_perp_swaps(
         &tx_batch,
-        perp_order_books.get(&market).clone().unwrap(),
+        perp_order_book,
         &firebase_session,
         &backup_storage,
         perp_order,
         side,
         signature,
         user_id,
+        client_order_id,
         is_market,
         &ws_connections,
         &privileged_ws_connections,
         retry_messages,
         None,
     )
-    .await
-    {
-        return send_order_error_reply(e);
-    }
-
-    store_output_json(&swap_output_json, &main_storage);
+    .await?;
 
-    // Send a successful reply to the caller
-    let reply = OrderResponse {
-        successful: true,
-        error_message: "".to_string(),
-        order_id: new_order_id,
-    };
+    report_mm_protection_triggers(tx_batch, perp_order_book, true, ws_connections).await;
+    report_reduce_only_updates(tx_batch, perp_order_book, ws_connections).await;
 
-    return Ok(Response::new(reply));
+    return Ok(new_order_id);
 }
 
 //
@@ -429,6 +574,7 @@ pub async fn match_and_execute_perp_order(
 pub async fn submit_liquidation_order_inner(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
     semaphore: &Semaphore,
     is_paused: &Arc<TokioMutex<bool>>,
     request: Request<LiquidationOrderMessage>,
@@ -503,6 +649,16 @@ pub async fn submit_liquidation_order_inner(
             Ok(response) => {
                 store_output_json(&swap_output_json, &main_storage);
 
+                // ? Resize or cancel the reduce only orders on the liquidated position
+                let perp_order_book = perp_order_books.get(&market.unwrap()).unwrap();
+                let mut perp_orderbook = perp_order_book.lock().await;
+                perp_orderbook.update_reduce_only_orders(
+                    &response.liquidated_position_address,
+                    &response.liquidated_position,
+                );
+                drop(perp_orderbook);
+                report_reduce_only_updates(tx_batch, perp_order_book, ws_connections).await;
+
                 // TODO Send message to the user whose position was liquidated ?
 
                 println!("Position liquidated successfully!!!!!!!!!\n");
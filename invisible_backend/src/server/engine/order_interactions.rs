@@ -9,8 +9,12 @@ use super::super::{
     server_helpers::{
         amend_order_execution::{
             execute_perp_swaps_after_amend_order, execute_spot_swaps_after_amend_order,
+            verify_amended_order_amounts,
+        },
+        engine_helpers::{
+            handle_cancel_order_repsonse, report_mm_protection_triggers,
+            report_reduce_only_updates, store_output_json,
         },
-        engine_helpers::{handle_cancel_order_repsonse, store_output_json},
     },
 };
 use crate::matching_engine::orders::limit_order_cancel_request;
@@ -19,7 +23,9 @@ use crate::utils::crypto_utils::Signature;
 use crate::utils::errors::send_cancel_order_error_reply;
 use crate::{
     matching_engine::{
-        domain::OrderSide as OBOrderSide, orderbook::OrderBook, orders::new_amend_order,
+        domain::OrderSide as OBOrderSide,
+        orderbook::OrderBook,
+        orders::{amend_inner, new_amend_order},
     },
     utils::errors::send_amend_order_error_reply,
 };
@@ -65,10 +71,21 @@ pub async fn cancel_order_inner(
         OBOrderSide::Ask
     };
 
-    let cancel_request = limit_order_cancel_request(req.order_id, order_side, req.user_id);
-
     let mut order_book = order_book_m.lock().await;
 
+    let (order_id, order_side) = match resolve_order_id(
+        &order_book,
+        req.order_id,
+        req.client_order_id,
+        order_side,
+        req.user_id,
+    ) {
+        Some(res) => res,
+        None => return send_cancel_order_error_reply("Order not found".to_string()),
+    };
+
+    let cancel_request = limit_order_cancel_request(order_id, order_side, req.user_id);
+
     let res = order_book.process_order(cancel_request);
 
     let tx_batch_m = tx_batch.lock().await;
@@ -79,7 +96,7 @@ pub async fn cancel_order_inner(
     return handle_cancel_order_repsonse(
         &res[0],
         req.is_perp,
-        req.order_id,
+        order_id,
         &partial_fill_tracker,
         &perpetual_partial_fill_tracker,
     );
@@ -135,17 +152,56 @@ pub async fn amend_order_inner(
         OBOrderSide::Ask
     };
 
-    let amend_request = new_amend_order(
+    let mut order_book = order_book_m.lock().await;
+
+    let (order_id, order_side) = match resolve_order_id(
+        &order_book,
         req.order_id,
+        req.client_order_id,
         order_side,
         req.user_id,
-        req.new_price,
+    ) {
+        Some(res) => res,
+        None => return send_amend_order_error_reply("Order not found".to_string()),
+    };
+
+    let order_wrapper = order_book.get_order(order_id);
+    if order_wrapper.is_none() {
+        return send_amend_order_error_reply("Order not found".to_string());
+    }
+
+    // ? The order as it will be after the amend (used to validate and retry the amended order)
+    let mut amended_order = order_wrapper.unwrap();
+    let price = if req.new_price == 0.0 {
+        amended_order.order.get_price(order_side, None)
+    } else {
+        req.new_price
+    };
+    amend_inner(
+        &mut amended_order,
+        price,
         req.new_expiration,
+        req.new_qty,
         signature.clone(),
+    );
+
+    if req.new_qty != 0 {
+        if let Err(e) = verify_amended_order_amounts(&amended_order.order, order_side) {
+            return send_amend_order_error_reply(e);
+        }
+    }
+
+    let amend_request = new_amend_order(
+        order_id,
+        order_side,
+        req.user_id,
+        req.new_price,
+        req.new_expiration,
+        req.new_qty,
+        signature,
         req.match_only,
     );
 
-    let mut order_book = order_book_m.lock().await;
     let mut processed_res = order_book.process_order(amend_request);
     drop(order_book);
 
@@ -161,10 +217,7 @@ pub async fn amend_order_inner(
             &ws_connections,
             &privileged_ws_connections,
             &mut processed_res,
-            req.order_id,
-            order_side,
-            signature,
-            req.user_id,
+            amended_order,
         )
         .await
         {
@@ -177,10 +230,7 @@ pub async fn amend_order_inner(
             processed_res,
             &ws_connections,
             &privileged_ws_connections,
-            req.order_id,
-            order_side,
-            signature,
-            req.user_id,
+            amended_order,
         )
         .await
         {
@@ -188,6 +238,11 @@ pub async fn amend_order_inner(
         }
     }
 
+    report_mm_protection_triggers(&tx_batch, &order_book_m, req.is_perp, &ws_connections).await;
+    if req.is_perp {
+        report_reduce_only_updates(&tx_batch, &order_book_m, &ws_connections).await;
+    }
+
     store_output_json(&swap_output_json, &main_storage);
 
     let reply: AmendOrderResponse = AmendOrderResponse {
@@ -197,3 +252,21 @@ pub async fn amend_order_inner(
 
     return Ok(Response::new(reply));
 }
+
+/// Orders can be referenced either by their order id or by the client_order_id the user
+/// assigned to them (when order_id is 0), in which case the side is taken from the order.
+pub fn resolve_order_id(
+    order_book: &OrderBook,
+    order_id: u64,
+    client_order_id: u64,
+    order_side: OBOrderSide,
+    user_id: u64,
+) -> Option<(u64, OBOrderSide)> {
+    if order_id != 0 || client_order_id == 0 {
+        return Some((order_id, order_side));
+    }
+
+    let wrapper = order_book.get_order_by_client_id(user_id, client_order_id)?;
+
+    Some((wrapper.order_id, wrapper.order_side))
+}
@@ -1,21 +1,64 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use num_bigint::BigUint;
+
+use parking_lot::Mutex;
 
 use super::super::grpc::engine_proto::{
-    ActiveOrder, ActivePerpOrder, BookEntry, FundingInfo, FundingReq, FundingRes, GrpcNote,
-    GrpcOrderTab, LiquidityReq, LiquidityRes, OrdersReq, OrdersRes, StateInfoReq, StateInfoRes,
+    ActiveOrder, ActivePerpOrder, BookEntry, DepthLevel, DepthReq, DepthRes, FundingInfo,
+    FundingReq, FundingRes, GrpcLedgerEntry, GrpcNote, GrpcOrderTab, GrpcPerpPosition,
+    GrpcPositionRisk, GrpcSimulatedFill, L3Order, L3OrdersReq, L3OrdersRes, LiquidityReq,
+    LiquidityRes, MarketInfo, MarketInfoReq, MarketInfoRes, OrdersReq, OrdersRes, PerpOrderMessage,
+    PositionLedgerReq, PositionLedgerRes, PositionRiskReq, PositionRiskRes, PositionsRiskBatchReq,
+    PositionsRiskBatchRes, PredictedFunding, PredictedFundingRes, SimulatePerpOrderRes,
+    StateInfoReq, StateInfoRes,
+};
+use super::super::server_helpers::{
+    build_perp_swap, engine_helpers::verify_position_existence, PERP_MARKET_IDS,
 };
+use super::order_executions::{existance_checks, open_interest_checks, position_size_checks};
 
 use crate::server::grpc::engine_proto::{EmptyReq, IndexPriceRes};
-use crate::transaction_batch::TransactionBatch;
+use crate::transaction_batch::{
+    tx_batch_helpers::get_funding_rate, tx_batch_structs::SwapFundingInfo, TransactionBatch,
+};
 use crate::{
     matching_engine::{
         domain::{Order, OrderSide as OBOrderSide},
+        get_quote_qty,
+        order_queues::PriceLevel,
         orderbook::OrderBook,
+        sequence::market_id_from_order_id,
+    },
+    perpetual::{
+        get_price,
+        open_interest::max_open_interest,
+        order_execution::simulate_order::{simulate_perp_order, SimulatedPerpFill},
+        perp_order::PerpOrder,
+        perp_position::PerpPosition,
+        perp_swap::PerpSwap,
+        position_ledger::MAX_LEDGER_ENTRIES_PER_QUERY,
+        position_risk::{get_position_risk, MAX_POSITIONS_PER_RISK_QUERY},
+        OrderSide, PositionEffectType, COLLATERAL_TOKEN, SYNTHETIC_ASSETS,
     },
-    perpetual::PositionEffectType,
+    trees::superficial_tree::SuperficialTree,
 };
 
-use crate::utils::{errors::send_liquidity_error_reply, notes::Note};
+use crate::utils::{
+    errors::{
+        send_depth_error_reply, send_l3_orders_error_reply, send_liquidity_error_reply,
+        send_market_info_error_reply, send_position_ledger_error_reply,
+        send_position_risk_error_reply, send_positions_risk_batch_error_reply,
+        send_simulate_perp_order_error_reply,
+    },
+    notes::Note,
+    storage::local_storage::MainStorage,
+};
 
 use tokio::sync::Mutex as TokioMutex;
 use tonic::{Request, Response, Status};
@@ -87,6 +130,165 @@ pub async fn get_liquidity_inner(
     return Ok(Response::new(reply));
 }
 
+/// Default and maximum number of price levels returned by get_depth
+pub const DEFAULT_DEPTH_LEVELS: u32 = 20;
+pub const MAX_DEPTH_LEVELS: u32 = 500;
+
+pub async fn get_depth_inner(
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    //
+    request: Request<DepthReq>,
+) -> Result<Response<DepthRes>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: DepthReq = request.into_inner();
+
+    let levels = match req.levels {
+        0 => DEFAULT_DEPTH_LEVELS,
+        levels if levels > MAX_DEPTH_LEVELS => {
+            return send_depth_error_reply(format!(
+                "At most {} levels can be requested",
+                MAX_DEPTH_LEVELS
+            ));
+        }
+        levels => levels,
+    };
+    if !(req.grouping >= 0.0 && req.grouping.is_finite()) {
+        return send_depth_error_reply("Invalid price grouping".to_string());
+    }
+
+    let order_book_m =
+        match get_order_book(order_books, perp_order_books, req.is_perp, req.market_id) {
+            Some(book) => book,
+            None => {
+                return send_depth_error_reply("No market found for given market id".to_string());
+            }
+        };
+
+    let order_book = order_book_m.lock().await;
+    let bids = order_book.bid_queue.depth(levels as usize, req.grouping);
+    let asks = order_book.ask_queue.depth(levels as usize, req.grouping);
+    drop(order_book);
+
+    let to_grpc = |level: PriceLevel| DepthLevel {
+        price: level.price,
+        qty: level.qty,
+        order_count: level.order_count,
+    };
+
+    let reply = DepthRes {
+        successful: true,
+        error_message: "".to_string(),
+        bids: bids.into_iter().map(to_grpc).collect(),
+        asks: asks.into_iter().map(to_grpc).collect(),
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub async fn get_l3_orders_inner(
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    //
+    request: Request<L3OrdersReq>,
+) -> Result<Response<L3OrdersRes>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: L3OrdersReq = request.into_inner();
+
+    let order_book_m =
+        match get_order_book(order_books, perp_order_books, req.is_perp, req.market_id) {
+            Some(book) => book,
+            None => {
+                return send_l3_orders_error_reply(
+                    "No market found for given market id".to_string(),
+                );
+            }
+        };
+
+    let order_book = order_book_m.lock().await;
+    let bids = order_book.bid_queue.visualize();
+    let asks = order_book.ask_queue.visualize();
+    drop(order_book);
+
+    let to_grpc = |(price, qty, timestamp, order_id): (f64, u64, u64, u64)| L3Order {
+        order_id,
+        price,
+        qty,
+        timestamp,
+    };
+
+    let reply = L3OrdersRes {
+        successful: true,
+        error_message: "".to_string(),
+        bids: bids.into_iter().map(to_grpc).collect(),
+        asks: asks.into_iter().map(to_grpc).collect(),
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub async fn get_market_info_inner(
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    //
+    request: Request<MarketInfoReq>,
+) -> Result<Response<MarketInfoRes>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: MarketInfoReq = request.into_inner();
+
+    let mut books: Vec<(bool, u16, &Arc<TokioMutex<OrderBook>>)> = Vec::new();
+    if req.market_id == 0 {
+        books.extend(order_books.iter().map(|(id, book)| (false, *id, book)));
+        books.extend(perp_order_books.iter().map(|(id, book)| (true, *id, book)));
+    } else {
+        let order_books = if req.is_perp {
+            perp_order_books
+        } else {
+            order_books
+        };
+
+        match order_books.get(&(req.market_id as u16)) {
+            Some(book) => books.push((req.is_perp, req.market_id as u16, book)),
+            None => {
+                return send_market_info_error_reply(
+                    "No market found for given market id".to_string(),
+                );
+            }
+        }
+    }
+    books.sort_by_key(|(is_perp, market_id, _)| (*is_perp, *market_id));
+
+    let mut markets = Vec::new();
+    for (is_perp, market_id, book) in books {
+        let order_book = book.lock().await;
+        let params = order_book.market_params();
+
+        markets.push(MarketInfo {
+            market_id: market_id as u32,
+            is_perp,
+            base_token: order_book.order_asset,
+            quote_token: order_book.price_asset,
+            tick_size: params.tick_size,
+            price_decimals: params.price_decimals as u32,
+            lot_size: params.lot_size,
+            min_notional: params.min_notional,
+            max_order_size: params.max_order_size,
+            max_position_size: params.max_position_size,
+        });
+    }
+
+    let reply = MarketInfoRes {
+        successful: true,
+        error_message: "".to_string(),
+        markets,
+    };
+
+    return Ok(Response::new(reply));
+}
+
 pub async fn get_orders_inner(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
@@ -96,7 +298,36 @@ pub async fn get_orders_inner(
 ) -> Result<Response<OrdersRes>, Status> {
     tokio::task::yield_now().await;
 
-    let req: OrdersReq = request.into_inner();
+    let mut req: OrdersReq = request.into_inner();
+
+    // ? Orders can also be queried by the client_order_id the user assigned to them
+    let mut bad_client_order_ids: Vec<u64> = Vec::new();
+    for (client_order_ids, books, order_ids) in [
+        (&req.client_order_ids, order_books, &mut req.order_ids),
+        (
+            &req.perp_client_order_ids,
+            perp_order_books,
+            &mut req.perp_order_ids,
+        ),
+    ] {
+        for client_order_id in client_order_ids.iter() {
+            let mut found = false;
+            for order_book in books.values() {
+                let order_book = order_book.lock().await;
+                if let Some(wrapper) =
+                    order_book.get_order_by_client_id(req.user_id, *client_order_id)
+                {
+                    order_ids.push(wrapper.order_id);
+                    found = true;
+                    break;
+                }
+            }
+
+            if !found {
+                bad_client_order_ids.push(*client_order_id);
+            }
+        }
+    }
 
     let tx_batch_m = tx_batch.lock().await;
     let partial_fill_tracker = Arc::clone(&tx_batch_m.partial_fill_tracker);
@@ -107,7 +338,7 @@ pub async fn get_orders_inner(
     let mut active_orders: Vec<ActiveOrder> = Vec::new();
     let mut pfr_notes: Vec<Note> = Vec::new();
     for order_id in req.order_ids {
-        let market_id = order_id as u16;
+        let market_id = market_id_from_order_id(order_id);
 
         if !order_books.contains_key(&market_id) {
             // ? order is non-existent or invalid
@@ -166,6 +397,7 @@ pub async fn get_orders_inner(
 
                 let active_order = ActiveOrder {
                     order_id: limit_order.order_id,
+                    client_order_id: wrapper.client_order_id,
                     expiration_timestamp: limit_order.expiration_timestamp,
                     base_asset,
                     quote_asset,
@@ -186,7 +418,7 @@ pub async fn get_orders_inner(
         drop(order_book);
 
         let partial_fill_tracker_m = partial_fill_tracker.lock();
-        let pfr_info = partial_fill_tracker_m.get(&(order_id % 2_u64.pow(32)));
+        let pfr_info = partial_fill_tracker_m.get(&order_id);
         if pfr_info.is_some() && pfr_info.unwrap().0.is_some() {
             pfr_notes.push(pfr_info.unwrap().0.as_ref().unwrap().clone());
         }
@@ -197,7 +429,7 @@ pub async fn get_orders_inner(
     let mut active_perp_orders: Vec<ActivePerpOrder> = Vec::new();
 
     for order_id in req.perp_order_ids {
-        let market_id = order_id as u16;
+        let market_id = market_id_from_order_id(order_id);
 
         if !perp_order_books.contains_key(&market_id) {
             // ? order is non-existent or invalid
@@ -253,6 +485,7 @@ pub async fn get_orders_inner(
 
                 let active_order = ActivePerpOrder {
                     order_id: perp_order.order_id,
+                    client_order_id: wrapper.client_order_id,
                     expiration_timestamp: perp_order.expiration_timestamp,
                     synthetic_token: perp_order.synthetic_token,
                     position_effect_type,
@@ -264,6 +497,7 @@ pub async fn get_orders_inner(
                     notes_in,
                     refund_note,
                     position_address,
+                    reduce_only: perp_order.reduce_only,
                 };
 
                 active_perp_orders.push(active_order)
@@ -288,6 +522,7 @@ pub async fn get_orders_inner(
         bad_perp_order_ids,
         perp_orders: active_perp_orders,
         pfr_notes: pfr_notes.into_iter().map(|n| GrpcNote::from(n)).collect(),
+        bad_client_order_ids,
     };
 
     return Ok(Response::new(reply));
@@ -353,6 +588,7 @@ pub async fn get_funding_info_inner(
     let tx_batch_m = tx_batch.lock().await;
     let funding_rates = tx_batch_m.funding_rates.clone();
     let funding_prices = tx_batch_m.funding_prices.clone();
+    let open_interest = tx_batch_m.open_interest.lock().clone();
     drop(tx_batch_m);
 
     let mut fundings = Vec::new();
@@ -364,6 +600,8 @@ pub async fn get_funding_info_inner(
             token: *token,
             funding_rates: rates.clone(),
             funding_prices: prices.clone(),
+            open_interest: *open_interest.get(token).unwrap_or(&0),
+            max_open_interest: max_open_interest(*token),
         };
 
         fundings.push(funding_info);
@@ -377,3 +615,465 @@ pub async fn get_funding_info_inner(
 
     return Ok(Response::new(reply));
 }
+
+pub async fn get_predicted_funding_inner(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    _: Request<FundingReq>,
+) -> Result<Response<PredictedFundingRes>, Status> {
+    tokio::task::yield_now().await;
+
+    let tx_batch_m = tx_batch.lock().await;
+    let running_funding_tick_sums = tx_batch_m.running_funding_tick_sums.clone();
+    let current_funding_count = tx_batch_m.current_funding_count;
+    let last_funding_slot = tx_batch_m.last_funding_slot;
+    let funding_config = tx_batch_m.funding_config.clone();
+    drop(tx_batch_m);
+
+    let mut predictions = Vec::new();
+    for token in SYNTHETIC_ASSETS {
+        let premium_sum = *running_funding_tick_sums.get(&token).unwrap_or(&0);
+        let premium = if current_funding_count == 0 {
+            0
+        } else {
+            premium_sum / current_funding_count as i64
+        };
+
+        let prediction = PredictedFunding {
+            token,
+            funding_rate: get_funding_rate(
+                &funding_config,
+                token,
+                premium_sum,
+                current_funding_count,
+            ),
+            premium,
+            samples: current_funding_count as u32,
+        };
+
+        predictions.push(prediction);
+    }
+
+    // ? Without any samples since the start, the next settlement is derived from the current time
+    let slot = last_funding_slot.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / funding_config.sample_interval_secs
+    });
+    let samples_per_settlement = funding_config.samples_per_settlement as u64;
+    let next_funding_timestamp = (slot / samples_per_settlement + 1)
+        * samples_per_settlement
+        * funding_config.sample_interval_secs;
+
+    let reply = PredictedFundingRes {
+        successful: true,
+        predictions,
+        next_funding_timestamp,
+        error_message: "".to_string(),
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub async fn get_position_ledger_inner(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    request: Request<PositionLedgerReq>,
+) -> Result<Response<PositionLedgerRes>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: PositionLedgerReq = request.into_inner();
+
+    let position_address = match BigUint::from_str(&req.position_address) {
+        Ok(address) => address.to_string(),
+        Err(_) => {
+            return send_position_ledger_error_reply("Invalid position address".to_string());
+        }
+    };
+
+    let limit = if req.limit == 0 || req.limit > MAX_LEDGER_ENTRIES_PER_QUERY {
+        MAX_LEDGER_ENTRIES_PER_QUERY
+    } else {
+        req.limit
+    };
+
+    let tx_batch_m = tx_batch.lock().await;
+    let main_storage = Arc::clone(&tx_batch_m.main_storage);
+    drop(tx_batch_m);
+
+    let entries = main_storage.lock().read_ledger_entries(
+        &position_address,
+        req.offset as usize,
+        limit as usize,
+    );
+
+    let reply = PositionLedgerRes {
+        successful: true,
+        entries: entries.into_iter().map(GrpcLedgerEntry::from).collect(),
+        error_message: "".to_string(),
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub async fn get_position_risk_inner(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    request: Request<PositionRiskReq>,
+) -> Result<Response<PositionRiskRes>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: PositionRiskReq = request.into_inner();
+
+    let market_state = get_risk_market_state(tx_batch, perp_order_books).await;
+
+    match get_position_risk_reply(&market_state, &req) {
+        Ok(reply) => return Ok(Response::new(reply)),
+        Err(err) => return send_position_risk_error_reply(err),
+    }
+}
+
+pub async fn get_positions_risk_batch_inner(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    request: Request<PositionsRiskBatchReq>,
+) -> Result<Response<PositionsRiskBatchRes>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: PositionsRiskBatchReq = request.into_inner();
+
+    if req.positions.is_empty() || req.positions.len() > MAX_POSITIONS_PER_RISK_QUERY {
+        return send_positions_risk_batch_error_reply(format!(
+            "A batch must query between 1 and {} positions",
+            MAX_POSITIONS_PER_RISK_QUERY
+        ));
+    }
+
+    let market_state = get_risk_market_state(tx_batch, perp_order_books).await;
+
+    // ? A position that can't be found only fails its own result
+    let results = req
+        .positions
+        .iter()
+        .map(
+            |position_req| match get_position_risk_reply(&market_state, position_req) {
+                Ok(reply) => reply,
+                Err(err) => PositionRiskRes {
+                    successful: false,
+                    position: None,
+                    risk: None,
+                    error_message: err,
+                },
+            },
+        )
+        .collect();
+
+    let reply = PositionsRiskBatchRes {
+        successful: true,
+        results,
+        error_message: "".to_string(),
+    };
+
+    return Ok(Response::new(reply));
+}
+
+/// Runs a perp order against the current book and index price without executing it.
+///
+/// The order doesn't have to be signed (the signature is ignored), all the other checks of
+/// submit_perpetual_order and the order execution are run.
+pub async fn simulate_perp_order_inner(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    request: Request<PerpOrderMessage>,
+) -> Result<Response<SimulatePerpOrderRes>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: PerpOrderMessage = request.into_inner();
+    let is_market = req.is_market;
+
+    let perp_order = match PerpOrder::try_from(req) {
+        Ok(po) => po,
+        Err(_) => {
+            return send_simulate_perp_order_error_reply(
+                "Error unpacking the perp order (verify the format is correct)".to_string(),
+            )
+        }
+    };
+    let synthetic_token = perp_order.synthetic_token;
+
+    let order_book = PERP_MARKET_IDS
+        .get(&synthetic_token.to_string())
+        .and_then(|market_id| perp_order_books.get(market_id));
+    if order_book.is_none() {
+        return send_simulate_perp_order_error_reply(
+            "Market (token pair) does not exist for this token".to_string(),
+        );
+    }
+
+    let res = order_book
+        .unwrap()
+        .lock()
+        .await
+        .simulate_perp_order(&perp_order, is_market);
+    let (book_fills, unfilled_amount) = match res {
+        Ok(res) => res,
+        Err(err) => return send_simulate_perp_order_error_reply(err),
+    };
+
+    // ? The fills are built into swaps like the matches of submitted orders (the taker pays the fee)
+    let is_long = perp_order.order_side == OrderSide::Long;
+    let swaps: Vec<PerpSwap> = book_fills
+        .into_iter()
+        .filter_map(|fill| match fill.maker_order {
+            Order::Perp(maker_order) => {
+                let quote_qty = get_quote_qty(
+                    fill.qty,
+                    fill.price,
+                    synthetic_token,
+                    COLLATERAL_TOKEN,
+                    None,
+                );
+
+                let taker = (perp_order.clone(), None, true);
+                let maker = (maker_order, None, false);
+                let (a, b) = if is_long {
+                    (taker, maker)
+                } else {
+                    (maker, taker)
+                };
+
+                Some(build_perp_swap(a, b, fill.price, quote_qty, fill.qty))
+            }
+            Order::Spot(_) => None,
+        })
+        .collect();
+
+    let tx_batch_m = tx_batch.lock().await;
+    let state_tree = Arc::clone(&tx_batch_m.state_tree);
+    let open_interest = Arc::clone(&tx_batch_m.open_interest);
+    let main_storage = Arc::clone(&tx_batch_m.main_storage);
+    let index_price = *tx_batch_m
+        .latest_index_price
+        .get(&synthetic_token)
+        .unwrap_or(&0);
+    let swap_funding_info = match swaps.first() {
+        Some(swap) => tx_batch_m.swap_funding_info(swap),
+        None => SwapFundingInfo::new(
+            &tx_batch_m.funding_rates,
+            &tx_batch_m.funding_prices,
+            synthetic_token,
+            &perp_order.position,
+            &None,
+        ),
+    };
+    drop(tx_batch_m);
+
+    if let Err(err) = existance_checks(&state_tree, &perp_order) {
+        return send_simulate_perp_order_error_reply(err);
+    }
+    if let Err(err) = open_interest_checks(&open_interest, &perp_order) {
+        return send_simulate_perp_order_error_reply(err);
+    }
+    if let Err(err) = position_size_checks(&main_storage, &perp_order) {
+        return send_simulate_perp_order_error_reply(err);
+    }
+
+    let fills: Vec<SimulatedPerpFill> = swaps
+        .into_iter()
+        .map(|swap| {
+            if is_long {
+                SimulatedPerpFill {
+                    maker_order: swap.order_b,
+                    spent_synthetic: swap.spent_synthetic,
+                    spent_collateral: swap.spent_collateral,
+                    fee_taken: swap.fee_taken_a,
+                }
+            } else {
+                SimulatedPerpFill {
+                    maker_order: swap.order_a,
+                    spent_synthetic: swap.spent_synthetic,
+                    spent_collateral: swap.spent_collateral,
+                    fee_taken: swap.fee_taken_b,
+                }
+            }
+        })
+        .collect();
+
+    let simulation = match simulate_perp_order(
+        &state_tree,
+        &perp_order,
+        &fills,
+        index_price,
+        &swap_funding_info,
+    ) {
+        Ok(simulation) => simulation,
+        Err(err) => {
+            return send_simulate_perp_order_error_reply(err.current_context().err_msg.clone())
+        }
+    };
+
+    let average_price = if simulation.spent_synthetic > 0 {
+        get_price(
+            synthetic_token,
+            simulation.spent_collateral,
+            simulation.spent_synthetic,
+        )
+    } else {
+        0
+    };
+
+    let reply = SimulatePerpOrderRes {
+        successful: true,
+        fills: fills
+            .iter()
+            .map(|fill| GrpcSimulatedFill {
+                price: get_price(synthetic_token, fill.spent_collateral, fill.spent_synthetic),
+                synthetic_amount: fill.spent_synthetic,
+                collateral_amount: fill.spent_collateral,
+                fee_taken: fill.fee_taken,
+            })
+            .collect(),
+        synthetic_filled: simulation.spent_synthetic,
+        collateral_filled: simulation.spent_collateral,
+        average_price,
+        fee_taken: simulation.fee_taken,
+        unfilled_amount,
+        position: simulation.position.map(GrpcPerpPosition::from),
+        collateral_returned: simulation.collateral_returned,
+        error_message: "".to_string(),
+    };
+
+    return Ok(Response::new(reply));
+}
+
+// * HELPERS * //
+
+/// The state the risk of positions is computed from (loaded once per query)
+struct RiskMarketState {
+    state_tree: Arc<Mutex<SuperficialTree>>,
+    main_storage: Arc<Mutex<MainStorage>>,
+    index_prices: HashMap<u32, u64>,
+    mark_prices: HashMap<u32, u64>,
+    funding_rates: HashMap<u32, Vec<i64>>,
+    funding_prices: HashMap<u32, Vec<u64>>,
+}
+
+async fn get_risk_market_state(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+) -> RiskMarketState {
+    let tx_batch_m = tx_batch.lock().await;
+    let state_tree = Arc::clone(&tx_batch_m.state_tree);
+    let main_storage = Arc::clone(&tx_batch_m.main_storage);
+    let index_prices = tx_batch_m.latest_index_price.clone();
+    let funding_rates = tx_batch_m.funding_rates.clone();
+    let funding_prices = tx_batch_m.funding_prices.clone();
+    drop(tx_batch_m);
+
+    // ? Markets without a two sided book have no mark price
+    let mut mark_prices = HashMap::new();
+    for (token, market_id) in PERP_MARKET_IDS.entries() {
+        if let Some(book) = perp_order_books.get(market_id) {
+            if let Ok(mark_price) = book.lock().await.get_market_price() {
+                mark_prices.insert(token.parse::<u32>().unwrap(), mark_price);
+            }
+        }
+    }
+
+    RiskMarketState {
+        state_tree,
+        main_storage,
+        index_prices,
+        mark_prices,
+        funding_rates,
+        funding_prices,
+    }
+}
+
+/// Looks up the position (by address or index) and computes its risk
+fn get_position_risk_reply(
+    market_state: &RiskMarketState,
+    req: &PositionRiskReq,
+) -> Result<PositionRiskRes, String> {
+    let position = find_position(market_state, req)?;
+
+    let synthetic_token = position.position_header.synthetic_token;
+    let index_price = *market_state
+        .index_prices
+        .get(&synthetic_token)
+        .unwrap_or(&0);
+    if index_price == 0 {
+        return Err("No index price for the position's market".to_string());
+    }
+    let mark_price = *market_state
+        .mark_prices
+        .get(&synthetic_token)
+        .unwrap_or(&index_price);
+
+    let risk = get_position_risk(
+        &position,
+        index_price,
+        mark_price,
+        market_state
+            .funding_rates
+            .get(&synthetic_token)
+            .map(|r| r.as_slice())
+            .unwrap_or(&[]),
+        market_state
+            .funding_prices
+            .get(&synthetic_token)
+            .map(|p| p.as_slice())
+            .unwrap_or(&[]),
+    );
+
+    return Ok(PositionRiskRes {
+        successful: true,
+        position: Some(GrpcPerpPosition::from(position)),
+        risk: Some(GrpcPositionRisk::from(risk)),
+        error_message: "".to_string(),
+    });
+}
+
+/// Reads the position from the local storage, only positions matching the state are returned
+fn find_position(
+    market_state: &RiskMarketState,
+    req: &PositionRiskReq,
+) -> Result<PerpPosition, String> {
+    let main_storage = market_state.main_storage.lock();
+    let positions = if req.position_address.is_empty() {
+        main_storage
+            .read_position(req.index)
+            .into_iter()
+            .collect::<Vec<_>>()
+    } else {
+        let position_address = BigUint::from_str(&req.position_address)
+            .map_err(|_| "Invalid position address".to_string())?;
+
+        main_storage.read_positions_at_address(&position_address.to_string())
+    };
+    drop(main_storage);
+
+    let mut positions: Vec<PerpPosition> = positions
+        .into_iter()
+        .filter(|p| verify_position_existence(p, &market_state.state_tree).is_ok())
+        .collect();
+
+    match positions.len() {
+        0 => Err("Position not found".to_string()),
+        1 => Ok(positions.pop().unwrap()),
+        _ => Err("Several positions are open at this address, query them by index".to_string()),
+    }
+}
+
+fn get_order_book<'a>(
+    order_books: &'a HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &'a HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    is_perp: bool,
+    market_id: u32,
+) -> Option<&'a Arc<TokioMutex<OrderBook>>> {
+    if is_perp {
+        perp_order_books.get(&(market_id as u16))
+    } else {
+        order_books.get(&(market_id as u16))
+    }
+}
@@ -7,6 +7,8 @@ use crate::{
     perpetual::{
         perp_order::CloseOrderFields,
         perp_position::{PerpPosition, PositionHeader, _hash_position},
+        position_ledger::{LedgerEntry, LedgerEntryType},
+        position_risk::PositionRisk,
         OrderSide, COLLATERAL_TOKEN,
     },
     transaction_batch::tx_batch_structs::OracleUpdate,
@@ -19,8 +21,9 @@ use crate::{
 
 use super::{
     engine_proto::{
-        Address, GrcpPositionHeader, GrpcNote, GrpcOnchainActionType, GrpcOracleUpdate,
-        GrpcPerpPosition, MarginChangeReq, Signature as GrpcSignature,
+        Address, GrcpPositionHeader, GrpcLedgerEntry, GrpcLedgerEntryType, GrpcNote,
+        GrpcOnchainActionType, GrpcOracleUpdate, GrpcPerpPosition, GrpcPositionRisk,
+        MarginChangeReq, SetLeverageReq, Signature as GrpcSignature,
     },
     ChangeMarginMessage,
 };
@@ -255,6 +258,48 @@ impl TryFrom<MarginChangeReq> for ChangeMarginMessage {
             position,
             signature: sig,
             user_id: req.user_id,
+            target_leverage: None,
+        })
+    }
+}
+
+impl TryFrom<SetLeverageReq> for ChangeMarginMessage {
+    type Error = Report<GrpcMessageError>;
+
+    fn try_from(req: SetLeverageReq) -> Result<Self, GrpcMessageError> {
+        let position = PerpPosition::try_from(req.position.ok_or(GrpcMessageError {})?)?;
+        let sig = Signature::try_from(req.signature.ok_or(GrpcMessageError {})?)?;
+        let close_order_fields =
+            CloseOrderFields::try_from(req.close_order_fields.ok_or(GrpcMessageError {})?)?;
+
+        // ? The refund note is computed when the request is executed, after the margin change
+        // ? is checked against the target leverage
+        let notes_in = if req.notes_in.is_empty() {
+            None
+        } else {
+            let mut notes_in: Vec<Note> = Vec::new();
+            for n in req.notes_in.into_iter() {
+                let note = Note::try_from(n)?;
+
+                if COLLATERAL_TOKEN != note.token {
+                    return Err(Report::new(GrpcMessageError {}));
+                }
+
+                notes_in.push(note);
+            }
+
+            Some(notes_in)
+        };
+
+        Ok(ChangeMarginMessage {
+            margin_change: req.margin_change,
+            notes_in,
+            refund_note: None,
+            close_order_fields: Some(close_order_fields),
+            position,
+            signature: sig,
+            user_id: req.user_id,
+            target_leverage: Some(req.leverage),
         })
     }
 }
@@ -298,3 +343,51 @@ impl From<GrpcOnchainActionType> for OnchainActionType {
         }
     }
 }
+
+// POSITION LEDGER
+impl From<LedgerEntryType> for GrpcLedgerEntryType {
+    fn from(req: LedgerEntryType) -> Self {
+        match req {
+            LedgerEntryType::Funding => GrpcLedgerEntryType::Funding,
+            LedgerEntryType::RealizedPnl => GrpcLedgerEntryType::RealizedPnl,
+            LedgerEntryType::Fee => GrpcLedgerEntryType::Fee,
+            LedgerEntryType::Liquidation => GrpcLedgerEntryType::Liquidation,
+            LedgerEntryType::MarginChange => GrpcLedgerEntryType::MarginChange,
+        }
+    }
+}
+
+impl From<LedgerEntry> for GrpcLedgerEntry {
+    fn from(req: LedgerEntry) -> Self {
+        GrpcLedgerEntry {
+            entry_type: GrpcLedgerEntryType::from(req.entry_type) as i32,
+            position_index: req.position_index,
+            synthetic_token: req.synthetic_token,
+            amount: req.amount,
+            size: req.size,
+            price: req.price,
+            funding_idx: req.funding_idx,
+            timestamp: req.timestamp,
+        }
+    }
+}
+
+// POSITION RISK
+impl From<PositionRisk> for GrpcPositionRisk {
+    fn from(req: PositionRisk) -> Self {
+        GrpcPositionRisk {
+            index_price: req.index_price,
+            mark_price: req.mark_price,
+            unrealized_pnl_index: req.unrealized_pnl_index,
+            unrealized_pnl_mark: req.unrealized_pnl_mark,
+            pending_funding: req.pending_funding,
+            equity: req.equity,
+            margin_ratio: req.margin_ratio,
+            maintenance_margin_ratio: req.maintenance_margin_ratio,
+            liquidation_price: req.liquidation_price,
+            bankruptcy_price: req.bankruptcy_price,
+            max_withdrawable_margin: req.max_withdrawable_margin,
+            is_liquidatable: req.is_liquidatable,
+        }
+    }
+}
@@ -66,7 +66,8 @@ pub struct MarginChangeResponse {
 
 #[derive(Clone)]
 pub struct FundingUpdateMessage {
-    pub impact_prices: HashMap<u32, (u64, u64)>,
+    pub impact_prices: HashMap<u32, (u64, u64)>, // (impact_bid, impact_ask)
+    pub timestamp: u64,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -78,6 +79,8 @@ pub struct ChangeMarginMessage {
     pub position: PerpPosition,
     pub signature: Signature,
     pub user_id: u64,
+    #[serde(skip_serializing)]
+    pub target_leverage: Option<u64>, // set for margin changes from a set_leverage request (not signed nor output)
 }
 
 pub struct OrderTabActionMessage {
@@ -10,11 +10,13 @@ use crate::{
         liquidations::liquidation_order::LiquidationOrder,
         perp_order::{CloseOrderFields, OpenOrderFields, PerpOrder},
         perp_position::PerpPosition,
-        OrderSide,
+        OrderSide, PositionEffectType,
     },
     transactions::{
         deposit::Deposit,
         limit_order::{LimitOrder, SpotNotesInfo},
+        note_restructure::NoteRestructure,
+        transfer::Transfer,
         withdrawal::Withdrawal,
     },
     utils::crypto_utils::{EcPoint, Signature},
@@ -27,8 +29,8 @@ use crate::{
 
 use super::engine_proto::{
     DepositMessage, GrpcCloseOrderFields, GrpcOpenOrderFields, GrpcOrderTab, GrpcTabHeader,
-    LimitOrderMessage, LiquidationOrderMessage, PerpOrderMessage, SpotNotesInfoMessage,
-    WithdrawalMessage,
+    LimitOrderMessage, LiquidationOrderMessage, NoteRestructureMessage, PerpOrderMessage,
+    SpotNotesInfoMessage, TransferMessage, WithdrawalMessage,
 };
 
 // ------ DEPOSITS -------------------------------------------
@@ -222,13 +224,88 @@ impl TryFrom<WithdrawalMessage> for Withdrawal {
     }
 }
 
+// ------ TRANSFERS -------------------------------------------
+
+impl TryFrom<TransferMessage> for Transfer {
+    type Error = Report<GrpcMessageError>;
+
+    fn try_from(req: TransferMessage) -> Result<Self, GrpcMessageError> {
+        let mut notes_in: Vec<Note> = Vec::new();
+        for n in req.notes_in.iter() {
+            let note = Note::try_from(n.clone())?;
+
+            notes_in.push(note);
+        }
+
+        let mut notes_out: Vec<Note> = Vec::new();
+        for n in req.notes_out.iter() {
+            let note = Note::try_from(n.clone())?;
+
+            notes_out.push(note);
+        }
+
+        let refund_note: Option<Note>;
+        if req.refund_note.is_some() {
+            let n = Note::try_from(req.refund_note.ok_or(GrpcMessageError {})?)?;
+            refund_note = Some(n);
+        } else {
+            refund_note = None
+        }
+
+        let transfer = Transfer {
+            transaction_type: "transfer".to_string(),
+            token: req.token,
+            recipient: EcPoint::try_from(req.recipient.ok_or(GrpcMessageError {})?)?,
+            notes_in,
+            notes_out,
+            refund_note,
+            signature: Signature::try_from(req.signature.ok_or(GrpcMessageError {})?)?,
+        };
+
+        Ok(transfer)
+    }
+}
+
+// ------ NOTE RESTRUCTURES -------------------------------------------
+
+impl TryFrom<NoteRestructureMessage> for NoteRestructure {
+    type Error = Report<GrpcMessageError>;
+
+    fn try_from(req: NoteRestructureMessage) -> Result<Self, GrpcMessageError> {
+        let mut notes_in: Vec<Note> = Vec::new();
+        for n in req.notes_in.iter() {
+            let note = Note::try_from(n.clone())?;
+
+            notes_in.push(note);
+        }
+
+        let mut notes_out: Vec<Note> = Vec::new();
+        for n in req.notes_out.iter() {
+            let note = Note::try_from(n.clone())?;
+
+            notes_out.push(note);
+        }
+
+        let restructure = NoteRestructure {
+            transaction_type: "note_restructure".to_string(),
+            token: req.token,
+            notes_in,
+            notes_out,
+            fee: req.fee,
+            signature: Signature::try_from(req.signature.ok_or(GrpcMessageError {})?)?,
+        };
+
+        Ok(restructure)
+    }
+}
+
 // ------ PERPETUAL SWAPS -------------------------------------------
 
 impl TryFrom<PerpOrderMessage> for PerpOrder {
     type Error = Report<GrpcMessageError>;
 
     fn try_from(req: PerpOrderMessage) -> Result<Self, GrpcMessageError> {
-        let result: PerpOrder;
+        let mut result: PerpOrder;
         match req.position_effect_type {
             0 => {
                 let open_order_fields =
@@ -295,6 +372,11 @@ impl TryFrom<PerpOrderMessage> for PerpOrder {
             }
         }
 
+        if req.reduce_only && result.position_effect_type == PositionEffectType::Open {
+            return Err(Report::new(GrpcMessageError {}).attach("Open orders can't be reduce only"));
+        }
+        result.reduce_only = req.reduce_only;
+
         Ok(result)
     }
 }
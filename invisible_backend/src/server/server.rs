@@ -10,9 +10,15 @@ use tokio::net::TcpListener;
 
 use invisible_backend::server::{
     engine::EngineService,
-    server_helpers::{handle_connection, init_order_books, WsConnectionsMap},
+    server_helpers::{
+        dead_mans_switch::DeadMansSwitches,
+        handle_connection, init_order_books,
+        rate_limiter::{default_quotas, RateLimiter},
+        WsConnectionsMap,
+    },
 };
 
+use parking_lot::Mutex;
 use tokio::sync::{Mutex as TokioMutex, Semaphore};
 use tonic::transport::Server;
 
@@ -63,6 +69,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let privileged_ws_connections_ = privileged_ws_connections.clone();
 
+    let dead_mans_switches: Arc<TokioMutex<DeadMansSwitches>> =
+        Arc::new(TokioMutex::new(HashMap::new()));
+
+    let tx_batch_ = transaction_batch.clone();
+    let order_books_ = order_books.clone();
+    let perp_order_books_ = perp_order_books.clone();
+    let dead_mans_switches_ = dead_mans_switches.clone();
+
     // Handle incoming websocket connections
     tokio::spawn(async move {
         loop {
@@ -73,8 +87,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             tokio::spawn(handle_connection(
                 stream,
+                tx_batch_.clone(),
+                order_books_.clone(),
+                perp_order_books_.clone(),
                 ws_conn_,
                 privileged_ws_connections_,
+                dead_mans_switches_.clone(),
             ));
         }
     });
@@ -88,6 +106,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         &perp_order_books,
         &ws_conn_mutex,
         &privileged_ws_connections,
+        &dead_mans_switches,
     )
     .await;
 
@@ -97,6 +116,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         perp_order_books,
         ws_connections,
         privileged_ws_connections,
+        dead_mans_switches,
+        rate_limiter: Arc::new(Mutex::new(RateLimiter::new(default_quotas()))),
         semaphore: Semaphore::new(25),
         is_paused: Arc::new(TokioMutex::new(false)),
     };
@@ -9,15 +9,16 @@ use tokio::sync::Mutex as TokioMutex;
 
 use crate::matching_engine::orderbook::{Failed, Success};
 use crate::matching_engine::{
-    domain::{Order, OrderSide as OBOrderSide},
+    domain::{Order, OrderSide as OBOrderSide, OrderWrapper},
     orderbook::OrderBook,
 };
+use crate::perpetual::open_interest::max_position_size;
+use crate::perpetual::perp_helpers::perp_swap_helpers::get_max_leverage;
 use crate::perpetual::perp_order::PerpOrder;
+use crate::perpetual::{get_collateral_amount, get_price, PositionEffectType, LEVERAGE_DECIMALS};
 use crate::transaction_batch::TransactionBatch;
 use crate::transactions::limit_order::LimitOrder;
 
-use crate::utils::crypto_utils::Signature;
-
 use super::WsConnectionsMap;
 
 pub async fn execute_spot_swaps_after_amend_order(
@@ -27,11 +28,10 @@ pub async fn execute_spot_swaps_after_amend_order(
     ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
     privileged_ws_connections: &Arc<TokioMutex<Vec<u64>>>,
     //
-    order_id: u64,
-    order_side: OBOrderSide,
-    signature: Signature,
-    user_id: u64,
+    amended_order: OrderWrapper,
 ) -> Result<(), String> {
+    let user_id = amended_order.user_id;
+
     let tx_batch_m = tx_batch.lock().await;
     let session = Arc::clone(&tx_batch_m.firebase_session);
     let backup_storage = Arc::clone(&tx_batch_m.backup_storage);
@@ -65,18 +65,11 @@ pub async fn execute_spot_swaps_after_amend_order(
     };
 
     if retry_messages.len() > 0 {
-        let order_book_ = order_book.lock().await;
-        let order_wrapper = order_book_.get_order(order_id);
-        drop(order_book_);
-
-        if order_wrapper.is_none() {
-            return Err("Order not found".to_string());
-        }
-
-        let wrapper = order_wrapper.unwrap();
+        // ? Retry with the amended order, since it might have been filled (or reverted for
+        // ? match only amends) in the orderbook by now
         let limit_order: LimitOrder;
-        if let Order::Spot(limit_order_) = wrapper.order {
-            limit_order = limit_order_.clone();
+        if let Order::Spot(limit_order_) = amended_order.order {
+            limit_order = limit_order_;
         } else {
             return Err("Order not found".to_string());
         }
@@ -86,10 +79,11 @@ pub async fn execute_spot_swaps_after_amend_order(
             order_book,
             &session,
             &backup_storage,
-            limit_order.clone(),
-            order_side,
-            signature,
+            limit_order,
+            amended_order.order_side,
+            amended_order.signature,
             user_id,
+            amended_order.client_order_id,
             true,
             &ws_connections,
             &privileged_ws_connections,
@@ -112,11 +106,10 @@ pub async fn execute_perp_swaps_after_amend_order(
     privileged_ws_connections: &Arc<TokioMutex<Vec<u64>>>,
     processed_res: &mut Vec<std::result::Result<Success, Failed>>,
     //
-    order_id: u64,
-    order_side: OBOrderSide,
-    signature: Signature,
-    user_id: u64,
+    amended_order: OrderWrapper,
 ) -> Result<(), String> {
+    let user_id = amended_order.user_id;
+
     let tx_batch_m = tx_batch.lock().await;
     let session = Arc::clone(&tx_batch_m.firebase_session);
     let backup_storage = Arc::clone(&tx_batch_m.backup_storage);
@@ -146,18 +139,9 @@ pub async fn execute_perp_swaps_after_amend_order(
     };
 
     if retry_messages.len() > 0 {
-        let order_book_ = perp_order_book.lock().await;
-        let order_wrapper = order_book_.get_order(order_id);
-        drop(order_book_);
-
-        if order_wrapper.is_none() {
-            return Err("Order not found".to_string());
-        }
-
-        let wrapper = order_wrapper.unwrap();
         let perp_order: PerpOrder;
-        if let Order::Perp(perp_order_) = wrapper.order {
-            perp_order = perp_order_.clone();
+        if let Order::Perp(perp_order_) = amended_order.order {
+            perp_order = perp_order_;
         } else {
             return Err("Order not found".to_string());
         }
@@ -167,10 +151,11 @@ pub async fn execute_perp_swaps_after_amend_order(
             perp_order_book,
             &session,
             &backup_storage,
-            perp_order.clone(),
-            order_side,
-            signature,
+            perp_order,
+            amended_order.order_side,
+            amended_order.signature,
             user_id,
+            amended_order.client_order_id,
             true,
             ws_connections,
             privileged_ws_connections,
@@ -185,3 +170,174 @@ pub async fn execute_perp_swaps_after_amend_order(
 
     Ok(())
 }
+
+/// Verifies that the notes, order tab or position backing an order can cover its amended amounts. \
+/// Orders modifying a position are checked at the order price, the index price check
+/// happens when their swaps are executed.
+pub fn verify_amended_order_amounts(order: &Order, side: OBOrderSide) -> Result<(), String> {
+    match order {
+        Order::Spot(ord) => {
+            let available_amount = if let Some(notes_info) = &ord.spot_note_info {
+                let sum_notes: u64 = notes_info.notes_in.iter().map(|n| n.amount).sum();
+                let refund_amount = notes_info.refund_note.as_ref().map_or(0, |n| n.amount);
+
+                sum_notes.saturating_sub(refund_amount)
+            } else if let Some(order_tab) = &ord.order_tab {
+                let tab = order_tab.lock();
+                if side == OBOrderSide::Bid {
+                    tab.quote_amount
+                } else {
+                    tab.base_amount
+                }
+            } else {
+                return Err("Order tab is not defined for this limit order".to_string());
+            };
+
+            if ord.amount_spent > available_amount {
+                return Err(format!(
+                    "Amount spent {} exceeds the available amount {}",
+                    ord.amount_spent, available_amount
+                ));
+            }
+        }
+        Order::Perp(ord) => match ord.position_effect_type {
+            PositionEffectType::Open => {
+                let initial_margin = ord.open_order_fields.as_ref().unwrap().initial_margin;
+
+                let leverage = (ord.collateral_amount as u128
+                    * 10_u128.pow(LEVERAGE_DECIMALS as u32)
+                    / initial_margin.max(1) as u128) as u64;
+                let max_leverage = get_max_leverage(ord.synthetic_token, ord.collateral_amount);
+                if max_leverage * 103 / 100 < leverage {
+                    return Err("Leverage is too high".to_string());
+                }
+            }
+            PositionEffectType::Close => {
+                let position_size = ord.position.as_ref().map_or(0, |p| p.position_size);
+                if ord.synthetic_amount > position_size {
+                    return Err(format!(
+                        "Amount {} exceeds the position size {}",
+                        ord.synthetic_amount, position_size
+                    ));
+                }
+            }
+            PositionEffectType::Modify => {
+                let position = match &ord.position {
+                    Some(position) => position,
+                    None => return Err("Position to update is undefined".to_string()),
+                };
+
+                // ? Reduce only orders can't increase or flip the position
+                if ord.reduce_only
+                    && (position.order_side == ord.order_side
+                        || ord.synthetic_amount > position.position_size)
+                {
+                    return Err("Reduce only order would increase the position".to_string());
+                }
+
+                // & The position size after the order fills, priced at the order's price
+                let new_position_size = if position.order_side == ord.order_side {
+                    position.position_size + ord.synthetic_amount
+                } else {
+                    ord.synthetic_amount.saturating_sub(position.position_size)
+                };
+                if new_position_size == 0 {
+                    return Ok(());
+                }
+
+                let max_size = max_position_size(ord.synthetic_token);
+                if new_position_size > max_size {
+                    return Err(format!(
+                        "Position size {} would exceed the market limit {}",
+                        new_position_size, max_size
+                    ));
+                }
+
+                let price = get_price(
+                    ord.synthetic_token,
+                    ord.collateral_amount,
+                    ord.synthetic_amount,
+                );
+                let notional = get_collateral_amount(ord.synthetic_token, new_position_size, price);
+
+                let leverage = (notional as u128 * 10_u128.pow(LEVERAGE_DECIMALS as u32)
+                    / position.margin.max(1) as u128) as u64;
+                let max_leverage = get_max_leverage(ord.synthetic_token, notional);
+                if max_leverage * 103 / 100 < leverage {
+                    return Err("Leverage would be too high".to_string());
+                }
+            }
+        },
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use num_bigint::BigUint;
+    use num_traits::FromPrimitive;
+
+    use crate::perpetual::{perp_position::PerpPosition, OrderSide, COLLATERAL_TOKEN};
+
+    const BTC: u32 = 3592681469;
+
+    // ? 1 BTC long at 30_000 with 10x leverage
+    fn modify_order(order_side: OrderSide, synthetic_amount: u64) -> Order {
+        let position = PerpPosition::new(
+            OrderSide::Long,
+            100_000_000,
+            BTC,
+            COLLATERAL_TOKEN,
+            3_000 * 10u64.pow(6),
+            10 * 10u64.pow(4),
+            false,
+            BigUint::from_u64(1234).unwrap(),
+            0,
+            7,
+            0,
+        );
+
+        let collateral_amount = synthetic_amount / 100 * 30_000;
+        let order = PerpOrder::new_modify_order(
+            1,
+            u64::MAX,
+            position,
+            order_side,
+            BTC,
+            synthetic_amount,
+            collateral_amount,
+            0,
+        );
+
+        return Order::Perp(order);
+    }
+
+    #[test]
+    fn amended_modify_orders_are_checked_against_the_position() {
+        // ? Growing the position to 1.1 BTC keeps it at 11x
+        let order = modify_order(OrderSide::Long, 10_000_000);
+        assert!(verify_amended_order_amounts(&order, OBOrderSide::Bid).is_ok());
+
+        // ? Growing it to 6 BTC on the same margin would take it to 60x
+        let order = modify_order(OrderSide::Long, 500_000_000);
+        assert!(verify_amended_order_amounts(&order, OBOrderSide::Bid).is_err());
+
+        // ? Flipping it to a 5 BTC short is just as far over the limit
+        let order = modify_order(OrderSide::Short, 600_000_000);
+        assert!(verify_amended_order_amounts(&order, OBOrderSide::Ask).is_err());
+
+        // ? Reducing it is always fine
+        let order = modify_order(OrderSide::Short, 50_000_000);
+        assert!(verify_amended_order_amounts(&order, OBOrderSide::Ask).is_ok());
+
+        // ? Reduce only orders can't grow the position
+        let mut order = modify_order(OrderSide::Long, 10_000_000);
+        if let Order::Perp(ord) = &mut order {
+            ord.reduce_only = true;
+        }
+        assert!(verify_amended_order_amounts(&order, OBOrderSide::Bid).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{collections::HashMap, sync::Arc};
+use tokio_tungstenite::tungstenite::Message;
+use tonic::{Request, Response, Status};
+
+use crate::matching_engine::orderbook::OrderBook;
+use crate::server::grpc::engine_proto::{DeadMansSwitchReq, DeadMansSwitchRes};
+use crate::transaction_batch::TransactionBatch;
+use crate::utils::crypto_utils::{hash_many, verify, Signature};
+use crate::utils::errors::send_dead_mans_switch_error_reply;
+use crate::utils::notes::Note;
+
+use tokio::sync::Mutex as TokioMutex;
+
+use super::engine_helpers::{cancel_user_orders, verify_signature_format};
+use super::{send_direct_message, WsConnectionsMap};
+
+/// The longest countdown a user can register (10 minutes)
+pub const MAX_DEAD_MANS_SWITCH_TIMEOUT: u64 = 600_000;
+/// How long a signed switch message is accepted for (1 minute), so it can't be replayed later
+pub const MAX_DEAD_MANS_SWITCH_MESSAGE_AGE: u64 = 60_000;
+
+/// A user's dead man's switch.
+///
+/// All the user's orders are cancelled once the deadline passes without the countdown being
+/// refreshed, or when their websocket disconnects if `cancel_on_disconnect` is set.
+pub struct DeadMansSwitch {
+    pub timeout: u64, // countdown in milliseconds (0 if only cancel_on_disconnect is set)
+    pub deadline: u64, // unix timestamp in milliseconds (0 if there is no countdown)
+    pub cancel_on_disconnect: bool,
+}
+
+pub type DeadMansSwitches = HashMap<u64, DeadMansSwitch>;
+
+/// Arms or refreshes the switch of user_id and returns the new deadline.
+///
+/// A timeout of 0 stops the countdown, and the switch is removed entirely if
+/// cancel_on_disconnect is not set either.
+pub fn set_dead_mans_switch(
+    switches: &mut DeadMansSwitches,
+    user_id: u64,
+    timeout: u64,
+    cancel_on_disconnect: bool,
+) -> std::result::Result<u64, String> {
+    if user_id == 0 {
+        return Err("Invalid user id".to_string());
+    }
+    if timeout > MAX_DEAD_MANS_SWITCH_TIMEOUT {
+        return Err(format!(
+            "Timeout must be at most {} ms",
+            MAX_DEAD_MANS_SWITCH_TIMEOUT
+        ));
+    }
+
+    if timeout == 0 && !cancel_on_disconnect {
+        switches.remove(&user_id);
+        return Ok(0);
+    }
+
+    let deadline = if timeout > 0 {
+        now_millis() + timeout
+    } else {
+        0
+    };
+
+    switches.insert(
+        user_id,
+        DeadMansSwitch {
+            timeout,
+            deadline,
+            cancel_on_disconnect,
+        },
+    );
+
+    return Ok(deadline);
+}
+
+/// The user id a stark key can set a dead man's switch for (the low 64 bits of H(stark_key)).
+///
+/// User ids are chosen by the client, so this is what ties a switch to the key that signed it.
+pub fn dead_mans_switch_user_id(stark_key: &BigUint) -> u64 {
+    let key_hash = hash_many(&vec![stark_key]);
+
+    return (key_hash & BigUint::from(u64::MAX)).to_u64().unwrap();
+}
+
+/// & hash = H(user_id, timeout_ms, cancel_on_disconnect, timestamp)
+pub fn dead_mans_switch_message_hash(
+    user_id: u64,
+    timeout: u64,
+    cancel_on_disconnect: bool,
+    timestamp: u64,
+) -> BigUint {
+    let user_id = BigUint::from(user_id);
+    let timeout = BigUint::from(timeout);
+    let cancel_on_disconnect = BigUint::from(cancel_on_disconnect as u8);
+    let timestamp = BigUint::from(timestamp);
+
+    return hash_many(&vec![&user_id, &timeout, &cancel_on_disconnect, &timestamp]);
+}
+
+/// Verifies that the switch message was signed in the last minute by the stark key of user_id
+/// (used for both the set_dead_mans_switch rpc and the websocket heartbeats).
+pub fn verify_dead_mans_switch_signature(
+    user_id: u64,
+    timeout: u64,
+    cancel_on_disconnect: bool,
+    timestamp: u64,
+    stark_key: &str,
+    signature: &Signature,
+) -> std::result::Result<(), String> {
+    let now = now_millis();
+    if timestamp > now + MAX_DEAD_MANS_SWITCH_MESSAGE_AGE
+        || timestamp + MAX_DEAD_MANS_SWITCH_MESSAGE_AGE < now
+    {
+        return Err("Message timestamp is too old or in the future".to_string());
+    }
+
+    // ? The key and signature are parsed as field elements when verifying, so they must fit in 251 bits
+    let is_felt = |felt: &str| BigUint::from_str(felt).map_or(false, |felt| felt.bits() <= 251);
+    if !is_felt(stark_key) {
+        return Err("Invalid stark key".to_string());
+    }
+    if !is_felt(&signature.r) || !is_felt(&signature.s) {
+        return Err("Signature format is invalid".to_string());
+    }
+    let stark_key = BigUint::from_str(stark_key).unwrap();
+
+    if dead_mans_switch_user_id(&stark_key) != user_id {
+        return Err("User id does not belong to the stark key".to_string());
+    }
+
+    let msg_hash = dead_mans_switch_message_hash(user_id, timeout, cancel_on_disconnect, timestamp);
+    if !verify(&stark_key, &msg_hash, signature) {
+        return Err("Invalid signature".to_string());
+    }
+
+    return Ok(());
+}
+
+/// Parses and verifies a websocket heartbeat of user_id, returning the timeout and cancel_on_disconnect.\
+/// {"type": "heartbeat", "timeout_ms": "...", "cancel_on_disconnect": bool, "timestamp": "...",
+/// "stark_key": "...", "signature": {"r": "...", "s": "..."}}
+pub fn verify_heartbeat(
+    user_id: u64,
+    heartbeat: &Value,
+) -> std::result::Result<(u64, bool), String> {
+    let timeout = u64::from_str_radix(heartbeat["timeout_ms"].as_str().unwrap_or("0"), 10)
+        .map_err(|_| "Invalid timeout".to_string())?;
+    let cancel_on_disconnect = heartbeat["cancel_on_disconnect"].as_bool().unwrap_or(false);
+    let timestamp = u64::from_str_radix(heartbeat["timestamp"].as_str().unwrap_or("0"), 10)
+        .map_err(|_| "Invalid timestamp".to_string())?;
+
+    let stark_key = heartbeat["stark_key"].as_str().unwrap_or("0");
+    let signature = Signature {
+        r: heartbeat["signature"]["r"]
+            .as_str()
+            .unwrap_or("0")
+            .to_string(),
+        s: heartbeat["signature"]["s"]
+            .as_str()
+            .unwrap_or("0")
+            .to_string(),
+    };
+
+    verify_dead_mans_switch_signature(
+        user_id,
+        timeout,
+        cancel_on_disconnect,
+        timestamp,
+        stark_key,
+        &signature,
+    )?;
+
+    return Ok((timeout, cancel_on_disconnect));
+}
+
+pub async fn set_dead_mans_switch_inner(
+    dead_mans_switches: &Arc<TokioMutex<DeadMansSwitches>>,
+    request: Request<DeadMansSwitchReq>,
+) -> std::result::Result<Response<DeadMansSwitchRes>, Status> {
+    tokio::task::yield_now().await;
+
+    let req: DeadMansSwitchReq = request.into_inner();
+
+    let signature = match verify_signature_format(&req.signature) {
+        Ok(sig) => sig,
+        Err(err_msg) => return send_dead_mans_switch_error_reply(err_msg),
+    };
+    if let Err(err_msg) = verify_dead_mans_switch_signature(
+        req.user_id,
+        req.timeout_ms,
+        req.cancel_on_disconnect,
+        req.timestamp,
+        &req.stark_key,
+        &signature,
+    ) {
+        return send_dead_mans_switch_error_reply(err_msg);
+    }
+
+    let mut switches = dead_mans_switches.lock().await;
+    let res = set_dead_mans_switch(
+        &mut switches,
+        req.user_id,
+        req.timeout_ms,
+        req.cancel_on_disconnect,
+    );
+    drop(switches);
+
+    match res {
+        Ok(deadline) => {
+            let reply = DeadMansSwitchRes {
+                successful: true,
+                error_message: "".to_string(),
+                deadline,
+            };
+
+            return Ok(Response::new(reply));
+        }
+        Err(err_msg) => return send_dead_mans_switch_error_reply(err_msg),
+    }
+}
+
+/// Cancels the orders of every user whose countdown has lapsed and removes their switches.
+///
+/// Returns the ids of the users whose switches were triggered.
+pub async fn cancel_expired_switches(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
+    dead_mans_switches: &Arc<TokioMutex<DeadMansSwitches>>,
+) -> Vec<u64> {
+    let now = now_millis();
+
+    let mut switches = dead_mans_switches.lock().await;
+    let expired_users: Vec<u64> = switches
+        .iter()
+        .filter(|(_, switch)| switch.deadline > 0 && switch.deadline <= now)
+        .map(|(user_id, _)| *user_id)
+        .collect();
+    for user_id in expired_users.iter() {
+        switches.remove(user_id);
+    }
+    drop(switches);
+
+    for user_id in expired_users.iter() {
+        trigger_dead_mans_switch(
+            tx_batch,
+            order_books,
+            perp_order_books,
+            ws_connections,
+            *user_id,
+        )
+        .await;
+    }
+
+    return expired_users;
+}
+
+/// Called when the websocket of user_id closes, cancels all their orders if they asked for it.
+pub async fn cancel_on_disconnect(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
+    dead_mans_switches: &Arc<TokioMutex<DeadMansSwitches>>,
+    user_id: u64,
+) {
+    let mut switches = dead_mans_switches.lock().await;
+    let triggered = switches
+        .get(&user_id)
+        .map_or(false, |switch| switch.cancel_on_disconnect);
+    if triggered {
+        switches.remove(&user_id);
+    }
+    drop(switches);
+
+    if triggered {
+        trigger_dead_mans_switch(
+            tx_batch,
+            order_books,
+            perp_order_books,
+            ws_connections,
+            user_id,
+        )
+        .await;
+    }
+}
+
+// * HELPERS * //
+
+/// Cancels the orders of user_id in every spot and perp book and lets them know over the websocket
+async fn trigger_dead_mans_switch(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
+    user_id: u64,
+) {
+    let mut books: Vec<(bool, &Arc<TokioMutex<OrderBook>>)> = Vec::new();
+    books.extend(order_books.values().map(|book| (false, book)));
+    books.extend(perp_order_books.values().map(|book| (true, book)));
+
+    let (cancelled_order_ids, pfr_notes) = cancel_user_orders(tx_batch, books, user_id).await;
+
+    let pfr_notes: Vec<Note> = pfr_notes
+        .into_iter()
+        .filter_map(|note| Note::try_from(note).ok())
+        .collect();
+
+    let msg = json!({
+        "message_id": "DEAD_MANS_SWITCH",
+        "cancelled_order_ids": cancelled_order_ids,
+        "pfr_notes": pfr_notes,
+    });
+    let msg = Message::Text(msg.to_string());
+
+    // ? The user may already be gone if this was triggered by a disconnect
+    if let Err(_) = send_direct_message(ws_connections, user_id, msg).await {
+        println!("Error sending dead man's switch message")
+    };
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
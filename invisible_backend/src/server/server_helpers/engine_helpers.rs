@@ -9,21 +9,29 @@ use tokio_tungstenite::tungstenite::Message;
 use tonic::{Response, Status};
 
 use crate::{
-    matching_engine::orderbook::{Failed, OrderBook, Success},
+    matching_engine::{
+        order_queues::ReduceOnlyUpdate,
+        orderbook::{Failed, OrderBook, Success},
+        orders::limit_order_cancel_request,
+    },
     order_tab::OrderTab,
-    perpetual::{perp_position::PerpPosition, OrderSide},
+    perpetual::{
+        perp_order::CloseOrderFields, perp_position::PerpPosition, OrderSide, COLLATERAL_TOKEN,
+    },
     server::grpc::{
         engine_proto::{
             CancelOrderResponse, DepositResponse, GrpcNote, MarginChangeRes,
-            Signature as GrpcSignature, SplitNotesRes, SuccessResponse,
+            NoteRestructureResponse, Signature as GrpcSignature, SuccessResponse, TransferResponse,
         },
         ChangeMarginMessage,
     },
+    transaction_batch::TransactionBatch,
     transactions::swap::SwapResponse,
     trees::superficial_tree::SuperficialTree,
     utils::{
         errors::{
-            send_cancel_order_error_reply, send_deposit_error_reply, send_split_notes_error_reply,
+            send_cancel_order_error_reply, send_deposit_error_reply,
+            send_note_restructure_error_reply, send_transfer_error_reply,
             send_withdrawal_error_reply, TransactionExecutionError,
         },
         storage::local_storage::MainStorage,
@@ -35,7 +43,7 @@ use crate::utils::crypto_utils::{hash_many, verify, EcPoint, Signature};
 
 use crate::utils::notes::Note;
 
-use super::{send_to_relay_server, WsConnectionsMap, PERP_MARKET_IDS};
+use super::{send_direct_message, send_to_relay_server, WsConnectionsMap, PERP_MARKET_IDS};
 
 pub fn verify_signature_format(sig: &Option<GrpcSignature>) -> Result<Signature, String> {
     // ? Verify the signature is defined and has a valid format
@@ -149,26 +157,38 @@ pub fn verify_margin_change_signature(margin_change: &ChangeMarginMessage) -> Re
 }
 
 fn hash_margin_change_message(margin_change: &ChangeMarginMessage) -> BigUint {
-    //
+    return hash_margin_change(
+        margin_change.margin_change,
+        margin_change.notes_in.as_ref(),
+        margin_change.refund_note.as_ref(),
+        margin_change.close_order_fields.as_ref(),
+        &margin_change.position,
+    );
+}
 
-    if margin_change.margin_change >= 0 {
-        let mut hash_inputs: Vec<&BigUint> = margin_change
-            .notes_in
-            .as_ref()
+fn hash_margin_change(
+    margin_change: i64,
+    notes_in: Option<&Vec<Note>>,
+    refund_note: Option<&Note>,
+    close_order_fields: Option<&CloseOrderFields>,
+    position: &PerpPosition,
+) -> BigUint {
+    if margin_change >= 0 {
+        let mut hash_inputs: Vec<&BigUint> = notes_in
             .unwrap()
             .iter()
             .map(|note| &note.hash)
             .collect::<Vec<&BigUint>>();
 
         let z = BigUint::zero();
-        let refund_hash = if margin_change.refund_note.is_some() {
-            &margin_change.refund_note.as_ref().unwrap().hash
+        let refund_hash = if refund_note.is_some() {
+            &refund_note.unwrap().hash
         } else {
             &z
         };
         hash_inputs.push(refund_hash);
 
-        hash_inputs.push(&margin_change.position.hash);
+        hash_inputs.push(&position.hash);
 
         let hash = hash_many(&hash_inputs);
 
@@ -181,14 +201,13 @@ fn hash_margin_change_message(margin_change: &ChangeMarginMessage) -> BigUint {
         )
         .unwrap();
 
-        let margin_change_amount =
-            p - BigUint::from_u64(margin_change.margin_change.abs() as u64).unwrap();
+        let margin_change_amount = p - BigUint::from_u64(margin_change.abs() as u64).unwrap();
         hash_inputs.push(&margin_change_amount);
 
-        let fields_hash = &margin_change.close_order_fields.as_ref().unwrap().hash();
+        let fields_hash = &close_order_fields.unwrap().hash();
         hash_inputs.push(fields_hash);
 
-        hash_inputs.push(&margin_change.position.hash);
+        hash_inputs.push(&position.hash);
 
         let hash = hash_many(&hash_inputs);
 
@@ -196,6 +215,46 @@ fn hash_margin_change_message(margin_change: &ChangeMarginMessage) -> BigUint {
     }
 }
 
+/// The hash signed for a set leverage request. It's the hash of the margin change message of the
+/// margin_change returned by the dry run, so the executed margin change verifies like any other.
+pub fn hash_set_leverage_message(
+    margin_change: i64,
+    notes_in: &Vec<Note>,
+    close_order_fields: &CloseOrderFields,
+    position: &PerpPosition,
+) -> BigUint {
+    let refund_note = set_leverage_refund_note(margin_change, notes_in, close_order_fields);
+
+    return hash_margin_change(
+        margin_change,
+        Some(notes_in),
+        refund_note.as_ref(),
+        Some(close_order_fields),
+        position,
+    );
+}
+
+/// What is left of the notes_in after adding margin_change to the position, refunded to the
+/// close_order_fields address at the index of the first note
+pub fn set_leverage_refund_note(
+    margin_change: i64,
+    notes_in: &Vec<Note>,
+    close_order_fields: &CloseOrderFields,
+) -> Option<Note> {
+    let amount_in = notes_in.iter().map(|n| n.amount).sum::<u64>();
+    if notes_in.is_empty() || margin_change < 0 || amount_in <= margin_change as u64 {
+        return None;
+    }
+
+    return Some(Note::new(
+        notes_in[0].index,
+        close_order_fields.dest_received_address.clone(),
+        COLLATERAL_TOKEN,
+        amount_in - margin_change as u64,
+        close_order_fields.dest_received_blinding.clone(),
+    ));
+}
+
 pub fn store_output_json(
     swap_output_json_: &Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
     main_storage_: &Arc<Mutex<MainStorage>>,
@@ -218,25 +277,39 @@ pub fn store_output_json(
 // * ===========================================================================================================================0
 // * HANDLE GRPC_TX RESPONSE
 
-pub async fn handle_split_notes_repsonse(
-    zero_idxs: Result<Vec<u64>, String>,
+pub async fn handle_note_restructure_repsonse(
+    restructure_response: Result<
+        (Option<SwapResponse>, Option<Vec<u64>>),
+        Report<TransactionExecutionError>,
+    >,
     swap_output_json: &Arc<Mutex<Vec<Map<String, Value>>>>,
     main_storage: &Arc<Mutex<MainStorage>>,
-) -> Result<Response<SplitNotesRes>, Status> {
-    match zero_idxs {
-        Ok(zero_idxs) => {
+) -> Result<Response<NoteRestructureResponse>, Status> {
+    match restructure_response {
+        Ok(response) => {
             store_output_json(swap_output_json, main_storage);
 
-            let reply = SplitNotesRes {
+            let reply = NoteRestructureResponse {
                 successful: true,
                 error_message: "".to_string(),
-                zero_idxs,
+                zero_idxs: response.1.unwrap(),
             };
 
             return Ok(Response::new(reply));
         }
-        Err(e) => {
-            return send_split_notes_error_reply(e.to_string());
+        Err(err) => {
+            println!("\n{:?}", err);
+
+            let error_message_response: String;
+            if let TransactionExecutionError::NoteRestructure(restructure_execution_error) =
+                err.current_context()
+            {
+                error_message_response = restructure_execution_error.err_msg.clone();
+            } else {
+                error_message_response = err.current_context().to_string();
+            }
+
+            return send_note_restructure_error_reply(error_message_response);
         }
     }
 }
@@ -331,6 +404,44 @@ pub async fn handle_withdrawal_repsonse(
     }
 }
 
+// & TRANSFERS ——————————————————————————————————————————————————————————-
+pub async fn handle_transfer_repsonse(
+    transfer_response: Result<
+        (Option<SwapResponse>, Option<Vec<u64>>),
+        Report<TransactionExecutionError>,
+    >,
+    swap_output_json: &Arc<Mutex<Vec<Map<String, Value>>>>,
+    main_storage: &Arc<Mutex<MainStorage>>,
+) -> Result<Response<TransferResponse>, Status> {
+    match transfer_response {
+        Ok(response) => {
+            store_output_json(&swap_output_json, &main_storage);
+
+            let reply = TransferResponse {
+                successful: true,
+                error_message: "".to_string(),
+                zero_idxs: response.1.unwrap(),
+            };
+
+            return Ok(Response::new(reply));
+        }
+        Err(err) => {
+            println!("\n{:?}", err);
+
+            let error_message_response: String;
+            if let TransactionExecutionError::Transfer(transfer_execution_error) =
+                err.current_context()
+            {
+                error_message_response = transfer_execution_error.err_msg.clone();
+            } else {
+                error_message_response = err.current_context().to_string();
+            }
+
+            return send_transfer_error_reply(error_message_response);
+        }
+    }
+}
+
 // & DEPOSITS  ——————————————————————————————————————————————————————————-
 pub async fn handle_deposit_repsonse(
     deposit_response: Result<
@@ -427,3 +538,166 @@ pub fn handle_cancel_order_repsonse(
         }
     }
 }
+
+/// Cancels all the open orders of user_id in the given (is_perp, order book) pairs and returns
+/// the ids of the cancelled orders with the partial fill refund notes they left behind.
+pub async fn cancel_user_orders(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    order_books: Vec<(bool, &Arc<TokioMutex<OrderBook>>)>,
+    user_id: u64,
+) -> (Vec<u64>, Vec<GrpcNote>) {
+    let tx_batch_m = tx_batch.lock().await;
+    let partial_fill_tracker = Arc::clone(&tx_batch_m.partial_fill_tracker);
+    let perpetual_partial_fill_tracker = Arc::clone(&tx_batch_m.perpetual_partial_fill_tracker);
+    drop(tx_batch_m);
+
+    let mut cancelled_order_ids = Vec::new();
+    let mut pfr_notes = Vec::new();
+    for (is_perp, order_book_m) in order_books {
+        let mut order_book = order_book_m.lock().await;
+
+        for (order_id, order_side) in order_book.get_user_orders(user_id) {
+            let cancel_request = limit_order_cancel_request(order_id, order_side, user_id);
+            let res = order_book.process_order(cancel_request);
+
+            let cancel_res = handle_cancel_order_repsonse(
+                &res[0],
+                is_perp,
+                order_id,
+                &partial_fill_tracker,
+                &perpetual_partial_fill_tracker,
+            )
+            .unwrap()
+            .into_inner();
+
+            if cancel_res.successful {
+                cancelled_order_ids.push(order_id);
+                if let Some(pfr_note) = cancel_res.pfr_note {
+                    pfr_notes.push(pfr_note);
+                }
+            }
+        }
+
+        drop(order_book);
+    }
+
+    return (cancelled_order_ids, pfr_notes);
+}
+
+/// Lets the users whose market maker protection was triggered in order_book know which of their
+/// orders were pulled, along with the partial fill refund notes those orders left behind.
+pub async fn report_mm_protection_triggers(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    order_book: &Arc<TokioMutex<OrderBook>>,
+    is_perp: bool,
+    ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
+) {
+    let mut order_book_m = order_book.lock().await;
+    let market_id = order_book_m.market_id;
+    let triggers = order_book_m.mm_protection.take_triggers();
+    drop(order_book_m);
+
+    if triggers.is_empty() {
+        return;
+    }
+
+    let tx_batch_m = tx_batch.lock().await;
+    let partial_fill_tracker = Arc::clone(&tx_batch_m.partial_fill_tracker);
+    let perpetual_partial_fill_tracker = Arc::clone(&tx_batch_m.perpetual_partial_fill_tracker);
+    drop(tx_batch_m);
+
+    for trigger in triggers {
+        let mut pfr_notes: Vec<Note> = Vec::new();
+        for order_id in trigger.cancelled_order_ids.iter() {
+            let pfr_note = if is_perp {
+                let pfr_info = perpetual_partial_fill_tracker.lock().remove(order_id);
+                pfr_info.and_then(|info| info.0)
+            } else {
+                let pfr_info = partial_fill_tracker.lock().remove(order_id);
+                pfr_info.and_then(|info| info.0)
+            };
+
+            if let Some(pfr_note) = pfr_note {
+                pfr_notes.push(pfr_note);
+            }
+        }
+
+        let msg = json!({
+            "message_id": "MM_PROTECTION_TRIGGERED",
+            "market_id": market_id,
+            "is_perp": is_perp,
+            "cancelled_order_ids": trigger.cancelled_order_ids,
+            "pfr_notes": pfr_notes,
+        });
+        let msg = Message::Text(msg.to_string());
+
+        if let Err(_) = send_direct_message(ws_connections, trigger.user_id, msg).await {
+            println!("Error sending market maker protection message")
+        };
+    }
+}
+
+/// Lets the owners of the reduce only orders that were resized or cancelled in perp_order_book
+/// (because their positions decreased) know about the new sizes and the cancelled orders.
+pub async fn report_reduce_only_updates(
+    tx_batch: &Arc<TokioMutex<TransactionBatch>>,
+    perp_order_book: &Arc<TokioMutex<OrderBook>>,
+    ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
+) {
+    let mut order_book_m = perp_order_book.lock().await;
+    let market_id = order_book_m.market_id;
+    let updates = order_book_m.take_reduce_only_updates();
+    drop(order_book_m);
+
+    if updates.is_empty() {
+        return;
+    }
+
+    let tx_batch_m = tx_batch.lock().await;
+    let perpetual_partial_fill_tracker = Arc::clone(&tx_batch_m.perpetual_partial_fill_tracker);
+    drop(tx_batch_m);
+
+    let mut updates_per_user: HashMap<u64, Vec<ReduceOnlyUpdate>> = HashMap::new();
+    for update in updates {
+        updates_per_user
+            .entry(update.user_id)
+            .or_default()
+            .push(update);
+    }
+
+    for (user_id, updates) in updates_per_user {
+        let mut resized_orders: Vec<Value> = Vec::new();
+        let mut cancelled_order_ids: Vec<u64> = Vec::new();
+        let mut pfr_notes: Vec<Note> = Vec::new();
+        for update in updates {
+            if update.qty_left > 0 {
+                resized_orders.push(json!({
+                    "order_id": update.order_id,
+                    "qty_left": update.qty_left,
+                }));
+                continue;
+            }
+
+            cancelled_order_ids.push(update.order_id);
+            let pfr_info = perpetual_partial_fill_tracker
+                .lock()
+                .remove(&update.order_id);
+            if let Some(pfr_note) = pfr_info.and_then(|info| info.0) {
+                pfr_notes.push(pfr_note);
+            }
+        }
+
+        let msg = json!({
+            "message_id": "REDUCE_ONLY_ORDERS_UPDATED",
+            "market_id": market_id,
+            "resized_orders": resized_orders,
+            "cancelled_order_ids": cancelled_order_ids,
+            "pfr_notes": pfr_notes,
+        });
+        let msg = Message::Text(msg.to_string());
+
+        if let Err(_) = send_direct_message(ws_connections, user_id, msg).await {
+            println!("Error sending reduce only update message")
+        };
+    }
+}
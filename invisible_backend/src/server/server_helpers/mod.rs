@@ -16,6 +16,7 @@ use crate::matching_engine::get_quote_qty;
 use crate::perpetual::perp_order::PerpOrder;
 use crate::perpetual::perp_swap::PerpSwap;
 use crate::perpetual::{COLLATERAL_TOKEN, COLLATERAL_TOKEN_DECIMALS, DECIMALS_PER_ASSET};
+use crate::transaction_batch::TransactionBatch;
 use crate::utils::crypto_utils::Signature;
 use crate::{
     matching_engine::{
@@ -28,6 +29,10 @@ use crate::{
 
 use tokio_tungstenite::tungstenite::{Message, Result as WsResult};
 
+use self::dead_mans_switch::{
+    cancel_on_disconnect, set_dead_mans_switch, verify_heartbeat, DeadMansSwitches,
+};
+
 const BTC: u32 = 3592681469;
 const ETH: u32 = 453755560;
 const USDC: u32 = 2413654107;
@@ -44,10 +49,15 @@ pub static PERP_MARKET_IDS: phf::Map<&'static str, u16> = phf_map! {
     "277158171" => 23, // SOL
 };
 
+/// Fee rate taken from the taker of every swap
+const TAKER_FEE_RATE: f64 = 0.0005;
+
 pub mod amend_order_execution;
+pub mod dead_mans_switch;
 pub mod engine_helpers;
 pub mod periodic_updates;
 pub mod perp_swap_execution;
+pub mod rate_limiter;
 pub mod swap_execution;
 
 pub fn init_order_books() -> (
@@ -295,12 +305,12 @@ pub fn proccess_spot_matching_result(
             );
 
             let fee_taken_a = if take_fee_a {
-                (spent_amount_b as f64 * 0.0005) as u64
+                get_taker_fee(spent_amount_b)
             } else {
                 0
             };
             let fee_taken_b = if take_fee_b {
-                (spent_amount_a as f64 * 0.0005) as u64
+                get_taker_fee(spent_amount_a)
             } else {
                 0
             };
@@ -481,44 +491,12 @@ pub fn proccess_perp_matching_result(
             let (order_a, signature_a, spent_collateral, user_id_a, take_fee_a) = a;
             let (order_b, signature_b, spent_synthetic, user_id_b, take_fee_b) = b;
 
-            let synthetic_decimals: u8 = DECIMALS_PER_ASSET[&order_a.synthetic_token.to_string()];
-
-            let synthetic_: f64 = (spent_collateral as f64
-                / price as f64
-                / 10_f64.pow(COLLATERAL_TOKEN_DECIMALS as i32))
-            .ceil();
-            let spent_synthetic = min(
-                spent_synthetic,
-                (synthetic_ * 10_f64.pow(synthetic_decimals)) as u64,
-            );
-
-            let collateral_ =
-                spent_synthetic as f64 * price as f64 / 10_f64.pow(synthetic_decimals);
-            let spent_collateral = min(
-                spent_collateral,
-                (collateral_ * 10_f64.pow(COLLATERAL_TOKEN_DECIMALS as i32)) as u64,
-            );
-
-            let fee_taken_a = if take_fee_a {
-                (spent_collateral as f64 * 0.0005) as u64
-            } else {
-                0
-            };
-            let fee_taken_b = if take_fee_b {
-                (spent_collateral as f64 * 0.0005) as u64
-            } else {
-                0
-            };
-
-            let swap = PerpSwap::new(
-                order_a,
-                order_b,
-                Some(signature_a),
-                Some(signature_b),
+            let swap = build_perp_swap(
+                (order_a, Some(signature_a), take_fee_a),
+                (order_b, Some(signature_b), take_fee_b),
+                price,
                 spent_collateral,
                 spent_synthetic,
-                fee_taken_a,
-                fee_taken_b,
             );
 
             swaps.push((swap, user_id_a, user_id_b));
@@ -531,6 +509,81 @@ pub fn proccess_perp_matching_result(
     }
 }
 
+/// Builds the swap of a perp fill between the long order_a and the short order_b.
+///
+/// The amounts are capped by each other at the fill price and the taker pays the fee on the
+/// spent collateral. Simulated orders go through here as well, so they are charged the same.
+pub fn build_perp_swap(
+    (order_a, signature_a, take_fee_a): (PerpOrder, Option<Signature>, bool),
+    (order_b, signature_b, take_fee_b): (PerpOrder, Option<Signature>, bool),
+    price: f64,
+    spent_collateral: u64,
+    spent_synthetic: u64,
+) -> PerpSwap {
+    let (spent_collateral, spent_synthetic) = get_perp_swap_amounts(
+        order_a.synthetic_token,
+        price,
+        spent_collateral,
+        spent_synthetic,
+    );
+
+    let fee_taken_a = if take_fee_a {
+        get_taker_fee(spent_collateral)
+    } else {
+        0
+    };
+    let fee_taken_b = if take_fee_b {
+        get_taker_fee(spent_collateral)
+    } else {
+        0
+    };
+
+    return PerpSwap::new(
+        order_a,
+        order_b,
+        signature_a,
+        signature_b,
+        spent_collateral,
+        spent_synthetic,
+        fee_taken_a,
+        fee_taken_b,
+    );
+}
+
+/// Caps the collateral and synthetic amounts of a perp fill by each other at the fill price
+///
+/// ### Returns:
+/// * (spent_collateral, spent_synthetic)
+pub fn get_perp_swap_amounts(
+    synthetic_token: u32,
+    price: f64,
+    spent_collateral: u64,
+    spent_synthetic: u64,
+) -> (u64, u64) {
+    let synthetic_decimals: u8 = DECIMALS_PER_ASSET[&synthetic_token.to_string()];
+
+    let synthetic_: f64 =
+        (spent_collateral as f64 / price as f64 / 10_f64.pow(COLLATERAL_TOKEN_DECIMALS as i32))
+            .ceil();
+    let spent_synthetic = min(
+        spent_synthetic,
+        (synthetic_ * 10_f64.pow(synthetic_decimals)) as u64,
+    );
+
+    let collateral_ = spent_synthetic as f64 * price as f64 / 10_f64.pow(synthetic_decimals);
+    let spent_collateral = min(
+        spent_collateral,
+        (collateral_ * 10_f64.pow(COLLATERAL_TOKEN_DECIMALS as i32)) as u64,
+    );
+
+    return (spent_collateral, spent_synthetic);
+}
+
+/// The fee taken from the taker of a swap (makers pay no fees)
+pub fn get_taker_fee(amount_received: u64) -> u64 {
+    return (amount_received as f64 * TAKER_FEE_RATE) as u64;
+}
+
 fn handle_error(e: &Failed) -> Report<MatchingEngineError> {
     match e {
         Failed::ValidationFailed(e) => {
@@ -539,6 +592,9 @@ fn handle_error(e: &Failed) -> Report<MatchingEngineError> {
         Failed::DuplicateOrderID(e) => {
             return send_matching_error(format!("DuplicateOrderID: {:#?}", e))
         }
+        Failed::DuplicateClientOrderID(e) => {
+            return send_matching_error(format!("DuplicateClientOrderID: {:#?}", e))
+        }
         Failed::NoMatch(e) => return send_matching_error(format!("NoMatch: {:#?}", e)),
         Failed::OrderNotFound(e) => return send_matching_error(format!("OrderNotFound: {:#?}", e)),
         Failed::TooMuchSlippage(e) => {
@@ -556,8 +612,12 @@ const CONFIG_CODE: u64 = 1234567890;
 
 pub async fn handle_connection(
     raw_stream: TcpStream,
+    tx_batch: Arc<TokioMutex<TransactionBatch>>,
+    order_books: HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+    perp_order_books: HashMap<u16, Arc<TokioMutex<OrderBook>>>,
     ws_connections: Arc<TokioMutex<WsConnectionsMap>>,
     privileged_ws_connections: Arc<TokioMutex<Vec<u64>>>,
+    dead_mans_switches: Arc<TokioMutex<DeadMansSwitches>>,
 ) -> WsResult<()> {
     let ws_stream = tokio_tungstenite::accept_async(raw_stream).await?;
 
@@ -607,6 +667,32 @@ pub async fn handle_connection(
     loop {
         let msg = ws_receiver.next().await;
         match msg {
+            Some(Ok(Message::Text(m))) => {
+                // ? A signed heartbeat (see verify_heartbeat) arms or refreshes the user's dead man's switch
+                let json: std::result::Result<Value, _> = from_str(&m);
+
+                if let Ok(json) = json {
+                    if user_id == 0 || json["type"].as_str() != Some("heartbeat") {
+                        continue;
+                    }
+
+                    let (timeout, cancel_on_disconnect_) = match verify_heartbeat(user_id, &json) {
+                        Ok(heartbeat) => heartbeat,
+                        Err(e) => {
+                            println!("Invalid heartbeat from user {}: {}", user_id, e);
+                            continue;
+                        }
+                    };
+
+                    let mut switches = dead_mans_switches.lock().await;
+                    if let Err(e) =
+                        set_dead_mans_switch(&mut switches, user_id, timeout, cancel_on_disconnect_)
+                    {
+                        println!("Invalid heartbeat from user {}: {}", user_id, e);
+                    }
+                    drop(switches);
+                }
+            }
             Some(_msg) => {
                 // let msg: Message = msg?;
             }
@@ -618,6 +704,18 @@ pub async fn handle_connection(
     ws_connections__.remove(&user_id);
     drop(ws_connections__);
 
+    if user_id > 0 {
+        cancel_on_disconnect(
+            &tx_batch,
+            &order_books,
+            &perp_order_books,
+            &ws_connections,
+            &dead_mans_switches,
+            user_id,
+        )
+        .await;
+    }
+
     if config_code == CONFIG_CODE {
         let mut privileged_ws_connections__ = privileged_ws_connections.lock().await;
         let index = privileged_ws_connections__
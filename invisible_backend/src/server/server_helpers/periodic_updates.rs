@@ -1,6 +1,6 @@
 use serde_json::json;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, sync::Arc};
 use tokio_tungstenite::tungstenite::Message;
 
@@ -14,14 +14,42 @@ use crate::utils::storage::firestore::{create_session, retry_failed_updates};
 use tokio::sync::Mutex as TokioMutex;
 use tokio::time;
 
+use super::dead_mans_switch::{cancel_expired_switches, DeadMansSwitches};
 use super::WsConnectionsMap;
 
+/// The (impact_bid, impact_ask) prices of the perp markets, in the order the funding
+/// samples expect them (markets without an impact price on both sides are skipped)
+pub async fn get_impact_prices(
+    perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
+) -> HashMap<u32, (u64, u64)> {
+    let mut impact_prices: HashMap<u32, (u64, u64)> = HashMap::new();
+    for (_, b) in perp_order_books.iter() {
+        let book = b.lock().await;
+
+        let impact_notional: u64 = *IMPACT_NOTIONAL_PER_ASSET
+            .get(book.order_asset.to_string().as_str())
+            .unwrap();
+
+        let res = book.get_impact_prices(impact_notional);
+        if let Err(_e) = res {
+            continue;
+        }
+
+        let (impact_bid_price, impact_ask_price) = res.unwrap();
+
+        impact_prices.insert(book.order_asset, (impact_bid_price, impact_ask_price));
+    }
+
+    return impact_prices;
+}
+
 pub async fn start_periodic_updates(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
     perp_order_books: &HashMap<u16, Arc<TokioMutex<OrderBook>>>,
     ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
     privileged_ws_connections: &Arc<TokioMutex<Vec<u64>>>,
+    dead_mans_switches: &Arc<TokioMutex<DeadMansSwitches>>,
 ) {
     let perp_order_books_ = perp_order_books.clone();
 
@@ -30,11 +58,14 @@ pub async fn start_periodic_updates(
     let backup_storage = Arc::clone(&tx_batch_m.backup_storage);
     let state_tree = Arc::clone(&tx_batch_m.state_tree);
     let storage_m = Arc::clone(&tx_batch_m.main_storage);
+    let funding_sample_interval = tx_batch_m.funding_config.sample_interval_secs;
     drop(tx_batch_m);
 
-    // * UPDATE FUNDING RATES EVERY 60 SECONDS
+    // * SAMPLE FUNDING PREMIUMS EVERY SAMPLE INTERVAL OF THE FUNDING CONFIG
     let tx_batch_c = Arc::clone(&tx_batch);
-    let mut interval = time::interval(time::Duration::from_secs(60));
+    let mut interval = time::interval(time::Duration::from_secs(funding_sample_interval));
+    // ? Missed ticks are dropped instead of fired in a burst, the slots are derived from the timestamp
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
     tokio::spawn(async move {
         // ? Skip the first tick
         interval.tick().await;
@@ -42,30 +73,21 @@ pub async fn start_periodic_updates(
         'outer: loop {
             interval.tick().await;
 
-            let mut impact_prices: HashMap<u32, (u64, u64)> = HashMap::new();
-            for (_, b) in perp_order_books_.iter() {
-                let book = b.lock().await;
-
-                let impact_notional: u64 = *IMPACT_NOTIONAL_PER_ASSET
-                    .get(book.order_asset.to_string().as_str())
-                    .unwrap();
-
-                let res = book.get_impact_prices(impact_notional);
-                if let Err(_e) = res {
-                    continue;
-                }
-
-                let (impact_bid_price, impact_ask_price) = res.unwrap();
-
-                impact_prices.insert(book.order_asset, (impact_ask_price, impact_bid_price));
-            }
-
+            let impact_prices = get_impact_prices(&perp_order_books_).await;
             if impact_prices.is_empty() {
                 continue 'outer;
             }
 
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+
             let mut tx_batch_m = tx_batch_c.lock().await;
-            let funding_update_msg = FundingUpdateMessage { impact_prices };
+            let funding_update_msg = FundingUpdateMessage {
+                impact_prices,
+                timestamp,
+            };
             tx_batch_m.per_minute_funding_updates(funding_update_msg);
             drop(tx_batch_m);
         }
@@ -106,13 +128,36 @@ pub async fn start_periodic_updates(
         }
     });
 
+    // * CHECK FOR LAPSED DEAD MAN'S SWITCHES EVERY SECOND
+    let tx_batch_c = Arc::clone(&tx_batch);
+    let order_books_ = order_books.clone();
+    let perp_order_books_ = perp_order_books.clone();
+    let ws_connections_ = ws_connections.clone();
+    let dead_mans_switches_ = dead_mans_switches.clone();
+
+    let mut interval5 = time::interval(time::Duration::from_secs(1));
+    tokio::spawn(async move {
+        loop {
+            interval5.tick().await;
+
+            cancel_expired_switches(
+                &tx_batch_c,
+                &order_books_,
+                &perp_order_books_,
+                &ws_connections_,
+                &dead_mans_switches_,
+            )
+            .await;
+        }
+    });
+
     // * CREATE NEW FIREBASE SESSION EVERY 30 MINUTES
     std::thread::spawn(move || loop {
         thread::sleep(Duration::from_secs(1800));
 
         let new_session = create_session();
         let mut sess = session_.lock();
-        *sess = new_session;
+        *sess = Some(new_session);
 
         drop(sess);
     });
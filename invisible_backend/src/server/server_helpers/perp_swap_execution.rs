@@ -2,7 +2,6 @@ use std::sync::Arc;
 use std::time::SystemTime;
 
 use async_recursion::async_recursion;
-use firestore_db_and_auth::ServiceSession;
 use serde_json::json;
 use tokio::sync::Mutex as TokioMutex;
 
@@ -22,6 +21,7 @@ use crate::perpetual::perp_position::PerpPosition;
 use crate::perpetual::{get_cross_price, scale_up_price, PositionEffectType, COLLATERAL_TOKEN};
 use crate::perpetual::{perp_order::PerpOrder, perp_swap::PerpSwap, OrderSide};
 use crate::transaction_batch::TransactionBatch;
+use crate::utils::storage::firestore::FirestoreSession;
 
 use crate::utils::crypto_utils::Signature;
 use crate::utils::storage::backup_storage::BackupStorage;
@@ -39,7 +39,7 @@ pub async fn execute_perp_swap(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     perp_order_book: &Arc<TokioMutex<OrderBook>>,
     user_id_pair: (u64, u64),
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
 ) -> (
     Option<(
@@ -112,6 +112,19 @@ pub async fn execute_perp_swap(
                 book.update_order_positions(user_id_pair.0, &response.position_a);
                 book.update_order_positions(user_id_pair.1, &response.position_b);
 
+                // ? Resize or cancel the reduce only orders on the updated positions
+                for (order, new_position) in [
+                    (&order_a_clone, &response.position_a),
+                    (&order_b_clone, &response.position_b),
+                ] {
+                    if let Some(prev_position) = &order.position {
+                        book.update_reduce_only_orders(
+                            &prev_position.position_header.position_address,
+                            new_position,
+                        );
+                    }
+                }
+
                 let fill_res_a = PerpOrderFillResponse::from_swap_response(
                     &response,
                     true,
@@ -259,7 +272,7 @@ pub async fn execute_perp_swap(
 pub async fn process_and_execute_perp_swaps(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     perp_order_book: &Arc<TokioMutex<OrderBook>>,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
     privileged_ws_connections: &Arc<TokioMutex<Vec<u64>>>,
@@ -329,6 +342,7 @@ pub async fn process_perp_order_request(
     side: OBOrderSide,
     signature: Signature,
     user_id: u64,
+    client_order_id: u64,
     is_market: bool,
     is_retry: bool, // if the order has been matched before but the swap failed for some reason
     retry_qty: u64, // the qty that has been matched before in the swap that failed
@@ -344,6 +358,7 @@ pub async fn process_perp_order_request(
         SystemTime::now(),
         is_market,
         user_id,
+        client_order_id,
     );
 
     // ? Insert the order into the book and get back the matched results if any
@@ -494,12 +509,13 @@ pub async fn await_perp_handle(
 pub async fn retry_failed_perp_swaps(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     perp_order_book: &Arc<TokioMutex<OrderBook>>,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     perp_order: PerpOrder,
     side: OBOrderSide,
     signature: Signature,
     user_id: u64,
+    client_order_id: u64,
     is_market: bool,
     ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
     privileged_ws_connections: &Arc<TokioMutex<Vec<u64>>>,
@@ -523,6 +539,7 @@ pub async fn retry_failed_perp_swaps(
             side,
             signature.clone(),
             user_id,
+            client_order_id,
             is_market,
             true,
             qty,
@@ -567,6 +584,7 @@ pub async fn retry_failed_perp_swaps(
             side,
             signature.clone(),
             user_id,
+            client_order_id,
             is_market,
             ws_connections,
             privileged_ws_connections,
@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::server::grpc::engine_proto::{
+    AmendOrderRequest, CancelAllRequest, CancelOrderMessage, CancelOrdersBatchRequest,
+    CloseOrderTabReq, DeadMansSwitchReq, DepositMessage, DepthReq, EmptyReq, EscapeMessage,
+    FundingReq, GrpcNote, GrpcOrderTab, GrpcPerpPosition, L3OrdersReq, LimitOrderMessage,
+    LiquidationOrderMessage, LiquidityReq, MarginChangeReq, MarketInfoReq, MmProtectionReq,
+    MmProtectionResetReq, NoteRestructureMessage, OnChainAddLiqReq, OnChainCloseMmReq,
+    OnChainRegisterMmReq, OnChainRemoveLiqReq, OpenOrderTabReq, OracleUpdateReq,
+    OrdersBatchRequest, OrdersReq, PerpOrderMessage, PositionLedgerReq, PositionRiskReq,
+    PositionsRiskBatchReq, RegisterOnchainActionRequest, RestoreOrderBookMessage,
+    SetAccountTierReq, SetLeverageReq, StateInfoReq, TransferMessage, UpdateDbIndexesReq,
+    WithdrawalMessage,
+};
+
+/// Buckets that haven't been touched for this long are full again and can be forgotten
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(60);
+const MAX_TRACKED_KEYS: usize = 100_000;
+
+/// The kind of a request, which decides how many tokens it costs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestKind {
+    Order,
+    Cancel,
+    Query,
+    Admin,
+}
+
+impl RequestKind {
+    pub fn weight(&self) -> u32 {
+        match self {
+            RequestKind::Order => 2,
+            RequestKind::Cancel => 1,
+            RequestKind::Query => 1,
+            RequestKind::Admin => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AccountTier {
+    Default,
+    MarketMaker,
+    Internal, // our own servers (e.g. the relay) calling without forwarding the client address
+}
+
+impl TryFrom<u32> for AccountTier {
+    type Error = String;
+
+    fn try_from(tier: u32) -> Result<Self, Self::Error> {
+        match tier {
+            0 => Ok(AccountTier::Default),
+            1 => Ok(AccountTier::MarketMaker),
+            2 => Ok(AccountTier::Internal),
+            _ => Err(format!("Invalid account tier {}", tier)),
+        }
+    }
+}
+
+/// A token bucket holding up to `burst` tokens, refilled at `per_second` tokens per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub burst: u32,
+    pub per_second: u32,
+}
+
+pub fn default_quotas() -> HashMap<AccountTier, Quota> {
+    HashMap::from([
+        (
+            AccountTier::Default,
+            Quota {
+                burst: 200,
+                per_second: 50,
+            },
+        ),
+        (
+            AccountTier::MarketMaker,
+            Quota {
+                burst: 2_000,
+                per_second: 500,
+            },
+        ),
+        (
+            AccountTier::Internal,
+            Quota {
+                burst: 20_000,
+                per_second: 5_000,
+            },
+        ),
+    ])
+}
+
+/// Who a request is made by. Requests are charged to every key they carry.
+#[derive(Debug, Clone, Default)]
+pub struct RequestOrigin {
+    pub user_id: u64,
+    pub stark_key: Option<String>,
+    pub peer: Option<IpAddr>,
+    pub is_internal_peer: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RateLimitKey {
+    User(u64),
+    StarkKey(String),
+    Peer(IpAddr),
+}
+
+impl fmt::Display for RateLimitKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RateLimitKey::User(user_id) => write!(f, "user {}", user_id),
+            RateLimitKey::StarkKey(stark_key) => write!(f, "stark key {}", stark_key),
+            RateLimitKey::Peer(addr) => write!(f, "address {}", addr),
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, quota: &Quota, now: Instant) {
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * quota.per_second as f64).min(quota.burst as f64);
+        self.last_update = now;
+    }
+}
+
+/// Token bucket rate limiter keyed by user id, stark key and peer address.
+///
+/// The user id and stark key buckets use the quota of the account's tier, the peer address
+/// buckets use the same quota unless the request comes from one of our own servers.
+pub struct RateLimiter {
+    quotas: HashMap<AccountTier, Quota>,
+    user_tiers: HashMap<u64, AccountTier>,
+    buckets: HashMap<RateLimitKey, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(quotas: HashMap<AccountTier, Quota>) -> Self {
+        RateLimiter {
+            quotas,
+            user_tiers: HashMap::new(),
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn set_quota(&mut self, tier: AccountTier, quota: Quota) {
+        self.quotas.insert(tier, quota);
+    }
+
+    pub fn set_user_tier(&mut self, user_id: u64, tier: AccountTier) {
+        // ? The user starts with a full bucket of the new tier
+        self.buckets.remove(&RateLimitKey::User(user_id));
+
+        if tier == AccountTier::Default {
+            self.user_tiers.remove(&user_id);
+        } else {
+            self.user_tiers.insert(user_id, tier);
+        }
+    }
+
+    /// Charges `cost` tokens (the sum of the weights of the requests) to all the keys of the origin.
+    ///
+    /// Nothing is charged if any of the buckets doesn't have enough tokens left, in which case
+    /// the error says which key was limited and when to retry.
+    pub fn check(&mut self, origin: &RequestOrigin, cost: u32) -> Result<(), String> {
+        let now = Instant::now();
+        let cost = cost.max(1) as f64;
+
+        let tier = *self
+            .user_tiers
+            .get(&origin.user_id)
+            .unwrap_or(&AccountTier::Default);
+
+        let mut keys: Vec<(RateLimitKey, AccountTier)> = Vec::new();
+        if origin.user_id != 0 {
+            keys.push((RateLimitKey::User(origin.user_id), tier));
+        }
+        if let Some(stark_key) = &origin.stark_key {
+            keys.push((RateLimitKey::StarkKey(stark_key.clone()), tier));
+        }
+        if let Some(peer) = origin.peer {
+            let peer_tier = if origin.is_internal_peer {
+                AccountTier::Internal
+            } else {
+                tier
+            };
+            keys.push((RateLimitKey::Peer(peer), peer_tier));
+        }
+
+        if self.buckets.len() > MAX_TRACKED_KEYS {
+            self.buckets
+                .retain(|_, bucket| now.duration_since(bucket.last_update) < IDLE_BUCKET_TTL);
+        }
+
+        for (key, tier) in keys.iter() {
+            // ? Tiers without a quota are not limited
+            let quota = match self.quotas.get(tier) {
+                Some(quota) => *quota,
+                None => continue,
+            };
+            if cost > quota.burst as f64 {
+                return Err(format!(
+                    "Rate limit exceeded for {}: the request costs more than {} tokens",
+                    key, quota.burst
+                ));
+            }
+
+            let bucket = self.buckets.entry(key.clone()).or_insert(TokenBucket {
+                tokens: quota.burst as f64,
+                last_update: now,
+            });
+            bucket.refill(&quota, now);
+
+            if bucket.tokens < cost {
+                let retry_ms =
+                    ((cost - bucket.tokens) * 1000.0 / quota.per_second as f64).ceil() as u64;
+
+                return Err(format!(
+                    "Rate limit exceeded for {}, retry in {} ms",
+                    key, retry_ms
+                ));
+            }
+        }
+
+        for (key, _) in keys.iter() {
+            if let Some(bucket) = self.buckets.get_mut(key) {
+                bucket.tokens -= cost;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// * REQUEST KEYS * //
+
+/// The account a request is made on behalf of, as far as the request tells
+pub trait RateLimitKeys {
+    fn user_id(&self) -> u64 {
+        0
+    }
+
+    fn stark_key(&self) -> Option<String> {
+        None
+    }
+}
+
+fn notes_owner(notes: &Vec<GrpcNote>) -> Option<String> {
+    let address = notes.first()?.address.as_ref()?;
+
+    Some(address.x.clone())
+}
+
+fn position_owner(position: &Option<GrpcPerpPosition>) -> Option<String> {
+    let header = position.as_ref()?.position_header.as_ref()?;
+
+    Some(header.position_address.clone())
+}
+
+fn tab_owner(order_tab: &Option<GrpcOrderTab>) -> Option<String> {
+    let header = order_tab.as_ref()?.tab_header.as_ref()?;
+
+    Some(header.pub_key.clone())
+}
+
+impl RateLimitKeys for LimitOrderMessage {
+    fn user_id(&self) -> u64 {
+        self.user_id
+    }
+
+    fn stark_key(&self) -> Option<String> {
+        match &self.spot_note_info {
+            Some(note_info) => notes_owner(&note_info.notes_in),
+            None => tab_owner(&self.order_tab),
+        }
+    }
+}
+
+impl RateLimitKeys for PerpOrderMessage {
+    fn user_id(&self) -> u64 {
+        self.user_id
+    }
+
+    fn stark_key(&self) -> Option<String> {
+        match &self.open_order_fields {
+            Some(fields) if self.position.is_none() => notes_owner(&fields.notes_in),
+            _ => position_owner(&self.position),
+        }
+    }
+}
+
+impl RateLimitKeys for LiquidationOrderMessage {
+    fn user_id(&self) -> u64 {
+        self.user_id
+    }
+
+    fn stark_key(&self) -> Option<String> {
+        notes_owner(&self.open_order_fields.as_ref()?.notes_in)
+    }
+}
+
+impl RateLimitKeys for OrdersBatchRequest {
+    fn user_id(&self) -> u64 {
+        let user_ids = self.limit_orders.iter().map(|order| order.user_id);
+        let user_ids = user_ids.chain(self.perp_orders.iter().map(|order| order.user_id));
+        let mut user_ids = user_ids.chain(self.cancel_orders.iter().map(|cancel| cancel.user_id));
+
+        user_ids.find(|user_id| *user_id != 0).unwrap_or_default()
+    }
+}
+
+impl RateLimitKeys for CancelOrdersBatchRequest {
+    fn user_id(&self) -> u64 {
+        self.cancel_orders
+            .first()
+            .map_or(0, |cancel| cancel.user_id)
+    }
+}
+
+impl RateLimitKeys for DepositMessage {
+    fn stark_key(&self) -> Option<String> {
+        Some(self.stark_key.clone())
+    }
+}
+
+impl RateLimitKeys for WithdrawalMessage {
+    fn stark_key(&self) -> Option<String> {
+        notes_owner(&self.notes_in)
+    }
+}
+
+impl RateLimitKeys for NoteRestructureMessage {
+    fn stark_key(&self) -> Option<String> {
+        notes_owner(&self.notes_in)
+    }
+}
+
+impl RateLimitKeys for TransferMessage {
+    fn stark_key(&self) -> Option<String> {
+        notes_owner(&self.notes_in)
+    }
+}
+
+impl RateLimitKeys for MarginChangeReq {
+    fn user_id(&self) -> u64 {
+        self.user_id
+    }
+
+    fn stark_key(&self) -> Option<String> {
+        position_owner(&self.position)
+    }
+}
+
+impl RateLimitKeys for SetLeverageReq {
+    fn user_id(&self) -> u64 {
+        self.user_id
+    }
+
+    fn stark_key(&self) -> Option<String> {
+        position_owner(&self.position)
+    }
+}
+
+impl RateLimitKeys for OpenOrderTabReq {
+    fn stark_key(&self) -> Option<String> {
+        tab_owner(&self.order_tab)
+    }
+}
+
+impl RateLimitKeys for CloseOrderTabReq {
+    fn stark_key(&self) -> Option<String> {
+        tab_owner(&self.order_tab)
+    }
+}
+
+impl RateLimitKeys for OnChainRegisterMmReq {
+    fn stark_key(&self) -> Option<String> {
+        position_owner(&self.position)
+    }
+}
+
+impl RateLimitKeys for OnChainAddLiqReq {
+    fn stark_key(&self) -> Option<String> {
+        position_owner(&self.position)
+    }
+}
+
+impl RateLimitKeys for OnChainRemoveLiqReq {
+    fn stark_key(&self) -> Option<String> {
+        position_owner(&self.position)
+    }
+}
+
+impl RateLimitKeys for OnChainCloseMmReq {
+    fn stark_key(&self) -> Option<String> {
+        position_owner(&self.position)
+    }
+}
+
+macro_rules! impl_user_id_keys {
+    ($($msg:ty),*) => {
+        $(
+            impl RateLimitKeys for $msg {
+                fn user_id(&self) -> u64 {
+                    self.user_id
+                }
+            }
+        )*
+    };
+}
+
+impl_user_id_keys!(
+    CancelOrderMessage,
+    AmendOrderRequest,
+    CancelAllRequest,
+    DeadMansSwitchReq,
+    MmProtectionReq,
+    MmProtectionResetReq,
+    OrdersReq
+);
+
+// ? Requests that are only limited by the address they come from
+impl RateLimitKeys for EscapeMessage {}
+impl RateLimitKeys for EmptyReq {}
+impl RateLimitKeys for OracleUpdateReq {}
+impl RateLimitKeys for RestoreOrderBookMessage {}
+impl RateLimitKeys for RegisterOnchainActionRequest {}
+impl RateLimitKeys for UpdateDbIndexesReq {}
+impl RateLimitKeys for SetAccountTierReq {}
+impl RateLimitKeys for LiquidityReq {}
+impl RateLimitKeys for DepthReq {}
+impl RateLimitKeys for L3OrdersReq {}
+impl RateLimitKeys for MarketInfoReq {}
+impl RateLimitKeys for FundingReq {}
+impl RateLimitKeys for PositionLedgerReq {}
+impl RateLimitKeys for PositionRiskReq {}
+impl RateLimitKeys for PositionsRiskBatchReq {}
+impl RateLimitKeys for StateInfoReq {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RequestKind::{Admin, Cancel, Order, Query};
+
+    fn limiter(burst: u32, per_second: u32) -> RateLimiter {
+        let quota = Quota { burst, per_second };
+
+        RateLimiter::new(HashMap::from([
+            (AccountTier::Default, quota),
+            (AccountTier::MarketMaker, quota),
+            (AccountTier::Internal, quota),
+        ]))
+    }
+
+    fn user(user_id: u64) -> RequestOrigin {
+        RequestOrigin {
+            user_id,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn buckets_are_separate_per_user() {
+        let mut rate_limiter = limiter(4, 1);
+
+        assert!(rate_limiter.check(&user(1), Order.weight() * 2).is_ok());
+        assert!(rate_limiter.check(&user(1), Cancel.weight()).is_err());
+        assert!(rate_limiter.check(&user(2), Cancel.weight()).is_ok());
+
+        // ? Requests that can never fit in the bucket are rejected outright
+        let err = rate_limiter.check(&user(3), Admin.weight());
+        assert!(err.unwrap_err().contains("costs more than"));
+    }
+
+    #[test]
+    fn nothing_is_charged_when_any_key_is_limited() {
+        let mut rate_limiter = limiter(4, 1);
+
+        let stark_key = Some("0x1234".to_string());
+        let origin = RequestOrigin {
+            user_id: 1,
+            stark_key: stark_key.clone(),
+            ..Default::default()
+        };
+        assert!(rate_limiter.check(&origin, Order.weight() * 2).is_ok());
+
+        // ? The stark key is exhausted, so the new user's bucket is left untouched
+        let origin = RequestOrigin {
+            user_id: 2,
+            stark_key,
+            ..Default::default()
+        };
+        assert!(rate_limiter.check(&origin, Query.weight()).is_err());
+        assert!(rate_limiter.check(&user(2), Query.weight() * 4).is_ok());
+    }
+
+    #[test]
+    fn account_tiers_have_their_own_quotas() {
+        let mut rate_limiter = limiter(4, 1);
+        rate_limiter.set_quota(
+            AccountTier::MarketMaker,
+            Quota {
+                burst: 100,
+                per_second: 10,
+            },
+        );
+        rate_limiter.set_user_tier(1, AccountTier::MarketMaker);
+
+        assert!(rate_limiter.check(&user(1), Order.weight() * 50).is_ok());
+        assert!(rate_limiter.check(&user(2), Order.weight() * 3).is_err());
+    }
+}
@@ -1,7 +1,6 @@
 use std::sync::Arc;
 use std::time::SystemTime;
 
-use firestore_db_and_auth::ServiceSession;
 use serde_json::json;
 use tokio::sync::Mutex as TokioMutex;
 
@@ -15,6 +14,7 @@ use crate::matching_engine::{
     orderbook::OrderBook,
 };
 use crate::perpetual::{get_cross_price, scale_up_price};
+use crate::utils::storage::firestore::FirestoreSession;
 
 use crate::transaction_batch::TransactionBatch;
 use crate::transactions::limit_order::LimitOrder;
@@ -37,7 +37,7 @@ pub async fn execute_swap(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     order_book: Arc<TokioMutex<OrderBook>>,
     user_id_pair: (u64, u64),
-    session: Arc<Mutex<ServiceSession>>,
+    session: Arc<Mutex<FirestoreSession>>,
     backup_storage: Arc<Mutex<BackupStorage>>,
 ) -> (
     Option<((Message, Message), (u64, u64), Message)>,
@@ -228,7 +228,7 @@ pub async fn execute_swap(
 
                     if let Some(invalid_order_id) = swap_execution_error.invalid_order {
                         // ? only add the order back into the orderbook if not eql invalid_order_id
-                        if maker_order_id % 2_u64.pow(32) == invalid_order_id % 2_u64.pow(32) {
+                        if maker_order_id == invalid_order_id {
                             if maker_side == OBOrderSide::Bid {
                                 book.bid_queue.reduce_pending_order(maker_order_id, 0, true);
                             } else {
@@ -247,7 +247,7 @@ pub async fn execute_swap(
                                     .restore_pending_order(Order::Spot(maker_order), qty);
                             }
 
-                            if taker_order_id % 2_u64.pow(32) == invalid_order_id % 2_u64.pow(32) {
+                            if taker_order_id == invalid_order_id {
                                 return (None, Some((None, 0, 0, error_message.to_owned())));
                             }
                         }
@@ -298,7 +298,7 @@ pub async fn execute_swap(
 pub async fn process_and_execute_spot_swaps(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     order_book: &Arc<TokioMutex<OrderBook>>,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     processed_res: &mut Vec<std::result::Result<Success, Failed>>,
 ) -> std::result::Result<
@@ -353,6 +353,7 @@ pub async fn process_limit_order_request(
     side: OBOrderSide,
     signature: Signature,
     user_id: u64,
+    client_order_id: u64,
     is_market: bool,
     is_retry: bool, // if the order has been matched before but the swap failed for some reason
     retry_qty: u64, // the qty that has been matched before in the swap that failed
@@ -368,6 +369,7 @@ pub async fn process_limit_order_request(
         SystemTime::now(),
         is_market,
         user_id,
+        client_order_id,
     );
 
     // ? Insert the order into the book and get back the matched results if any
@@ -454,12 +456,13 @@ pub async fn handle_swap_execution_results(
 pub async fn retry_failed_swaps(
     tx_batch: &Arc<TokioMutex<TransactionBatch>>,
     order_book: &Arc<TokioMutex<OrderBook>>,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     limit_order: LimitOrder,
     side: OBOrderSide,
     signature: Signature,
     user_id: u64,
+    client_order_id: u64,
     is_market: bool,
     ws_connections: &Arc<TokioMutex<WsConnectionsMap>>,
     privileged_ws_connections: &Arc<TokioMutex<Vec<u64>>>,
@@ -495,6 +498,7 @@ pub async fn retry_failed_swaps(
             side,
             signature.clone(),
             user_id,
+            client_order_id,
             is_market,
             true,
             qty,
@@ -548,6 +552,7 @@ pub async fn retry_failed_swaps(
                 side,
                 signature,
                 user_id,
+                client_order_id,
                 is_market,
                 ws_connections,
                 privileged_ws_connections,
@@ -4,10 +4,9 @@ use num_bigint::BigUint;
 use parking_lot::Mutex;
 use serde_json::Value;
 
-use firestore_db_and_auth::ServiceSession;
-
 use crate::utils::storage::backup_storage::BackupStorage;
 use crate::utils::storage::firestore::start_add_position_thread;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::utils::storage::local_storage::{MainStorage, OnchainActionType};
 use crate::{
     perpetual::perp_position::PerpPosition, server::grpc::engine_proto::OnChainRegisterMmReq,
@@ -25,7 +24,7 @@ use super::helpers::{
 
 /// Claim the deposit that was created onchain
 pub fn onchain_register_mm(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     main_storage: &Arc<Mutex<MainStorage>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     register_mm_req: OnChainRegisterMmReq,
@@ -101,6 +100,7 @@ pub fn onchain_register_mm(
 
     // ? UPDATE THE DATABASE ----------------------------------------------------------------------
     let _h = start_add_position_thread(position.clone(), session, backup_storage);
+    main_storage.lock().store_position(&position);
 
     return Ok(position);
 }
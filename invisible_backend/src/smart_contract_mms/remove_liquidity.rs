@@ -4,9 +4,8 @@ use num_bigint::BigUint;
 use parking_lot::Mutex;
 use serde_json::Value;
 
-use firestore_db_and_auth::ServiceSession;
-
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::utils::storage::local_storage::{MainStorage, OnchainActionType};
 use crate::{
     perpetual::perp_position::PerpPosition, server::grpc::engine_proto::OnChainRemoveLiqReq,
@@ -27,7 +26,7 @@ use super::helpers::{
 
 /// Claim the deposit that was created onchain
 pub fn remove_liquidity_from_order_tab(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     main_storage: &Arc<Mutex<MainStorage>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     remove_liquidity_req: OnChainRemoveLiqReq,
@@ -114,6 +113,7 @@ pub fn remove_liquidity_from_order_tab(
 
     // ? UPDATE THE DATABASE ----------------------------------------------------------------------
     let _h = start_add_position_thread(new_position.clone(), session, backup_storage);
+    main_storage.lock().store_position(&new_position);
 
     return Ok(new_position);
 }
@@ -3,7 +3,7 @@ use std::{collections::HashMap, sync::Arc};
 
 use error_stack::Result;
 
-use crate::perpetual::SYNTHETIC_ASSETS;
+use crate::perpetual::{funding_config::FundingConfig, SYNTHETIC_ASSETS};
 use crate::transaction_batch::tx_batch_helpers::{
     _calculate_funding_rates, _per_minute_funding_update_inner,
 };
@@ -23,6 +23,7 @@ pub fn _init_inner(
     funding_rates: &mut HashMap<u32, Vec<i64>>,
     funding_prices: &mut HashMap<u32, Vec<u64>>,
     min_funding_idxs: &mut Arc<Mutex<HashMap<u32, u32>>>,
+    open_interest: &mut Arc<Mutex<HashMap<u32, u64>>>,
     latest_index_price: &mut HashMap<u32, u64>,
     min_index_price_data: &mut HashMap<u32, (u64, OracleUpdate)>,
     max_index_price_data: &mut HashMap<u32, (u64, OracleUpdate)>,
@@ -41,6 +42,10 @@ pub fn _init_inner(
         }
     }
 
+    if let Some(open_interest_) = storage.read_open_interest() {
+        *open_interest = Arc::new(Mutex::new(open_interest_));
+    }
+
     if !storage.price_db.is_empty() {
         if let Some((latest_index_price_, min_index_price_data_, max_index_price_data_)) =
             storage.read_price_data()
@@ -58,56 +63,110 @@ pub fn _init_inner(
     *state_tree = Arc::new(Mutex::new(state_tree_));
 }
 
-/// Get the funding rate every minute.
-/// At the end of the hour calculate the TWAP and scale
+/// Samples the funding premium every sample interval of the funding config.
+/// At the end of every settlement period calculate the TWAP and scale
 /// it to have a realization period of 8 hours.
+///
+/// Samples are placed in slots derived from their timestamp, so a period is always
+/// averaged over the same number of samples. A repeated slot is ignored, a period left
+/// unsettled (e.g. after downtime) is settled with the samples it has, and periods
+/// without any samples are skipped.
 pub fn _per_minute_funding_updates(
     running_funding_tick_sums: &mut HashMap<u32, i64>,
     latest_index_price: &mut HashMap<u32, u64>,
     current_funding_count: &mut u16,
+    last_funding_slot: &mut Option<u64>,
     funding_rates: &mut HashMap<u32, Vec<i64>>,
     funding_prices: &mut HashMap<u32, Vec<u64>>,
     min_funding_idxs: &Arc<Mutex<HashMap<u32, u32>>>,
     main_storage: &Arc<Mutex<MainStorage>>,
+    funding_config: &FundingConfig,
     funding_update: FundingUpdateMessage,
 ) {
-    let mut running_sums: Vec<(u32, i64)> = Vec::new();
-    for tup in running_funding_tick_sums.drain() {
-        running_sums.push(tup);
-    }
+    let samples_per_settlement = funding_config.samples_per_settlement as u64;
+    let slot = funding_update.timestamp / funding_config.sample_interval_secs;
 
-    for (token, sum) in running_sums {
-        let index_price = latest_index_price.get(&token).unwrap().clone();
+    if let Some(last_slot) = *last_funding_slot {
+        if slot <= last_slot {
+            return;
+        }
 
+        // ? The period of the last sample ended before it could be settled
+        if slot / samples_per_settlement != last_slot / samples_per_settlement
+            && *current_funding_count > 0
+        {
+            _settle_funding(
+                running_funding_tick_sums,
+                latest_index_price,
+                current_funding_count,
+                funding_rates,
+                funding_prices,
+                min_funding_idxs,
+                main_storage,
+                funding_config,
+            );
+        }
+    }
+    *last_funding_slot = Some(slot);
+
+    for token in SYNTHETIC_ASSETS {
         if !funding_update.impact_prices.contains_key(&token) {
             continue;
         };
+        let index_price = *latest_index_price.get(&token).unwrap_or(&0);
+        if index_price == 0 {
+            continue;
+        }
+
+        let sum = *running_funding_tick_sums.get(&token).unwrap_or(&0);
         let (impact_bid, impact_ask) = funding_update.impact_prices.get(&token).unwrap();
         let new_sum = _per_minute_funding_update_inner(*impact_bid, *impact_ask, sum, index_price);
 
         running_funding_tick_sums.insert(token, new_sum);
     }
 
-    *current_funding_count += 1;
+    *current_funding_count = (slot % samples_per_settlement) as u16 + 1;
 
     // ? 1 hour realization/settlement period
-    if *current_funding_count == 60 {
-        let fundings = _calculate_funding_rates(running_funding_tick_sums);
-
-        for (token, funding) in fundings.iter() {
-            funding_rates.get_mut(token).unwrap().push(*funding);
-            let price = latest_index_price.get(token).unwrap().clone();
-            funding_prices.get_mut(token).unwrap().push(price);
-        }
+    if *current_funding_count == funding_config.samples_per_settlement {
+        _settle_funding(
+            running_funding_tick_sums,
+            latest_index_price,
+            current_funding_count,
+            funding_rates,
+            funding_prices,
+            min_funding_idxs,
+            main_storage,
+            funding_config,
+        );
+    }
+}
 
-        // Reinitialize the funding tick sums
-        *current_funding_count = 0;
-        _init_empty_tokens_map::<i64>(&mut *running_funding_tick_sums);
+fn _settle_funding(
+    running_funding_tick_sums: &mut HashMap<u32, i64>,
+    latest_index_price: &HashMap<u32, u64>,
+    current_funding_count: &mut u16,
+    funding_rates: &mut HashMap<u32, Vec<i64>>,
+    funding_prices: &mut HashMap<u32, Vec<u64>>,
+    min_funding_idxs: &Arc<Mutex<HashMap<u32, u32>>>,
+    main_storage: &Arc<Mutex<MainStorage>>,
+    funding_config: &FundingConfig,
+) {
+    let fundings = _calculate_funding_rates(running_funding_tick_sums, funding_config);
 
-        let storage = main_storage.lock();
-        storage.store_funding_info(&funding_rates, &funding_prices, &min_funding_idxs.lock());
-        drop(storage);
+    for (token, funding) in fundings.iter() {
+        funding_rates.get_mut(token).unwrap().push(*funding);
+        let price = latest_index_price.get(token).unwrap().clone();
+        funding_prices.get_mut(token).unwrap().push(price);
     }
+
+    // Reinitialize the funding tick sums
+    *current_funding_count = 0;
+    _init_empty_tokens_map::<i64>(&mut *running_funding_tick_sums);
+
+    let storage = main_storage.lock();
+    storage.store_funding_info(&funding_rates, &funding_prices, &min_funding_idxs.lock());
+    drop(storage);
 }
 
 pub fn _update_index_prices_inner(
@@ -11,6 +11,7 @@ use crate::transaction_batch::restore_state::da_output::helpers::{
     DepositRequest, WithdrawalRequest,
 };
 use crate::trees::{superficial_tree::SuperficialTree, Tree};
+use crate::utils::crypto_utils::hash_many;
 use crate::utils::storage::local_storage::MainStorage;
 use crate::{
     transaction_batch::{
@@ -208,6 +209,7 @@ pub fn _transition_state(
 }
 
 pub fn _construct_da_output(
+    state_tree: &Arc<Mutex<SuperficialTree>>,
     main_storage_m: &Arc<Mutex<MainStorage>>,
     funding_rates: &HashMap<u32, Vec<i64>>,
     funding_prices: &HashMap<u32, Vec<u64>>,
@@ -222,8 +224,7 @@ pub fn _construct_da_output(
     drop(main_storage);
 
     let (
-        da_commitment,
-        da_output_data,
+        state_updates,
         accumulated_deposit_hashes,
         accumulated_withdrawal_hashes,
         deposit_outputs,
@@ -235,6 +236,26 @@ pub fn _construct_da_output(
         &swap_output_json,
     );
 
+    // ? Store the new state updates localy on disk
+    state_updates.store();
+
+    // ? Backfill the positions of the batch that were updated without being stored
+    let state_tree = state_tree.lock();
+    main_storage_m
+        .lock()
+        .backfill_positions(&state_updates.positions(), &state_tree);
+    drop(state_tree);
+
+    // ? Hash and upload the data output
+    let da_output_data = state_updates.data_output();
+    let references: Vec<&BigUint> = da_output_data.iter().collect();
+    let da_commitment = hash_many(&references);
+
+    let da_output_data: Vec<String> = da_output_data
+        .into_iter()
+        .map(|el| el.to_string())
+        .collect();
+
     // for (i, val) in da_output_data.iter().enumerate() {
     //     println!("{},", val);
     // }
@@ -1,18 +1,18 @@
-use firestore_db_and_auth::ServiceSession;
 use num_bigint::BigUint;
-use num_traits::Zero;
 use parking_lot::Mutex;
-use serde_json::{json, Value};
+use serde_json::Value;
 use std::{
     collections::HashMap,
     sync::Arc,
     thread::{self, JoinHandle},
 };
 
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     order_tab::{close_tab::close_order_tab, open_tab::open_order_tab},
     perpetual::{
-        perp_helpers::perp_swap_helpers::get_max_leverage, perp_position::PerpPosition,
+        get_collateral_amount, perp_helpers::perp_swap_helpers::get_max_leverage,
+        perp_position::PerpPosition, position_ledger::margin_change_ledger_entries,
         COLLATERAL_TOKEN,
     },
     server::grpc::{OrderTabActionMessage, OrderTabActionResponse, SCMMActionMessage},
@@ -21,7 +21,7 @@ use crate::{
         register_mm::onchain_register_mm, remove_liquidity::remove_liquidity_from_order_tab,
     },
     transaction_batch::LeafNodeType,
-    transactions::transaction_helpers::db_updates::{update_db_after_note_split, DbNoteUpdater},
+    transactions::transaction_helpers::db_updates::DbNoteUpdater,
     utils::storage::{
         firestore::{start_add_note_thread, start_add_position_thread},
         local_storage::MainStorage,
@@ -35,140 +35,21 @@ use crate::utils::notes::Note;
 
 use crate::server::{
     grpc::ChangeMarginMessage,
-    server_helpers::engine_helpers::{verify_margin_change_signature, verify_position_existence},
+    server_helpers::engine_helpers::{
+        set_leverage_refund_note, verify_margin_change_signature, verify_position_existence,
+    },
 };
 
 use crate::transaction_batch::tx_batch_helpers::{
     add_margin_state_updates, reduce_margin_state_updates,
 };
 
-pub fn _split_notes_inner(
-    state_tree: &Arc<Mutex<SuperficialTree>>,
-    updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
-    firebase_session: &Arc<Mutex<ServiceSession>>,
-    backup_storage: &Arc<Mutex<BackupStorage>>,
-    swap_output_json: &Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
-    notes_in: Vec<Note>,
-    mut new_note: Note,
-    mut refund_note: Option<Note>,
-) -> std::result::Result<Vec<u64>, String> {
-    let token = notes_in[0].token;
-
-    let mut sum_in: u64 = 0;
-
-    let mut state_tree_m = state_tree.lock();
-    for note in notes_in.iter() {
-        if note.token != token {
-            return Err("Invalid token".to_string());
-        }
-
-        let leaf_hash = state_tree_m.get_leaf_by_index(note.index);
-
-        if leaf_hash != note.hash {
-            return Err("Note does not exist".to_string());
-        }
-
-        sum_in += note.amount;
-    }
-
-    if new_note.token != token {
-        return Err("Invalid token".to_string());
-    }
-
-    let note_in1 = &notes_in[0];
-    if new_note.blinding != note_in1.blinding || new_note.address.x != note_in1.address.x {
-        return Err("Mismatch od address and blinding between input/output notes".to_string());
-    }
-    let new_amount = new_note.amount;
-
-    // ? get and set new index
-    let new_index = note_in1.index;
-    new_note.index = new_index;
-
-    let mut new_indexes = vec![new_index];
-
-    let mut refund_amount: u64 = 0;
-    if refund_note.is_some() {
-        let refund_note = refund_note.as_mut().unwrap();
-
-        if refund_note.token != token {
-            return Err("Invalid token".to_string());
-        }
-
-        let note_in2 = &notes_in[notes_in.len() - 1];
-        if refund_note.blinding != note_in2.blinding || refund_note.address.x != note_in2.address.x
-        {
-            return Err("Mismatch of address and blinding between input/output notes".to_string());
-        }
-
-        refund_amount = refund_note.amount;
-
-        let new_index = if notes_in.len() > 1 {
-            notes_in[1].index
-        } else {
-            state_tree_m.first_zero_idx()
-        };
-        refund_note.index = new_index;
-        new_indexes.push(new_index)
-    }
-
-    if sum_in != new_amount + refund_amount {
-        return Err("New note amounts exceed old note amounts".to_string());
-    }
-
-    // *  Make Updates * //
-
-    let mut json_map = serde_json::map::Map::new();
-    json_map.insert(
-        String::from("transaction_type"),
-        serde_json::to_value("note_split").unwrap(),
-    );
-    json_map.insert(
-            String::from("note_split"),
-            json!({"token": token, "notes_in": notes_in, "new_note": new_note, "refund_note": refund_note}),
-        );
-    let mut updated_state_hashes_m = updated_state_hashes.lock();
-
-    // ? Add return note in to state
-    state_tree_m.update_leaf_node(&new_note.hash, new_note.index);
-    updated_state_hashes_m.insert(new_note.index, (LeafNodeType::Note, new_note.hash.clone()));
-
-    // ? Remove notes in from state
-    for note in notes_in.iter().skip(1) {
-        state_tree_m.update_leaf_node(&BigUint::zero(), note.index);
-        updated_state_hashes_m.insert(note.index, (LeafNodeType::Note, BigUint::zero()));
-    }
-
-    if let Some(note) = refund_note.as_ref() {
-        state_tree_m.update_leaf_node(&note.hash, note.index);
-        updated_state_hashes_m.insert(note.index, (LeafNodeType::Note, note.hash.clone()));
-    }
-    drop(updated_state_hashes_m);
-    drop(state_tree_m);
-
-    let mut swap_output_json = swap_output_json.lock();
-    swap_output_json.push(json_map);
-
-    drop(swap_output_json);
-
-    // ----------------------------------------------
-
-    update_db_after_note_split(
-        &firebase_session,
-        &backup_storage,
-        &notes_in,
-        new_note.clone(),
-        refund_note.clone(),
-    );
-
-    Ok(new_indexes)
-}
-
 pub fn _change_position_margin_inner(
     state_tree: &Arc<Mutex<SuperficialTree>>,
     updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
-    firebase_session: &Arc<Mutex<ServiceSession>>,
+    firebase_session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
+    main_storage: &Arc<Mutex<MainStorage>>,
     swap_output_json: &Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
     latest_index_price: &HashMap<u32, u64>,
     margin_change: ChangeMarginMessage,
@@ -183,6 +64,7 @@ pub fn _change_position_margin_inner(
     verify_position_existence(&position, &state_tree)?;
 
     position.modify_margin(margin_change.margin_change)?;
+    let margin_change_amount = margin_change.margin_change;
 
     if margin_change.margin_change < 0 {
         let leverage = position
@@ -190,20 +72,12 @@ pub fn _change_position_margin_inner(
             .map_err(|e| e.to_string())?;
 
         // ? Check that leverage is valid relative to the notional position size after increasing size
-        if margin_change.margin_change < 0
-            && get_max_leverage(
-                position.position_header.synthetic_token,
-                position.position_size,
-            ) < leverage
-        {
-            println!(
-                "Leverage would be too high {} > {}",
-                leverage,
-                get_max_leverage(
-                    position.position_header.synthetic_token,
-                    position.position_size
-                ),
-            );
+        let synthetic_token = position.position_header.synthetic_token;
+        let notional =
+            get_collateral_amount(synthetic_token, position.position_size, current_index_price);
+        let max_leverage = get_max_leverage(synthetic_token, notional);
+        if margin_change.margin_change < 0 && max_leverage < leverage {
+            println!("Leverage would be too high {} > {}", leverage, max_leverage,);
             return Err("Leverage would be too high".to_string());
         }
     }
@@ -350,13 +224,104 @@ pub fn _change_position_margin_inner(
             start_add_note_thread(return_collateral_note, &firebase_session, &backup_storage);
     }
 
+    let (position_address, entries) = margin_change_ledger_entries(&position, margin_change_amount);
+    let main_storage_m = main_storage.lock();
+    main_storage_m.store_ledger_entries(&position_address, &entries);
+    main_storage_m.store_position(&position);
+    drop(main_storage_m);
+
     Ok((z_index, position))
 }
 
+/// Computes the margin change that brings the position to the target leverage at the index price,
+/// returns it together with the resulting position
+pub fn get_set_leverage_margin_change(
+    position: &PerpPosition,
+    leverage: u64,
+    index_price: u64,
+) -> std::result::Result<(i64, PerpPosition), String> {
+    let synthetic_token = position.position_header.synthetic_token;
+    let notional = get_collateral_amount(synthetic_token, position.position_size, index_price);
+    let max_leverage = get_max_leverage(synthetic_token, notional);
+    if leverage == 0 || leverage > max_leverage {
+        return Err(format!("Leverage should be between 0 and {}", max_leverage));
+    }
+
+    let margin = position
+        .get_margin_for_leverage(index_price, leverage)
+        .map_err(|e| e.to_string())?;
+    let margin_change = margin as i64 - position.margin as i64;
+
+    let mut new_position = position.clone();
+    new_position.modify_margin(margin_change)?;
+
+    Ok((margin_change, new_position))
+}
+
+/// Executes a set leverage request as a margin change. Margin is added from the notes_in (the rest
+/// is refunded to the close_order_fields address) or removed to the close_order_fields address.
+///
+/// The request signs the margin change of its dry run, which has to be the one that still brings
+/// the position to the target leverage at the current index price.
+pub fn _set_position_leverage_inner(
+    state_tree: &Arc<Mutex<SuperficialTree>>,
+    updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
+    firebase_session: &Arc<Mutex<FirestoreSession>>,
+    backup_storage: &Arc<Mutex<BackupStorage>>,
+    main_storage: &Arc<Mutex<MainStorage>>,
+    swap_output_json: &Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
+    latest_index_price: &HashMap<u32, u64>,
+    mut margin_change: ChangeMarginMessage,
+) -> std::result::Result<(u64, PerpPosition), String> {
+    let leverage = margin_change
+        .target_leverage
+        .ok_or("Target leverage is not defined".to_string())?;
+    let index_price = *latest_index_price
+        .get(&margin_change.position.position_header.synthetic_token)
+        .ok_or("No index price for the synthetic token".to_string())?;
+
+    let (delta, _) =
+        get_set_leverage_margin_change(&margin_change.position, leverage, index_price)?;
+    if delta != margin_change.margin_change {
+        return Err(format!(
+            "The margin change for this leverage is now {}, sign the new dry run",
+            delta
+        ));
+    }
+
+    // ? Notes are only spent when the leverage is lowered, their signature covers them
+    if let Some(notes_in) = &margin_change.notes_in {
+        if delta <= 0 {
+            return Err("Leverage would increase, no notes should be spent".to_string());
+        }
+
+        let amount_in = notes_in.iter().map(|n| n.amount).sum::<u64>();
+        if amount_in < delta as u64 {
+            return Err("Notes in don't cover the margin needed".to_string());
+        }
+
+        let fields = margin_change.close_order_fields.as_ref().unwrap();
+        margin_change.refund_note = set_leverage_refund_note(delta, notes_in, fields);
+    } else if delta >= 0 {
+        return Err("Notes in are needed to lower the leverage".to_string());
+    }
+
+    return _change_position_margin_inner(
+        state_tree,
+        updated_state_hashes,
+        firebase_session,
+        backup_storage,
+        main_storage,
+        swap_output_json,
+        latest_index_price,
+        margin_change,
+    );
+}
+
 pub fn _execute_order_tab_modification_inner(
     state_tree: &Arc<Mutex<SuperficialTree>>,
     updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
-    firebase_session: &Arc<Mutex<ServiceSession>>,
+    firebase_session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     swap_output_json: &Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
     tab_action_message: OrderTabActionMessage,
@@ -413,7 +378,7 @@ pub fn _execute_order_tab_modification_inner(
 pub fn _execute_sc_mm_modification_inner(
     state_tree: &Arc<Mutex<SuperficialTree>>,
     updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
-    firebase_session: &Arc<Mutex<ServiceSession>>,
+    firebase_session: &Arc<Mutex<FirestoreSession>>,
     main_storage: &Arc<Mutex<MainStorage>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     swap_output_json: &Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
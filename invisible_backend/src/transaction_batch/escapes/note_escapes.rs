@@ -1,10 +1,10 @@
-use firestore_db_and_auth::ServiceSession;
 use num_bigint::BigUint;
 use num_traits::{FromPrimitive, Zero};
 use parking_lot::Mutex;
 use starknet::curve::AffinePoint;
 use std::{collections::HashMap, sync::Arc};
 
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     transaction_batch::LeafNodeType,
     utils::{
@@ -32,7 +32,7 @@ pub struct NoteEscape {
 pub fn verify_note_escape(
     state_tree: &Arc<Mutex<SuperficialTree>>,
     updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
-    firebase_session: &Arc<Mutex<ServiceSession>>,
+    firebase_session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     escape_id: u32,
     escape_notes: Vec<Note>,
@@ -1,4 +1,3 @@
-use firestore_db_and_auth::ServiceSession;
 use num_bigint::BigUint;
 use num_traits::{FromPrimitive, Zero};
 use parking_lot::Mutex;
@@ -8,6 +7,7 @@ use std::{collections::HashMap, sync::Arc};
 use crate::trees::superficial_tree::SuperficialTree;
 use crate::utils::crypto_utils::keccak256;
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     order_tab::OrderTab,
     transaction_batch::LeafNodeType,
@@ -32,7 +32,7 @@ pub struct OrderTabEscape {
 pub fn verify_order_tab_escape(
     state_tree: &Arc<Mutex<SuperficialTree>>,
     updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
-    firebase_session: &Arc<Mutex<ServiceSession>>,
+    firebase_session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     escape_id: u32,
     order_tab: OrderTab,
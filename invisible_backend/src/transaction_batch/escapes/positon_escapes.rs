@@ -1,4 +1,3 @@
-use firestore_db_and_auth::ServiceSession;
 use num_bigint::BigUint;
 use num_traits::{FromPrimitive, Num, One, Zero};
 use parking_lot::Mutex;
@@ -9,6 +8,7 @@ use crate::perpetual::OrderSide;
 use crate::trees::superficial_tree::SuperficialTree;
 use crate::utils::crypto_utils::keccak256;
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     perpetual::{
         get_collateral_amount, perp_helpers::perp_swap_helpers::_check_note_sums,
@@ -54,7 +54,7 @@ pub struct PositionEscape {
 pub fn verify_position_escape(
     state_tree: &Arc<Mutex<SuperficialTree>>,
     updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
-    firebase_session: &Arc<Mutex<ServiceSession>>,
+    firebase_session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     escape_id: u32,
     position_a: PerpPosition,
@@ -346,7 +346,7 @@ fn handle_counter_party_modify_order(
 fn update_state_after_escape(
     state_tree: &Arc<Mutex<SuperficialTree>>,
     updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
-    firebase_session: &Arc<Mutex<ServiceSession>>,
+    firebase_session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     position_a: PerpPosition,
     new_position_b: PerpPosition,
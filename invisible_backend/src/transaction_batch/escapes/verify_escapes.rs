@@ -1,4 +1,3 @@
-use firestore_db_and_auth::ServiceSession;
 use num_bigint::BigUint;
 use num_traits::FromPrimitive;
 use parking_lot::Mutex;
@@ -13,6 +12,7 @@ use crate::transaction_batch::tx_batch_structs::SwapFundingInfo;
 use crate::trees::superficial_tree::SuperficialTree;
 use crate::utils::crypto_utils::{hash_many, Signature};
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::utils::storage::local_storage::{MainStorage, OnchainActionType};
 use crate::{server::grpc::engine_proto::EscapeMessage, transaction_batch::LeafNodeType};
 
@@ -25,7 +25,7 @@ use super::positon_escapes::verify_position_escape;
 pub fn _execute_forced_escape_inner(
     state_tree: &Arc<Mutex<SuperficialTree>>,
     updated_state_hashes: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
-    firebase_session: &Arc<Mutex<ServiceSession>>,
+    firebase_session: &Arc<Mutex<FirestoreSession>>,
     main_storage: &Arc<Mutex<MainStorage>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     swap_output_json: &Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
@@ -1,4 +1,3 @@
-use firestore_db_and_auth::ServiceSession;
 use num_bigint::BigUint;
 use parking_lot::Mutex;
 use serde_json::{Map, Value};
@@ -12,8 +11,10 @@ use error_stack::Result;
 
 use crate::trees::superficial_tree::SuperficialTree;
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     perpetual::{
+        funding_config::FundingConfig,
         liquidations::{
             liquidation_engine::LiquidationSwap, liquidation_output::LiquidationResponse,
         },
@@ -34,7 +35,7 @@ use crate::utils::{
         TransactionExecutionError,
     },
     notes::Note,
-    storage::firestore::create_session,
+    storage::{firestore::create_session, get_state_positions},
 };
 
 use crate::transactions::swap::SwapResponse;
@@ -52,7 +53,7 @@ use self::{
         batch_transition::{_construct_da_output, _finalize_batch_inner, _transition_state},
         state_modifications::{
             _change_position_margin_inner, _execute_order_tab_modification_inner,
-            _execute_sc_mm_modification_inner, _split_notes_inner,
+            _execute_sc_mm_modification_inner, _set_position_leverage_inner,
         },
     },
     escapes::verify_escapes::{_execute_forced_escape_inner, _get_position_close_escape_info},
@@ -97,20 +98,41 @@ pub struct TransactionBatch {
     //
     pub running_funding_tick_sums: HashMap<u32, i64>, // maps asset id to the sum of all funding ticks in this batch (used for TWAP)
     pub current_funding_count: u16, // maps asset id to the number of funding ticks applied already (used for TWAP, goes up to 480)
+    pub last_funding_slot: Option<u64>, // the timestamp slot of the last funding sample (used to skip repeated and missed samples)
 
     pub funding_rates: HashMap<u32, Vec<i64>>, // maps asset id to an array of funding rates (not reset at new batch)
     pub funding_prices: HashMap<u32, Vec<u64>>, // maps asset id to an array of funding prices (corresponding to the funding rates) (not reset at new batch)
     pub min_funding_idxs: Arc<Mutex<HashMap<u32, u32>>>, // the min funding index of a position being updated in this batch for each asset
+    pub open_interest: Arc<Mutex<HashMap<u32, u64>>>, // maps asset id to the total size of its long positions (not reset at new batch)
+    pub funding_config: FundingConfig, // funding parameters of the markets (read from the exchange config)
     //
-    pub firebase_session: Arc<Mutex<ServiceSession>>, // Firebase session for updating the database in the cloud
-    pub main_storage: Arc<Mutex<MainStorage>>,        // Storage Connection to store data on disk
-    pub backup_storage: Arc<Mutex<BackupStorage>>,    // Storage for failed database updates
+    pub firebase_session: Arc<Mutex<FirestoreSession>>, // Firebase session for updating the database in the cloud
+    pub main_storage: Arc<Mutex<MainStorage>>,          // Storage Connection to store data on disk
+    pub backup_storage: Arc<Mutex<BackupStorage>>,      // Storage for failed database updates
     //
     pub running_index_price_count: u16, // number of index price updates in the current micro batch
 }
 
 impl TransactionBatch {
     pub fn new(tree_depth: u32) -> TransactionBatch {
+        let session = Some(create_session());
+
+        return TransactionBatch::new_with_storage(
+            tree_depth,
+            session,
+            MainStorage::new(),
+            BackupStorage::new(),
+        );
+    }
+
+    /// Creates a new transaction batch with the given firebase session and storage
+    /// (e.g. in-memory storage and no session for testing, so no database updates are written).
+    pub fn new_with_storage(
+        tree_depth: u32,
+        session: FirestoreSession,
+        main_storage: MainStorage,
+        backup_storage: BackupStorage,
+    ) -> TransactionBatch {
         let state_tree = SuperficialTree::new(tree_depth);
         let partial_fill_tracker: HashMap<u64, (Option<Note>, u64)> = HashMap::new();
         let updated_state_hashes: HashMap<u64, (LeafNodeType, BigUint)> = HashMap::new();
@@ -132,8 +154,8 @@ impl TransactionBatch {
         let mut funding_rates: HashMap<u32, Vec<i64>> = HashMap::new();
         let mut funding_prices: HashMap<u32, Vec<u64>> = HashMap::new();
         let mut min_funding_idxs: HashMap<u32, u32> = HashMap::new();
+        let mut open_interest: HashMap<u32, u64> = HashMap::new();
 
-        let session = create_session();
         let session = Arc::new(Mutex::new(session));
 
         // Init empty maps
@@ -144,6 +166,7 @@ impl TransactionBatch {
         _init_empty_tokens_map::<Vec<i64>>(&mut funding_rates);
         _init_empty_tokens_map::<Vec<u64>>(&mut funding_prices);
         _init_empty_tokens_map::<u32>(&mut min_funding_idxs);
+        _init_empty_tokens_map::<u64>(&mut open_interest);
 
         // TODO: For testing only =================================================
         latest_index_price.insert(54321, 2000 * 10u64.pow(6));
@@ -169,14 +192,17 @@ impl TransactionBatch {
             //
             running_funding_tick_sums,
             current_funding_count: 0,
+            last_funding_slot: None,
             funding_rates,
             funding_prices,
             min_funding_idxs: Arc::new(Mutex::new(min_funding_idxs)),
+            open_interest: Arc::new(Mutex::new(open_interest)),
+            funding_config: FundingConfig::load(),
 
             //
             firebase_session: session,
-            main_storage: Arc::new(Mutex::new(MainStorage::new())),
-            backup_storage: Arc::new(Mutex::new(BackupStorage::new())),
+            main_storage: Arc::new(Mutex::new(main_storage)),
+            backup_storage: Arc::new(Mutex::new(backup_storage)),
             //
             running_index_price_count: 0,
         };
@@ -191,6 +217,7 @@ impl TransactionBatch {
             &mut self.funding_rates,
             &mut self.funding_prices,
             &mut self.min_funding_idxs,
+            &mut self.open_interest,
             &mut self.latest_index_price,
             &mut self.min_index_price_data,
             &mut self.max_index_price_data,
@@ -203,6 +230,17 @@ impl TransactionBatch {
             drop(storage);
             self.restore_state(swap_output_json);
         }
+
+        // ? Positions opened before they were stored (or restored from the DA state) are backfilled
+        let state_positions: Vec<PerpPosition> = get_state_positions()
+            .iter()
+            .map(PerpPosition::from_position_output)
+            .collect();
+        let state_tree = self.state_tree.lock();
+        self.main_storage
+            .lock()
+            .backfill_positions(&state_positions, &state_tree);
+        drop(state_tree);
     }
 
     pub fn execute_transaction<T: Transaction + std::marker::Send + 'static>(
@@ -252,20 +290,16 @@ impl TransactionBatch {
 
         let session = Arc::clone(&self.firebase_session);
         let backup_storage = Arc::clone(&self.backup_storage);
+        let main_storage = Arc::clone(&self.main_storage);
 
         let current_index_price = *self
             .latest_index_price
             .get(&transaction.order_a.synthetic_token)
             .unwrap();
         let min_funding_idxs = self.min_funding_idxs.clone();
+        let open_interest = self.open_interest.clone();
 
-        let swap_funding_info = SwapFundingInfo::new(
-            &self.funding_rates,
-            &self.funding_prices,
-            transaction.order_a.synthetic_token,
-            &transaction.order_a.position,
-            &transaction.order_b.position,
-        );
+        let swap_funding_info = self.swap_funding_info(&transaction);
 
         let handle = thread::spawn(move || {
             return transaction.execute(
@@ -277,15 +311,28 @@ impl TransactionBatch {
                 partialy_opened_positions,
                 current_index_price,
                 min_funding_idxs,
+                open_interest,
                 swap_funding_info,
                 session,
                 backup_storage,
+                main_storage,
             );
         });
 
         return handle;
     }
 
+    /// The funding rates and prices applied to the positions updated by the perp swap
+    pub fn swap_funding_info(&self, transaction: &PerpSwap) -> SwapFundingInfo {
+        return SwapFundingInfo::new(
+            &self.funding_rates,
+            &self.funding_prices,
+            transaction.order_a.synthetic_token,
+            &transaction.order_a.position,
+            &transaction.order_b.position,
+        );
+    }
+
     pub fn execute_liquidation_transaction(
         &mut self,
         liquidation_transaction: LiquidationSwap,
@@ -296,6 +343,7 @@ impl TransactionBatch {
 
         let session = self.firebase_session.clone();
         let backup_storage = self.backup_storage.clone();
+        let main_storage = self.main_storage.clone();
 
         let insurance_fund = self.insurance_fund.clone();
 
@@ -304,6 +352,7 @@ impl TransactionBatch {
             .get(&liquidation_transaction.liquidation_order.synthetic_token)
             .unwrap();
         let min_funding_idxs = self.min_funding_idxs.clone();
+        let open_interest = self.open_interest.clone();
 
         let swap_funding_info = SwapFundingInfo::new(
             &self.funding_rates,
@@ -321,9 +370,11 @@ impl TransactionBatch {
                 insurance_fund,
                 current_index_price,
                 min_funding_idxs,
+                open_interest,
                 swap_funding_info,
                 session,
                 backup_storage,
+                main_storage,
             );
         });
 
@@ -331,34 +382,33 @@ impl TransactionBatch {
     }
 
     // * =================================================================
-    // TODO: These two functions should take a constant fee to ensure not being DOSed
-    pub fn split_notes(
-        &mut self,
-        notes_in: Vec<Note>,
-        new_note: Note,
-        refund_note: Option<Note>,
-    ) -> std::result::Result<Vec<u64>, String> {
-        return _split_notes_inner(
+    // TODO: This function should take a constant fee to ensure not being DOSed
+    pub fn change_position_margin(
+        &self,
+        margin_change: ChangeMarginMessage,
+    ) -> std::result::Result<(u64, PerpPosition), String> {
+        return _change_position_margin_inner(
             &self.state_tree,
             &self.updated_state_hashes,
             &self.firebase_session,
             &self.backup_storage,
+            &self.main_storage,
             &self.swap_output_json,
-            notes_in,
-            new_note,
-            refund_note,
+            &self.latest_index_price,
+            margin_change,
         );
     }
 
-    pub fn change_position_margin(
+    pub fn set_position_leverage(
         &self,
         margin_change: ChangeMarginMessage,
     ) -> std::result::Result<(u64, PerpPosition), String> {
-        return _change_position_margin_inner(
+        return _set_position_leverage_inner(
             &self.state_tree,
             &self.updated_state_hashes,
             &self.firebase_session,
             &self.backup_storage,
+            &self.main_storage,
             &self.swap_output_json,
             &self.latest_index_price,
             margin_change,
@@ -462,6 +512,7 @@ impl TransactionBatch {
 
         let tx_batch_index = self.main_storage.lock().latest_batch - 1;
         _construct_da_output(
+            &self.state_tree,
             &self.main_storage,
             &self.funding_rates,
             &self.funding_prices,
@@ -490,10 +541,12 @@ impl TransactionBatch {
             &mut self.running_funding_tick_sums,
             &mut self.latest_index_price,
             &mut self.current_funding_count,
+            &mut self.last_funding_slot,
             &mut self.funding_rates,
             &mut self.funding_prices,
             &self.min_funding_idxs,
             &self.main_storage,
+            &self.funding_config,
             funding_update,
         )
     }
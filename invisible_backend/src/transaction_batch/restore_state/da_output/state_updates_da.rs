@@ -81,6 +81,45 @@ pub fn note_split_da_output(
     }
 }
 
+pub fn transfer_da_output(
+    updated_state_hashes: &HashMap<u64, (LeafNodeType, BigUint)>,
+    note_outputs: &mut Vec<(u64, [BigUint; 4])>,
+    transaction: &Map<String, Value>,
+) {
+    let transfer = transaction.get("transfer").unwrap();
+
+    let notes_out = transfer.get("notes_out").unwrap().as_array().unwrap();
+    for note in notes_out.iter() {
+        append_note_output(updated_state_hashes, note_outputs, &note_from_json(note));
+    }
+
+    let refund_note = transfer.get("refund_note").unwrap();
+    if !refund_note.is_null() {
+        append_note_output(
+            updated_state_hashes,
+            note_outputs,
+            &note_from_json(refund_note),
+        );
+    }
+}
+
+pub fn note_restructure_da_output(
+    updated_state_hashes: &HashMap<u64, (LeafNodeType, BigUint)>,
+    note_outputs: &mut Vec<(u64, [BigUint; 4])>,
+    transaction: &Map<String, Value>,
+) {
+    let notes_out = transaction
+        .get("note_restructure")
+        .unwrap()
+        .get("notes_out")
+        .unwrap()
+        .as_array()
+        .unwrap();
+    for note in notes_out.iter() {
+        append_note_output(updated_state_hashes, note_outputs, &note_from_json(note));
+    }
+}
+
 // * ORDER TABS DA FUNCTIONS ==========================================================================================
 pub fn open_order_tab_da_output(
     updated_state_hashes: &HashMap<u64, (LeafNodeType, BigUint)>,
@@ -217,6 +217,85 @@ pub fn restore_note_split(
     drop(state_tree);
 }
 
+// * TRANSFER RESTORE FUNCTIONS ================================================================================
+
+pub fn restore_transfer(
+    tree_m: &Arc<Mutex<SuperficialTree>>,
+    updated_state_hashes_m: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
+    transaction: &Map<String, Value>,
+) {
+    let mut state_tree = tree_m.lock();
+    let mut updated_state_hashes = updated_state_hashes_m.lock();
+
+    let transfer = transaction.get("transfer").unwrap();
+    let notes_in = transfer.get("notes_in").unwrap().as_array().unwrap();
+    let notes_out = transfer.get("notes_out").unwrap().as_array().unwrap();
+    let refund_note = transfer.get("refund_note").unwrap();
+
+    // ? Add the recipient's notes to the state
+    for note in notes_out.iter() {
+        let idx = note.get("index").unwrap().as_u64().unwrap();
+        let hash = BigUint::from_str(note.get("hash").unwrap().as_str().unwrap()).unwrap();
+
+        state_tree.update_leaf_node(&hash, idx);
+        updated_state_hashes.insert(idx, (LeafNodeType::Note, hash));
+    }
+
+    // ? Replace the first note spent with the refund note and remove the rest
+    let refund_idx = notes_in[0].get("index").unwrap().as_u64().unwrap();
+    let refund_note_hash = if refund_note.is_null() {
+        BigUint::zero()
+    } else {
+        BigUint::from_str(refund_note.get("hash").unwrap().as_str().unwrap()).unwrap()
+    };
+    state_tree.update_leaf_node(&refund_note_hash, refund_idx);
+    updated_state_hashes.insert(refund_idx, (LeafNodeType::Note, refund_note_hash));
+
+    for note in notes_in.iter().skip(1) {
+        let idx = note.get("index").unwrap().as_u64().unwrap();
+
+        state_tree.update_leaf_node(&BigUint::zero(), idx);
+        updated_state_hashes.insert(idx, (LeafNodeType::Note, BigUint::zero()));
+    }
+
+    drop(updated_state_hashes);
+    drop(state_tree);
+}
+
+// * NOTE RESTRUCTURE RESTORE FUNCTIONS ================================================================================
+
+pub fn restore_note_restructure(
+    tree_m: &Arc<Mutex<SuperficialTree>>,
+    updated_state_hashes_m: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
+    transaction: &Map<String, Value>,
+) {
+    let mut state_tree = tree_m.lock();
+    let mut updated_state_hashes = updated_state_hashes_m.lock();
+
+    let restructure = transaction.get("note_restructure").unwrap();
+    let notes_in = restructure.get("notes_in").unwrap().as_array().unwrap();
+    let notes_out = restructure.get("notes_out").unwrap().as_array().unwrap();
+
+    // ? Remove notes in from state (before adding the notes out, since they reuse their indexes)
+    for note in notes_in.iter() {
+        let idx = note.get("index").unwrap().as_u64().unwrap();
+
+        state_tree.update_leaf_node(&BigUint::zero(), idx);
+        updated_state_hashes.insert(idx, (LeafNodeType::Note, BigUint::zero()));
+    }
+
+    for note in notes_out.iter() {
+        let idx = note.get("index").unwrap().as_u64().unwrap();
+        let hash = BigUint::from_str(note.get("hash").unwrap().as_str().unwrap()).unwrap();
+
+        state_tree.update_leaf_node(&hash, idx);
+        updated_state_hashes.insert(idx, (LeafNodeType::Note, hash));
+    }
+
+    drop(updated_state_hashes);
+    drop(state_tree);
+}
+
 // * ONCHAIN MM ACTION ============================================================0
 
 pub fn restore_mm_action(
@@ -6,10 +6,12 @@ use parking_lot::Mutex;
 use serde_json::{Map, Value};
 
 use crate::{
+    perpetual::perp_position::PerpPosition,
     transaction_batch::restore_state::da_output::{
         state_updates_da::{
             close_order_tab_da_ouput, forced_position_escape_da_output, margin_update_da_output,
-            note_split_da_output, onchain_mm_action_da_output, open_order_tab_da_output,
+            note_restructure_da_output, note_split_da_output, onchain_mm_action_da_output,
+            open_order_tab_da_output, transfer_da_output,
         },
         transactions_da::{
             deposit_da_output, liquidations_da_output, perp_swap_da_output, spot_order_da_output,
@@ -17,12 +19,17 @@ use crate::{
         },
     },
     trees::superficial_tree::SuperficialTree,
-    utils::{crypto_utils::hash_many, notes::Note, storage::store_new_state_updates},
+    utils::{
+        notes::Note,
+        storage::{parse_position_data, store_new_state_updates},
+    },
 };
 
 use self::{
     da_output::helpers::{DepositRequest, WithdrawalRequest},
-    helpers::state_helpers::{restore_margin_update, restore_note_split},
+    helpers::state_helpers::{
+        restore_margin_update, restore_note_restructure, restore_note_split, restore_transfer,
+    },
     restore_functions::{
         restore_forced_escapes::{
             restore_forced_note_escape, restore_forced_position_escape, restore_forced_tab_escape,
@@ -41,6 +48,7 @@ use super::LeafNodeType;
 
 pub mod da_output;
 mod helpers;
+pub mod replay;
 mod restore_functions;
 
 // * RESTORE STATE FROM THE TRANSACTION BATCH * //
@@ -124,6 +132,10 @@ pub fn _restore_state_inner(
                 restore_margin_update(&state_tree, &updated_state_hashes, &transaction)
             }
             "note_split" => restore_note_split(&state_tree, &updated_state_hashes, &transaction),
+            "note_restructure" => {
+                restore_note_restructure(&state_tree, &updated_state_hashes, &transaction)
+            }
+            "transfer" => restore_transfer(&state_tree, &updated_state_hashes, &transaction),
             "open_order_tab" => {
                 restore_open_order_tab(&state_tree, &updated_state_hashes, &transaction);
             }
@@ -156,14 +168,70 @@ pub fn _restore_state_inner(
 
 // * DATA AVAILABILITY OUTPUT (Notes/Positions/OrderTabs) * //
 
+/// The note, position and order tab leaves (and the emptied indexes) published in the DA output of a batch
+pub struct DaStateUpdates {
+    pub note_outputs: Vec<(u64, [BigUint; 4])>,
+    pub position_outputs: Vec<(u64, [BigUint; 3])>,
+    pub tab_outputs: Vec<(u64, [BigUint; 4])>,
+    pub zero_indexes: Vec<u64>,
+}
+
+impl DaStateUpdates {
+    /// Stores the new state updates localy on disk
+    pub fn store(&self) {
+        store_new_state_updates(
+            &self.note_outputs,
+            &self.position_outputs,
+            &self.tab_outputs,
+            &self.zero_indexes,
+        );
+    }
+
+    /// The positions updated in the batch
+    pub fn positions(&self) -> Vec<PerpPosition> {
+        return self
+            .position_outputs
+            .iter()
+            .map(|(_, output)| {
+                PerpPosition::from_position_output(&parse_position_data(output.clone()))
+            })
+            .collect();
+    }
+
+    /// Joins all the outputs into a single vector: \
+    /// notes (4 felts each) | positions (3 felts each) | tabs (4 felts each) | zero indexes (3 per felt)
+    pub fn data_output(&self) -> Vec<BigUint> {
+        let mut data_output: Vec<BigUint> = Vec::new();
+
+        for (_, _output) in self.note_outputs.iter() {
+            data_output.extend_from_slice(_output);
+        }
+        for (_, _output) in self.position_outputs.iter() {
+            data_output.extend_from_slice(_output);
+        }
+        for (_, _output) in self.tab_outputs.iter() {
+            data_output.extend_from_slice(_output);
+        }
+        for _chunk in self.zero_indexes.chunks(3) {
+            let mut idx_batched = BigUint::zero();
+
+            for idx in _chunk {
+                idx_batched = idx_batched << 64 | BigUint::from_u64(*idx).unwrap();
+            }
+            data_output.push(idx_batched);
+        }
+
+        return data_output;
+    }
+}
+
 pub fn _get_da_updates_inner(
     updated_state_hashes: &HashMap<u64, (LeafNodeType, BigUint)>,
     funding_rates: &HashMap<u32, Vec<i64>>,
     funding_prices: &HashMap<u32, Vec<u64>>,
     transactions: &Vec<Map<String, Value>>,
 ) -> (
-    BigUint,
-    Vec<String>,
+    DaStateUpdates,
     HashMap<u32, BigUint>,
     HashMap<u32, BigUint>,
     HashMap<u32, Vec<DepositRequest>>,
@@ -264,6 +332,10 @@ pub fn _get_da_updates_inner(
             "note_split" => {
                 note_split_da_output(updated_state_hashes, &mut note_outputs, &transaction)
             }
+            "note_restructure" => {
+                note_restructure_da_output(updated_state_hashes, &mut note_outputs, &transaction)
+            }
+            "transfer" => transfer_da_output(updated_state_hashes, &mut note_outputs, &transaction),
             "open_order_tab" => {
                 open_order_tab_da_output(
                     updated_state_hashes,
@@ -321,51 +393,15 @@ pub fn _get_da_updates_inner(
     tab_outputs.dedup();
     zero_indexes.dedup();
 
-    // ? Store the new state updates localy on disk
-
-    store_new_state_updates(
-        &note_outputs,
-        &position_outputs,
-        &tab_outputs,
-        &zero_indexes,
-    );
-
-    // Join all the outputs into a single vector
-    let mut data_output: Vec<BigUint> = Vec::new();
-
-    for (_, _output) in note_outputs.drain(..) {
-        for el in _output {
-            data_output.push(el);
-        }
-    }
-    for (_, _output) in position_outputs.drain(..) {
-        for el in _output {
-            data_output.push(el);
-        }
-    }
-    for (_, _output) in tab_outputs.drain(..) {
-        for el in _output {
-            data_output.push(el);
-        }
-    }
-    for _chunk in zero_indexes.chunks(3) {
-        let mut idx_batched = BigUint::zero();
-
-        for idx in _chunk {
-            idx_batched = idx_batched << 64 | BigUint::from_u64(*idx).unwrap();
-        }
-        data_output.push(idx_batched);
-    }
-
-    // ? Hash and upload the data output
-    let references: Vec<&BigUint> = data_output.iter().collect();
-    let data_commitment = hash_many(&references);
-
-    let data_output: Vec<String> = data_output.into_iter().map(|el| el.to_string()).collect();
+    let state_updates = DaStateUpdates {
+        note_outputs,
+        position_outputs,
+        tab_outputs,
+        zero_indexes,
+    };
 
     return (
-        data_commitment,
-        data_output,
+        state_updates,
         accumulated_deposit_hashes,
         accumulated_withdrawal_hashes,
         deposit_outputs,
@@ -0,0 +1,995 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    panic::{catch_unwind, AssertUnwindSafe},
+    str::FromStr,
+    sync::Arc,
+    thread::JoinHandle,
+};
+
+use error_stack::{Context, Report};
+use num_bigint::BigUint;
+use num_traits::Zero;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    perpetual::{
+        get_price,
+        liquidations::{liquidation_engine::LiquidationSwap, liquidation_order::LiquidationOrder},
+        perp_order::{CloseOrderFields, OpenOrderFields, PerpOrder},
+        perp_position::PerpPosition,
+        perp_swap::PerpSwap,
+        PositionEffectType,
+    },
+    transaction_batch::{
+        batch_functions::batch_transition::{
+            _finalize_batch_inner, BatchTransitionInfo, TREE_DEPTH,
+        },
+        LeafNodeType, TransactionBatch,
+    },
+    transactions::{
+        deposit::Deposit, limit_order::LimitOrder, note_restructure::NoteRestructure, swap::Swap,
+        transfer::Transfer, withdrawal::Withdrawal,
+    },
+    trees::superficial_tree::SuperficialTree,
+    utils::storage::{
+        backup_storage::BackupStorage,
+        local_storage::{MainStorage, OnchainActionType},
+    },
+};
+
+use super::{_restore_state_inner, helpers::spot_helpers::order_tab_from_json};
+
+// * BATCH REPLAY ================================================================================
+
+/// A difference between the replayed batch and the recorded batch output.
+#[derive(Debug, Clone, Serialize)]
+pub enum ReplayDiff {
+    /// The final leaf hash at an index differs from the one recorded in the batch transition info
+    LeafHash {
+        index: u64,
+        recorded: Option<String>,
+        replayed: Option<String>,
+    },
+    /// The position hash output by the re-executed transaction differs from the recorded one
+    PositionHash {
+        tx_index: usize,
+        position_index: u64,
+        recorded: Option<String>,
+        replayed: Option<String>,
+    },
+    /// The funding index applied by the re-executed transaction differs from the recorded one
+    FundingIdx {
+        tx_index: usize,
+        position_index: u64,
+        recorded: u32,
+        replayed: u32,
+    },
+    /// The min funding index passed to the prover differs from the one of the replayed batch
+    MinFundingIdx {
+        token: u32,
+        recorded: Option<u32>,
+        replayed: Option<u32>,
+    },
+    /// The re-executed swap allocated new indexes that can't be moved to the recorded ones
+    NewIndexes {
+        tx_index: usize,
+        recorded: Vec<u64>,
+        replayed: Vec<u64>,
+    },
+    /// The insurance fund balance at the end of the batch differs from the recorded one
+    InsuranceFund { recorded: i64, replayed: i64 },
+    /// The transaction was rejected by the engine (or could not be rebuilt from its output)
+    ExecutionFailed {
+        tx_index: usize,
+        transaction_type: String,
+        err_msg: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchReplayReport {
+    pub batch_index: u32,
+    pub n_transactions: usize,
+    pub diffs: Vec<ReplayDiff>,
+    /// Transactions applied from their recorded output instead of being re-executed
+    /// (transaction types the replay doesn't rebuild, and fills of orders that were
+    /// partially filled before this batch, since their fill state isn't in the batch).
+    pub restored: Vec<usize>,
+}
+
+impl BatchReplayReport {
+    pub fn is_consistent(&self) -> bool {
+        return self.diffs.is_empty();
+    }
+}
+
+/// Re-executes the transactions of a batch with a fresh in-memory transaction batch,
+/// starting from the state at the end of the previous batch, and compares the
+/// replayed batch with the recorded batch transition info.
+///
+/// # Arguments
+/// * prev_state - the state tree leaves at the end of the previous batch
+/// * transactions - the json output of the transactions in the batch (as stored in tx_db)
+/// * batch_transition_info - the recorded info of the batch after finalization
+/// * prev_insurance_fund - the insurance fund balance at the start of the batch
+///
+/// The replay runs without a firebase session, so none of its database updates are written.
+///
+pub fn replay_batch(
+    batch_index: u32,
+    prev_state: SuperficialTree,
+    transactions: Vec<Map<String, Value>>,
+    batch_transition_info: &BatchTransitionInfo,
+    prev_insurance_fund: i64,
+) -> BatchReplayReport {
+    let mut diffs: Vec<ReplayDiff> = Vec::new();
+    let mut restored: Vec<usize> = Vec::new();
+
+    let n_transactions = transactions.len();
+
+    let mut tx_batch = TransactionBatch::new_with_storage(
+        TREE_DEPTH,
+        None,
+        MainStorage::new_temporary(),
+        BackupStorage::new_temporary(),
+    );
+
+    // ? The zero indexes are recomputed from the leaves at the end of every batch
+    let mut prev_state = prev_state;
+    prev_state.update_zero_idxs();
+    *tx_batch.state_tree.lock() = prev_state;
+    *tx_batch.insurance_fund.lock() = prev_insurance_fund;
+
+    let (funding_rates, funding_prices) = get_batch_funding_info(batch_transition_info);
+
+    // ? (transaction_type, order_id) of the orders filled by the replay and of the orders
+    // ? that were partially filled before this batch
+    let mut filled_orders: HashSet<(String, u64)> = HashSet::new();
+    let mut carried_orders: HashSet<(String, u64)> = HashSet::new();
+
+    for (tx_index, transaction) in transactions.iter().enumerate() {
+        let transaction_type = transaction
+            .get("transaction_type")
+            .and_then(|t| t.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        if !is_replayable(transaction, &filled_orders, &mut carried_orders) {
+            _restore_state_inner(
+                &tx_batch.state_tree,
+                &tx_batch.updated_state_hashes,
+                &tx_batch.perpetual_partial_fill_tracker,
+                vec![transaction.clone()],
+            );
+
+            restored.push(tx_index);
+            continue;
+        }
+
+        // ? Execute the transaction with the funding rates and price it was executed with
+        set_execution_prices(&mut tx_batch, transaction, &funding_rates, &funding_prices);
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            replay_transaction(&mut tx_batch, transaction)
+        }));
+        let err_msg = match res {
+            Ok(Ok(())) => None,
+            Ok(Err(err_msg)) => Some(err_msg),
+            Err(_) => Some("replay panicked".to_string()),
+        };
+        if let Some(err_msg) = err_msg {
+            diffs.push(ReplayDiff::ExecutionFailed {
+                tx_index,
+                transaction_type,
+                err_msg,
+            });
+            continue;
+        }
+
+        for (order_id, _) in swap_orders(transaction) {
+            filled_orders.insert((transaction_type.clone(), order_id));
+        }
+
+        let replayed_output = tx_batch.swap_output_json.lock().last().cloned();
+        if let Some(replayed_output) = replayed_output {
+            align_new_indexes(
+                &tx_batch,
+                tx_index,
+                transaction,
+                &replayed_output,
+                &mut diffs,
+            );
+            diff_transaction_output(tx_index, transaction, &replayed_output, &mut diffs);
+        }
+    }
+
+    // ? Finalize the replayed batch with all the funding rates of the batch
+    tx_batch.funding_rates = funding_rates;
+    tx_batch.funding_prices = funding_prices;
+
+    let replayed_info = _finalize_batch_inner(
+        &tx_batch.state_tree,
+        &tx_batch.updated_state_hashes,
+        &tx_batch.swap_output_json,
+        &tx_batch.main_storage,
+        &tx_batch.insurance_fund,
+        &mut tx_batch.funding_rates,
+        &mut tx_batch.funding_prices,
+        &tx_batch.min_funding_idxs,
+        &mut tx_batch.min_index_price_data,
+        &mut tx_batch.max_index_price_data,
+    );
+
+    diff_leaf_hashes(
+        &batch_transition_info.updated_state_hashes,
+        &replayed_info.updated_state_hashes,
+        &mut diffs,
+    );
+
+    // ? Compare the funding indexes passed to the prover
+    let recorded_min_funding_idxs =
+        parse_min_funding_idxs(&batch_transition_info.funding_info.funding_rates);
+    let replayed_min_funding_idxs =
+        parse_min_funding_idxs(&replayed_info.funding_info.funding_rates);
+    let mut tokens: Vec<u32> = recorded_min_funding_idxs
+        .keys()
+        .chain(replayed_min_funding_idxs.keys())
+        .copied()
+        .collect::<HashSet<u32>>()
+        .into_iter()
+        .collect();
+    tokens.sort_unstable();
+    for token in tokens {
+        let recorded = recorded_min_funding_idxs.get(&token).copied();
+        let replayed = replayed_min_funding_idxs.get(&token).copied();
+        if recorded != replayed {
+            diffs.push(ReplayDiff::MinFundingIdx {
+                token,
+                recorded,
+                replayed,
+            });
+        }
+    }
+
+    // ? Compare the insurance fund
+    let recorded_insurance_fund = get_insurance_fund(batch_transition_info);
+    let replayed_insurance_fund = get_insurance_fund(&replayed_info);
+    if recorded_insurance_fund != replayed_insurance_fund {
+        diffs.push(ReplayDiff::InsuranceFund {
+            recorded: recorded_insurance_fund,
+            replayed: replayed_insurance_fund,
+        });
+    }
+
+    return BatchReplayReport {
+        batch_index,
+        n_transactions,
+        diffs,
+        restored,
+    };
+}
+
+// * EXECUTION ================================================================================
+
+/// Rebuilds the transaction from its json output and executes it in the transaction batch.
+fn replay_transaction(
+    tx_batch: &mut TransactionBatch,
+    transaction: &Map<String, Value>,
+) -> Result<(), String> {
+    let transaction_type = transaction
+        .get("transaction_type")
+        .and_then(|t| t.as_str())
+        .unwrap_or_default();
+
+    match transaction_type {
+        "deposit" => {
+            let deposit_json = get_json(transaction, "deposit")?;
+            let deposit = Deposit {
+                transaction_type: "deposit".to_string(),
+                deposit_id: field(deposit_json, "deposit_id")?,
+                deposit_token: field(deposit_json, "deposit_token")?,
+                deposit_amount: field(deposit_json, "deposit_amount")?,
+                stark_key: biguint_field(deposit_json, "stark_key")?,
+                notes: field(deposit_json, "notes")?,
+                signature: field(deposit_json, "signature")?,
+            };
+
+            // ? The deposit was registered onchain before it was executed
+            tx_batch.main_storage.lock().register_onchain_action(
+                OnchainActionType::Deposit,
+                deposit.deposit_id,
+                deposit.get_action_commitment(),
+            );
+
+            join(tx_batch.execute_transaction(deposit))?;
+        }
+        "withdrawal" => {
+            let withdrawal_json = get_json(transaction, "withdrawal")?;
+            let withdrawal = Withdrawal {
+                transaction_type: "withdrawal".to_string(),
+                withdrawal_id: 0, // not part of the output (nor of the signed withdrawal hash)
+                chain_id: field(withdrawal_json, "chain_id")?,
+                token: field(withdrawal_json, "token")?,
+                amount: field(withdrawal_json, "amount")?,
+                recipient: biguint_field(withdrawal_json, "recipient")?,
+                max_gas_fee: field(withdrawal_json, "max_gas_fee")?,
+                notes_in: field(withdrawal_json, "notes_in")?,
+                refund_note: field(withdrawal_json, "refund_note")?,
+                signature: field(withdrawal_json, "signature")?,
+                execution_gas_fee: field(&Value::Object(transaction.clone()), "execution_gas_fee")?,
+            };
+
+            join(tx_batch.execute_transaction(withdrawal))?;
+        }
+        "transfer" => {
+            let transfer_json = get_json(transaction, "transfer")?;
+            let transfer = Transfer {
+                transaction_type: "transfer".to_string(),
+                token: field(transfer_json, "token")?,
+                recipient: field(transfer_json, "recipient")?,
+                notes_in: field(transfer_json, "notes_in")?,
+                notes_out: field(transfer_json, "notes_out")?,
+                refund_note: field(transfer_json, "refund_note")?,
+                signature: field(transfer_json, "signature")?,
+            };
+
+            join(tx_batch.execute_transaction(transfer))?;
+        }
+        "note_restructure" => {
+            let restructure_json = get_json(transaction, "note_restructure")?;
+            let restructure = NoteRestructure {
+                transaction_type: "note_restructure".to_string(),
+                token: field(restructure_json, "token")?,
+                notes_in: field(restructure_json, "notes_in")?,
+                notes_out: field(restructure_json, "notes_out")?,
+                fee: field(restructure_json, "fee")?,
+                signature: field(restructure_json, "signature")?,
+            };
+
+            join(tx_batch.execute_transaction(restructure))?;
+        }
+        "swap" => {
+            let swap_json = get_json(transaction, "swap_data")?;
+
+            let mut orders: Vec<LimitOrder> = Vec::new();
+            for (order_key, tab_key) in [
+                ("order_a", "prev_order_tab_a"),
+                ("order_b", "prev_order_tab_b"),
+            ] {
+                let mut order: LimitOrder = field(swap_json, order_key)?;
+
+                // ? Tab orders are filled from the order tab as it was before the swap
+                let prev_order_tab = get_json(transaction, tab_key)?;
+                if !prev_order_tab.is_null() {
+                    let order_tab = order_tab_from_json(prev_order_tab);
+                    order.order_tab = Some(Arc::new(Mutex::new(order_tab)));
+                }
+
+                orders.push(order);
+            }
+            let order_b = orders.pop().unwrap();
+            let order_a = orders.pop().unwrap();
+
+            let swap = Swap::new(
+                order_a,
+                order_b,
+                field(swap_json, "signature_a")?,
+                field(swap_json, "signature_b")?,
+                field(swap_json, "spent_amount_a")?,
+                field(swap_json, "spent_amount_b")?,
+                field(swap_json, "fee_taken_a")?,
+                field(swap_json, "fee_taken_b")?,
+            );
+
+            join(tx_batch.execute_transaction(swap))?;
+        }
+        "perpetual_swap" => {
+            let swap_json = get_json(transaction, "swap_data")?;
+
+            let order_a = perp_order_from_json(
+                get_json(transaction, "order_a")?,
+                field(&Value::Object(transaction.clone()), "prev_position_a")?,
+            )?;
+            let order_b = perp_order_from_json(
+                get_json(transaction, "order_b")?,
+                field(&Value::Object(transaction.clone()), "prev_position_b")?,
+            )?;
+
+            let perp_swap = PerpSwap::new(
+                order_a,
+                order_b,
+                field(swap_json, "signature_a")?,
+                field(swap_json, "signature_b")?,
+                field(swap_json, "spent_collateral")?,
+                field(swap_json, "spent_synthetic")?,
+                field(swap_json, "fee_taken_a")?,
+                field(swap_json, "fee_taken_b")?,
+            );
+
+            join(tx_batch.execute_perpetual_transaction(perp_swap))?;
+        }
+        "liquidation_order" => {
+            let order_json = get_json(transaction, "liquidation_order")?;
+
+            let liquidation_order = LiquidationOrder::new(
+                field(order_json, "position")?,
+                field(order_json, "order_side")?,
+                field(order_json, "synthetic_token")?,
+                field(order_json, "synthetic_amount")?,
+                field(order_json, "collateral_amount")?,
+                open_order_fields_from_json(get_json_value(order_json, "open_order_fields")?)?,
+            );
+
+            let transaction_json = Value::Object(transaction.clone());
+            let liquidation_swap = LiquidationSwap::new(
+                liquidation_order,
+                field(&transaction_json, "signature")?,
+                field(&transaction_json, "market_price")?,
+            );
+
+            join(tx_batch.execute_liquidation_transaction(liquidation_swap))?;
+        }
+        _ => {
+            return Err(format!(
+                "{} transactions can't be replayed",
+                transaction_type
+            ));
+        }
+    }
+
+    return Ok(());
+}
+
+/// Whether the transaction can be re-executed with the state of the replay. Fills of orders
+/// that were partially filled in a previous batch depend on the partial fill info of the
+/// engine at the start of the batch, so these are restored from the recorded output.
+fn is_replayable(
+    transaction: &Map<String, Value>,
+    filled_orders: &HashSet<(String, u64)>,
+    carried_orders: &mut HashSet<(String, u64)>,
+) -> bool {
+    let transaction_type = transaction
+        .get("transaction_type")
+        .and_then(|t| t.as_str())
+        .unwrap_or_default();
+
+    match transaction_type {
+        "deposit" | "withdrawal" | "transfer" | "note_restructure" | "liquidation_order" => {
+            return true;
+        }
+        "swap" | "perpetual_swap" => {}
+        _ => return false,
+    }
+
+    let mut is_replayable = true;
+    for (order_id, is_later_fill) in swap_orders(transaction) {
+        let order = (transaction_type.to_string(), order_id);
+
+        if carried_orders.contains(&order) || (is_later_fill && !filled_orders.contains(&order)) {
+            carried_orders.insert(order);
+            is_replayable = false;
+        }
+    }
+
+    return is_replayable;
+}
+
+/// Sets the funding rates and index price of the market to the ones at the time the
+/// perpetual transaction was executed (the funding index is recorded in the output).
+fn set_execution_prices(
+    tx_batch: &mut TransactionBatch,
+    transaction: &Map<String, Value>,
+    funding_rates: &HashMap<u32, Vec<i64>>,
+    funding_prices: &HashMap<u32, Vec<u64>>,
+) {
+    let transaction_json = Value::Object(transaction.clone());
+
+    let (synthetic_token, funding_idx, index_price) =
+        match transaction.get("transaction_type").and_then(|t| t.as_str()) {
+            Some("perpetual_swap") => {
+                let synthetic_token = transaction_json
+                    .pointer("/order_a/synthetic_token")
+                    .and_then(|t| t.as_u64());
+                let funding_idx = transaction_json
+                    .pointer("/indexes/order_a/new_funding_idx")
+                    .and_then(|i| i.as_u64());
+                let spent_collateral = transaction_json
+                    .pointer("/swap_data/spent_collateral")
+                    .and_then(|a| a.as_u64());
+                let spent_synthetic = transaction_json
+                    .pointer("/swap_data/spent_synthetic")
+                    .and_then(|a| a.as_u64());
+
+                // ? The index price is only used for the margin checks, so the swap price is used
+                let (
+                    Some(synthetic_token),
+                    Some(funding_idx),
+                    Some(spent_collateral),
+                    Some(spent_synthetic),
+                ) = (
+                    synthetic_token,
+                    funding_idx,
+                    spent_collateral,
+                    spent_synthetic,
+                )
+                else {
+                    return;
+                };
+                if spent_synthetic == 0 {
+                    return;
+                }
+                let synthetic_token = synthetic_token as u32;
+
+                (
+                    synthetic_token,
+                    funding_idx,
+                    get_price(synthetic_token, spent_collateral, spent_synthetic),
+                )
+            }
+            Some("liquidation_order") => {
+                let synthetic_token = transaction_json
+                    .pointer("/liquidation_order/synthetic_token")
+                    .and_then(|t| t.as_u64());
+                let funding_idx = transaction_json
+                    .pointer("/indexes/new_funding_idx")
+                    .and_then(|i| i.as_u64());
+                let index_price = transaction_json.get("index_price").and_then(|p| p.as_u64());
+
+                let (Some(synthetic_token), Some(funding_idx), Some(index_price)) =
+                    (synthetic_token, funding_idx, index_price)
+                else {
+                    return;
+                };
+
+                (synthetic_token as u32, funding_idx, index_price)
+            }
+            _ => return,
+        };
+
+    let rates = funding_rates
+        .get(&synthetic_token)
+        .cloned()
+        .unwrap_or_default();
+    let prices = funding_prices
+        .get(&synthetic_token)
+        .cloned()
+        .unwrap_or_default();
+
+    tx_batch.funding_rates.insert(
+        synthetic_token,
+        rates[..(funding_idx as usize).min(rates.len())].to_vec(),
+    );
+    tx_batch.funding_prices.insert(
+        synthetic_token,
+        prices[..(funding_idx as usize).min(prices.len())].to_vec(),
+    );
+    tx_batch
+        .latest_index_price
+        .insert(synthetic_token, index_price);
+}
+
+fn join<T, C: Context + Debug>(
+    handle: JoinHandle<std::result::Result<T, Report<C>>>,
+) -> Result<T, String> {
+    return match handle.join() {
+        Ok(Ok(res)) => Ok(res),
+        Ok(Err(err)) => Err(format!("{:?}", err.current_context())),
+        Err(_) => Err("execution panicked".to_string()),
+    };
+}
+
+// * JSON PARSING ================================================================================
+
+fn get_json<'a>(transaction: &'a Map<String, Value>, key: &str) -> Result<&'a Value, String> {
+    return transaction
+        .get(key)
+        .ok_or(format!("missing {} in the transaction output", key));
+}
+
+fn get_json_value<'a>(json: &'a Value, key: &str) -> Result<&'a Value, String> {
+    return json
+        .get(key)
+        .ok_or(format!("missing {} in the transaction output", key));
+}
+
+fn field<T: DeserializeOwned>(json: &Value, key: &str) -> Result<T, String> {
+    let value = get_json_value(json, key)?;
+
+    return serde_json::from_value(value.clone())
+        .map_err(|err| format!("invalid {} in the transaction output: {}", key, err));
+}
+
+fn biguint_field(json: &Value, key: &str) -> Result<BigUint, String> {
+    let value: String = field(json, key)?;
+
+    return BigUint::from_str(&value)
+        .map_err(|_| format!("invalid {} in the transaction output", key));
+}
+
+fn open_order_fields_from_json(fields_json: &Value) -> Result<OpenOrderFields, String> {
+    return Ok(OpenOrderFields {
+        initial_margin: field(fields_json, "initial_margin")?,
+        collateral_token: field(fields_json, "collateral_token")?,
+        notes_in: field(fields_json, "notes_in")?,
+        refund_note: field(fields_json, "refund_note")?,
+        position_address: biguint_field(fields_json, "position_address")?,
+        allow_partial_liquidations: field(fields_json, "allow_partial_liquidations")?,
+    });
+}
+
+/// Rebuilds a perp order from its output, modify and close orders reference the position
+/// as it was before the swap.
+fn perp_order_from_json(
+    order_json: &Value,
+    prev_position: Option<PerpPosition>,
+) -> Result<PerpOrder, String> {
+    let order_id: u64 = field(order_json, "order_id")?;
+    let expiration_timestamp: u64 = field(order_json, "expiration_timestamp")?;
+    let position_effect_type: PositionEffectType = field(order_json, "position_effect_type")?;
+
+    let order = match position_effect_type {
+        PositionEffectType::Open => PerpOrder::new_open_order(
+            order_id,
+            expiration_timestamp,
+            field(order_json, "order_side")?,
+            field(order_json, "synthetic_token")?,
+            field(order_json, "synthetic_amount")?,
+            field(order_json, "collateral_amount")?,
+            field(order_json, "fee_limit")?,
+            open_order_fields_from_json(get_json_value(order_json, "open_order_fields")?)?,
+        ),
+        PositionEffectType::Modify => PerpOrder::new_modify_order(
+            order_id,
+            expiration_timestamp,
+            prev_position.ok_or("modify order without a previous position".to_string())?,
+            field(order_json, "order_side")?,
+            field(order_json, "synthetic_token")?,
+            field(order_json, "synthetic_amount")?,
+            field(order_json, "collateral_amount")?,
+            field(order_json, "fee_limit")?,
+        ),
+        PositionEffectType::Close => {
+            let fields_json = get_json_value(order_json, "close_order_fields")?;
+            let close_order_fields = CloseOrderFields {
+                dest_received_address: field(fields_json, "dest_received_address")?,
+                dest_received_blinding: biguint_field(fields_json, "dest_received_blinding")?,
+            };
+
+            PerpOrder::new_close_order(
+                order_id,
+                expiration_timestamp,
+                prev_position.ok_or("close order without a previous position".to_string())?,
+                field(order_json, "order_side")?,
+                field(order_json, "synthetic_token")?,
+                field(order_json, "synthetic_amount")?,
+                field(order_json, "collateral_amount")?,
+                field(order_json, "fee_limit")?,
+                close_order_fields,
+            )
+        }
+    };
+
+    return Ok(order);
+}
+
+/// The ids of the orders filled by a swap (spot or perpetual) and whether the swap
+/// was a later fill of the order (it spent the partial fill refund note of a previous fill).
+fn swap_orders(transaction: &Map<String, Value>) -> Vec<(u64, bool)> {
+    let orders_ptr = match transaction.get("transaction_type").and_then(|t| t.as_str()) {
+        Some("swap") => "/swap_data",
+        Some("perpetual_swap") => "",
+        _ => return vec![],
+    };
+
+    let transaction_json = Value::Object(transaction.clone());
+
+    return [
+        ("order_a", "prev_pfr_note_a"),
+        ("order_b", "prev_pfr_note_b"),
+    ]
+    .iter()
+    .filter_map(|(order_key, prev_pfr_key)| {
+        let order_id = transaction_json
+            .pointer(&format!("{}/{}/order_id", orders_ptr, order_key))?
+            .as_u64()?;
+        let is_later_fill = transaction
+            .get(*prev_pfr_key)
+            .is_some_and(|note| !note.is_null());
+
+        Some((order_id, is_later_fill))
+    })
+    .collect();
+}
+
+// * INDEX ALIGNMENT ================================================================================
+
+/// Pointers to the state indexes allocated by the orders of a swap in its json output.
+fn new_index_pointers(transaction_type: &str) -> Vec<&'static str> {
+    return match transaction_type {
+        "swap" => vec![
+            "/indexes/order_a/swap_note_idx",
+            "/indexes/order_a/partial_fill_idx",
+            "/indexes/order_b/swap_note_idx",
+            "/indexes/order_b/partial_fill_idx",
+        ],
+        "perpetual_swap" => vec![
+            "/indexes/order_a/position_idx",
+            "/indexes/order_a/new_pfr_idx",
+            "/indexes/order_a/return_collateral_idx",
+            "/indexes/order_b/position_idx",
+            "/indexes/order_b/new_pfr_idx",
+            "/indexes/order_b/return_collateral_idx",
+        ],
+        _ => vec![],
+    };
+}
+
+/// The two orders of a swap are executed in parallel threads, so the engine can hand out
+/// their new indexes in either order. This moves the leaves allocated by the replay to the
+/// indexes recorded for the same notes and positions, so the following transactions (which
+/// are rebuilt with the recorded indexes) are replayed against the same state.
+fn align_new_indexes(
+    tx_batch: &TransactionBatch,
+    tx_index: usize,
+    recorded: &Map<String, Value>,
+    replayed: &Map<String, Value>,
+    diffs: &mut Vec<ReplayDiff>,
+) {
+    let transaction_type = recorded
+        .get("transaction_type")
+        .and_then(|t| t.as_str())
+        .unwrap_or_default();
+    if replayed.get("transaction_type").and_then(|t| t.as_str()) != Some(transaction_type) {
+        return;
+    }
+
+    let recorded = Value::Object(recorded.clone());
+    let replayed = Value::Object(replayed.clone());
+
+    // ? Maps the indexes allocated by the replay to the recorded ones (0 means no new note)
+    let mut index_map: HashMap<u64, u64> = HashMap::new();
+    let mut recorded_idxs: Vec<u64> = Vec::new();
+    let mut replayed_idxs: Vec<u64> = Vec::new();
+    for ptr in new_index_pointers(transaction_type) {
+        let recorded_idx = recorded.pointer(ptr).and_then(|i| i.as_u64());
+        let replayed_idx = replayed.pointer(ptr).and_then(|i| i.as_u64());
+
+        if let (Some(recorded_idx), Some(replayed_idx)) = (recorded_idx, replayed_idx) {
+            recorded_idxs.push(recorded_idx);
+            replayed_idxs.push(replayed_idx);
+
+            if recorded_idx != replayed_idx {
+                index_map.insert(replayed_idx, recorded_idx);
+            }
+        }
+    }
+    if index_map.is_empty() {
+        return;
+    }
+
+    // ? A leaf can only be moved to an index the replay also allocated, or to an index it left
+    // ? empty (open orders allocate indexes that end up unused, e.g. when adding to a position)
+    let mut state_tree = tx_batch.state_tree.lock();
+    let is_aligned = index_map.len() == index_map.values().collect::<HashSet<_>>().len()
+        && index_map.iter().all(|(replayed_idx, recorded_idx)| {
+            *replayed_idx != 0
+                && *recorded_idx != 0
+                && (index_map.contains_key(recorded_idx)
+                    || state_tree.get_leaf_by_index(*recorded_idx).is_zero())
+        });
+    if !is_aligned {
+        diffs.push(ReplayDiff::NewIndexes {
+            tx_index,
+            recorded: recorded_idxs,
+            replayed: replayed_idxs,
+        });
+        return;
+    }
+
+    let leaves: Vec<(u64, BigUint)> = index_map
+        .iter()
+        .map(|(replayed_idx, recorded_idx)| {
+            (*recorded_idx, state_tree.get_leaf_by_index(*replayed_idx))
+        })
+        .collect();
+    // ? The vacated indexes stay allocated (like the unused ones of the live engine)
+    for replayed_idx in index_map.keys() {
+        if let Some(leaf) = state_tree.leaf_nodes.get_mut(*replayed_idx as usize) {
+            *leaf = BigUint::zero();
+        }
+    }
+    for (idx, leaf) in leaves.iter() {
+        state_tree.update_leaf_node(leaf, *idx);
+    }
+    drop(state_tree);
+
+    let mut updated_state_hashes = tx_batch.updated_state_hashes.lock();
+    let updates: Vec<(u64, Option<(LeafNodeType, BigUint)>)> = index_map
+        .iter()
+        .map(|(replayed_idx, recorded_idx)| {
+            (*recorded_idx, updated_state_hashes.remove(replayed_idx))
+        })
+        .collect();
+    for (idx, update) in updates {
+        if let Some(update) = update {
+            updated_state_hashes.insert(idx, update);
+        }
+    }
+    drop(updated_state_hashes);
+
+    // ? The partial fill notes and partially opened positions are spent by the next fills
+    for (pfr_note, _) in tx_batch.partial_fill_tracker.lock().values_mut() {
+        if let Some(note) = pfr_note {
+            if let Some(idx) = index_map.get(&note.index) {
+                note.index = *idx;
+            }
+        }
+    }
+    for (pfr_note, _, _) in tx_batch.perpetual_partial_fill_tracker.lock().values_mut() {
+        if let Some(note) = pfr_note {
+            if let Some(idx) = index_map.get(&note.index) {
+                note.index = *idx;
+            }
+        }
+    }
+    for (position, _) in tx_batch.partialy_opened_positions.lock().values_mut() {
+        if let Some(idx) = index_map.get(&position.index) {
+            position.index = *idx;
+        }
+    }
+}
+
+// * DIFFS ================================================================================
+
+fn diff_leaf_hashes(
+    recorded_hashes: &HashMap<u64, (LeafNodeType, BigUint)>,
+    replayed_hashes: &HashMap<u64, (LeafNodeType, BigUint)>,
+    diffs: &mut Vec<ReplayDiff>,
+) {
+    let indexes: HashSet<&u64> = recorded_hashes
+        .keys()
+        .chain(replayed_hashes.keys())
+        .collect();
+    let mut indexes: Vec<u64> = indexes.into_iter().copied().collect();
+    indexes.sort_unstable();
+
+    for index in indexes {
+        let recorded = recorded_hashes.get(&index).map(|(_, h)| h.to_string());
+        let replayed = replayed_hashes.get(&index).map(|(_, h)| h.to_string());
+
+        if recorded != replayed {
+            diffs.push(ReplayDiff::LeafHash {
+                index,
+                recorded,
+                replayed,
+            });
+        }
+    }
+}
+
+/// Compares the positions and funding indexes output by a re-executed perpetual
+/// transaction with the recorded ones.
+fn diff_transaction_output(
+    tx_index: usize,
+    recorded: &Map<String, Value>,
+    replayed: &Map<String, Value>,
+    diffs: &mut Vec<ReplayDiff>,
+) {
+    // & (position hash, position index, funding index) pointers in the output
+    let positions = match recorded.get("transaction_type").and_then(|t| t.as_str()) {
+        Some("perpetual_swap") => [
+            (
+                "/new_position_hash_a",
+                "/indexes/order_a/position_idx",
+                "/indexes/order_a/new_funding_idx",
+            ),
+            (
+                "/new_position_hash_b",
+                "/indexes/order_b/position_idx",
+                "/indexes/order_b/new_funding_idx",
+            ),
+        ],
+        Some("liquidation_order") => [
+            (
+                "/new_liquidated_position_hash",
+                "/liquidation_order/position/index",
+                "/indexes/new_funding_idx",
+            ),
+            (
+                "/new_position_hash",
+                "/indexes/new_position_index",
+                "/indexes/new_funding_idx",
+            ),
+        ],
+        _ => return,
+    };
+
+    let recorded = Value::Object(recorded.clone());
+    let replayed = Value::Object(replayed.clone());
+
+    for (hash_ptr, index_ptr, funding_idx_ptr) in positions {
+        let position_index = recorded
+            .pointer(index_ptr)
+            .and_then(|i| i.as_u64())
+            .unwrap_or_default();
+
+        let recorded_hash = recorded
+            .pointer(hash_ptr)
+            .and_then(|h| h.as_str())
+            .map(|h| h.to_string());
+        let replayed_hash = replayed
+            .pointer(hash_ptr)
+            .and_then(|h| h.as_str())
+            .map(|h| h.to_string());
+        if recorded_hash != replayed_hash {
+            diffs.push(ReplayDiff::PositionHash {
+                tx_index,
+                position_index,
+                recorded: recorded_hash,
+                replayed: replayed_hash,
+            });
+        }
+
+        let recorded_funding_idx = recorded.pointer(funding_idx_ptr).and_then(|i| i.as_u64());
+        let replayed_funding_idx = replayed.pointer(funding_idx_ptr).and_then(|i| i.as_u64());
+        if recorded_funding_idx != replayed_funding_idx {
+            diffs.push(ReplayDiff::FundingIdx {
+                tx_index,
+                position_index,
+                recorded: recorded_funding_idx.unwrap_or_default() as u32,
+                replayed: replayed_funding_idx.unwrap_or_default() as u32,
+            });
+        }
+    }
+}
+
+// * HELPERS ================================================================================
+
+/// The funding rates and prices stored at the end of the batch (these are never reset,
+/// so they cover every funding index used by the transactions in the batch).
+pub fn get_batch_funding_info(
+    batch_transition_info: &BatchTransitionInfo,
+) -> (HashMap<u32, Vec<i64>>, HashMap<u32, Vec<u64>>) {
+    let state_storage = &batch_transition_info.exchange_state_storage;
+
+    let funding_rates: HashMap<u32, Vec<i64>> = state_storage
+        .get("funding_rates")
+        .and_then(|v| serde_json::from_value(v.clone()).ok())
+        .unwrap_or_default();
+    let funding_prices: HashMap<u32, Vec<u64>> = state_storage
+        .get("funding_prices")
+        .and_then(|v| serde_json::from_value(v.clone()).ok())
+        .unwrap_or_default();
+
+    return (funding_rates, funding_prices);
+}
+
+fn get_insurance_fund(batch_transition_info: &BatchTransitionInfo) -> i64 {
+    return batch_transition_info
+        .exchange_state_storage
+        .get("insurance_fund")
+        .and_then(|v| v.as_i64())
+        .unwrap_or_default();
+}
+
+/// Parses the min funding index of each token from the FundingInfo.funding_rates layout: \
+/// \[0] = token id, \[1] = min_funding_idx, \[2] = token funding_rates len (n), \[3..3+n] = funding_rates, ...
+fn parse_min_funding_idxs(funding_rates: &[i64]) -> HashMap<u32, u32> {
+    let mut min_funding_idxs: HashMap<u32, u32> = HashMap::new();
+
+    let mut i = 0;
+    while i + 2 < funding_rates.len() {
+        let token = funding_rates[i] as u32;
+        let min_funding_idx = funding_rates[i + 1] as u32;
+        let n_rates = funding_rates[i + 2] as usize;
+
+        min_funding_idxs.insert(token, min_funding_idx);
+
+        i += 3 + n_rates;
+    }
+
+    return min_funding_idxs;
+}
@@ -14,7 +14,7 @@ use std::{
 };
 
 use crate::{
-    perpetual::{perp_position::PerpPosition, SYNTHETIC_ASSETS},
+    perpetual::{funding_config::FundingConfig, perp_position::PerpPosition, SYNTHETIC_ASSETS},
     trees::superficial_tree::SuperficialTree,
     utils::notes::Note,
 };
@@ -399,23 +399,63 @@ pub fn _per_minute_funding_update_inner(
     return sum + update;
 }
 
+/// Calculates the funding rate of a settlement from the sum of the premium samples
+///
+/// funding_rate = premium + clamp(interest_rate - premium, -damper, damper)\
+/// The rate is clamped to the market's floor and cap (per realization period) and
+/// scaled to a single settlement.
+///
+/// # Arguments
+/// * `funding_config` - The funding parameters of the markets
+/// * `token` - The synthetic token
+/// * `premium_sum` - The sum of the per minute premium samples
+/// * `sample_count` - The number of samples to average over (missed samples count as a zero premium)
+///
+/// # Returns
+/// * `i64` - The funding rate applied at the settlement
+pub fn get_funding_rate(
+    funding_config: &FundingConfig,
+    token: u32,
+    premium_sum: i64,
+    sample_count: u16,
+) -> i64 {
+    let premium = if sample_count == 0 {
+        0
+    } else {
+        premium_sum / sample_count as i64
+    };
+
+    let interest_rate = funding_config.interest_rate(token);
+    let damper = funding_config.interest_damper;
+    let funding_rate = premium + (interest_rate - premium).clamp(-damper, damper);
+
+    let (min_rate, max_rate) = funding_config.rate_bounds(token);
+
+    return funding_rate.clamp(min_rate, max_rate) / funding_config.realization_periods;
+}
+
 /// Calculates the funding rate to apply to all positions
-/// It is the twap of the per minute funding updates over the last 8 hours
+/// It is the twap of the premium samples over the settlement period
 ///
 /// # Returns
 /// * `HashMap<u64, i64>` - The funding rates for each token
 pub fn _calculate_funding_rates(
     running_funding_tick_sums: &mut HashMap<u32, i64>,
+    funding_config: &FundingConfig,
 ) -> HashMap<u32, i64> {
-    // Should do once every hour (60 minutes)
-
     let mut funding_rates: HashMap<u32, i64> = HashMap::new();
 
     for t in SYNTHETIC_ASSETS {
         let twap_sum = running_funding_tick_sums.remove(&t).unwrap_or(0);
 
-        let funding_premium = twap_sum / 60; // divide by 60 to get the average funding premium
-        funding_rates.insert(t, funding_premium / 8); // scale to a realization period of 8 hours
+        // ? Always averaged over the full period, so a period with missed samples can't spike
+        let funding_rate = get_funding_rate(
+            funding_config,
+            t,
+            twap_sum,
+            funding_config.samples_per_settlement,
+        );
+        funding_rates.insert(t, funding_rate);
     }
 
     return funding_rates;
@@ -14,7 +14,8 @@ use crate::{
     perpetual::{
         perp_position::PerpPosition, ASSETS, COLLATERAL_TOKEN, DECIMALS_PER_ASSET,
         DUST_AMOUNT_PER_ASSET, LEVERAGE_BOUNDS_PER_ASSET, LEVERAGE_DECIMALS,
-        MIN_PARTIAL_LIQUIDATION_SIZE, PRICE_DECIMALS_PER_ASSET, SYNTHETIC_ASSETS,
+        MIN_PARTIAL_LIQUIDATION_SIZE, PRICE_DECIMALS_PER_ASSET, RISK_TIERS_PER_ASSET,
+        SYNTHETIC_ASSETS,
     },
     utils::crypto_utils::verify,
     utils::errors::{send_oracle_update_error, OracleUpdateError},
@@ -378,6 +379,11 @@ pub struct GlobalConfig {
     pub min_partial_liquidation_sizes: Vec<u64>,
     //
     pub observers: Vec<String>,
+    //
+    #[serde(default)]
+    pub risk_tier_counts: Vec<u64>,
+    #[serde(default)]
+    pub risk_tiers: Vec<u64>, // [max_notional, initial_margin_fraction, maintenance_margin_fraction, ...]
 }
 
 impl GlobalConfig {
@@ -400,6 +406,8 @@ impl GlobalConfig {
 
         let observers = OBSERVERS.iter().map(|x| x.to_string()).collect();
 
+        let (risk_tier_counts, risk_tiers) = flatten_risk_tiers(&synthetic_assets);
+
         GlobalConfig {
             assets,
             synthetic_assets,
@@ -412,6 +420,8 @@ impl GlobalConfig {
             leverage_bounds_per_asset,
             min_partial_liquidation_sizes,
             observers,
+            risk_tier_counts,
+            risk_tiers,
         }
     }
 }
@@ -428,6 +438,24 @@ where
     return v;
 }
 
+fn flatten_risk_tiers(synthetic_assets: &Vec<u32>) -> (Vec<u64>, Vec<u64>) {
+    let mut counts: Vec<u64> = Vec::new();
+    let mut tiers: Vec<u64> = Vec::new();
+
+    for k in synthetic_assets {
+        let asset_tiers = RISK_TIERS_PER_ASSET.get(&k.to_string()).unwrap();
+
+        counts.push(asset_tiers.len() as u64);
+        for tier in asset_tiers.iter() {
+            tiers.push(tier.max_notional);
+            tiers.push(tier.initial_margin_fraction);
+            tiers.push(tier.maintenance_margin_fraction);
+        }
+    }
+
+    return (counts, tiers);
+}
+
 fn flatten_leverage_bounds(x: &phf::Map<&'static str, [f32; 2]>, assets: &Vec<u32>) -> Vec<f64> {
     let mut v: Vec<f64> = Vec::new();
 
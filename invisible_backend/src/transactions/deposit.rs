@@ -1,4 +1,3 @@
-use firestore_db_and_auth::ServiceSession;
 use parking_lot::Mutex;
 use std::collections::HashMap;
 use std::str::FromStr;
@@ -10,6 +9,7 @@ use crate::trees::superficial_tree::SuperficialTree;
 use crate::utils::errors::{
     send_deposit_error, DepositThreadExecutionError, TransactionExecutionError,
 };
+use crate::utils::storage::firestore::FirestoreSession;
 
 use crate::utils::crypto_utils::{hash_many, verify, Signature};
 use crate::utils::storage::backup_storage::BackupStorage;
@@ -46,7 +46,7 @@ impl Deposit {
         tree_m: Arc<Mutex<SuperficialTree>>,
         updated_state_hashes_m: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
         swap_output_json_m: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
-        session: &Arc<Mutex<ServiceSession>>,
+        session: &Arc<Mutex<FirestoreSession>>,
         main_storage: &Arc<Mutex<MainStorage>>,
         backup_storage: &Arc<Mutex<BackupStorage>>,
     ) -> Result<Vec<u64>, DepositThreadExecutionError> {
@@ -182,7 +182,7 @@ impl Deposit {
         }
     }
 
-    fn get_action_commitment(&self) -> BigUint {
+    pub fn get_action_commitment(&self) -> BigUint {
         // & h = H(depositId, starkKey, token, deposit_amount)
 
         let deposit_commitment = hash_many(&vec![
@@ -218,7 +218,7 @@ impl Transaction for Deposit {
         updated_state_hashes_m: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
         swap_output_json_m: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
         _blocked_order_ids_m: Arc<Mutex<HashMap<u64, bool>>>,
-        session: &Arc<Mutex<ServiceSession>>,
+        session: &Arc<Mutex<FirestoreSession>>,
         main_storage: &Arc<Mutex<MainStorage>>,
         backup_storage: &Arc<Mutex<BackupStorage>>,
     ) -> Result<(Option<SwapResponse>, Option<Vec<u64>>), TransactionExecutionError> {
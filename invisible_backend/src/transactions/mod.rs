@@ -1,11 +1,11 @@
 use error_stack::Result;
-use firestore_db_and_auth::ServiceSession;
 use std::{collections::HashMap, sync::Arc};
 
 use num_bigint::BigUint;
 use parking_lot::Mutex;
 use serde_json::Value;
 
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     transaction_batch::LeafNodeType,
     trees::superficial_tree::SuperficialTree,
@@ -19,10 +19,12 @@ use self::swap::SwapResponse;
 
 pub mod deposit;
 pub mod limit_order;
+pub mod note_restructure;
 mod order_execution;
 pub mod swap;
 mod swap_execution;
 pub mod transaction_helpers;
+pub mod transfer;
 pub mod withdrawal;
 
 pub trait Transaction {
@@ -35,7 +37,7 @@ pub trait Transaction {
         updated_state_hashes: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
         swap_output_json: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
         blocked_order_ids: Arc<Mutex<HashMap<u64, bool>>>,
-        session: &Arc<Mutex<ServiceSession>>,
+        session: &Arc<Mutex<FirestoreSession>>,
         main_storage: &Arc<Mutex<MainStorage>>,
         backup_storage: &Arc<Mutex<BackupStorage>>,
     ) -> Result<(Option<SwapResponse>, Option<Vec<u64>>), TransactionExecutionError>;
@@ -0,0 +1,306 @@
+use std::collections::{HashMap, HashSet};
+
+use parking_lot::Mutex;
+use starknet::curve::AffinePoint;
+use std::sync::Arc;
+
+use crate::perpetual::{DUST_AMOUNT_PER_ASSET, NOTE_RESTRUCTURE_FEE_PER_ASSET};
+use crate::transaction_batch::LeafNodeType;
+use crate::trees::superficial_tree::SuperficialTree;
+use crate::utils::crypto_utils::{hash_many, verify, EcPoint, Signature};
+use crate::utils::errors::{
+    send_note_restructure_error, NoteRestructureThreadExecutionError, TransactionExecutionError,
+};
+use crate::utils::storage::firestore::FirestoreSession;
+
+use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::local_storage::MainStorage;
+use error_stack::{Report, Result};
+use num_bigint::BigUint;
+use num_traits::FromPrimitive;
+use serde_json::Value;
+
+use super::transaction_helpers::db_updates::update_db_after_note_restructure;
+use super::transaction_helpers::state_updates::update_state_after_note_restructure;
+use super::Transaction;
+//
+use super::swap::SwapResponse;
+use crate::utils::notes::Note;
+//
+
+/// Splits and/or merges a user's notes of one token into new notes with the same owner.
+///
+/// A fee of at least NOTE_RESTRUCTURE_FEE_PER_ASSET is deducted from the notes, except when
+/// merging dust notes (all below DUST_AMOUNT_PER_ASSET) into a single note.
+pub struct NoteRestructure {
+    pub transaction_type: String,
+    pub token: u32,
+    pub notes_in: Vec<Note>,
+    pub notes_out: Vec<Note>,
+    pub fee: u64,
+    pub signature: Signature,
+}
+
+impl NoteRestructure {
+    /// Returns the state indexes of the notes out.
+    pub fn execute_note_restructure(
+        &mut self,
+        tree_m: Arc<Mutex<SuperficialTree>>,
+        updated_state_hashes_m: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
+        swap_output_json_m: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
+        session: &Arc<Mutex<FirestoreSession>>,
+        backup_storage: &Arc<Mutex<BackupStorage>>,
+    ) -> Result<Vec<u64>, NoteRestructureThreadExecutionError> {
+        //
+
+        self.verify_note_amounts()?;
+
+        // ? Verify signature
+        self.verify_restructure_signature()?;
+
+        // ? Update state
+        let mut tree = tree_m.lock();
+        let mut updated_state_hashes = updated_state_hashes_m.lock();
+        let new_indexes = update_state_after_note_restructure(
+            &mut tree,
+            &mut updated_state_hashes,
+            &self.notes_in,
+            &mut self.notes_out,
+        )?;
+        drop(tree);
+        drop(updated_state_hashes);
+
+        // ? Update the database
+        update_db_after_note_restructure(&session, &backup_storage, &self);
+
+        let mut json_map = serde_json::map::Map::new();
+        json_map.insert(
+            String::from("transaction_type"),
+            serde_json::to_value(&self.transaction_type).unwrap(),
+        );
+        json_map.insert(
+            String::from("note_restructure"),
+            serde_json::to_value(&self).unwrap(),
+        );
+
+        let mut swap_output_json = swap_output_json_m.lock();
+        swap_output_json.push(json_map);
+        drop(swap_output_json);
+
+        Ok(new_indexes)
+    }
+
+    /// Merging only dust notes into a single note is free, to encourage cleaning them up.
+    pub fn is_dust_consolidation(&self) -> bool {
+        let dust_amount = DUST_AMOUNT_PER_ASSET
+            .get(&self.token.to_string())
+            .unwrap_or(&0);
+
+        return self.notes_in.len() > 1
+            && self.notes_out.len() == 1
+            && self.notes_in.iter().all(|n| n.amount < *dust_amount);
+    }
+
+    // * HELPER FUNCTIONS * //
+
+    fn verify_note_amounts(&self) -> Result<(), NoteRestructureThreadExecutionError> {
+        if self.notes_in.is_empty() || self.notes_out.is_empty() {
+            return Err(send_note_restructure_error(
+                "Restructure must spend and create at least one note".to_string(),
+                None,
+            ));
+        }
+
+        let token = self.token.to_string();
+        if !DUST_AMOUNT_PER_ASSET.contains_key(&token) {
+            return Err(send_note_restructure_error(
+                "Invalid token".to_string(),
+                None,
+            ));
+        }
+
+        let is_dust_consolidation = self.is_dust_consolidation();
+        let min_fee = if is_dust_consolidation {
+            0
+        } else {
+            NOTE_RESTRUCTURE_FEE_PER_ASSET[&token]
+        };
+        if self.fee < min_fee {
+            return Err(send_note_restructure_error(
+                "Fee is too low".to_string(),
+                Some(format!("fee: {}, min fee: {}", self.fee, min_fee)),
+            ));
+        }
+
+        let owner = &self.notes_in[0].address.x;
+
+        let mut indexes: HashSet<u64> = HashSet::new();
+        let mut amount_in: u64 = 0;
+        for note in self.notes_in.iter() {
+            if note.token != self.token || note.address.x != *owner {
+                return Err(send_note_restructure_error(
+                    "Notes in must have the same token and owner".to_string(),
+                    None,
+                ));
+            }
+            if !indexes.insert(note.index) {
+                return Err(send_note_restructure_error(
+                    "Duplicate notes in restructure".to_string(),
+                    Some(format!("note {} is spent more than once", note.index)),
+                ));
+            }
+
+            amount_in = amount_in
+                .checked_add(note.amount)
+                .ok_or_else(amount_overflow_error)?;
+        }
+
+        let mut amount_out: u64 = 0;
+        for note in self.notes_out.iter() {
+            if note.token != self.token || note.address.x != *owner {
+                return Err(send_note_restructure_error(
+                    "Notes out must have the same token and owner as the notes in".to_string(),
+                    None,
+                ));
+            }
+
+            // ? Splitting notes can't create new dust notes
+            if note.amount == 0
+                || (!is_dust_consolidation && note.amount < DUST_AMOUNT_PER_ASSET[&token])
+            {
+                return Err(send_note_restructure_error(
+                    "Note out amount is too small".to_string(),
+                    Some(format!("note out amount: {}", note.amount)),
+                ));
+            }
+
+            amount_out = amount_out
+                .checked_add(note.amount)
+                .ok_or_else(amount_overflow_error)?;
+        }
+
+        let total_out = amount_out
+            .checked_add(self.fee)
+            .ok_or_else(amount_overflow_error)?;
+        if amount_in != total_out {
+            return Err(send_note_restructure_error(
+                "Notes in do not match notes out and fee".to_string(),
+                Some(format!(
+                    "amount in: {}, amount out: {}, fee: {}",
+                    amount_in, amount_out, self.fee
+                )),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn verify_restructure_signature(&self) -> Result<(), NoteRestructureThreadExecutionError> {
+        let restructure_hash = self.hash_transaction();
+
+        let mut pub_key_sum: AffinePoint = AffinePoint::identity();
+
+        for i in 0..self.notes_in.len() {
+            let ec_point = AffinePoint::from(&self.notes_in[i].address);
+            pub_key_sum = &pub_key_sum + &ec_point;
+        }
+
+        let pub_key: EcPoint = EcPoint::from(&pub_key_sum);
+
+        let valid = verify(
+            &pub_key.x.to_biguint().unwrap(),
+            &restructure_hash,
+            &self.signature,
+        );
+
+        if valid {
+            return Ok(());
+        } else {
+            return Err(send_note_restructure_error(
+                "Invalid Signature".to_string(),
+                Some(format!(
+                    "Invalid signature: r:{:?} s:{:?} hash:{:?} pub_key:{:?}",
+                    &self.signature.r, &self.signature.s, restructure_hash, pub_key
+                )),
+            ));
+        }
+    }
+
+    /// restructure_hash = H(notes_in hashes, notes_out hashes, fee)
+    pub fn hash_transaction(&self) -> BigUint {
+        let mut hash_inputs: Vec<&BigUint> = self.notes_in.iter().map(|note| &note.hash).collect();
+
+        for note in self.notes_out.iter() {
+            hash_inputs.push(&note.hash);
+        }
+
+        let fee = BigUint::from_u64(self.fee).unwrap();
+        hash_inputs.push(&fee);
+
+        return hash_many(&hash_inputs);
+    }
+}
+
+fn amount_overflow_error() -> Report<NoteRestructureThreadExecutionError> {
+    send_note_restructure_error("Note amounts overflow".to_string(), None)
+}
+
+// * Transaction Trait * //
+impl Transaction for NoteRestructure {
+    fn transaction_type(&self) -> &str {
+        return self.transaction_type.as_str();
+    }
+
+    fn execute_transaction(
+        &mut self,
+        tree: Arc<Mutex<SuperficialTree>>,
+        _partial_fill_tracker: Arc<Mutex<HashMap<u64, (Option<Note>, u64)>>>,
+        updated_state_hashes: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
+        swap_output_json: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
+        _blocked_order_ids: Arc<Mutex<HashMap<u64, bool>>>,
+        session: &Arc<Mutex<FirestoreSession>>,
+        _main_storage: &Arc<Mutex<MainStorage>>,
+        backup_storage: &Arc<Mutex<BackupStorage>>,
+    ) -> Result<(Option<SwapResponse>, Option<Vec<u64>>), TransactionExecutionError> {
+        let new_indexes = self
+            .execute_note_restructure(
+                tree,
+                updated_state_hashes,
+                swap_output_json,
+                session,
+                backup_storage,
+            )
+            .or_else(|err: Report<NoteRestructureThreadExecutionError>| {
+                let error_context = err.current_context().clone();
+                Err(Report::new(TransactionExecutionError::NoteRestructure(
+                    error_context.clone(),
+                ))
+                .attach_printable(format!(
+                    "Note restructure execution failed with error {:?}",
+                    error_context
+                )))
+            })?;
+
+        return Ok((None, Some(new_indexes)));
+    }
+}
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+impl Serialize for NoteRestructure {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut restructure = serializer.serialize_struct("NoteRestructure", 6)?;
+
+        restructure.serialize_field("transaction_type", &self.transaction_type)?;
+        restructure.serialize_field("token", &self.token)?;
+        restructure.serialize_field("notes_in", &self.notes_in)?;
+        restructure.serialize_field("notes_out", &self.notes_out)?;
+        restructure.serialize_field("fee", &self.fee)?;
+        restructure.serialize_field("signature", &self.signature)?;
+
+        return restructure.end();
+    }
+}
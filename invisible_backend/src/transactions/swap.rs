@@ -1,4 +1,3 @@
-use firestore_db_and_auth::ServiceSession;
 use parking_lot::Mutex;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -9,6 +8,7 @@ use serde_json::Value;
 use error_stack::{Report, Result};
 
 use super::Transaction;
+use crate::utils::storage::firestore::FirestoreSession;
 //
 use super::limit_order::LimitOrder;
 use super::swap_execution::{
@@ -72,7 +72,7 @@ impl Swap {
         updated_state_hashes_m: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
         swap_output_json_m: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
         blocked_order_ids_m: Arc<Mutex<HashMap<u64, bool>>>,
-        session: &Arc<Mutex<ServiceSession>>,
+        session: &Arc<Mutex<FirestoreSession>>,
         backup_storage: &Arc<Mutex<BackupStorage>>,
     ) -> Result<SwapResponse, SwapThreadExecutionError> {
         //
@@ -204,7 +204,7 @@ impl Transaction for Swap {
         updated_state_hashes_m: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
         swap_output_json_m: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
         blocked_order_ids_m: Arc<Mutex<HashMap<u64, bool>>>,
-        session: &Arc<Mutex<ServiceSession>>,
+        session: &Arc<Mutex<FirestoreSession>>,
         _main_storage: &Arc<Mutex<MainStorage>>,
         backup_storage: &Arc<Mutex<BackupStorage>>,
     ) -> Result<(Option<SwapResponse>, Option<Vec<u64>>), TransactionExecutionError> {
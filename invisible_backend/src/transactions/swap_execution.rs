@@ -1,4 +1,3 @@
-use firestore_db_and_auth::ServiceSession;
 use parking_lot::{Mutex, MutexGuard};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -24,6 +23,7 @@ use crate::utils::crypto_utils::Signature;
 use crate::utils::errors::{send_swap_error, SwapThreadExecutionError};
 use crate::utils::notes::Note;
 use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::firestore::FirestoreSession;
 
 type ExecutionResult = (TxExecutionThreadOutput, TxExecutionThreadOutput);
 pub fn execute_swap_transaction(
@@ -169,7 +169,7 @@ pub fn update_state_and_finalize(
     partial_fill_tracker_m: &Arc<Mutex<HashMap<u64, (Option<Note>, u64)>>>,
     updated_state_hashes_m: &Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
     blocked_order_ids_m: &Arc<Mutex<HashMap<u64, bool>>>,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     execution_result: &ExecutionResult,
     order_a: &LimitOrder,
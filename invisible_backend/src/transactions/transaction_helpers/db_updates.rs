@@ -1,11 +1,14 @@
 use std::{collections::HashMap, sync::Arc, thread::JoinHandle};
 
-use firestore_db_and_auth::ServiceSession;
 use parking_lot::Mutex;
 
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     order_tab::OrderTab,
-    transactions::{limit_order::LimitOrder, withdrawal::Withdrawal},
+    transactions::{
+        limit_order::LimitOrder, note_restructure::NoteRestructure, transfer::Transfer,
+        withdrawal::Withdrawal,
+    },
     utils::{
         notes::Note,
         storage::{
@@ -25,7 +28,7 @@ use super::{swap_helpers::NoteInfoExecutionOutput, transaction_output::FillInfo}
 /// Remove the spent notes from the database and add the new ones as well as the refund and pfr notes (if necessary)
 ///
 pub fn update_db_after_spot_swap(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     order: &LimitOrder,
     note_info_output: &Option<NoteInfoExecutionOutput>,
@@ -101,7 +104,7 @@ fn _update_non_tab_order<'a>(
 }
 
 pub fn store_spot_fill(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     amount: u64,
     price: u64,
@@ -130,7 +133,7 @@ pub fn store_spot_fill(
 
 /// Add all the newly generated deposit notes (in most cases only one) to the database
 pub fn update_db_after_deposit(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     new_notes: Vec<Note>,
     zero_indexes: &Vec<u64>,
@@ -152,7 +155,7 @@ pub fn update_db_after_deposit(
 
 /// Remove the withdrawn notes from the database and add the refund note (if necessary)
 pub fn update_db_after_withdrawal(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     withdrawal: &Withdrawal,
     execution_fee: u64,
@@ -189,29 +192,58 @@ pub fn update_db_after_withdrawal(
     );
 }
 
-// NOTE SPLITS -----------------------------------------------------
+// TRANSFERS ----------------------------------------------------
+
+/// Remove the spent notes from the database and add the recipient's notes and the refund note (if necessary)
+pub fn update_db_after_transfer(
+    session: &Arc<Mutex<FirestoreSession>>,
+    backup_storage: &Arc<Mutex<BackupStorage>>,
+    transfer: &Transfer,
+) {
+    let mut delete_notes: Vec<(u64, String)> = Vec::new();
+    let mut add_notes: Vec<&Note> = Vec::new();
+
+    if let Some(refund_note) = &transfer.refund_note {
+        // ? Store the refund note in place of the first note
+        add_notes.push(refund_note)
+    }
+
+    for n in transfer.notes_out.iter() {
+        add_notes.push(n)
+    }
+
+    for n in transfer.notes_in.iter() {
+        delete_notes.push((n.index, n.address.x.to_string()))
+    }
+
+    let updater = DbNoteUpdater {
+        session,
+        backup_storage,
+        delete_notes,
+        add_notes,
+    };
+
+    let _handles = updater.update_db();
+}
+
+// NOTE RESTRUCTURES -----------------------------------------------------
 /// Remove the old notes from the database and add the new ones
-pub fn update_db_after_note_split(
-    session: &Arc<Mutex<ServiceSession>>,
+pub fn update_db_after_note_restructure(
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
-    notes_in: &Vec<Note>,
-    new_note: Note,
-    refund_note: Option<Note>,
+    restructure: &NoteRestructure,
 ) {
     let mut delete_notes: Vec<(u64, String)> = Vec::new();
-    let mut add_notes: Vec<Note> = Vec::new();
+    let mut add_notes: Vec<&Note> = Vec::new();
 
-    for note in notes_in {
+    for note in restructure.notes_in.iter() {
         delete_notes.push((note.index, note.address.x.to_string()))
     }
 
-    add_notes.push(new_note.clone());
-    if let Some(n) = refund_note {
-        add_notes.push(n);
+    for note in restructure.notes_out.iter() {
+        add_notes.push(note);
     }
 
-    let add_notes = add_notes.iter().collect::<Vec<&Note>>();
-
     let updater = DbNoteUpdater {
         session,
         backup_storage,
@@ -223,7 +255,7 @@ pub fn update_db_after_note_split(
 }
 
 pub struct DbNoteUpdater<'a> {
-    pub session: &'a Arc<Mutex<ServiceSession>>,
+    pub session: &'a Arc<Mutex<FirestoreSession>>,
     pub backup_storage: &'a Arc<Mutex<BackupStorage>>,
     pub delete_notes: Vec<(u64, String)>,
     pub add_notes: Vec<&'a Note>,
@@ -9,7 +9,11 @@ use crate::{
     transaction_batch::LeafNodeType,
     trees::superficial_tree::SuperficialTree,
     utils::{
-        errors::{send_withdrawal_error, WithdrawalThreadExecutionError},
+        errors::{
+            send_note_restructure_error, send_transfer_error, send_withdrawal_error,
+            NoteRestructureThreadExecutionError, TransferThreadExecutionError,
+            WithdrawalThreadExecutionError,
+        },
         notes::Note,
     },
 };
@@ -173,3 +177,105 @@ pub fn update_state_after_withdrawal(
 
     Ok(())
 }
+
+// * ===============================================================================================================================================
+// * Transfer state updates ------------------------------------------------------------------------------------------------------------------------
+
+/// Replaces the first note spent with the refund note, removes the other notes spent and
+/// adds the notes out at new indexes (so they can't be linked to the sender's notes by index).
+///
+/// Returns the indexes of the notes out.
+pub fn update_state_after_transfer(
+    tree: &mut SuperficialTree,
+    updated_state_hashes: &mut HashMap<u64, (LeafNodeType, BigUint)>,
+    notes_in: &Vec<Note>,
+    refund_note: &Option<Note>,
+    notes_out: &mut Vec<Note>,
+) -> Result<Vec<u64>, TransferThreadExecutionError> {
+    //
+
+    // ? Verify all the notes exist before making any changes to the state
+    for note in notes_in.iter() {
+        let leaf_hash = tree.get_leaf_by_index(note.index);
+        if leaf_hash != note.hash {
+            return Err(send_transfer_error(
+                "note spent does not exist in the state".to_string(),
+                Some(format!("note spent does not exist: {}", note.index)),
+            ));
+        }
+    }
+
+    // ? Get the new indexes before the notes spent are removed, to avoid reusing their indexes
+    let mut new_indexes: Vec<u64> = Vec::new();
+    for note in notes_out.iter_mut() {
+        note.index = tree.first_zero_idx();
+        new_indexes.push(note.index);
+
+        tree.update_leaf_node(&note.hash, note.index);
+        updated_state_hashes.insert(note.index, (LeafNodeType::Note, note.hash.clone()));
+    }
+
+    let refund_idx = notes_in[0].index;
+    let refund_note_hash = if let Some(refund_note) = refund_note {
+        refund_note.hash.clone()
+    } else {
+        BigUint::zero()
+    };
+
+    tree.update_leaf_node(&refund_note_hash, refund_idx);
+    updated_state_hashes.insert(refund_idx, (LeafNodeType::Note, refund_note_hash));
+
+    for note in notes_in.iter().skip(1) {
+        tree.update_leaf_node(&BigUint::zero(), note.index);
+        updated_state_hashes.insert(note.index, (LeafNodeType::Note, BigUint::zero()));
+    }
+
+    Ok(new_indexes)
+}
+
+// * ===============================================================================================================================================
+// * Note restructure state updates ----------------------------------------------------------------------------------------------------------------
+
+/// Places the notes out at the indexes of the notes in (in order), removing the notes in that
+/// are left over or adding the extra notes out at new indexes.
+///
+/// Returns the indexes of the notes out.
+pub fn update_state_after_note_restructure(
+    tree: &mut SuperficialTree,
+    updated_state_hashes: &mut HashMap<u64, (LeafNodeType, BigUint)>,
+    notes_in: &Vec<Note>,
+    notes_out: &mut Vec<Note>,
+) -> Result<Vec<u64>, NoteRestructureThreadExecutionError> {
+    //
+
+    // ? Verify all the notes exist before making any changes to the state
+    for note in notes_in.iter() {
+        let leaf_hash = tree.get_leaf_by_index(note.index);
+        if leaf_hash != note.hash {
+            return Err(send_note_restructure_error(
+                "note spent does not exist in the state".to_string(),
+                Some(format!("note spent does not exist: {}", note.index)),
+            ));
+        }
+    }
+
+    let mut new_indexes: Vec<u64> = Vec::new();
+    for (i, note) in notes_out.iter_mut().enumerate() {
+        note.index = if i < notes_in.len() {
+            notes_in[i].index
+        } else {
+            tree.first_zero_idx()
+        };
+        new_indexes.push(note.index);
+
+        tree.update_leaf_node(&note.hash, note.index);
+        updated_state_hashes.insert(note.index, (LeafNodeType::Note, note.hash.clone()));
+    }
+
+    for note in notes_in.iter().skip(notes_out.len()) {
+        tree.update_leaf_node(&BigUint::zero(), note.index);
+        updated_state_hashes.insert(note.index, (LeafNodeType::Note, BigUint::zero()));
+    }
+
+    Ok(new_indexes)
+}
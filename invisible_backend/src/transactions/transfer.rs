@@ -0,0 +1,311 @@
+use std::collections::{HashMap, HashSet};
+
+use parking_lot::Mutex;
+use starknet::curve::AffinePoint;
+use std::sync::Arc;
+
+use crate::transaction_batch::LeafNodeType;
+use crate::trees::superficial_tree::SuperficialTree;
+use crate::utils::crypto_utils::{hash_many, verify, EcPoint, Signature};
+use crate::utils::errors::{
+    send_transfer_error, TransactionExecutionError, TransferThreadExecutionError,
+};
+use crate::utils::storage::firestore::FirestoreSession;
+
+use crate::utils::storage::backup_storage::BackupStorage;
+use crate::utils::storage::local_storage::MainStorage;
+use error_stack::{Report, Result};
+use num_bigint::BigUint;
+use num_traits::Zero;
+use serde_json::Value;
+
+use super::transaction_helpers::db_updates::update_db_after_transfer;
+use super::transaction_helpers::state_updates::update_state_after_transfer;
+use super::Transaction;
+//
+use super::swap::SwapResponse;
+use crate::utils::notes::Note;
+//
+
+/// A private payment inside the rollup: spends the sender's notes and creates
+/// new notes owned by the recipient (with blindings chosen by the sender),
+/// returning the change to the sender in a refund note.
+pub struct Transfer {
+    pub transaction_type: String,
+    pub token: u32,
+    pub recipient: EcPoint,
+    pub notes_in: Vec<Note>,
+    pub notes_out: Vec<Note>,
+    pub refund_note: Option<Note>,
+    pub signature: Signature,
+}
+
+impl Transfer {
+    /// Returns the state indexes of the notes created for the recipient.
+    pub fn execute_transfer(
+        &mut self,
+        tree_m: Arc<Mutex<SuperficialTree>>,
+        updated_state_hashes_m: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
+        swap_output_json_m: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
+        session: &Arc<Mutex<FirestoreSession>>,
+        backup_storage: &Arc<Mutex<BackupStorage>>,
+    ) -> Result<Vec<u64>, TransferThreadExecutionError> {
+        //
+
+        self.verify_note_amounts()?;
+
+        // ? Verify signature
+        self.verify_transfer_signature()?;
+
+        // ? The refund note takes the place of the first note spent
+        let refund_idx = self.notes_in[0].index;
+        if let Some(refund_note) = self.refund_note.as_mut() {
+            refund_note.index = refund_idx;
+        }
+
+        // ? Update state
+        let mut tree = tree_m.lock();
+        let mut updated_state_hashes = updated_state_hashes_m.lock();
+        let new_indexes = update_state_after_transfer(
+            &mut tree,
+            &mut updated_state_hashes,
+            &self.notes_in,
+            &self.refund_note,
+            &mut self.notes_out,
+        )?;
+        drop(tree);
+        drop(updated_state_hashes);
+
+        // ? Update the database
+        update_db_after_transfer(&session, &backup_storage, &self);
+
+        let mut json_map = serde_json::map::Map::new();
+        json_map.insert(
+            String::from("transaction_type"),
+            serde_json::to_value(&self.transaction_type).unwrap(),
+        );
+        json_map.insert(
+            String::from("transfer"),
+            serde_json::to_value(&self).unwrap(),
+        );
+
+        let mut swap_output_json = swap_output_json_m.lock();
+        swap_output_json.push(json_map);
+        drop(swap_output_json);
+
+        Ok(new_indexes)
+    }
+
+    // * HELPER FUNCTIONS * //
+
+    fn verify_note_amounts(&self) -> Result<(), TransferThreadExecutionError> {
+        if self.notes_in.is_empty() || self.notes_out.is_empty() {
+            return Err(send_transfer_error(
+                "Transfer must spend and create at least one note".to_string(),
+                None,
+            ));
+        }
+
+        let mut indexes: HashSet<u64> = HashSet::new();
+        let mut blindings: HashSet<&BigUint> = HashSet::new();
+        let mut amount_in: u64 = 0;
+        for note in self.notes_in.iter() {
+            if note.token != self.token {
+                return Err(send_transfer_error(
+                    "Notes do not match transfer token".to_string(),
+                    None,
+                ));
+            }
+            if !indexes.insert(note.index) {
+                return Err(send_transfer_error(
+                    "Duplicate notes in transfer".to_string(),
+                    Some(format!("note {} is spent more than once", note.index)),
+                ));
+            }
+
+            blindings.insert(&note.blinding);
+            amount_in = amount_in
+                .checked_add(note.amount)
+                .ok_or_else(amount_overflow_error)?;
+        }
+
+        // ? Reusing the sender's blindings would link the recipient's notes to the spent ones
+        let mut amount_out: u64 = 0;
+        for note in self.notes_out.iter() {
+            if note.token != self.token {
+                return Err(send_transfer_error(
+                    "Notes do not match transfer token".to_string(),
+                    None,
+                ));
+            }
+            if note.address.x != self.recipient.x {
+                return Err(send_transfer_error(
+                    "Notes out are not owned by the recipient".to_string(),
+                    None,
+                ));
+            }
+            if note.amount == 0 {
+                return Err(send_transfer_error(
+                    "Notes out must have a non-zero amount".to_string(),
+                    None,
+                ));
+            }
+            if blindings.contains(&note.blinding) {
+                return Err(send_transfer_error(
+                    "Notes out must use fresh blindings".to_string(),
+                    None,
+                ));
+            }
+
+            amount_out = amount_out
+                .checked_add(note.amount)
+                .ok_or_else(amount_overflow_error)?;
+        }
+
+        let mut refund_amount: u64 = 0;
+        if let Some(refund_note) = &self.refund_note {
+            if refund_note.token != self.token
+                || refund_note.address.x != self.notes_in[0].address.x
+            {
+                return Err(send_transfer_error(
+                    "Refund note must be returned to the sender".to_string(),
+                    None,
+                ));
+            }
+
+            refund_amount = refund_note.amount;
+        }
+
+        let total_out = amount_out
+            .checked_add(refund_amount)
+            .ok_or_else(amount_overflow_error)?;
+        if amount_in != total_out {
+            return Err(send_transfer_error(
+                "Notes do not match transfer and refund amount".to_string(),
+                Some(format!(
+                    "amount in: {}, amount out: {}, refund amount: {}",
+                    amount_in, amount_out, refund_amount
+                )),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn verify_transfer_signature(&self) -> Result<(), TransferThreadExecutionError> {
+        let transfer_hash = self.hash_transaction();
+
+        let mut pub_key_sum: AffinePoint = AffinePoint::identity();
+
+        for i in 0..self.notes_in.len() {
+            let ec_point = AffinePoint::from(&self.notes_in[i].address);
+            pub_key_sum = &pub_key_sum + &ec_point;
+        }
+
+        let pub_key: EcPoint = EcPoint::from(&pub_key_sum);
+
+        let valid = verify(
+            &pub_key.x.to_biguint().unwrap(),
+            &transfer_hash,
+            &self.signature,
+        );
+
+        if valid {
+            return Ok(());
+        } else {
+            return Err(send_transfer_error(
+                "Invalid Signature".to_string(),
+                Some(format!(
+                    "Invalid signature: r:{:?} s:{:?} hash:{:?} pub_key:{:?}",
+                    &self.signature.r, &self.signature.s, transfer_hash, pub_key
+                )),
+            ));
+        }
+    }
+
+    /// transfer_hash = H(notes_in hashes, refund_note hash, notes_out hashes)
+    ///
+    /// The recipient is committed to through the addresses of the notes out.
+    pub fn hash_transaction(&self) -> BigUint {
+        let z = BigUint::zero();
+        let mut hash_inputs: Vec<&BigUint> = self.notes_in.iter().map(|note| &note.hash).collect();
+
+        let refund_note_hash = if let Some(refund_note) = &self.refund_note {
+            &refund_note.hash
+        } else {
+            &z
+        };
+        hash_inputs.push(refund_note_hash);
+
+        for note in self.notes_out.iter() {
+            hash_inputs.push(&note.hash);
+        }
+
+        return hash_many(&hash_inputs);
+    }
+}
+
+fn amount_overflow_error() -> Report<TransferThreadExecutionError> {
+    send_transfer_error("Note amounts overflow".to_string(), None)
+}
+
+// * Transaction Trait * //
+impl Transaction for Transfer {
+    fn transaction_type(&self) -> &str {
+        return self.transaction_type.as_str();
+    }
+
+    fn execute_transaction(
+        &mut self,
+        tree: Arc<Mutex<SuperficialTree>>,
+        _partial_fill_tracker: Arc<Mutex<HashMap<u64, (Option<Note>, u64)>>>,
+        updated_state_hashes: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
+        swap_output_json: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
+        _blocked_order_ids: Arc<Mutex<HashMap<u64, bool>>>,
+        session: &Arc<Mutex<FirestoreSession>>,
+        _main_storage: &Arc<Mutex<MainStorage>>,
+        backup_storage: &Arc<Mutex<BackupStorage>>,
+    ) -> Result<(Option<SwapResponse>, Option<Vec<u64>>), TransactionExecutionError> {
+        let new_indexes = self
+            .execute_transfer(
+                tree,
+                updated_state_hashes,
+                swap_output_json,
+                session,
+                backup_storage,
+            )
+            .or_else(|err: Report<TransferThreadExecutionError>| {
+                let error_context = err.current_context().clone();
+                Err(
+                    Report::new(TransactionExecutionError::Transfer(error_context.clone()))
+                        .attach_printable(format!(
+                            "Transfer transaction execution failed with error {:?}",
+                            error_context
+                        )),
+                )
+            })?;
+
+        return Ok((None, Some(new_indexes)));
+    }
+}
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+impl Serialize for Transfer {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut transfer = serializer.serialize_struct("Transfer", 7)?;
+
+        transfer.serialize_field("transaction_type", &self.transaction_type)?;
+        transfer.serialize_field("token", &self.token)?;
+        transfer.serialize_field("recipient", &self.recipient)?;
+        transfer.serialize_field("notes_in", &self.notes_in)?;
+        transfer.serialize_field("notes_out", &self.notes_out)?;
+        transfer.serialize_field("refund_note", &self.refund_note)?;
+        transfer.serialize_field("signature", &self.signature)?;
+
+        return transfer.end();
+    }
+}
@@ -1,6 +1,5 @@
 use std::collections::HashMap;
 
-use firestore_db_and_auth::ServiceSession;
 use parking_lot::Mutex;
 use starknet::curve::AffinePoint;
 use std::sync::Arc;
@@ -12,6 +11,7 @@ use crate::utils::crypto_utils::{hash_many, verify, EcPoint, Signature};
 use crate::utils::errors::{
     send_withdrawal_error, TransactionExecutionError, WithdrawalThreadExecutionError,
 };
+use crate::utils::storage::firestore::FirestoreSession;
 
 use crate::utils::storage::backup_storage::BackupStorage;
 use crate::utils::storage::local_storage::MainStorage;
@@ -49,7 +49,7 @@ impl Withdrawal {
         tree_m: Arc<Mutex<SuperficialTree>>,
         updated_state_hashes_m: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
         swap_output_json_m: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
-        session: &Arc<Mutex<ServiceSession>>,
+        session: &Arc<Mutex<FirestoreSession>>,
         backup_storage: &Arc<Mutex<BackupStorage>>,
     ) -> Result<(), WithdrawalThreadExecutionError> {
         let withdrawal_handle = thread::scope(move |_s| {
@@ -219,7 +219,7 @@ impl Transaction for Withdrawal {
         updated_state_hashes: Arc<Mutex<HashMap<u64, (LeafNodeType, BigUint)>>>,
         swap_output_json: Arc<Mutex<Vec<serde_json::Map<String, Value>>>>,
         _blocked_order_ids: Arc<Mutex<HashMap<u64, bool>>>,
-        session: &Arc<Mutex<ServiceSession>>,
+        session: &Arc<Mutex<FirestoreSession>>,
         _main_storage: &Arc<Mutex<MainStorage>>,
         backup_storage: &Arc<Mutex<BackupStorage>>,
     ) -> Result<(Option<SwapResponse>, Option<Vec<u64>>), TransactionExecutionError> {
@@ -301,6 +301,7 @@ fn pad_leaf_nodes(arr: &Vec<BigUint>, depth: usize, pad_value: BigUint) -> Vec<B
 // * =================================================================================================================
 // * HELPER FUNCTION FOR PARALLEL UPDATES
 
+// ? Number of leaves each parallel task hashes per row (see benches/state.rs before changing it)
 const STRIDE: usize = 250; // Must be even
 
 fn split_and_run_first_row(
@@ -191,7 +191,7 @@ fn parse_global_config(output: &[BigUint]) -> (GlobalConfig, &[BigUint]) {
     let observers_len = res_vec[4].to_u32().unwrap();
     let chain_ids_len = res_vec[5].to_u32().unwrap();
 
-    // ? 1 + 3*assets_len + 5*synthetic_assets_len + observers_len + chain_ids_len
+    // ? 1 + 3*assets_len + 6*synthetic_assets_len + observers_len + chain_ids_len + 3*sum(risk_tier_counts)
 
     // ? assets
     let mut i = 1;
@@ -264,6 +264,21 @@ fn parse_global_config(output: &[BigUint]) -> (GlobalConfig, &[BigUint]) {
         .collect::<Vec<String>>();
     i = i_next;
 
+    // ? risk_tier_counts
+    let i_next = i + synthetic_assets_len as usize;
+    let risk_tier_counts = output[i..i_next]
+        .into_iter()
+        .map(|o| o.to_u64().unwrap())
+        .collect::<Vec<u64>>();
+    i = i_next;
+    // ? risk_tiers
+    let i_next = i + 3 * risk_tier_counts.iter().sum::<u64>() as usize;
+    let risk_tiers = output[i..i_next]
+        .into_iter()
+        .map(|o| o.to_u64().unwrap())
+        .collect::<Vec<u64>>();
+    i = i_next;
+
     let shifted_output = &output[i..];
 
     return (
@@ -283,6 +298,9 @@ fn parse_global_config(output: &[BigUint]) -> (GlobalConfig, &[BigUint]) {
             min_partial_liquidation_sizes,
 
             observers,
+
+            risk_tier_counts,
+            risk_tiers,
         },
         shifted_output,
     );
@@ -596,7 +614,7 @@ fn parse_note_outputs(output: &[BigUint], num_notes: u32) -> (Vec<NoteOutput>, &
         let address_x = &output[(i * 3 + 2) as usize];
         let address_y = &output[(i * 3 + 3) as usize];
 
-        let hash = hash_note_output(token, &commitment, &address_y).to_string();
+        let hash = hash_note_output(token, &commitment, &address_x).to_string();
 
         let note = NoteOutput {
             index,
@@ -89,6 +89,65 @@ pub fn send_withdrawal_error(
     return report;
 }
 
+// * TRANSFER ERRORS ------------------------------------------------------------
+
+#[derive(Debug, Clone)]
+pub struct TransferThreadExecutionError {
+    pub err_msg: String,
+}
+
+impl fmt::Display for TransferThreadExecutionError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str("Error executing a transfer")
+    }
+}
+
+impl Error for TransferThreadExecutionError {}
+
+pub fn send_transfer_error(
+    err_msg: String,
+    attachment: Option<String>,
+) -> Report<TransferThreadExecutionError> {
+    println!("ERROR in transfer: {:?} \n{:?}", err_msg, attachment);
+    let report = Report::new(TransferThreadExecutionError {
+        err_msg: err_msg.clone(),
+    })
+    .attach_printable(attachment.unwrap_or(err_msg));
+
+    return report;
+}
+
+// * NOTE RESTRUCTURE ERRORS ----------------------------------------------------
+
+#[derive(Debug, Clone)]
+pub struct NoteRestructureThreadExecutionError {
+    pub err_msg: String,
+}
+
+impl fmt::Display for NoteRestructureThreadExecutionError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str("Error executing a note restructure")
+    }
+}
+
+impl Error for NoteRestructureThreadExecutionError {}
+
+pub fn send_note_restructure_error(
+    err_msg: String,
+    attachment: Option<String>,
+) -> Report<NoteRestructureThreadExecutionError> {
+    println!(
+        "ERROR in note restructure: {:?} \n{:?}",
+        err_msg, attachment
+    );
+    let report = Report::new(NoteRestructureThreadExecutionError {
+        err_msg: err_msg.clone(),
+    })
+    .attach_printable(attachment.unwrap_or(err_msg));
+
+    return report;
+}
+
 // * TRANSACTION ERRORS ---------------------------------------------------------
 
 #[derive(Debug)]
@@ -96,6 +155,8 @@ pub enum TransactionExecutionError {
     Deposit(DepositThreadExecutionError),
     Swap(SwapThreadExecutionError),
     Withdrawal(WithdrawalThreadExecutionError),
+    Transfer(TransferThreadExecutionError),
+    NoteRestructure(NoteRestructureThreadExecutionError),
 }
 
 impl fmt::Display for TransactionExecutionError {
@@ -166,6 +227,30 @@ impl fmt::Display for BatchFinalizationError {
 
 impl Error for BatchFinalizationError {}
 
+// * STATE RECONSTRUCTION ERRORS -------------------------------------------------------------
+
+#[derive(Debug)]
+pub struct StateReconstructionError {
+    pub err_msg: String,
+}
+
+impl fmt::Display for StateReconstructionError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str("Error reconstructing the state from the program output")
+    }
+}
+
+impl Error for StateReconstructionError {}
+
+pub fn send_state_reconstruction_error(err_msg: String) -> Report<StateReconstructionError> {
+    let report = Report::new(StateReconstructionError {
+        err_msg: err_msg.clone(),
+    })
+    .attach_printable(err_msg);
+
+    return report;
+}
+
 // * ORACLE UPDATE ERRORS -------------------------------------------------------------------
 
 #[derive(Debug)]
@@ -217,9 +302,12 @@ pub fn send_matching_error(err_msg: String) -> Report<MatchingEngineError> {
 use tonic::{Response, Status};
 
 use crate::server::grpc::engine_proto::{
-    AmendOrderResponse, CancelOrderResponse, CloseOrderTabRes, DepositResponse, FundingRes,
-    LiquidationOrderResponse, LiquidityRes, MarginChangeRes, OnChainScmmRes, OpenOrderTabRes,
-    OrderResponse, SplitNotesRes, SuccessResponse,
+    AmendOrderResponse, CancelAllResponse, CancelOrderResponse, CancelOrdersBatchResponse,
+    CloseOrderTabRes, DeadMansSwitchRes, DepositResponse, DepthRes, FundingRes, L3OrdersRes,
+    LiquidationOrderResponse, LiquidityRes, MarginChangeRes, MarketInfoRes,
+    NoteRestructureResponse, OnChainScmmRes, OpenOrderTabRes, OrderResponse, OrdersBatchResponse,
+    PositionLedgerRes, PositionRiskRes, PositionsRiskBatchRes, SetLeverageRes,
+    SimulatePerpOrderRes, SuccessResponse, TransferResponse,
 };
 
 // * ERROR GRPC REPLIES
@@ -269,6 +357,55 @@ pub fn send_amend_order_error_reply(
     return Ok(Response::new(reply));
 }
 
+pub fn send_orders_batch_error_reply(
+    err_msg: String,
+) -> Result<Response<OrdersBatchResponse>, Status> {
+    let reply = OrdersBatchResponse {
+        successful: false,
+        error_message: err_msg,
+        limit_order_responses: vec![],
+        perp_order_responses: vec![],
+        cancel_responses: vec![],
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub fn send_cancel_orders_batch_error_reply(
+    err_msg: String,
+) -> Result<Response<CancelOrdersBatchResponse>, Status> {
+    let reply = CancelOrdersBatchResponse {
+        successful: false,
+        error_message: err_msg,
+        cancel_responses: vec![],
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub fn send_cancel_all_error_reply(err_msg: String) -> Result<Response<CancelAllResponse>, Status> {
+    let reply = CancelAllResponse {
+        successful: false,
+        error_message: err_msg,
+        cancelled_order_ids: vec![],
+        pfr_notes: vec![],
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub fn send_dead_mans_switch_error_reply(
+    err_msg: String,
+) -> Result<Response<DeadMansSwitchRes>, Status> {
+    let reply = DeadMansSwitchRes {
+        successful: false,
+        error_message: err_msg,
+        deadline: 0,
+    };
+
+    return Ok(Response::new(reply));
+}
+
 pub fn send_deposit_error_reply(err_msg: String) -> Result<Response<DepositResponse>, Status> {
     let reply = DepositResponse {
         successful: false,
@@ -288,6 +425,16 @@ pub fn send_withdrawal_error_reply(err_msg: String) -> Result<Response<SuccessRe
     return Ok(Response::new(reply));
 }
 
+pub fn send_transfer_error_reply(err_msg: String) -> Result<Response<TransferResponse>, Status> {
+    let reply = TransferResponse {
+        successful: false,
+        error_message: err_msg,
+        zero_idxs: vec![],
+    };
+
+    return Ok(Response::new(reply));
+}
+
 pub fn send_liquidity_error_reply(err_msg: String) -> Result<Response<LiquidityRes>, Status> {
     let reply = LiquidityRes {
         successful: false,
@@ -299,8 +446,42 @@ pub fn send_liquidity_error_reply(err_msg: String) -> Result<Response<LiquidityR
     return Ok(Response::new(reply));
 }
 
-pub fn send_split_notes_error_reply(err_msg: String) -> Result<Response<SplitNotesRes>, Status> {
-    let reply = SplitNotesRes {
+pub fn send_depth_error_reply(err_msg: String) -> Result<Response<DepthRes>, Status> {
+    let reply = DepthRes {
+        successful: false,
+        error_message: err_msg,
+        bids: vec![],
+        asks: vec![],
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub fn send_l3_orders_error_reply(err_msg: String) -> Result<Response<L3OrdersRes>, Status> {
+    let reply = L3OrdersRes {
+        successful: false,
+        error_message: err_msg,
+        bids: vec![],
+        asks: vec![],
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub fn send_market_info_error_reply(err_msg: String) -> Result<Response<MarketInfoRes>, Status> {
+    let reply = MarketInfoRes {
+        successful: false,
+        error_message: err_msg,
+        markets: vec![],
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub fn send_note_restructure_error_reply(
+    err_msg: String,
+) -> Result<Response<NoteRestructureResponse>, Status> {
+    let reply = NoteRestructureResponse {
         successful: false,
         error_message: err_msg,
         zero_idxs: vec![],
@@ -330,6 +511,20 @@ pub fn send_margin_change_error_reply(
     return Ok(Response::new(reply));
 }
 
+pub fn send_set_leverage_error_reply(err_msg: String) -> Result<Response<SetLeverageRes>, Status> {
+    let reply = SetLeverageRes {
+        successful: false,
+        error_message: err_msg,
+        margin_change: 0,
+        leverage: 0,
+        liquidation_price: 0,
+        return_collateral_index: 0,
+        position: None,
+    };
+
+    return Ok(Response::new(reply));
+}
+
 pub fn send_open_tab_error_reply(err_msg: String) -> Result<Response<OpenOrderTabRes>, Status> {
     let reply = OpenOrderTabRes {
         successful: false,
@@ -396,6 +591,26 @@ pub fn send_oracle_update_error_reply(
     return Ok(Response::new(reply));
 }
 
+pub fn send_mm_protection_error_reply(
+    err_msg: String,
+) -> Result<Response<SuccessResponse>, Status> {
+    let reply = SuccessResponse {
+        successful: false,
+        error_message: err_msg,
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub fn send_account_tier_error_reply(err_msg: String) -> Result<Response<SuccessResponse>, Status> {
+    let reply = SuccessResponse {
+        successful: false,
+        error_message: err_msg,
+    };
+
+    return Ok(Response::new(reply));
+}
+
 pub fn send_funding_error_reply(err_msg: String) -> Result<Response<FundingRes>, Status> {
     let reply = FundingRes {
         successful: false,
@@ -405,3 +620,59 @@ pub fn send_funding_error_reply(err_msg: String) -> Result<Response<FundingRes>,
 
     return Ok(Response::new(reply));
 }
+
+pub fn send_position_ledger_error_reply(
+    err_msg: String,
+) -> Result<Response<PositionLedgerRes>, Status> {
+    let reply = PositionLedgerRes {
+        successful: false,
+        entries: vec![],
+        error_message: err_msg,
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub fn send_position_risk_error_reply(
+    err_msg: String,
+) -> Result<Response<PositionRiskRes>, Status> {
+    let reply = PositionRiskRes {
+        successful: false,
+        position: None,
+        risk: None,
+        error_message: err_msg,
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub fn send_positions_risk_batch_error_reply(
+    err_msg: String,
+) -> Result<Response<PositionsRiskBatchRes>, Status> {
+    let reply = PositionsRiskBatchRes {
+        successful: false,
+        results: vec![],
+        error_message: err_msg,
+    };
+
+    return Ok(Response::new(reply));
+}
+
+pub fn send_simulate_perp_order_error_reply(
+    err_msg: String,
+) -> Result<Response<SimulatePerpOrderRes>, Status> {
+    let reply = SimulatePerpOrderRes {
+        successful: false,
+        fills: vec![],
+        synthetic_filled: 0,
+        collateral_filled: 0,
+        average_price: 0,
+        fee_taken: 0,
+        unfilled_amount: 0,
+        position: None,
+        collateral_returned: 0,
+        error_message: err_msg,
+    };
+
+    return Ok(Response::new(reply));
+}
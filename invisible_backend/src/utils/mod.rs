@@ -4,4 +4,5 @@ pub mod ffi;
 
 pub mod crypto_utils;
 pub mod notes;
+pub mod state_reconstruction;
 pub mod storage;
@@ -0,0 +1,329 @@
+use std::{collections::HashMap, str::FromStr};
+
+use error_stack::Result;
+use num_bigint::BigUint;
+use num_traits::{ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    transaction_batch::{
+        batch_functions::batch_transition::TREE_DEPTH,
+        tx_batch_structs::{GlobalDexState, ProgramInputCounts},
+    },
+    trees::{superficial_tree::SuperficialTree, Tree},
+};
+
+use super::{
+    cairo_output::{
+        split_by_bytes, NoteOutput, OrderTabOutput, PerpPositionOutput, ProgramOutput,
+    },
+    errors::{send_state_reconstruction_error, StateReconstructionError},
+    storage::{parse_note_data, parse_position_data, parse_tab_data},
+};
+
+// * BATCH STATE UPDATES ================================================================================
+
+/// The leaf updates of a single transaction batch.\
+/// These can be parsed from the cairo program output or from the DA data of the batch.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BatchStateUpdates {
+    pub note_outputs: Vec<NoteOutput>,
+    pub position_outputs: Vec<PerpPositionOutput>,
+    pub tab_outputs: Vec<OrderTabOutput>,
+    pub zero_idxs: Vec<u64>,
+}
+
+impl BatchStateUpdates {
+    pub fn from_program_output(program_output: &ProgramOutput) -> BatchStateUpdates {
+        BatchStateUpdates {
+            note_outputs: program_output.note_outputs.clone(),
+            position_outputs: program_output.position_outputs.clone(),
+            tab_outputs: program_output.tab_outputs.clone(),
+            zero_idxs: program_output.zero_note_idxs.clone(),
+        }
+    }
+
+    /// Parses the data emitted by _construct_da_output for a batch.\
+    /// The DA data holds no counts, so these are taken from the batch's program output.
+    pub fn from_da_output(
+        da_output: &[BigUint],
+        counts: &ProgramInputCounts,
+    ) -> Result<BatchStateUpdates, StateReconstructionError> {
+        // & da_output structure:
+        // & notes (4 felts each) | positions (3 felts each) | tabs (4 felts each) | zero indexes (3 per felt)
+
+        let n_notes = counts.n_output_notes as usize;
+        let n_positions = counts.n_output_positions as usize;
+        let n_tabs = counts.n_output_tabs as usize;
+        let n_zero_idxs = counts.n_zero_indexes as usize;
+
+        let expected_len = 4 * n_notes + 3 * n_positions + 4 * n_tabs + (n_zero_idxs + 2) / 3;
+        if da_output.len() != expected_len {
+            return Err(send_state_reconstruction_error(format!(
+                "invalid DA output length: expected {} got {}",
+                expected_len,
+                da_output.len()
+            )));
+        }
+
+        let mut i = 0;
+
+        let mut note_outputs = Vec::with_capacity(n_notes);
+        for _ in 0..n_notes {
+            let note_data: [BigUint; 4] = da_output[i..i + 4].to_vec().try_into().unwrap();
+            note_outputs.push(parse_note_data(note_data));
+            i += 4;
+        }
+
+        let mut position_outputs = Vec::with_capacity(n_positions);
+        for _ in 0..n_positions {
+            let position_data: [BigUint; 3] = da_output[i..i + 3].to_vec().try_into().unwrap();
+            position_outputs.push(parse_position_data(position_data));
+            i += 3;
+        }
+
+        let mut tab_outputs = Vec::with_capacity(n_tabs);
+        for _ in 0..n_tabs {
+            let tab_data: [BigUint; 4] = da_output[i..i + 4].to_vec().try_into().unwrap();
+            tab_outputs.push(parse_tab_data(tab_data));
+            i += 4;
+        }
+
+        let zero_idxs = parse_da_zero_idxs(&da_output[i..], n_zero_idxs);
+
+        Ok(BatchStateUpdates {
+            note_outputs,
+            position_outputs,
+            tab_outputs,
+            zero_idxs,
+        })
+    }
+}
+
+/// Zero indexes are packed three per felt (| idx (64 bits) | idx (64 bits) | idx (64 bits) |),
+/// where the last felt only holds the remaining n_zero_idxs % 3 indexes.
+fn parse_da_zero_idxs(output: &[BigUint], n_zero_idxs: usize) -> Vec<u64> {
+    let mut zero_idxs: Vec<u64> = Vec::with_capacity(n_zero_idxs);
+
+    for (i, batched_idxs) in output.iter().enumerate() {
+        let remaining = n_zero_idxs - i * 3;
+        let chunk_len = std::cmp::min(remaining, 3);
+
+        let split_vec = split_by_bytes(batched_idxs, vec![64, 64, 64]);
+        for idx in split_vec[3 - chunk_len..].iter() {
+            zero_idxs.push(idx.to_u64().unwrap());
+        }
+    }
+
+    return zero_idxs;
+}
+
+// * STATE RECONSTRUCTOR ================================================================================
+
+/// Rebuilds the state of the exchange from the public outputs of each batch,
+/// without relying on any of our databases.
+pub struct StateReconstructor {
+    pub state_tree: SuperficialTree, // reconstructed leaves of the state tree
+    pub tree: Tree,                  // full merkle tree used to verify the state roots
+    pub notes: HashMap<u64, NoteOutput>,
+    pub positions: HashMap<u64, PerpPositionOutput>,
+    pub order_tabs: HashMap<u64, OrderTabOutput>,
+    pub latest_batch_id: Option<u32>,
+}
+
+impl StateReconstructor {
+    pub fn new() -> StateReconstructor {
+        StateReconstructor {
+            state_tree: SuperficialTree::new(TREE_DEPTH),
+            tree: Tree::new(TREE_DEPTH, 0),
+            notes: HashMap::new(),
+            positions: HashMap::new(),
+            order_tabs: HashMap::new(),
+            latest_batch_id: None,
+        }
+    }
+
+    pub fn state_root(&self) -> &BigUint {
+        return &self.tree.root;
+    }
+
+    /// Applies the leaf updates contained in the program output of a batch.
+    pub fn apply_program_output(
+        &mut self,
+        program_output: &ProgramOutput,
+    ) -> Result<BigUint, StateReconstructionError> {
+        let updates = BatchStateUpdates::from_program_output(program_output);
+
+        return self.apply_batch(&program_output.dex_state, updates);
+    }
+
+    /// Applies the leaf updates of a batch and verifies that the
+    /// initial and final state roots match the ones in the dex state.
+    pub fn apply_batch(
+        &mut self,
+        dex_state: &GlobalDexState,
+        updates: BatchStateUpdates,
+    ) -> Result<BigUint, StateReconstructionError> {
+        let init_state_root = BigUint::from_str(&dex_state.init_state_root)
+            .map_err(|e| send_state_reconstruction_error(e.to_string()))?;
+        let final_state_root = BigUint::from_str(&dex_state.final_state_root)
+            .map_err(|e| send_state_reconstruction_error(e.to_string()))?;
+
+        if init_state_root != self.tree.root {
+            return Err(send_state_reconstruction_error(format!(
+                "init state root of batch {} does not match the reconstructed root: {} != {}",
+                dex_state.tx_batch_id, init_state_root, self.tree.root
+            )));
+        }
+
+        let mut leaf_updates: HashMap<u64, BigUint> = HashMap::new();
+
+        for note in updates.note_outputs {
+            let hash = parse_leaf_hash(&note.hash)?;
+            leaf_updates.insert(note.index, hash);
+
+            self.positions.remove(&note.index);
+            self.order_tabs.remove(&note.index);
+            self.notes.insert(note.index, note);
+        }
+        for position in updates.position_outputs {
+            let hash = parse_leaf_hash(&position.hash)?;
+            leaf_updates.insert(position.index, hash);
+
+            self.notes.remove(&position.index);
+            self.order_tabs.remove(&position.index);
+            self.positions.insert(position.index, position);
+        }
+        for order_tab in updates.tab_outputs {
+            let hash = parse_leaf_hash(&order_tab.hash)?;
+            leaf_updates.insert(order_tab.index, hash);
+
+            self.notes.remove(&order_tab.index);
+            self.positions.remove(&order_tab.index);
+            self.order_tabs.insert(order_tab.index, order_tab);
+        }
+        for idx in updates.zero_idxs {
+            leaf_updates.insert(idx, BigUint::zero());
+
+            self.notes.remove(&idx);
+            self.positions.remove(&idx);
+            self.order_tabs.remove(&idx);
+        }
+
+        for (idx, leaf_hash) in leaf_updates.iter() {
+            self.state_tree.update_leaf_node(leaf_hash, *idx);
+        }
+        self.state_tree.update_zero_idxs();
+
+        let mut preimage: Map<String, Value> = Map::new();
+        self.tree
+            .batch_transition_updates(&leaf_updates, &mut preimage);
+
+        if final_state_root != self.tree.root {
+            return Err(send_state_reconstruction_error(format!(
+                "final state root of batch {} does not match the reconstructed root: {} != {}",
+                dex_state.tx_batch_id, final_state_root, self.tree.root
+            )));
+        }
+
+        self.latest_batch_id = Some(dex_state.tx_batch_id);
+
+        return Ok(self.tree.root.clone());
+    }
+
+    /// Serializes the reconstructed notes, positions and order tabs.
+    pub fn to_json(&self) -> Value {
+        let mut state_json: Map<String, Value> = Map::new();
+
+        state_json.insert(
+            String::from("latest_batch_id"),
+            serde_json::to_value(&self.latest_batch_id).unwrap(),
+        );
+        state_json.insert(
+            String::from("state_root"),
+            serde_json::to_value(&self.tree.root.to_string()).unwrap(),
+        );
+        state_json.insert(
+            String::from("notes"),
+            serde_json::to_value(&self.notes).unwrap(),
+        );
+        state_json.insert(
+            String::from("positions"),
+            serde_json::to_value(&self.positions).unwrap(),
+        );
+        state_json.insert(
+            String::from("order_tabs"),
+            serde_json::to_value(&self.order_tabs).unwrap(),
+        );
+
+        return Value::Object(state_json);
+    }
+}
+
+/// Replays a sequence of batch program outputs (in batch order) starting from an empty state.
+pub fn reconstruct_state(
+    program_outputs: &[ProgramOutput],
+) -> Result<StateReconstructor, StateReconstructionError> {
+    let mut reconstructor = StateReconstructor::new();
+
+    for program_output in program_outputs {
+        reconstructor.apply_program_output(program_output)?;
+    }
+
+    return Ok(reconstructor);
+}
+
+fn parse_leaf_hash(hash: &str) -> Result<BigUint, StateReconstructionError> {
+    return BigUint::from_str(hash).map_err(|e| send_state_reconstruction_error(e.to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+    use num_traits::FromPrimitive;
+
+    use super::*;
+
+    #[test]
+    fn da_zero_idxs_keep_leading_zero_index() {
+        // ? [0, 5, 7] packed into a single felt followed by a partial chunk [9]
+        let first = BigUint::from_u64(5).unwrap() << 64 | BigUint::from_u64(7).unwrap();
+        let second = BigUint::from_u64(9).unwrap();
+
+        let zero_idxs = parse_da_zero_idxs(&[first, second], 4);
+
+        assert_eq!(zero_idxs, vec![0, 5, 7, 9]);
+    }
+
+    #[test]
+    fn mismatched_init_root_is_rejected() {
+        let mut reconstructor = StateReconstructor::new();
+
+        let counts = ProgramInputCounts {
+            n_output_notes: 0,
+            n_output_positions: 0,
+            n_output_tabs: 0,
+            n_zero_indexes: 0,
+            n_deposits: 0,
+            n_withdrawals: 0,
+            n_onchain_mm_actions: 0,
+            n_note_escapes: 0,
+            n_position_escapes: 0,
+            n_tab_escapes: 0,
+        };
+        let dex_state = GlobalDexState::new(
+            0,
+            &BigUint::from_u8(1).unwrap(),
+            &BigUint::from_u8(1).unwrap(),
+            TREE_DEPTH,
+            0,
+            counts,
+        );
+
+        let res = reconstructor.apply_batch(&dex_state, BatchStateUpdates::default());
+
+        assert!(res.is_err());
+        assert!(reconstructor.latest_batch_id.is_none());
+    }
+}
@@ -63,6 +63,22 @@ impl BackupStorage {
         }
     }
 
+    /// Same as new but the databases are kept in memory and removed when dropped (used for testing).
+    pub fn new_temporary() -> Self {
+        let open_temporary = || Config::new().temporary(true).open().unwrap();
+
+        BackupStorage {
+            note_db: open_temporary(),
+            removable_notes_db: open_temporary(),
+            position_db: open_temporary(),
+            removable_positions_db: open_temporary(),
+            fills_db: open_temporary(),
+            perp_fills_db: open_temporary(),
+            order_tab_db: open_temporary(),
+            removable_order_tab_db: open_temporary(),
+        }
+    }
+
     /// Stores a failed note update in the database.
     pub fn store_note(&self, note: &Note) -> Result<()> {
         // for x in self.note_db.iter() {}
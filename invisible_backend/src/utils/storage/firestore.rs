@@ -41,9 +41,13 @@ pub fn create_session() -> ServiceSession {
     session
 }
 
+/// The session the database updates are written with.\
+/// An offline session (`None`) skips all the writes, so no network calls are made (used for testing and replays).
+pub type FirestoreSession = Option<ServiceSession>;
+
 pub fn retry_failed_updates(
     state_tree: &Arc<Mutex<SuperficialTree>>,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let s: parking_lot::lock_api::MutexGuard<parking_lot::RawMutex, BackupStorage> =
@@ -58,6 +62,10 @@ pub fn retry_failed_updates(
     drop(s);
 
     let sess = session.lock();
+    let sess = match sess.as_ref() {
+        Some(sess) => sess,
+        None => return Ok(()),
+    };
 
     // ? ADD AND REMOVE NOTES TO/FROM THE DATABASE
     let state_tree_m = state_tree.lock();
@@ -169,7 +177,7 @@ fn store_new_perp_fill(
 
 pub fn start_add_note_thread(
     note: Note,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
 ) -> JoinHandle<()> {
     let s = Arc::clone(&session);
@@ -179,14 +187,16 @@ pub fn start_add_note_thread(
         let session_ = s.lock();
 
         // TODO
-        store_new_note(&session_, &backup, &note);
+        if let Some(session_) = session_.as_ref() {
+            store_new_note(session_, &backup, &note);
+        }
         drop(session_);
     });
     return handle;
 }
 
 pub fn start_delete_note_thread(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     address: String,
     idx: String,
@@ -197,7 +207,9 @@ pub fn start_delete_note_thread(
     let handle = spawn(move || {
         let session_ = s.lock();
         // TODO
-        delete_note_at_address(&session_, &backup, address.as_str(), idx.as_str());
+        if let Some(session_) = session_.as_ref() {
+            delete_note_at_address(session_, &backup, address.as_str(), idx.as_str());
+        }
         drop(session_);
     });
     return handle;
@@ -206,7 +218,7 @@ pub fn start_delete_note_thread(
 // POSITIONS
 pub fn start_add_position_thread(
     position: PerpPosition,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
 ) -> JoinHandle<()> {
     let s = Arc::clone(&session);
@@ -217,19 +229,21 @@ pub fn start_add_position_thread(
 
         // let valid_indexes: Vec<u32> = vec![0, 1, 10, 2, 3, 7];
 
-        // TODO
-        // if valid_indexes.contains(&position.index) {
-        store_new_position(&session_, &backup, &position);
-        // }
+        if let Some(session_) = session_.as_ref() {
+            // TODO
+            // if valid_indexes.contains(&position.index) {
+            store_new_position(session_, &backup, &position);
+            // }
 
-        store_new_position(&session_, &backup, &position);
+            store_new_position(session_, &backup, &position);
+        }
         drop(session_);
     });
     return handle;
 }
 
 pub fn start_delete_position_thread(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     address: String,
     idx: String,
@@ -240,7 +254,9 @@ pub fn start_delete_position_thread(
     let handle = spawn(move || {
         let session_ = s.lock();
         // TODO
-        delete_position_at_address(&session_, &backup, address.as_str(), idx.as_str());
+        if let Some(session_) = session_.as_ref() {
+            delete_position_at_address(session_, &backup, address.as_str(), idx.as_str());
+        }
         drop(session_);
     });
     return handle;
@@ -250,7 +266,7 @@ pub fn start_delete_position_thread(
 
 pub fn start_add_order_tab_thread(
     order_tab: OrderTab,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
 ) -> JoinHandle<()> {
     let s = Arc::clone(&session);
@@ -260,14 +276,16 @@ pub fn start_add_order_tab_thread(
         let session_ = s.lock();
         // let backup_storage = backup_storage.lock();
 
-        store_order_tab(&session_, &backup, &order_tab);
+        if let Some(session_) = session_.as_ref() {
+            store_order_tab(session_, &backup, &order_tab);
+        }
         drop(session_);
     });
     return handle;
 }
 
 pub fn start_delete_order_tab_thread(
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     pub_key: String,
     idx: String,
@@ -277,7 +295,9 @@ pub fn start_delete_order_tab_thread(
 
     let handle = spawn(move || {
         let session_ = s.lock();
-        delete_order_tab(&session_, &backup, pub_key.as_str(), idx.as_str());
+        if let Some(session_) = session_.as_ref() {
+            delete_order_tab(session_, &backup, pub_key.as_str(), idx.as_str());
+        }
         drop(session_);
     });
     return handle;
@@ -287,7 +307,7 @@ pub fn start_delete_order_tab_thread(
 
 pub fn start_add_fill_thread(
     fill_info: FillInfo,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
 ) -> JoinHandle<()> {
     let s = Arc::clone(&session);
@@ -304,7 +324,7 @@ pub fn start_add_fill_thread(
 
 pub fn start_add_perp_fill_thread(
     fill_info: PerpFillInfo,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
 ) -> JoinHandle<()> {
     let s = Arc::clone(&session);
@@ -324,15 +344,17 @@ pub fn start_add_perp_fill_thread(
 
 pub fn start_delete_deposit_thread(
     deposit_id: u64,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
 ) -> JoinHandle<()> {
     let s = Arc::clone(&session);
 
     let handle = spawn(move || {
-        let session_: &ServiceSession = &s.lock();
+        let session_ = s.lock();
 
-        let delete_path = format!("deposits/{}", deposit_id);
-        let _r = documents::delete(session_, delete_path.as_str(), true);
+        if let Some(session_) = session_.as_ref() {
+            let delete_path = format!("deposits/{}", deposit_id);
+            let _r = documents::delete(session_, delete_path.as_str(), true);
+        }
     });
 
     return handle;
@@ -345,14 +367,18 @@ pub fn start_add_withdrawal_thread(
     token_id: u32,
     recipient: BigUint,
     is_automatic: bool,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
 ) -> JoinHandle<()> {
     let s = Arc::clone(&session);
 
     println!("storing withdrawal: {:?}", withdrawal_id);
 
     let handle = spawn(move || {
-        let session_: &ServiceSession = &s.lock();
+        let session_ = s.lock();
+        let session_ = match session_.as_ref() {
+            Some(session_) => session_,
+            None => return,
+        };
 
         let withdrawal_json = json!(
             {
@@ -6,11 +6,13 @@ use serde_json::{json, Map, Value};
 
 use sled::Config;
 
+use crate::perpetual::{perp_position::PerpPosition, position_ledger::LedgerEntry};
 use crate::transaction_batch::{
     batch_functions::batch_transition::BatchTransitionInfo,
     restore_state::da_output::helpers::{DepositRequest, WithdrawalRequest},
     tx_batch_structs::OracleUpdate,
 };
+use crate::trees::superficial_tree::SuperficialTree;
 
 use super::firestore::upload_file_to_storage;
 
@@ -37,6 +39,9 @@ pub struct MainStorage {
     pub registerd_onchain_actions_db: sled::Db, // Onchain actions that were registered by the server
     pub latest_batch: u32,                      // every transaction batch stores data separately
     pub batch_transition_info_db: sled::Db, // stores the batch transition info after every batch
+    pub position_ledger_db: sled::Db, // append-only ledger of funding, pnl, fees and liquidations per position address
+    pub positions_db: sled::Db, // latest state of the open positions by index (used for risk queries)
+    pub is_temporary: bool, // if true the databases are kept in memory and dropped with the storage
 }
 
 impl MainStorage {
@@ -70,6 +75,12 @@ impl MainStorage {
         let config = Config::new().path("./storage/db_pending_updates".to_string());
         let db_pending_updates = config.open().unwrap();
 
+        let config = Config::new().path("./storage/position_ledger".to_string());
+        let position_ledger_db = config.open().unwrap();
+
+        let config = Config::new().path("./storage/positions".to_string());
+        let positions_db = config.open().unwrap();
+
         MainStorage {
             tx_db,
             funding_db,
@@ -78,6 +89,30 @@ impl MainStorage {
             price_db,
             db_pending_updates,
             batch_transition_info_db,
+            position_ledger_db,
+            positions_db,
+            is_temporary: false,
+        }
+    }
+
+    /// Creates a storage where all the databases are kept in memory (used for testing).
+    ///
+    /// Only the transactions of the current batch are kept, so reading previous batches
+    /// with read_storage returns an empty result.
+    pub fn new_temporary() -> Self {
+        let open_temporary = || Config::new().temporary(true).open().unwrap();
+
+        MainStorage {
+            tx_db: open_temporary(),
+            funding_db: open_temporary(),
+            registerd_onchain_actions_db: open_temporary(),
+            latest_batch: 1,
+            price_db: open_temporary(),
+            db_pending_updates: open_temporary(),
+            batch_transition_info_db: open_temporary(),
+            position_ledger_db: open_temporary(),
+            positions_db: open_temporary(),
+            is_temporary: true,
         }
     }
 
@@ -138,6 +173,9 @@ impl MainStorage {
         let tx_db;
         let db = if shift_back == 0 {
             &self.tx_db
+        } else if self.is_temporary {
+            tx_db = Config::new().temporary(true).open().unwrap();
+            &tx_db
         } else {
             let batch_index = self.latest_batch - shift_back;
             let config = Config::new()
@@ -231,6 +269,22 @@ impl MainStorage {
         Ok((funding_rates, funding_prices, min_funding_idx))
     }
 
+    // ? The open interest is kept in its own tree, so it doesn't count as stored funding info
+    pub fn store_open_interest(&self, open_interest: &HashMap<u32, u64>) {
+        let open_interest_tree = self.funding_db.open_tree("open_interest").unwrap();
+
+        open_interest_tree
+            .insert("open_interest", serde_json::to_vec(&open_interest).unwrap())
+            .unwrap();
+    }
+
+    pub fn read_open_interest(&self) -> Option<HashMap<u32, u64>> {
+        let open_interest_tree = self.funding_db.open_tree("open_interest").unwrap();
+        let open_interest = open_interest_tree.get("open_interest").unwrap()?;
+
+        return serde_json::from_slice(&open_interest.to_vec()).ok();
+    }
+
     // * PRICE DATA ————————————————————————————————————————————————————————————————————- //
 
     pub fn store_price_data(
@@ -560,6 +614,153 @@ impl MainStorage {
         (deposit_outputs, withdrawal_outputs)
     }
 
+    // * POSITION LEDGER ————————————————————————————————————————————————————————————————- //
+
+    /// Appends entries to the ledger of a position. Entries are never updated or removed.
+    pub fn store_ledger_entries(&self, position_address: &String, entries: &Vec<LedgerEntry>) {
+        for entry in entries {
+            // ? Ids are increasing, so the entries of an address are scanned in insertion order
+            let id = self.position_ledger_db.generate_id().unwrap();
+
+            self.position_ledger_db
+                .insert(
+                    format!("{}-{:020}", position_address, id),
+                    serde_json::to_vec(entry).unwrap(),
+                )
+                .unwrap();
+        }
+    }
+
+    /// Reads the ledger of a position from the oldest entry, skipping the first `offset` entries.
+    pub fn read_ledger_entries(
+        &self,
+        position_address: &String,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<LedgerEntry> {
+        self.position_ledger_db
+            .scan_prefix(format!("{}-", position_address))
+            .skip(offset)
+            .take(limit)
+            .filter_map(|res| res.ok())
+            .filter_map(|(_, value)| serde_json::from_slice(&value).ok())
+            .collect()
+    }
+
+    // * POSITIONS ——————————————————————————————————————————————————————————————————————- //
+
+    /// Stores the latest state of a position (replacing the previous one at its index).
+    ///
+    /// Positions are also indexed by address, in a separate tree, so they can be looked up by either.
+    pub fn store_position(&self, position: &PerpPosition) {
+        self.remove_position(position.index);
+
+        let addresses_tree = self.positions_db.open_tree("addresses").unwrap();
+        addresses_tree
+            .insert(
+                format!(
+                    "{}-{:020}",
+                    position.position_header.position_address, position.index
+                ),
+                vec![],
+            )
+            .unwrap();
+
+        self.positions_db
+            .insert(
+                position.index.to_string(),
+                serde_json::to_vec(position).unwrap(),
+            )
+            .unwrap();
+    }
+
+    /// Removes the position at the index (after it was closed or fully liquidated).
+    pub fn remove_position(&self, index: u64) {
+        let prev_position = self.read_position(index);
+
+        if let Some(prev_position) = prev_position {
+            let addresses_tree = self.positions_db.open_tree("addresses").unwrap();
+            addresses_tree
+                .remove(format!(
+                    "{}-{:020}",
+                    prev_position.position_header.position_address, index
+                ))
+                .unwrap();
+
+            self.positions_db.remove(index.to_string()).unwrap();
+        }
+    }
+
+    /// Stores the new state of a position updated by a swap or liquidation
+    /// (None if it didn't exist before/was closed)
+    pub fn update_position(
+        &self,
+        prev_position: &Option<PerpPosition>,
+        position: &Option<PerpPosition>,
+    ) {
+        match (prev_position, position) {
+            (_, Some(position)) => self.store_position(position),
+            (Some(prev_position), None) => self.remove_position(prev_position.index),
+            (None, None) => {}
+        }
+    }
+
+    /// Syncs the stored positions with the state tree, for the positions that were updated without
+    /// being stored (opened before the positions were tracked here or restored from the DA state).
+    ///
+    /// Stored positions that no longer match their leaf are removed and the state_positions
+    /// that match their leaf are stored in their place.
+    pub fn backfill_positions(
+        &self,
+        state_positions: &[PerpPosition],
+        state_tree: &SuperficialTree,
+    ) {
+        let stale_indexes: Vec<u64> = self
+            .positions_db
+            .iter()
+            .filter_map(|res| res.ok())
+            .filter_map(|(_, value)| serde_json::from_slice::<PerpPosition>(&value).ok())
+            .filter(|position| state_tree.get_leaf_by_index(position.index) != position.hash)
+            .map(|position| position.index)
+            .collect();
+        for index in stale_indexes {
+            self.remove_position(index);
+        }
+
+        for position in state_positions {
+            if state_tree.get_leaf_by_index(position.index) != position.hash {
+                continue;
+            }
+
+            match self.read_position(position.index) {
+                Some(stored_position) if stored_position.hash == position.hash => {}
+                _ => self.store_position(position),
+            }
+        }
+    }
+
+    pub fn read_position(&self, index: u64) -> Option<PerpPosition> {
+        let position = self.positions_db.get(index.to_string()).unwrap()?;
+
+        return serde_json::from_slice(&position.to_vec()).ok();
+    }
+
+    /// Reads all the positions stored at an address (ordered by index).
+    pub fn read_positions_at_address(&self, position_address: &String) -> Vec<PerpPosition> {
+        let addresses_tree = self.positions_db.open_tree("addresses").unwrap();
+
+        addresses_tree
+            .scan_prefix(format!("{}-", position_address))
+            .filter_map(|res| res.ok())
+            .filter_map(|(key, _)| {
+                let key = String::from_utf8(key.to_vec()).ok()?;
+                let index = key.rsplit('-').next()?.parse::<u64>().ok()?;
+
+                self.read_position(index)
+            })
+            .collect()
+    }
+
     // * ------------------------------------------------------------------------------------------
 
     /// Clears the storage to make room for the next batch.
@@ -569,6 +770,13 @@ impl MainStorage {
     ) -> Option<impl std::future::Future<Output = StorageResult>> {
         let new_batch_index = self.latest_batch + 1;
 
+        if self.is_temporary {
+            self.tx_db = Config::new().temporary(true).open().unwrap();
+            self.latest_batch = new_batch_index;
+
+            return self.process_pending_batch_updates(true);
+        }
+
         if new_batch_index >= 5 {
             // ? delete the oldest batch
             let oldest_batch_index = new_batch_index - 5;
@@ -143,6 +143,44 @@ pub fn get_state_at_index(index: u64) -> Option<(LeafNodeType, StateValue)> {
     }
 }
 
+/// Reads all the positions in the stored state (as of the last finalized batch)
+pub fn get_state_positions() -> Vec<PerpPositionOutput> {
+    let config = Config::new().path("./storage/state".to_string());
+    let state_db = config.open().unwrap();
+
+    // ? Leaf types are stored under the json encoded "leaf_type{index}" keys
+    let mut positions = Vec::new();
+    for (key, leaf_type) in state_db
+        .scan_prefix(b"\"leaf_type")
+        .filter_map(|res| res.ok())
+    {
+        match bincode::deserialize::<LeafNodeType>(&leaf_type) {
+            Ok(LeafNodeType::Position) => {}
+            _ => continue,
+        }
+
+        let index = String::from_utf8_lossy(&key)
+            .trim_matches('"')
+            .trim_start_matches("leaf_type")
+            .parse::<u64>();
+        let index = match index {
+            Ok(index) => index,
+            Err(_) => continue,
+        };
+
+        if let Some(position_data) = state_db.get(to_vec(&index).unwrap()).unwrap() {
+            let position_data: [BigUint; 3] = match bincode::deserialize(&position_data) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+
+            positions.push(parse_position_data(position_data));
+        }
+    }
+
+    return positions;
+}
+
 pub fn parse_note_data(note_data: [BigUint; 4]) -> NoteOutput {
     let batched_note_info = note_data[0].clone();
 
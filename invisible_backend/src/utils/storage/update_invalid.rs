@@ -1,5 +1,6 @@
 use std::{collections::HashMap, sync::Arc};
 
+use crate::utils::storage::firestore::FirestoreSession;
 use crate::{
     trees::superficial_tree::SuperficialTree,
     utils::storage::{
@@ -7,7 +8,6 @@ use crate::{
         get_state_at_index, StateValue,
     },
 };
-use firestore_db_and_auth::ServiceSession;
 
 use num_bigint::BigUint;
 use num_traits::Zero;
@@ -20,13 +20,18 @@ use super::backup_storage::BackupStorage;
 /// We monitor the state externally and update it when necessary.
 pub fn update_invalid_state(
     state_tree_m: &Arc<Mutex<SuperficialTree>>,
-    session: &Arc<Mutex<ServiceSession>>,
+    session: &Arc<Mutex<FirestoreSession>>,
     backup_storage: &Arc<Mutex<BackupStorage>>,
     indexes: Vec<u64>,
 ) {
     let state_tree = state_tree_m.lock();
 
     let session = session.lock();
+    let session = match session.as_ref() {
+        Some(session) => session,
+        None => return,
+    };
+
     for i in indexes {
         let state_value = get_state_at_index(i as u64);
 
@@ -51,7 +56,7 @@ pub fn update_invalid_state(
                     i
                 );
 
-                store_note_output(&session, note);
+                store_note_output(session, note);
             }
             StateValue::OrderTab(order_tab_output) => {
                 assert!(
@@ -60,7 +65,7 @@ pub fn update_invalid_state(
                     i
                 );
 
-                store_order_tab_output(&session, order_tab_output);
+                store_order_tab_output(session, order_tab_output);
             }
             StateValue::Position(position_output) => {
                 assert!(
@@ -69,7 +74,7 @@ pub fn update_invalid_state(
                     i
                 );
 
-                store_position_output(&session, backup_storage, position_output);
+                store_position_output(session, backup_storage, position_output);
             }
         }
     }
@@ -0,0 +1,1050 @@
+#![allow(dead_code)]
+
+//! Test harness that runs the exchange in-process, without network access.
+//!
+//! A `TestExchange` starts the `TransactionBatch`, the order books and the `EngineService`
+//! with in-memory storage and an offline firebase session, and exposes helpers to deposit,
+//! place orders, update index prices (signed by a fake oracle), liquidate and finalize batches.
+
+use std::{collections::HashMap, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+
+use num_bigint::BigUint;
+use num_traits::FromPrimitive;
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+use starknet::core::{crypto::ecdsa_sign, types::FieldElement};
+use starknet::curve::{
+    curve_params::{EC_ORDER, GENERATOR},
+    AffinePoint,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{Mutex as TokioMutex, Semaphore},
+    time::timeout,
+};
+use tokio_tungstenite::{accept_async, client_async, tungstenite::Message, WebSocketStream};
+use tonic::{transport::server::TcpConnectInfo, Request};
+
+use invisible_backend::{
+    perpetual::{
+        funding_config::FundingConfig,
+        liquidations::liquidation_order::LiquidationOrder,
+        perp_order::{CloseOrderFields, OpenOrderFields, PerpOrder},
+        perp_position::PerpPosition,
+        OrderSide,
+    },
+    server::{
+        engine::EngineService,
+        grpc::{
+            engine_proto::{
+                engine_server::Engine, Address, DepositMessage, FundingReq, GrpcCloseOrderFields,
+                GrpcOnchainActionType, GrpcOpenOrderFields, GrpcOracleUpdate, GrpcPerpPosition,
+                LiquidationOrderMessage, LiquidationOrderResponse, NoteRestructureMessage,
+                NoteRestructureResponse, OracleUpdateReq, OrderResponse, PerpOrderMessage,
+                PositionLedgerReq, PositionLedgerRes, PositionRiskReq, PositionRiskRes,
+                PositionsRiskBatchReq, PositionsRiskBatchRes, PredictedFundingRes,
+                RegisterOnchainActionRequest, SetLeverageReq, SetLeverageRes,
+                Signature as GrpcSignature, SimulatePerpOrderRes, TransferMessage,
+                TransferResponse,
+            },
+            FundingUpdateMessage,
+        },
+        server_helpers::{
+            engine_helpers::hash_set_leverage_message,
+            init_order_books,
+            rate_limiter::{default_quotas, RateLimiter},
+            WsConnectionsMap,
+        },
+    },
+    transaction_batch::{
+        batch_functions::batch_transition::{
+            _finalize_batch_inner, BatchTransitionInfo, TREE_DEPTH,
+        },
+        tx_batch_helpers::CHAIN_IDS,
+        TransactionBatch,
+    },
+    trees::{superficial_tree::SuperficialTree, Tree},
+    utils::{
+        crypto_utils::{hash_many, EcPoint, Signature},
+        notes::Note,
+        storage::{backup_storage::BackupStorage, local_storage::MainStorage},
+    },
+};
+
+pub const BTC: u32 = 3592681469;
+pub const ETH: u32 = 453755560;
+pub const USDC: u32 = 2413654107;
+pub const SOL: u32 = 277158171;
+
+// Private keys of the oracle observers (see OBSERVERS in tx_batch_structs.rs)
+const OBSERVER_PRIV_KEYS: [u64; 4] = [1, 2, 3, 4];
+
+// * KEYS ==========================================================================================
+
+/// A user with a deterministic private key derived from its id.
+#[derive(Debug, Clone)]
+pub struct TestUser {
+    pub user_id: u64,
+    pub priv_key: BigUint,
+    pub address: EcPoint,
+}
+
+impl TestUser {
+    pub fn new(user_id: u64) -> TestUser {
+        // ? Shift the hash so the key is always smaller than the curve order
+        let priv_key = hash_many(&vec![&BigUint::from_u64(user_id).unwrap()]) >> 8;
+        let address = public_point(&priv_key);
+
+        TestUser {
+            user_id,
+            priv_key,
+            address,
+        }
+    }
+
+    /// The x coordinate of the public key (used as position address and deposit stark key).
+    pub fn stark_key(&self) -> BigUint {
+        return self.address.x.to_biguint().unwrap();
+    }
+
+    pub fn sign(&self, msg_hash: &BigUint) -> Signature {
+        return sign(&self.priv_key, msg_hash);
+    }
+
+    /// Signs a transaction spending several of the user's notes, which is verified against
+    /// the sum of the note addresses (so the key is the user's key times the number of notes).
+    pub fn sign_notes_spent(&self, notes_in: &[Note], msg_hash: &BigUint) -> Signature {
+        let ec_order = BigUint::from_str(&EC_ORDER.to_string()).unwrap();
+        let priv_key_sum = (&self.priv_key * BigUint::from(notes_in.len())) % ec_order;
+
+        return sign(&priv_key_sum, msg_hash);
+    }
+
+    pub fn grpc_address(&self) -> Address {
+        return Address::from(self.address.clone());
+    }
+}
+
+fn public_point(priv_key: &BigUint) -> EcPoint {
+    let priv_key = to_field_element(priv_key);
+    let point: AffinePoint = &GENERATOR * &priv_key.to_bits_le()[..];
+
+    return EcPoint::from(&point);
+}
+
+fn sign(priv_key: &BigUint, msg_hash: &BigUint) -> Signature {
+    let sig = ecdsa_sign(&to_field_element(priv_key), &to_field_element(msg_hash))
+        .expect("message hash out of range");
+
+    return Signature {
+        r: sig.r.to_string(),
+        s: sig.s.to_string(),
+    };
+}
+
+fn to_field_element(num: &BigUint) -> FieldElement {
+    return FieldElement::from_dec_str(&num.to_string()).unwrap();
+}
+
+pub fn grpc_signature(signature: &Signature) -> GrpcSignature {
+    return GrpcSignature {
+        r: signature.r.clone(),
+        s: signature.s.clone(),
+    };
+}
+
+/// Signs a perpetual order message with the user's key.
+pub fn sign_perp_order(user: &TestUser, mut order: PerpOrderMessage) -> PerpOrderMessage {
+    let order_hash = PerpOrder::try_from(order.clone()).unwrap().hash;
+    order.signature = Some(grpc_signature(&user.sign(&order_hash)));
+
+    order
+}
+
+/// Builds and signs an open order directly (without going through the grpc messages),
+/// using the whole margin note as initial margin.
+pub fn signed_open_order(
+    user: &TestUser,
+    order_side: OrderSide,
+    synthetic_token: u32,
+    synthetic_amount: u64,
+    collateral_amount: u64,
+    margin_note: &Note,
+) -> (PerpOrder, Signature) {
+    let order = PerpOrder::new_open_order(
+        0,
+        4_000_000_000,
+        order_side,
+        synthetic_token,
+        synthetic_amount,
+        collateral_amount,
+        collateral_amount / 100,
+        OpenOrderFields {
+            initial_margin: margin_note.amount,
+            collateral_token: USDC,
+            notes_in: vec![margin_note.clone()],
+            refund_note: None,
+            position_address: user.stark_key(),
+            allow_partial_liquidations: false,
+        },
+    );
+    let signature = user.sign(&order.hash);
+
+    return (order, signature);
+}
+
+// * FAKE ORACLE ===================================================================================
+
+/// Produces oracle price updates signed by all the observers.
+pub struct FakeOracle {
+    pub timestamp: u32,
+}
+
+impl FakeOracle {
+    pub fn new() -> FakeOracle {
+        FakeOracle {
+            timestamp: 1_700_000_000,
+        }
+    }
+
+    pub fn price_update(&mut self, token: u32, price: u64) -> GrpcOracleUpdate {
+        self.timestamp += 1;
+
+        // & msg = (price << 64 + token) << 64 + timestamp
+        let msg = (BigUint::from(price) * BigUint::from(2u128).pow(64) + BigUint::from(token))
+            * BigUint::from(2u128).pow(64)
+            + BigUint::from(self.timestamp);
+
+        let signatures = OBSERVER_PRIV_KEYS
+            .iter()
+            .map(|k| grpc_signature(&sign(&BigUint::from(*k), &msg)))
+            .collect::<Vec<GrpcSignature>>();
+
+        GrpcOracleUpdate {
+            token,
+            timestamp: self.timestamp,
+            observer_ids: (0..OBSERVER_PRIV_KEYS.len() as u32).collect(),
+            prices: vec![price; OBSERVER_PRIV_KEYS.len()],
+            signatures,
+        }
+    }
+}
+
+// * TEST EXCHANGE =================================================================================
+
+pub struct TestExchangeBuilder {
+    index_prices: Vec<(u32, u64)>,
+}
+
+impl TestExchangeBuilder {
+    pub fn new() -> TestExchangeBuilder {
+        TestExchangeBuilder {
+            index_prices: Vec::new(),
+        }
+    }
+
+    /// Sets the initial index price of a synthetic token through a signed oracle update.
+    pub fn with_index_price(mut self, token: u32, price: u64) -> TestExchangeBuilder {
+        self.index_prices.push((token, price));
+        self
+    }
+
+    pub async fn build(self) -> TestExchange {
+        // ? No firebase session, so the database updates are skipped
+        let tx_batch = TransactionBatch::new_with_storage(
+            TREE_DEPTH,
+            None,
+            MainStorage::new_temporary(),
+            BackupStorage::new_temporary(),
+        );
+
+        let (order_books, perp_order_books) = init_order_books();
+
+        let ws_connections: WsConnectionsMap = HashMap::new();
+
+        let service = EngineService {
+            transaction_batch: Arc::new(TokioMutex::new(tx_batch)),
+            order_books,
+            perp_order_books,
+            ws_connections: Arc::new(TokioMutex::new(ws_connections)),
+            privileged_ws_connections: Arc::new(TokioMutex::new(Vec::new())),
+            dead_mans_switches: Arc::new(TokioMutex::new(HashMap::new())),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::new(default_quotas()))),
+            semaphore: Semaphore::new(25),
+            is_paused: Arc::new(TokioMutex::new(false)),
+        };
+
+        let mut exchange = TestExchange {
+            service,
+            oracle: FakeOracle::new(),
+            tree: Tree::new(TREE_DEPTH, 0),
+            positions: HashMap::new(),
+            next_deposit_id: 0,
+            next_blinding: 1,
+        };
+
+        for (token, price) in self.index_prices {
+            exchange.update_index_price(token, price).await;
+        }
+
+        exchange
+    }
+}
+
+/// The result of finalizing a batch with the in-memory merkle tree.
+pub struct FinalizedBatch {
+    pub batch_index: u32,
+    pub prev_state_root: BigUint,
+    pub prev_state: SuperficialTree, // leaves of the state before the batch, to replay it from
+    pub new_state_root: BigUint,
+    pub transactions: Vec<Map<String, Value>>,
+    pub transition_info: BatchTransitionInfo,
+}
+
+pub struct TestExchange {
+    pub service: EngineService,
+    pub oracle: FakeOracle,
+    pub tree: Tree, // full merkle tree of the state, updated when a batch is finalized
+    pub positions: HashMap<u64, PerpPosition>, // latest known positions by state index
+    next_deposit_id: u64,
+    next_blinding: u64,
+}
+
+impl TestExchange {
+    pub fn builder() -> TestExchangeBuilder {
+        TestExchangeBuilder::new()
+    }
+
+    pub fn new_note(&mut self, user: &TestUser, token: u32, amount: u64, index: u64) -> Note {
+        self.next_blinding += 1;
+
+        return Note::new(
+            index,
+            user.address.clone(),
+            token,
+            amount,
+            BigUint::from(self.next_blinding),
+        );
+    }
+
+    // * ORACLE * //
+
+    pub async fn update_index_price(&mut self, token: u32, price: u64) {
+        let update = self.oracle.price_update(token, price);
+
+        let response = self
+            .service
+            .update_index_price(local_request(OracleUpdateReq {
+                oracle_price_updates: vec![update],
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert!(
+            response.successful,
+            "index price update failed: {}",
+            response.error_message
+        );
+    }
+
+    // * DEPOSITS * //
+
+    /// Registers a deposit onchain action and executes it, returning the deposited note.
+    pub async fn deposit(&mut self, user: &TestUser, token: u32, amount: u64) -> Note {
+        self.next_deposit_id += 1;
+        let deposit_id = (CHAIN_IDS[0] as u64) * 2u64.pow(32) + self.next_deposit_id;
+
+        let mut note = self.new_note(user, token, amount, 0);
+
+        // & commitment = H(depositId, starkKey, token, deposit_amount)
+        let stark_key = user.stark_key();
+        let data_commitment = hash_many(&vec![
+            &BigUint::from(deposit_id),
+            &stark_key,
+            &BigUint::from(token),
+            &BigUint::from(amount),
+        ]);
+
+        let response = self
+            .service
+            .register_onchain_action(local_request(RegisterOnchainActionRequest {
+                action_type: GrpcOnchainActionType::Deposit as i32,
+                data_id: deposit_id,
+                data_commitment: data_commitment.to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.successful, "{}", response.error_message);
+
+        // & deposit_hash = H(note hashes, deposit_id)
+        let deposit_hash = hash_many(&vec![&note.hash, &BigUint::from(deposit_id)]);
+        let signature = user.sign(&deposit_hash);
+
+        let response = self
+            .service
+            .execute_deposit(local_request(DepositMessage {
+                deposit_id,
+                deposit_token: token,
+                deposit_amount: amount,
+                stark_key: stark_key.to_string(),
+                notes: vec![note.clone().into()],
+                signature: Some(grpc_signature(&signature)),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(
+            response.successful,
+            "deposit failed: {}",
+            response.error_message
+        );
+
+        note.index = response.zero_idxs[0];
+
+        return note;
+    }
+
+    // * TRANSFERS * //
+
+    /// Builds a signed transfer of `amount` from one of the sender's notes to a new recipient
+    /// note, returning the rest to the sender.
+    pub fn transfer_message(
+        &mut self,
+        sender: &TestUser,
+        recipient: &TestUser,
+        note_in: &Note,
+        amount: u64,
+    ) -> TransferMessage {
+        let note_out = self.new_note(recipient, note_in.token, amount, 0);
+        let refund_note = if note_in.amount > amount {
+            Some(self.new_note(sender, note_in.token, note_in.amount - amount, 0))
+        } else {
+            None
+        };
+
+        // & transfer_hash = H(notes_in hashes, refund_note hash, notes_out hashes)
+        let z = BigUint::from(0u32);
+        let refund_hash = refund_note.as_ref().map(|n| &n.hash).unwrap_or(&z);
+        let transfer_hash = hash_many(&vec![&note_in.hash, refund_hash, &note_out.hash]);
+        let signature = sender.sign(&transfer_hash);
+
+        TransferMessage {
+            token: note_in.token,
+            recipient: Some(recipient.grpc_address()),
+            notes_in: vec![note_in.clone().into()],
+            notes_out: vec![note_out.into()],
+            refund_note: refund_note.map(|n| n.into()),
+            signature: Some(grpc_signature(&signature)),
+        }
+    }
+
+    pub async fn execute_transfer(&mut self, transfer: TransferMessage) -> TransferResponse {
+        return self
+            .service
+            .execute_transfer(Request::new(transfer))
+            .await
+            .unwrap()
+            .into_inner();
+    }
+
+    /// Restructures the user's notes into new notes of the given amounts, paying `fee`.
+    pub fn restructure_message(
+        &mut self,
+        user: &TestUser,
+        notes_in: &[Note],
+        amounts_out: &[u64],
+        fee: u64,
+    ) -> NoteRestructureMessage {
+        let token = notes_in[0].token;
+        let notes_out = amounts_out
+            .iter()
+            .map(|amount| self.new_note(user, token, *amount, 0))
+            .collect::<Vec<Note>>();
+
+        // & restructure_hash = H(notes_in hashes, notes_out hashes, fee)
+        let fee_ = BigUint::from(fee);
+        let mut hash_inputs = notes_in.iter().map(|n| &n.hash).collect::<Vec<&BigUint>>();
+        hash_inputs.extend(notes_out.iter().map(|n| &n.hash));
+        hash_inputs.push(&fee_);
+        let signature = user.sign_notes_spent(notes_in, &hash_many(&hash_inputs));
+
+        NoteRestructureMessage {
+            token,
+            notes_in: notes_in.iter().map(|n| n.clone().into()).collect(),
+            notes_out: notes_out.into_iter().map(|n| n.into()).collect(),
+            fee,
+            signature: Some(grpc_signature(&signature)),
+        }
+    }
+
+    pub async fn restructure_notes(
+        &mut self,
+        restructure: NoteRestructureMessage,
+    ) -> NoteRestructureResponse {
+        return self
+            .service
+            .restructure_notes(Request::new(restructure))
+            .await
+            .unwrap()
+            .into_inner();
+    }
+
+    // * PERPETUAL ORDERS * //
+
+    /// Builds an order opening a position, using a single collateral note as margin.
+    /// Whatever is left of the note after the initial margin is returned as a refund note.
+    pub fn open_order_message(
+        &mut self,
+        user: &TestUser,
+        order_side: OrderSide,
+        synthetic_token: u32,
+        synthetic_amount: u64,
+        collateral_amount: u64,
+        initial_margin: u64,
+        margin_note: &Note,
+    ) -> PerpOrderMessage {
+        let refund_note = if margin_note.amount > initial_margin {
+            let note = self.new_note(
+                user,
+                margin_note.token,
+                margin_note.amount - initial_margin,
+                margin_note.index,
+            );
+            Some(note.into())
+        } else {
+            None
+        };
+
+        PerpOrderMessage {
+            expiration_timestamp: 4_000_000_000,
+            position: None,
+            position_effect_type: 0,
+            order_side: order_side == OrderSide::Long,
+            synthetic_token,
+            collateral_token: USDC,
+            synthetic_amount,
+            collateral_amount,
+            fee_limit: collateral_amount / 100,
+            open_order_fields: Some(GrpcOpenOrderFields {
+                initial_margin,
+                collateral_token: USDC,
+                notes_in: vec![margin_note.clone().into()],
+                refund_note,
+                position_address: user.stark_key().to_string(),
+                allow_partial_liquidations: false,
+            }),
+            close_order_fields: None,
+            signature: None,
+            is_market: false,
+            user_id: user.user_id,
+            client_order_id: 0,
+            reduce_only: false,
+        }
+    }
+
+    /// Builds an order closing (part of) an existing position.
+    pub fn close_order_message(
+        &mut self,
+        user: &TestUser,
+        position: &PerpPosition,
+        synthetic_amount: u64,
+        collateral_amount: u64,
+    ) -> PerpOrderMessage {
+        let order_side = position.order_side != OrderSide::Long;
+
+        self.next_blinding += 1;
+
+        PerpOrderMessage {
+            expiration_timestamp: 4_000_000_000,
+            position: Some(GrpcPerpPosition::from(position.clone())),
+            position_effect_type: 2,
+            order_side,
+            synthetic_token: position.position_header.synthetic_token,
+            collateral_token: USDC,
+            synthetic_amount,
+            collateral_amount,
+            fee_limit: collateral_amount / 100,
+            open_order_fields: None,
+            close_order_fields: Some(GrpcCloseOrderFields {
+                dest_received_address: Some(user.grpc_address()),
+                dest_received_blinding: self.next_blinding.to_string(),
+            }),
+            signature: None,
+            is_market: false,
+            user_id: user.user_id,
+            client_order_id: 0,
+            reduce_only: false,
+        }
+    }
+
+    /// Builds an order increasing, decreasing or flipping an existing position.
+    pub fn modify_order_message(
+        &mut self,
+        user: &TestUser,
+        position: &PerpPosition,
+        order_side: OrderSide,
+        synthetic_amount: u64,
+        collateral_amount: u64,
+    ) -> PerpOrderMessage {
+        PerpOrderMessage {
+            expiration_timestamp: 4_000_000_000,
+            position: Some(GrpcPerpPosition::from(position.clone())),
+            position_effect_type: 1,
+            order_side: order_side == OrderSide::Long,
+            synthetic_token: position.position_header.synthetic_token,
+            collateral_token: USDC,
+            synthetic_amount,
+            collateral_amount,
+            fee_limit: collateral_amount / 100,
+            open_order_fields: None,
+            close_order_fields: None,
+            signature: None,
+            is_market: false,
+            user_id: user.user_id,
+            client_order_id: 0,
+            reduce_only: false,
+        }
+    }
+
+    /// Signs and submits a perpetual order, keeping track of the positions updated by its swaps.
+    pub async fn submit_perp_order(
+        &mut self,
+        user: &TestUser,
+        order: PerpOrderMessage,
+    ) -> OrderResponse {
+        let order = sign_perp_order(user, order);
+
+        let (response, updated_positions) = self
+            .service
+            .submit_perpetual_order_with_positions(order)
+            .await;
+
+        for (position_a, position_b) in updated_positions {
+            for position in [position_a, position_b].into_iter().flatten() {
+                self.track_position(position);
+            }
+        }
+
+        return response.unwrap().into_inner();
+    }
+
+    /// Returns the latest known position of the user for the synthetic token.
+    pub fn position(&self, user: &TestUser, synthetic_token: u32) -> Option<PerpPosition> {
+        let address = user.stark_key();
+
+        self.positions
+            .values()
+            .find(|p| {
+                p.position_header.position_address == address
+                    && p.position_header.synthetic_token == synthetic_token
+            })
+            .cloned()
+    }
+
+    fn track_position(&mut self, position: PerpPosition) {
+        // ? A position can be moved to a different index, so remove it by address and token first
+        self.positions.retain(|_, p| {
+            p.position_header.position_address != position.position_header.position_address
+                || p.position_header.synthetic_token != position.position_header.synthetic_token
+        });
+
+        if position.position_size > 0 {
+            self.positions.insert(position.index, position);
+        }
+    }
+
+    // * LIQUIDATIONS * //
+
+    /// Liquidates the position, opening a new position for the liquidator with the given margin.
+    ///
+    /// The liquidated position is no longer tracked afterwards (positions are opened
+    /// with allow_partial_liquidations = false, so they are always fully liquidated).
+    pub async fn liquidate(
+        &mut self,
+        liquidator: &TestUser,
+        position: &PerpPosition,
+        collateral_amount: u64,
+        initial_margin: u64,
+        margin_note: &Note,
+    ) -> LiquidationOrderResponse {
+        let refund_note = if margin_note.amount > initial_margin {
+            let note = self.new_note(
+                liquidator,
+                margin_note.token,
+                margin_note.amount - initial_margin,
+                margin_note.index,
+            );
+            Some(note.into())
+        } else {
+            None
+        };
+
+        let mut order = LiquidationOrderMessage {
+            position: Some(GrpcPerpPosition::from(position.clone())),
+            order_side: position.order_side == OrderSide::Long,
+            synthetic_token: position.position_header.synthetic_token,
+            synthetic_amount: position.position_size,
+            collateral_amount,
+            open_order_fields: Some(GrpcOpenOrderFields {
+                initial_margin,
+                collateral_token: USDC,
+                notes_in: vec![margin_note.clone().into()],
+                refund_note,
+                position_address: liquidator.stark_key().to_string(),
+                allow_partial_liquidations: false,
+            }),
+            signature: None,
+            user_id: liquidator.user_id,
+        };
+
+        let order_hash = LiquidationOrder::try_from(order.clone()).unwrap().hash;
+        order.signature = Some(grpc_signature(&liquidator.sign(&order_hash)));
+
+        let response = self
+            .service
+            .submit_liquidation_order(Request::new(order))
+            .await
+            .unwrap()
+            .into_inner();
+
+        if response.successful {
+            self.positions.remove(&position.index);
+
+            if let Some(new_position) = &response.new_position {
+                self.track_position(PerpPosition::try_from(new_position.clone()).unwrap());
+            }
+        }
+
+        return response;
+    }
+
+    /// Sets the leverage of the position, adding margin from margin_notes (if any) or removing it.
+    /// Refunds and removed margin go to a new note of the user. Requests sign the margin change
+    /// of a dry run and update the tracked position if successful, dry runs only return the preview.
+    pub async fn set_leverage(
+        &mut self,
+        user: &TestUser,
+        position: &PerpPosition,
+        leverage: u64,
+        margin_notes: &[Note],
+        dry_run: bool,
+    ) -> SetLeverageRes {
+        let preview = self
+            .send_set_leverage(user, position, leverage, margin_notes, None)
+            .await;
+        if dry_run || !preview.successful {
+            return preview;
+        }
+
+        return self
+            .set_leverage_with_margin_change(
+                user,
+                position,
+                leverage,
+                margin_notes,
+                preview.margin_change,
+            )
+            .await;
+    }
+
+    /// Sets the leverage of the position, signing margin_change instead of the dry run's one.
+    pub async fn set_leverage_with_margin_change(
+        &mut self,
+        user: &TestUser,
+        position: &PerpPosition,
+        leverage: u64,
+        margin_notes: &[Note],
+        margin_change: i64,
+    ) -> SetLeverageRes {
+        let response = self
+            .send_set_leverage(user, position, leverage, margin_notes, Some(margin_change))
+            .await;
+
+        if response.successful {
+            let new_position = response.position.clone().unwrap();
+            self.track_position(PerpPosition::try_from(new_position).unwrap());
+        }
+
+        return response;
+    }
+
+    /// Sends a set leverage request signing margin_change, or a dry run if there is none.
+    async fn send_set_leverage(
+        &mut self,
+        user: &TestUser,
+        position: &PerpPosition,
+        leverage: u64,
+        margin_notes: &[Note],
+        margin_change: Option<i64>,
+    ) -> SetLeverageRes {
+        self.next_blinding += 1;
+
+        let close_order_fields = GrpcCloseOrderFields {
+            dest_received_address: Some(user.grpc_address()),
+            dest_received_blinding: self.next_blinding.to_string(),
+        };
+
+        let signature = margin_change.map(|margin_change| {
+            let msg_hash = hash_set_leverage_message(
+                margin_change,
+                &margin_notes.to_vec(),
+                &CloseOrderFields::try_from(close_order_fields.clone()).unwrap(),
+                position,
+            );
+
+            grpc_signature(&user.sign(&msg_hash))
+        });
+
+        let request = SetLeverageReq {
+            leverage,
+            notes_in: margin_notes.iter().map(|n| n.clone().into()).collect(),
+            close_order_fields: Some(close_order_fields),
+            position: Some(GrpcPerpPosition::from(position.clone())),
+            signature,
+            user_id: user.user_id,
+            dry_run: margin_change.is_none(),
+            margin_change: margin_change.unwrap_or_default(),
+        };
+
+        return self
+            .service
+            .set_leverage(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+    }
+
+    // * FUNDING * //
+
+    /// Feeds a funding premium sample taken at `timestamp` (unix seconds),
+    /// with the (token, impact_bid, impact_ask) prices of the perp books.
+    pub async fn sample_funding(&mut self, timestamp: u64, impact_prices: &[(u32, u64, u64)]) {
+        let impact_prices = impact_prices
+            .iter()
+            .map(|(token, bid, ask)| (*token, (*bid, *ask)))
+            .collect();
+
+        let mut tx_batch = self.service.transaction_batch.lock().await;
+        tx_batch.per_minute_funding_updates(FundingUpdateMessage {
+            impact_prices,
+            timestamp,
+        });
+    }
+
+    pub async fn funding_config(&self) -> FundingConfig {
+        let tx_batch = self.service.transaction_batch.lock().await;
+
+        return tx_batch.funding_config.clone();
+    }
+
+    pub async fn funding_rates(&self, token: u32) -> Vec<i64> {
+        let tx_batch = self.service.transaction_batch.lock().await;
+
+        return tx_batch.funding_rates.get(&token).unwrap().clone();
+    }
+
+    /// The open interest of a market as reported by get_funding_info
+    pub async fn open_interest(&self, token: u32) -> u64 {
+        let response = self
+            .service
+            .get_funding_info(Request::new(FundingReq {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert!(response.successful, "{}", response.error_message);
+        response
+            .fundings
+            .iter()
+            .find(|f| f.token == token)
+            .map(|f| f.open_interest)
+            .unwrap()
+    }
+
+    pub async fn predicted_funding(&self) -> PredictedFundingRes {
+        let response = self
+            .service
+            .get_predicted_funding(Request::new(FundingReq {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert!(response.successful, "{}", response.error_message);
+        response
+    }
+
+    pub async fn position_ledger(&self, position_address: &BigUint) -> PositionLedgerRes {
+        let response = self
+            .service
+            .get_position_ledger(Request::new(PositionLedgerReq {
+                position_address: position_address.to_string(),
+                offset: 0,
+                limit: 0,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert!(response.successful, "{}", response.error_message);
+        response
+    }
+
+    /// Queries the risk of the position at an address (the response can be unsuccessful)
+    pub async fn position_risk(&self, position_address: &BigUint) -> PositionRiskRes {
+        self.service
+            .get_position_risk(Request::new(PositionRiskReq {
+                position_address: position_address.to_string(),
+                index: 0,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    pub async fn positions_risk_batch(
+        &self,
+        positions: Vec<PositionRiskReq>,
+    ) -> PositionsRiskBatchRes {
+        let response = self
+            .service
+            .get_positions_risk_batch(Request::new(PositionsRiskBatchReq { positions }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert!(response.successful, "{}", response.error_message);
+        response
+    }
+
+    /// Simulates the order against the current book (the order is not signed)
+    pub async fn simulate_perp_order(&self, order: PerpOrderMessage) -> SimulatePerpOrderRes {
+        self.service
+            .simulate_perp_order(Request::new(order))
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    // * BATCH FINALIZATION * //
+
+    /// Finalizes the current batch and computes the new state root with the in-memory tree
+    /// instead of the partitioned trees on disk (no prover input or uploads are produced).
+    pub async fn finalize_batch(&mut self) -> FinalizedBatch {
+        let mut tx_batch_m = self.service.transaction_batch.lock().await;
+        let tx_batch = &mut *tx_batch_m;
+
+        let transition_info = _finalize_batch_inner(
+            &tx_batch.state_tree,
+            &tx_batch.updated_state_hashes,
+            &tx_batch.swap_output_json,
+            &tx_batch.main_storage,
+            &tx_batch.insurance_fund,
+            &mut tx_batch.funding_rates,
+            &mut tx_batch.funding_prices,
+            &tx_batch.min_funding_idxs,
+            &mut tx_batch.min_index_price_data,
+            &mut tx_batch.max_index_price_data,
+        );
+
+        let mut main_storage = tx_batch.main_storage.lock();
+        let transactions = main_storage.read_storage(0);
+        // ? The pending updates are only uploaded if the returned future is awaited
+        let _ = main_storage.transition_to_new_batch();
+        drop(main_storage);
+        drop(tx_batch_m);
+
+        let leaf_updates: HashMap<u64, BigUint> = transition_info
+            .updated_state_hashes
+            .iter()
+            .map(|(idx, (_, hash))| (*idx, hash.clone()))
+            .collect();
+
+        let prev_state_root = self.tree.root.clone();
+        let prev_state = SuperficialTree::from_tree(self.tree.clone());
+        let mut preimage: Map<String, Value> = Map::new();
+        self.tree
+            .batch_transition_updates(&leaf_updates, &mut preimage);
+
+        FinalizedBatch {
+            batch_index: transition_info.current_batch_index,
+            prev_state_root,
+            prev_state,
+            new_state_root: self.tree.root.clone(),
+            transactions,
+            transition_info,
+        }
+    }
+
+    // * STATE * //
+
+    pub async fn leaf_hash(&self, index: u64) -> BigUint {
+        let tx_batch = self.service.transaction_batch.lock().await;
+        let state_tree = tx_batch.state_tree.lock();
+
+        return state_tree.get_leaf_by_index(index);
+    }
+
+    pub async fn insurance_fund(&self) -> i64 {
+        let tx_batch = self.service.transaction_batch.lock().await;
+        let insurance_fund = *tx_batch.insurance_fund.lock();
+
+        return insurance_fund;
+    }
+
+    // * WEBSOCKETS * //
+
+    /// Opens a websocket for user over localhost and registers the engine's end of it, so the
+    /// messages the engine sends to the user can be read from the returned client.
+    pub async fn connect_websocket(&self, user: &TestUser) -> WebSocketStream<TcpStream> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(
+            async {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                client_async(format!("ws://{}", addr), stream)
+                    .await
+                    .unwrap()
+                    .0
+            },
+            async {
+                let (stream, _) = listener.accept().await.unwrap();
+                accept_async(stream).await.unwrap()
+            },
+        );
+
+        let (ws_sender, _) = server.split();
+        let mut ws_connections = self.service.ws_connections.lock().await;
+        ws_connections.insert(user.user_id, ws_sender);
+
+        client
+    }
+}
+
+/// Reads the json messages that arrived on a websocket until it stays quiet for 200ms.
+pub async fn received_messages(websocket: &mut WebSocketStream<TcpStream>) -> Vec<Value> {
+    let mut messages = Vec::new();
+    while let Ok(Some(Ok(msg))) = timeout(Duration::from_millis(200), websocket.next()).await {
+        if let Message::Text(text) = msg {
+            messages.push(serde_json::from_str(&text).unwrap());
+        }
+    }
+
+    messages
+}
+
+/// Wraps a message in a request coming from localhost, as required by the admin endpoints.
+pub fn local_request<T>(message: T) -> Request<T> {
+    let mut request = Request::new(message);
+
+    request.extensions_mut().insert(TcpConnectInfo {
+        local_addr: None,
+        remote_addr: Some(SocketAddr::from_str("127.0.0.1:50052").unwrap()),
+    });
+
+    request
+}
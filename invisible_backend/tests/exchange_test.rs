@@ -0,0 +1,1823 @@
+mod common;
+
+use num_traits::Zero;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::WebSocketStream;
+
+use tonic::{Code, Request};
+
+use common::{
+    grpc_signature, local_request, received_messages, sign_perp_order, TestExchange, TestUser, BTC,
+    USDC,
+};
+use invisible_backend::{
+    perpetual::{perp_order::CloseOrderFields, position_risk::get_position_risk, OrderSide},
+    server::{
+        grpc::{
+            engine_proto::{
+                engine_server::Engine, CancelAllRequest, CancelOrderMessage, DeadMansSwitchReq,
+                GrpcLedgerEntryType, GrpcPositionRisk, OrdersBatchRequest, OrdersReq,
+                PositionRiskReq, SetAccountTierReq,
+            },
+            ChangeMarginMessage,
+        },
+        server_helpers::{
+            dead_mans_switch::{
+                cancel_expired_switches, dead_mans_switch_message_hash, dead_mans_switch_user_id,
+                MAX_DEAD_MANS_SWITCH_TIMEOUT,
+            },
+            engine_helpers::verify_margin_change_signature,
+            periodic_updates::get_impact_prices,
+            rate_limiter::{AccountTier, Quota},
+            PERP_MARKET_IDS,
+        },
+    },
+    transaction_batch::{
+        batch_functions::batch_transition::TREE_DEPTH,
+        restore_state::{
+            _get_da_updates_inner,
+            replay::{get_batch_funding_info, replay_batch},
+        },
+        tx_batch_helpers::{_per_minute_funding_update_inner, get_final_updated_counts},
+        tx_batch_structs::GlobalDexState,
+    },
+    utils::{
+        notes::Note,
+        state_reconstruction::{BatchStateUpdates, StateReconstructor},
+    },
+};
+
+// BTC amounts have 8 decimals, USDC amounts and prices have 6 decimals
+const BTC_PRICE: u64 = 30_000 * 10u64.pow(6);
+// Leverage has 4 decimals
+const LEVERAGE: u64 = 10u64.pow(4);
+
+fn btc(amount: f64) -> u64 {
+    (amount * 1e8) as u64
+}
+
+fn usdc(amount: f64) -> u64 {
+    (amount * 1e6) as u64
+}
+
+/// Rebuilds a margin change from its json in the batch output
+fn margin_change_from_json(json: &Value) -> ChangeMarginMessage {
+    let fields_json = &json["close_order_fields"];
+    let close_order_fields = CloseOrderFields {
+        dest_received_address: serde_json::from_value(fields_json["dest_received_address"].clone())
+            .unwrap(),
+        dest_received_blinding: fields_json["dest_received_blinding"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap(),
+    };
+
+    ChangeMarginMessage {
+        margin_change: json["margin_change"].as_i64().unwrap(),
+        notes_in: serde_json::from_value(json["notes_in"].clone()).unwrap(),
+        refund_note: serde_json::from_value(json["refund_note"].clone()).unwrap(),
+        close_order_fields: Some(close_order_fields),
+        position: serde_json::from_value(json["position"].clone()).unwrap(),
+        signature: serde_json::from_value(json["signature"].clone()).unwrap(),
+        user_id: json["user_id"].as_u64().unwrap(),
+        target_leverage: None,
+    }
+}
+
+/// The REDUCE_ONLY_ORDERS_UPDATED messages among the ones that arrived on the websocket
+async fn reduce_only_updates(websocket: &mut WebSocketStream<TcpStream>) -> Vec<Value> {
+    let messages = received_messages(websocket).await;
+
+    messages
+        .into_iter()
+        .filter(|msg| msg["message_id"] == "REDUCE_ONLY_ORDERS_UPDATED")
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deposits_are_added_to_the_state() {
+    let mut exchange = TestExchange::builder().build().await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+
+    let note_a = exchange.deposit(&alice, USDC, usdc(1000.0)).await;
+    let note_b = exchange.deposit(&bob, BTC, btc(0.5)).await;
+
+    assert_ne!(note_a.index, note_b.index);
+    assert_eq!(exchange.leaf_hash(note_a.index).await, note_a.hash);
+    assert_eq!(exchange.leaf_hash(note_b.index).await, note_b.hash);
+
+    let batch = exchange.finalize_batch().await;
+
+    assert_eq!(batch.transactions.len(), 2);
+    assert_ne!(batch.prev_state_root, batch.new_state_root);
+    assert_eq!(
+        batch.transition_info.updated_state_hashes[&note_a.index].1,
+        note_a.hash
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfers_move_notes_to_the_recipient() {
+    let mut exchange = TestExchange::builder().build().await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+
+    let note_a = exchange.deposit(&alice, USDC, usdc(1000.0)).await;
+
+    let transfer = exchange.transfer_message(&alice, &bob, &note_a, usdc(400.0));
+    let note_out = Note::try_from(transfer.notes_out[0].clone()).unwrap();
+    let refund_note = Note::try_from(transfer.refund_note.clone().unwrap()).unwrap();
+
+    let response = exchange.execute_transfer(transfer.clone()).await;
+    assert!(response.successful, "{}", response.error_message);
+
+    // ? Bob's note gets a new index and alice's change replaces the spent note
+    let bob_idx = response.zero_idxs[0];
+    assert_ne!(bob_idx, note_a.index);
+    assert_eq!(exchange.leaf_hash(bob_idx).await, note_out.hash);
+    assert_eq!(exchange.leaf_hash(note_a.index).await, refund_note.hash);
+
+    // ? The spent note can't be transferred again
+    let response = exchange.execute_transfer(transfer).await;
+    assert_eq!(
+        response.error_message,
+        "note spent does not exist in the state"
+    );
+
+    // ? Notes out reusing the sender's blinding are rejected
+    let note_b = exchange.deposit(&alice, USDC, usdc(100.0)).await;
+    let mut transfer = exchange.transfer_message(&alice, &bob, &note_b, usdc(100.0));
+    transfer.notes_out[0].blinding = note_b.blinding.to_string();
+    let response = exchange.execute_transfer(transfer).await;
+    assert_eq!(response.error_message, "Notes out must use fresh blindings");
+    assert_eq!(exchange.leaf_hash(note_b.index).await, note_b.hash);
+
+    // ? Amounts that overflow when summed are rejected
+    let mut transfer = exchange.transfer_message(&alice, &bob, &note_b, usdc(100.0));
+    let mut overflow_note = transfer.notes_out[0].clone();
+    overflow_note.amount = u64::MAX;
+    overflow_note.blinding = "123456789".to_string();
+    transfer.notes_out.push(overflow_note);
+    let response = exchange.execute_transfer(transfer).await;
+    assert_eq!(response.error_message, "Note amounts overflow");
+
+    // ? Restoring the batch should reproduce the same state
+    let batch = exchange.finalize_batch().await;
+    let report = replay_batch(
+        batch.batch_index,
+        batch.prev_state,
+        batch.transactions,
+        &batch.transition_info,
+        0,
+    );
+    assert!(report.is_consistent(), "{:?}", report.diffs);
+    assert!(report.restored.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn note_restructures_split_and_merge_notes() {
+    let mut exchange = TestExchange::builder().build().await;
+    let alice = TestUser::new(1);
+
+    // ? Splitting a note pays the restructure fee
+    let note = exchange.deposit(&alice, USDC, usdc(1000.0)).await;
+    let restructure = exchange.restructure_message(
+        &alice,
+        &[note.clone()],
+        &[usdc(600.0), usdc(400.0) - usdc(0.05)],
+        usdc(0.05),
+    );
+    let notes_out = restructure
+        .notes_out
+        .iter()
+        .map(|n| Note::try_from(n.clone()).unwrap())
+        .collect::<Vec<Note>>();
+
+    let response = exchange.restructure_notes(restructure).await;
+    assert!(response.successful, "{}", response.error_message);
+    assert_eq!(response.zero_idxs[0], note.index);
+    assert_eq!(exchange.leaf_hash(note.index).await, notes_out[0].hash);
+    assert_eq!(
+        exchange.leaf_hash(response.zero_idxs[1]).await,
+        notes_out[1].hash
+    );
+
+    let note = exchange.deposit(&alice, USDC, usdc(100.0)).await;
+    let restructure =
+        exchange.restructure_message(&alice, &[note.clone()], &[usdc(50.0), usdc(50.0)], 0);
+    let response = exchange.restructure_notes(restructure).await;
+    assert_eq!(response.error_message, "Fee is too low");
+    assert_eq!(exchange.leaf_hash(note.index).await, note.hash);
+
+    let restructure = exchange.restructure_message(
+        &alice,
+        &[note.clone()],
+        &[u64::MAX, usdc(100.0)],
+        usdc(0.05),
+    );
+    let response = exchange.restructure_notes(restructure).await;
+    assert_eq!(response.error_message, "Note amounts overflow");
+
+    // ? Merging dust notes into a single note is free
+    let dust_a = exchange.deposit(&alice, USDC, usdc(0.01)).await;
+    let dust_b = exchange.deposit(&alice, USDC, usdc(0.02)).await;
+    let restructure = exchange.restructure_message(
+        &alice,
+        &[dust_a.clone(), dust_b.clone()],
+        &[dust_a.amount + dust_b.amount],
+        0,
+    );
+    let merged_note = Note::try_from(restructure.notes_out[0].clone()).unwrap();
+
+    let response = exchange.restructure_notes(restructure).await;
+    assert!(response.successful, "{}", response.error_message);
+    assert_eq!(exchange.leaf_hash(dust_a.index).await, merged_note.hash);
+    assert!(exchange.leaf_hash(dust_b.index).await.is_zero());
+
+    // ? Restoring the batch should reproduce the same state
+    let batch = exchange.finalize_batch().await;
+    let report = replay_batch(
+        batch.batch_index,
+        batch.prev_state,
+        batch.transactions,
+        &batch.transition_info,
+        0,
+    );
+    assert!(report.is_consistent(), "{:?}", report.diffs);
+    assert!(report.restored.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn orders_can_be_referenced_by_client_order_id() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+
+    let note_a = exchange.deposit(&alice, USDC, usdc(2000.0)).await;
+    let note_b = exchange.deposit(&alice, USDC, usdc(2000.0)).await;
+
+    let mut order = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_a,
+    );
+    order.client_order_id = 7;
+    let response = exchange.submit_perp_order(&alice, order).await;
+    assert!(response.successful, "{}", response.error_message);
+    let order_id = response.order_id;
+
+    // ? The client order id is already used by an open order
+    let mut duplicate = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_b,
+    );
+    duplicate.client_order_id = 7;
+    let response = exchange.submit_perp_order(&alice, duplicate.clone()).await;
+    assert!(!response.successful);
+    assert!(response.error_message.contains("DuplicateClientOrderID"));
+
+    let orders_req = OrdersReq {
+        user_id: alice.user_id,
+        perp_client_order_ids: vec![7, 8],
+        ..Default::default()
+    };
+    let orders = exchange
+        .service
+        .get_orders(Request::new(orders_req.clone()))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(orders.perp_orders.len(), 1);
+    assert_eq!(orders.perp_orders[0].order_id, order_id);
+    assert_eq!(orders.perp_orders[0].client_order_id, 7);
+    assert_eq!(orders.bad_client_order_ids, vec![8]);
+
+    let response = exchange
+        .service
+        .cancel_order(Request::new(CancelOrderMessage {
+            market_id: PERP_MARKET_IDS[&BTC.to_string()] as u32,
+            order_id: 0,
+            order_side: false,
+            user_id: alice.user_id,
+            is_perp: true,
+            client_order_id: 7,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(response.successful, "{}", response.error_message);
+
+    let orders = exchange
+        .service
+        .get_orders(Request::new(orders_req))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(orders.perp_orders.is_empty());
+    assert_eq!(orders.bad_client_order_ids, vec![7, 8]);
+
+    // ? Once the order is closed the client order id can be reused, and order ids keep increasing
+    let response = exchange.submit_perp_order(&alice, duplicate).await;
+    assert!(response.successful, "{}", response.error_message);
+    assert!(response.order_id > order_id);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn orders_can_be_placed_and_cancelled_in_batches() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let market_id = PERP_MARKET_IDS[&BTC.to_string()] as u32;
+
+    let mut notes = Vec::new();
+    for _ in 0..3 {
+        notes.push(exchange.deposit(&alice, USDC, usdc(2000.0)).await);
+    }
+
+    // ? Bids for 0.1 BTC just below the index price
+    let quote = |exchange: &mut TestExchange, notional: f64, note: &Note, client_order_id: u64| {
+        let mut order = exchange.open_order_message(
+            &alice,
+            OrderSide::Long,
+            BTC,
+            btc(0.1),
+            usdc(notional),
+            usdc(1000.0),
+            note,
+        );
+        order.client_order_id = client_order_id;
+
+        sign_perp_order(&alice, order)
+    };
+
+    let batch = OrdersBatchRequest {
+        perp_orders: vec![
+            quote(&mut exchange, 2990.0, &notes[0], 1),
+            quote(&mut exchange, 2980.0, &notes[1], 2),
+        ],
+        ..Default::default()
+    };
+    let response = exchange
+        .service
+        .submit_orders_batch(Request::new(batch))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(response.successful, "{}", response.error_message);
+    assert_eq!(response.perp_order_responses.len(), 2);
+    for order_response in response.perp_order_responses.iter() {
+        assert!(
+            order_response.successful,
+            "{}",
+            order_response.error_message
+        );
+    }
+
+    // ? The cancel is processed first, so the replacing quote can reuse its client order id
+    let batch = OrdersBatchRequest {
+        perp_orders: vec![quote(&mut exchange, 2995.0, &notes[2], 1)],
+        cancel_orders: vec![CancelOrderMessage {
+            market_id,
+            order_id: 0,
+            order_side: true,
+            user_id: alice.user_id,
+            is_perp: true,
+            client_order_id: 1,
+        }],
+        ..Default::default()
+    };
+    let response = exchange
+        .service
+        .submit_orders_batch(Request::new(batch))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(response.successful, "{}", response.error_message);
+    assert!(
+        response.cancel_responses[0].successful,
+        "{}",
+        response.cancel_responses[0].error_message
+    );
+    assert!(
+        response.perp_order_responses[0].successful,
+        "{}",
+        response.perp_order_responses[0].error_message
+    );
+    let replaced_order_id = response.perp_order_responses[0].order_id;
+
+    // ? An all or nothing replacement that fails its checks doesn't cancel the quote it replaces
+    let batch = OrdersBatchRequest {
+        perp_orders: vec![quote(&mut exchange, 2985.005, &notes[1], 2)],
+        cancel_orders: vec![CancelOrderMessage {
+            market_id,
+            order_id: 0,
+            order_side: true,
+            user_id: alice.user_id,
+            is_perp: true,
+            client_order_id: 2,
+        }],
+        all_or_nothing: true,
+        ..Default::default()
+    };
+    let response = exchange
+        .service
+        .submit_orders_batch(Request::new(batch))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(!response.successful);
+    assert_eq!(
+        response.error_message,
+        "Batch rejected, nothing was cancelled or placed: price must be a multiple of the tick size"
+    );
+
+    let orders_req = OrdersReq {
+        user_id: alice.user_id,
+        perp_client_order_ids: vec![1, 2],
+        ..Default::default()
+    };
+    let orders = exchange
+        .service
+        .get_orders(Request::new(orders_req.clone()))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(orders.perp_orders.len(), 2);
+
+    let response = exchange
+        .service
+        .cancel_all(Request::new(CancelAllRequest {
+            user_id: alice.user_id,
+            market_id: 0,
+            is_perp: false,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(response.successful, "{}", response.error_message);
+    assert_eq!(response.cancelled_order_ids.len(), 2);
+    assert!(response.cancelled_order_ids.contains(&replaced_order_id));
+
+    let orders = exchange
+        .service
+        .get_orders(Request::new(orders_req))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(orders.perp_orders.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lapsed_dead_mans_switch_cancels_all_orders() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    // ? A switch can only be armed for the user id derived from the signing key
+    let mut alice = TestUser::new(1);
+    alice.user_id = dead_mans_switch_user_id(&alice.stark_key());
+    let bob = TestUser::new(2);
+
+    let note = exchange.deposit(&alice, USDC, usdc(2000.0)).await;
+    let order = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(2990.0),
+        usdc(1000.0),
+        &note,
+    );
+    let response = exchange.submit_perp_order(&alice, order).await;
+    assert!(response.successful, "{}", response.error_message);
+    let order_id = response.order_id;
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let switch_req = |signer: &TestUser, timeout_ms: u64| {
+        let msg_hash = dead_mans_switch_message_hash(alice.user_id, timeout_ms, false, timestamp);
+
+        DeadMansSwitchReq {
+            user_id: alice.user_id,
+            timeout_ms,
+            cancel_on_disconnect: false,
+            timestamp,
+            stark_key: signer.stark_key().to_string(),
+            signature: Some(grpc_signature(&signer.sign(&msg_hash))),
+        }
+    };
+
+    let response = exchange
+        .service
+        .set_dead_mans_switch(Request::new(switch_req(
+            &alice,
+            MAX_DEAD_MANS_SWITCH_TIMEOUT + 1,
+        )))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(!response.successful);
+
+    // ? Unsigned requests and requests signed by another key are rejected
+    let mut unsigned_req = switch_req(&alice, 1);
+    unsigned_req.signature = None;
+    let response = exchange
+        .service
+        .set_dead_mans_switch(Request::new(unsigned_req))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(!response.successful);
+
+    let response = exchange
+        .service
+        .set_dead_mans_switch(Request::new(switch_req(&bob, 1)))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(!response.successful);
+
+    let mut forged_req = switch_req(&alice, 1);
+    forged_req.timeout_ms = 2;
+    let response = exchange
+        .service
+        .set_dead_mans_switch(Request::new(forged_req))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(!response.successful);
+    assert!(exchange.service.dead_mans_switches.lock().await.is_empty());
+
+    let response = exchange
+        .service
+        .set_dead_mans_switch(Request::new(switch_req(&alice, 1)))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(response.successful, "{}", response.error_message);
+    assert!(response.deadline > 0);
+
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+    let service = &exchange.service;
+    let triggered_users = cancel_expired_switches(
+        &service.transaction_batch,
+        &service.order_books,
+        &service.perp_order_books,
+        &service.ws_connections,
+        &service.dead_mans_switches,
+    )
+    .await;
+    assert_eq!(triggered_users, vec![alice.user_id]);
+
+    let orders = exchange
+        .service
+        .get_orders(Request::new(OrdersReq {
+            perp_order_ids: vec![order_id],
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(orders.perp_orders.is_empty());
+
+    // ? The switch is disarmed once it has been triggered
+    assert!(service.dead_mans_switches.lock().await.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matched_perp_orders_open_positions() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+
+    let note_a = exchange.deposit(&alice, USDC, usdc(2000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(2000.0)).await;
+
+    let order_a = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_a,
+    );
+    let response = exchange.submit_perp_order(&alice, order_a).await;
+    assert!(response.successful, "{}", response.error_message);
+
+    let order_b = exchange.open_order_message(
+        &bob,
+        OrderSide::Short,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_b,
+    );
+    let response = exchange.submit_perp_order(&bob, order_b).await;
+    assert!(response.successful, "{}", response.error_message);
+
+    let position_a = exchange.position(&alice, BTC).expect("alice has no position");
+    let position_b = exchange.position(&bob, BTC).expect("bob has no position");
+
+    assert_eq!(position_a.order_side, OrderSide::Long);
+    assert_eq!(position_b.order_side, OrderSide::Short);
+    assert_eq!(position_a.position_size, btc(0.1));
+    assert_eq!(position_b.position_size, btc(0.1));
+    assert_eq!(exchange.leaf_hash(position_a.index).await, position_a.hash);
+    assert_eq!(exchange.leaf_hash(position_b.index).await, position_b.hash);
+
+    // ? Replaying the finalized batch offline should reproduce the same state
+    let batch = exchange.finalize_batch().await;
+    let report = replay_batch(
+        batch.batch_index,
+        batch.prev_state,
+        batch.transactions,
+        &batch.transition_info,
+        0,
+    );
+    assert!(report.is_consistent(), "{:?}", report.diffs);
+    assert!(report.restored.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn da_output_reconstructs_the_batch_state_root() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+
+    let note_a = exchange.deposit(&alice, USDC, usdc(2000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(2000.0)).await;
+
+    for (user, side, note) in [
+        (&alice, OrderSide::Long, &note_a),
+        (&bob, OrderSide::Short, &note_b),
+    ] {
+        let order = exchange.open_order_message(
+            user,
+            side,
+            BTC,
+            btc(0.1),
+            usdc(3000.0),
+            usdc(1000.0),
+            note,
+        );
+        let response = exchange.submit_perp_order(user, order).await;
+        assert!(response.successful, "{}", response.error_message);
+    }
+
+    // ? Merging two dust notes empties one of the leaves
+    let dust_a = exchange.deposit(&alice, USDC, usdc(0.01)).await;
+    let dust_b = exchange.deposit(&alice, USDC, usdc(0.02)).await;
+    let restructure = exchange.restructure_message(
+        &alice,
+        &[dust_a.clone(), dust_b.clone()],
+        &[dust_a.amount + dust_b.amount],
+        0,
+    );
+    let response = exchange.restructure_notes(restructure).await;
+    assert!(response.successful, "{}", response.error_message);
+
+    let batch = exchange.finalize_batch().await;
+    let updated_state_hashes = &batch.transition_info.updated_state_hashes;
+
+    // ? Produce the DA output the same way _construct_da_output does
+    let (funding_rates, funding_prices) = get_batch_funding_info(&batch.transition_info);
+    let (state_updates, _, _, _, _) = _get_da_updates_inner(
+        updated_state_hashes,
+        &funding_rates,
+        &funding_prices,
+        &batch.transactions,
+    );
+    let counts = get_final_updated_counts(updated_state_hashes, &batch.transactions);
+
+    assert_eq!(counts.n_output_positions, 2);
+    assert_eq!(counts.n_zero_indexes, 1);
+
+    // ? Reconstruct the state from the DA output alone
+    let updates = BatchStateUpdates::from_da_output(&state_updates.data_output(), &counts).unwrap();
+    let dex_state = GlobalDexState::new(
+        batch.batch_index,
+        &batch.prev_state_root,
+        &batch.new_state_root,
+        TREE_DEPTH,
+        0,
+        counts,
+    );
+
+    let mut reconstructor = StateReconstructor::new();
+    let root = reconstructor.apply_batch(&dex_state, updates).unwrap();
+
+    assert_eq!(root, batch.new_state_root);
+    assert_eq!(reconstructor.positions.len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn positions_missing_from_storage_are_backfilled_from_the_state() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+
+    let note_a = exchange.deposit(&alice, USDC, usdc(2000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(2000.0)).await;
+
+    for (user, side, note) in [
+        (&alice, OrderSide::Long, &note_a),
+        (&bob, OrderSide::Short, &note_b),
+    ] {
+        let order = exchange.open_order_message(
+            user,
+            side,
+            BTC,
+            btc(0.1),
+            usdc(3000.0),
+            usdc(1000.0),
+            note,
+        );
+        let response = exchange.submit_perp_order(user, order).await;
+        assert!(response.successful, "{}", response.error_message);
+    }
+    let position_a = exchange.position(&alice, BTC).unwrap();
+
+    let batch = exchange.finalize_batch().await;
+    let (funding_rates, funding_prices) = get_batch_funding_info(&batch.transition_info);
+    let (state_updates, _, _, _, _) = _get_da_updates_inner(
+        &batch.transition_info.updated_state_hashes,
+        &funding_rates,
+        &funding_prices,
+        &batch.transactions,
+    );
+
+    // ? The state positions are rebuilt from the DA output with the same hashes
+    let state_positions = state_updates.positions();
+    assert_eq!(state_positions.len(), 2);
+    assert!(state_positions.iter().any(|p| p.hash == position_a.hash));
+
+    let tx_batch = exchange.service.transaction_batch.lock().await;
+    let main_storage = tx_batch.main_storage.lock();
+    let address = position_a.position_header.position_address.to_string();
+
+    // ? Positions opened before they were stored are missing, and closed ones can be left behind
+    for position in state_positions.iter() {
+        main_storage.remove_position(position.index);
+    }
+    let mut stale_position = position_a.clone();
+    stale_position.index += 1000;
+    main_storage.store_position(&stale_position);
+    assert_eq!(main_storage.read_positions_at_address(&address).len(), 1);
+
+    main_storage.backfill_positions(&state_positions, &tx_batch.state_tree.lock());
+
+    let positions = main_storage.read_positions_at_address(&address);
+    assert_eq!(positions.len(), 1);
+    assert_eq!(positions[0].index, position_a.index);
+    assert_eq!(positions[0].hash, position_a.hash);
+    assert_eq!(positions[0].margin, position_a.margin);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reduce_only_orders_follow_the_position_size() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+    let carol = TestUser::new(3);
+    let dave = TestUser::new(4);
+
+    let note_a = exchange.deposit(&alice, USDC, usdc(2000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(2000.0)).await;
+
+    let order_a = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_a,
+    );
+    exchange.submit_perp_order(&alice, order_a).await;
+    let order_b = exchange.open_order_message(
+        &bob,
+        OrderSide::Short,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_b,
+    );
+    exchange.submit_perp_order(&bob, order_b).await;
+
+    let position_a = exchange.position(&alice, BTC).expect("alice has no position");
+
+    // ? Reduce only orders can't increase the position
+    let mut increase =
+        exchange.modify_order_message(&alice, &position_a, OrderSide::Long, btc(0.1), usdc(2900.0));
+    increase.reduce_only = true;
+    let response = exchange.submit_perp_order(&alice, increase).await;
+    assert!(!response.successful);
+
+    // ? A resting take profit order larger than the position
+    let mut take_profit = exchange.modify_order_message(
+        &alice,
+        &position_a,
+        OrderSide::Short,
+        btc(0.2),
+        usdc(6200.0),
+    );
+    take_profit.reduce_only = true;
+    let response = exchange.submit_perp_order(&alice, take_profit).await;
+    assert!(response.successful, "{}", response.error_message);
+    let take_profit_id = response.order_id;
+
+    let orders_req = OrdersReq {
+        user_id: alice.user_id,
+        perp_order_ids: vec![take_profit_id],
+        ..Default::default()
+    };
+    let orders = exchange
+        .service
+        .get_orders(Request::new(orders_req.clone()))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(orders.perp_orders.len(), 1);
+    assert!(orders.perp_orders[0].reduce_only);
+    assert_eq!(orders.perp_orders[0].qty_left, btc(0.1));
+    let mut websocket_a = exchange.connect_websocket(&alice).await;
+
+    // ? Closing part of the position shrinks the resting order
+    let note_c = exchange.deposit(&carol, USDC, usdc(1000.0)).await;
+    let bid = exchange.open_order_message(
+        &carol,
+        OrderSide::Long,
+        BTC,
+        btc(0.06),
+        usdc(1800.0),
+        usdc(600.0),
+        &note_c,
+    );
+    exchange.submit_perp_order(&carol, bid).await;
+    let close = exchange.close_order_message(&alice, &position_a, btc(0.06), usdc(1800.0));
+    let response = exchange.submit_perp_order(&alice, close).await;
+    assert!(response.successful, "{}", response.error_message);
+
+    let position_a = exchange.position(&alice, BTC).expect("alice has no position");
+    assert_eq!(position_a.position_size, btc(0.04));
+
+    let orders = exchange
+        .service
+        .get_orders(Request::new(orders_req.clone()))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(orders.perp_orders.len(), 1);
+    assert_eq!(orders.perp_orders[0].qty_left, btc(0.04));
+
+    let updates = reduce_only_updates(&mut websocket_a).await;
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0]["resized_orders"][0]["order_id"], take_profit_id);
+    assert_eq!(updates[0]["resized_orders"][0]["qty_left"], btc(0.04));
+    assert_eq!(updates[0]["cancelled_order_ids"], json!([]));
+
+    // ? Once the position is closed the order is cancelled
+    let note_d = exchange.deposit(&dave, USDC, usdc(1000.0)).await;
+    let bid = exchange.open_order_message(
+        &dave,
+        OrderSide::Long,
+        BTC,
+        btc(0.04),
+        usdc(1200.0),
+        usdc(400.0),
+        &note_d,
+    );
+    exchange.submit_perp_order(&dave, bid).await;
+    let close = exchange.close_order_message(&alice, &position_a, btc(0.04), usdc(1200.0));
+    let response = exchange.submit_perp_order(&alice, close).await;
+    assert!(response.successful, "{}", response.error_message);
+
+    let orders = exchange
+        .service
+        .get_orders(Request::new(orders_req))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(orders.perp_orders.is_empty());
+    assert_eq!(orders.bad_perp_order_ids, vec![take_profit_id]);
+
+    let updates = reduce_only_updates(&mut websocket_a).await;
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0]["resized_orders"], json!([]));
+    assert_eq!(updates[0]["cancelled_order_ids"], json!([take_profit_id]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn set_leverage_moves_margin_to_reach_the_target() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+
+    // ? Alice opens a 3x long against bob
+    let note_a = exchange.deposit(&alice, USDC, usdc(1000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(1000.0)).await;
+
+    let order_a = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_a,
+    );
+    exchange.submit_perp_order(&alice, order_a).await;
+    let order_b = exchange.open_order_message(
+        &bob,
+        OrderSide::Short,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_b,
+    );
+    exchange.submit_perp_order(&bob, order_b).await;
+
+    let position = exchange.position(&alice, BTC).expect("alice has no position");
+    let pnl = position.get_pnl(BTC_PRICE);
+
+    // ? The dry run previews 5x without changing the state
+    let preview = exchange
+        .set_leverage(&alice, &position, 5 * LEVERAGE, &[], true)
+        .await;
+    assert!(preview.successful, "{}", preview.error_message);
+    assert_eq!(
+        preview.margin_change,
+        usdc(600.0) as i64 - pnl - position.margin as i64
+    );
+    assert!(preview.leverage <= 5 * LEVERAGE);
+    assert!(preview.liquidation_price > position.liquidation_price);
+    assert_eq!(exchange.leaf_hash(position.index).await, position.hash);
+
+    // ? Raising the leverage returns the freed margin
+    let response = exchange
+        .set_leverage(&alice, &position, 5 * LEVERAGE, &[], false)
+        .await;
+    assert!(response.successful, "{}", response.error_message);
+    assert_eq!(response.margin_change, preview.margin_change);
+    assert_eq!(response.liquidation_price, preview.liquidation_price);
+
+    let position = exchange.position(&alice, BTC).expect("alice has no position");
+    assert_eq!(exchange.leaf_hash(position.index).await, position.hash);
+    assert!(!exchange
+        .leaf_hash(response.return_collateral_index)
+        .await
+        .is_zero());
+
+    // ? Lowering it needs notes, whatever they don't spend is refunded
+    let response = exchange
+        .set_leverage(&alice, &position, 2 * LEVERAGE, &[], false)
+        .await;
+    assert!(!response.successful);
+
+    let note = exchange.deposit(&alice, USDC, usdc(2000.0)).await;
+    let response = exchange
+        .set_leverage(&alice, &position, 2 * LEVERAGE, &[note.clone()], false)
+        .await;
+    assert!(response.successful, "{}", response.error_message);
+    assert_eq!(
+        response.margin_change,
+        usdc(1500.0) as i64 - pnl - position.margin as i64
+    );
+
+    let position = exchange.position(&alice, BTC).expect("alice has no position");
+    assert_eq!(exchange.leaf_hash(position.index).await, position.hash);
+    assert_ne!(exchange.leaf_hash(note.index).await, note.hash);
+    assert!(!exchange.leaf_hash(note.index).await.is_zero());
+
+    // ? A request has to sign the margin change of the current dry run
+    let preview = exchange
+        .set_leverage(&alice, &position, 3 * LEVERAGE, &[], true)
+        .await;
+    assert!(preview.successful, "{}", preview.error_message);
+    let response = exchange
+        .set_leverage_with_margin_change(
+            &alice,
+            &position,
+            3 * LEVERAGE,
+            &[],
+            preview.margin_change + 1,
+        )
+        .await;
+    assert!(!response.successful);
+    assert_eq!(exchange.leaf_hash(position.index).await, position.hash);
+
+    // ? Both requests are output as margin changes whose signatures verify like any other
+    let batch = exchange.finalize_batch().await;
+    let margin_changes: Vec<&Value> = batch
+        .transactions
+        .iter()
+        .filter(|tx| tx["transaction_type"] == "margin_change")
+        .map(|tx| &tx["margin_change"])
+        .collect();
+    assert_eq!(margin_changes.len(), 2);
+    for margin_change in margin_changes {
+        assert!(margin_change.get("target_leverage").is_none());
+        verify_margin_change_signature(&margin_change_from_json(margin_change)).unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn funding_samples_the_impact_bid_and_ask_of_the_book() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+
+    // ? The book straddles the index price: bids 1 % below it and asks 3 % above it
+    let note_a = exchange.deposit(&alice, USDC, usdc(2000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(2000.0)).await;
+    for (user, side, price, note) in [
+        (&alice, OrderSide::Long, 29_700.0, &note_a),
+        (&bob, OrderSide::Short, 30_900.0, &note_b),
+    ] {
+        let order = exchange.open_order_message(
+            user,
+            side,
+            BTC,
+            btc(0.2),
+            usdc(0.2 * price),
+            usdc(1000.0),
+            note,
+        );
+        let response = exchange.submit_perp_order(user, order).await;
+        assert!(response.successful, "{}", response.error_message);
+    }
+
+    let impact_prices = get_impact_prices(&exchange.service.perp_order_books).await;
+    let (impact_bid, impact_ask) = impact_prices[&BTC];
+    assert_eq!(impact_bid, usdc(29_700.0));
+    assert_eq!(impact_ask, usdc(30_900.0));
+
+    // ? Neither side crosses the index price, so there is no premium
+    // ? (with the prices swapped the asks would count as a 3 % - 1 % = 2 % premium)
+    assert_eq!(
+        _per_minute_funding_update_inner(impact_bid, impact_ask, 0, BTC_PRICE),
+        0
+    );
+    assert_eq!(
+        _per_minute_funding_update_inner(impact_ask, impact_bid, 0, BTC_PRICE),
+        2_000
+    );
+
+    // ? Bids above the index price make longs pay shorts and asks below it make shorts pay longs
+    let (above, below) = (BTC_PRICE / 100 * 101, BTC_PRICE / 100 * 99);
+    assert!(_per_minute_funding_update_inner(above, above + usdc(300.0), 0, BTC_PRICE) > 0);
+    assert!(_per_minute_funding_update_inner(below - usdc(300.0), below, 0, BTC_PRICE) < 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn funding_is_capped_and_missed_samples_count_as_zero_premium() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+
+    let funding_config = exchange.funding_config().await;
+    let samples_per_settlement = funding_config.samples_per_settlement as u64;
+    let period_secs = samples_per_settlement * funding_config.sample_interval_secs;
+    let period_start = (1_700_000_000 / period_secs + 1) * period_secs;
+    let minute = |period: u64, sample: u64| {
+        period_start + period * period_secs + sample * funding_config.sample_interval_secs
+    };
+
+    // ? The impact bid is 1 % above the index price, a premium of 1_000
+    let premium = [(BTC, BTC_PRICE / 100 * 101, BTC_PRICE / 100 * 102)];
+    let no_premium = [(BTC, BTC_PRICE / 100 * 99, BTC_PRICE / 100 * 101)];
+
+    // ? A full period: 1_000 + the damped interest is capped at 750 per 8 hours
+    for sample in 0..samples_per_settlement {
+        exchange.sample_funding(minute(0, sample), &premium).await;
+    }
+    assert_eq!(exchange.funding_rates(BTC).await, vec![750 / 8]);
+
+    // ? A repeated slot is ignored
+    exchange.sample_funding(minute(1, 0), &premium).await;
+    exchange.sample_funding(minute(1, 0) + 10, &premium).await;
+    let predicted = exchange.predicted_funding().await;
+    assert_eq!(predicted.predictions[0].token, BTC);
+    assert_eq!(predicted.predictions[0].samples, 1);
+
+    // ? Downtime after 6 samples: the period is settled over all 60 slots when the
+    // ? next sample comes in, and the fully missed period is skipped
+    for sample in 1..6 {
+        exchange.sample_funding(minute(1, sample), &premium).await;
+    }
+    exchange.sample_funding(minute(3, 29), &no_premium).await;
+    let funding_rates = exchange.funding_rates(BTC).await;
+    assert_eq!(funding_rates, vec![750 / 8, (100 - 50) / 8]);
+
+    let predicted = exchange.predicted_funding().await;
+    let btc_prediction = &predicted.predictions[0];
+    assert_eq!(btc_prediction.samples, 30);
+    assert_eq!(btc_prediction.premium, 0);
+    assert_eq!(btc_prediction.funding_rate, 10 / 8);
+    assert_eq!(predicted.next_funding_timestamp, minute(4, 0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn position_ledger_records_funding_pnl_fees_and_margin_changes() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+    let carol = TestUser::new(3);
+
+    let note_a = exchange.deposit(&alice, USDC, usdc(1000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(1000.0)).await;
+
+    let order_a = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_a,
+    );
+    exchange.submit_perp_order(&alice, order_a).await;
+    let order_b = exchange.open_order_message(
+        &bob,
+        OrderSide::Short,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_b,
+    );
+    exchange.submit_perp_order(&bob, order_b).await;
+
+    let position_a = exchange.position(&alice, BTC).expect("alice has no position");
+
+    // ? A full funding period at the capped rate
+    let funding_config = exchange.funding_config().await;
+    let period_secs =
+        funding_config.samples_per_settlement as u64 * funding_config.sample_interval_secs;
+    let period_start = (1_700_000_000 / period_secs + 1) * period_secs;
+    let premium = [(BTC, BTC_PRICE / 100 * 101, BTC_PRICE / 100 * 102)];
+    for sample in 0..funding_config.samples_per_settlement as u64 {
+        let timestamp = period_start + sample * funding_config.sample_interval_secs;
+        exchange.sample_funding(timestamp, &premium).await;
+    }
+    let funding_rates = exchange.funding_rates(BTC).await;
+
+    // ? Alice takes carol's bid at 31k to close half of the position
+    let note_c = exchange.deposit(&carol, USDC, usdc(1000.0)).await;
+    let bid = exchange.open_order_message(
+        &carol,
+        OrderSide::Long,
+        BTC,
+        btc(0.05),
+        usdc(1550.0),
+        usdc(1000.0),
+        &note_c,
+    );
+    exchange.submit_perp_order(&carol, bid).await;
+    let close = exchange.close_order_message(&alice, &position_a, btc(0.05), usdc(1550.0));
+    let response = exchange.submit_perp_order(&alice, close).await;
+    assert!(response.successful, "{}", response.error_message);
+
+    let position = exchange.position(&alice, BTC).expect("alice has no position");
+    let response = exchange
+        .set_leverage(&alice, &position, 5 * LEVERAGE, &[], false)
+        .await;
+    assert!(response.successful, "{}", response.error_message);
+
+    let ledger = exchange.position_ledger(&alice.stark_key()).await;
+    let entries_of = |entry_type: GrpcLedgerEntryType| {
+        ledger
+            .entries
+            .iter()
+            .filter(|e| e.entry_type == entry_type as i32)
+            .collect::<Vec<_>>()
+    };
+
+    let funding = entries_of(GrpcLedgerEntryType::Funding);
+    assert_eq!(funding.len(), 1);
+    assert!(funding[0].amount < 0);
+    assert_eq!(
+        funding[0].amount,
+        position_a.get_funding_payment(&funding_rates, &[BTC_PRICE], 1)
+    );
+
+    let realized_pnl = entries_of(GrpcLedgerEntryType::RealizedPnl);
+    assert_eq!(realized_pnl.len(), 1);
+    assert_eq!(realized_pnl[0].amount, usdc(50.0) as i64);
+    assert_eq!(realized_pnl[0].size, btc(0.05));
+    assert_eq!(realized_pnl[0].price, BTC_PRICE / 30 * 31);
+
+    // ? The taker fee of the close is 5 bps
+    let fees = entries_of(GrpcLedgerEntryType::Fee);
+    assert_eq!(fees.last().unwrap().amount, -(usdc(1550.0) as i64 / 2_000));
+
+    let margin_changes = entries_of(GrpcLedgerEntryType::MarginChange);
+    assert_eq!(margin_changes.len(), 1);
+    assert_eq!(margin_changes[0].amount, response.margin_change);
+    assert_eq!(
+        ledger.entries.last().unwrap().entry_type,
+        GrpcLedgerEntryType::MarginChange as i32
+    );
+
+    // ? Bob's position wasn't touched since it was opened
+    let ledger_b = exchange.position_ledger(&bob.stark_key()).await;
+    assert!(ledger_b
+        .entries
+        .iter()
+        .all(|e| e.entry_type == GrpcLedgerEntryType::Fee as i32));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn open_interest_follows_the_long_positions_and_caps_position_sizes() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+    let carol = TestUser::new(3);
+
+    assert_eq!(exchange.open_interest(BTC).await, 0);
+
+    // ? Alice opens a 100 BTC long against bob
+    let note_a = exchange.deposit(&alice, USDC, usdc(1_000_000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(1_000_000.0)).await;
+
+    let order_a = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(100.0),
+        usdc(3_000_000.0),
+        usdc(1_000_000.0),
+        &note_a,
+    );
+    exchange.submit_perp_order(&alice, order_a).await;
+    let order_b = exchange.open_order_message(
+        &bob,
+        OrderSide::Short,
+        BTC,
+        btc(100.0),
+        usdc(3_000_000.0),
+        usdc(1_000_000.0),
+        &note_b,
+    );
+    exchange.submit_perp_order(&bob, order_b).await;
+
+    let position_a = exchange.position(&alice, BTC).expect("alice has no position");
+    assert_eq!(exchange.open_interest(BTC).await, btc(100.0));
+
+    // ? Growing the position past 150 BTC is rejected
+    let increase = exchange.modify_order_message(
+        &alice,
+        &position_a,
+        OrderSide::Long,
+        btc(60.0),
+        usdc(1_800_000.0),
+    );
+    let response = exchange.submit_perp_order(&alice, increase).await;
+    assert!(!response.successful);
+
+    // ? Carol taking over part of alice's long doesn't change the open interest
+    let note_c = exchange.deposit(&carol, USDC, usdc(1_000_000.0)).await;
+    let bid = exchange.open_order_message(
+        &carol,
+        OrderSide::Long,
+        BTC,
+        btc(40.0),
+        usdc(1_200_000.0),
+        usdc(1_000_000.0),
+        &note_c,
+    );
+    exchange.submit_perp_order(&carol, bid).await;
+    let close = exchange.close_order_message(&alice, &position_a, btc(40.0), usdc(1_200_000.0));
+    let response = exchange.submit_perp_order(&alice, close).await;
+    assert!(response.successful, "{}", response.error_message);
+    assert_eq!(exchange.open_interest(BTC).await, btc(100.0));
+
+    // ? Bob buying back from alice reduces it
+    let position_a = exchange.position(&alice, BTC).expect("alice has no position");
+    let position_b = exchange.position(&bob, BTC).expect("bob has no position");
+    let bid = exchange.close_order_message(&bob, &position_b, btc(60.0), usdc(1_800_000.0));
+    exchange.submit_perp_order(&bob, bid).await;
+    let close = exchange.close_order_message(&alice, &position_a, btc(60.0), usdc(1_800_000.0));
+    let response = exchange.submit_perp_order(&alice, close).await;
+    assert!(response.successful, "{}", response.error_message);
+    assert_eq!(exchange.open_interest(BTC).await, btc(40.0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn position_size_cap_applies_to_all_the_positions_of_an_owner() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+    let carol = TestUser::new(3);
+
+    // ? Alice opens a 100 BTC long against bob
+    let note_a = exchange.deposit(&alice, USDC, usdc(1_000_000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(1_000_000.0)).await;
+
+    let order_a = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(100.0),
+        usdc(3_000_000.0),
+        usdc(1_000_000.0),
+        &note_a,
+    );
+    exchange.submit_perp_order(&alice, order_a).await;
+    let order_b = exchange.open_order_message(
+        &bob,
+        OrderSide::Short,
+        BTC,
+        btc(100.0),
+        usdc(3_000_000.0),
+        usdc(1_000_000.0),
+        &note_b,
+    );
+    exchange.submit_perp_order(&bob, order_b).await;
+    assert_eq!(exchange.open_interest(BTC).await, btc(100.0));
+
+    // ? A second 60 BTC position is under the cap on its own, but not with the first one
+    let note_a = exchange.deposit(&alice, USDC, usdc(1_000_000.0)).await;
+    let second = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(60.0),
+        usdc(1_800_000.0),
+        usdc(1_000_000.0),
+        &note_a,
+    );
+    let response = exchange.submit_perp_order(&alice, second).await;
+    assert!(!response.successful);
+    assert_eq!(
+        response.error_message,
+        "position size would be above the maximum position size"
+    );
+
+    // ? The positions of other owners don't count
+    let note_c = exchange.deposit(&carol, USDC, usdc(1_000_000.0)).await;
+    let order_c = exchange.open_order_message(
+        &carol,
+        OrderSide::Long,
+        BTC,
+        btc(60.0),
+        usdc(1_800_000.0),
+        usdc(1_000_000.0),
+        &note_c,
+    );
+    let simulation = exchange.simulate_perp_order(order_c).await;
+    assert!(simulation.successful, "{}", simulation.error_message);
+
+    // ? A second 40 BTC position keeps alice under the cap
+    let third = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(40.0),
+        usdc(1_200_000.0),
+        usdc(1_000_000.0),
+        &note_a,
+    );
+    let response = exchange.submit_perp_order(&alice, third).await;
+    assert!(response.successful, "{}", response.error_message);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn position_risk_reports_the_margin_health_of_open_positions() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+    let carol = TestUser::new(3);
+    let dave = TestUser::new(4);
+
+    let note_a = exchange.deposit(&alice, USDC, usdc(1000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(1000.0)).await;
+
+    let order_a = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_a,
+    );
+    exchange.submit_perp_order(&alice, order_a).await;
+    let order_b = exchange.open_order_message(
+        &bob,
+        OrderSide::Short,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_b,
+    );
+    exchange.submit_perp_order(&bob, order_b).await;
+
+    let position_a = exchange.position(&alice, BTC).expect("alice has no position");
+    let position_b = exchange.position(&bob, BTC).expect("bob has no position");
+
+    // ? Without a two sided book the mark price is the index price
+    let index_price = 32_000 * 10u64.pow(6);
+    exchange.update_index_price(BTC, index_price).await;
+
+    let response = exchange.position_risk(&alice.stark_key()).await;
+    assert!(response.successful, "{}", response.error_message);
+    assert_eq!(response.position.unwrap().index, position_a.index);
+
+    let risk = response.risk.unwrap();
+    assert_eq!(
+        risk,
+        GrpcPositionRisk::from(get_position_risk(
+            &position_a,
+            index_price,
+            index_price,
+            &[],
+            &[]
+        ))
+    );
+    assert_eq!(risk.unrealized_pnl_index, usdc(200.0) as i64);
+    assert_eq!(risk.equity, position_a.margin as i64 + usdc(200.0) as i64);
+    assert!(risk.max_withdrawable_margin > 0 && risk.max_withdrawable_margin < position_a.margin);
+    assert!(!risk.is_liquidatable);
+
+    // ? Carol bids at 31k and dave asks at 34k, the mark price is the mid price
+    let note_c = exchange.deposit(&carol, USDC, usdc(1000.0)).await;
+    let note_d = exchange.deposit(&dave, USDC, usdc(1000.0)).await;
+    let bid = exchange.open_order_message(
+        &carol,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3100.0),
+        usdc(1000.0),
+        &note_c,
+    );
+    exchange.submit_perp_order(&carol, bid).await;
+    let ask = exchange.open_order_message(
+        &dave,
+        OrderSide::Short,
+        BTC,
+        btc(0.1),
+        usdc(3400.0),
+        usdc(1000.0),
+        &note_d,
+    );
+    exchange.submit_perp_order(&dave, ask).await;
+
+    let unknown_index = 1_000_000;
+    let response = exchange
+        .positions_risk_batch(vec![
+            PositionRiskReq {
+                position_address: alice.stark_key().to_string(),
+                index: 0,
+            },
+            PositionRiskReq {
+                position_address: "".to_string(),
+                index: position_b.index,
+            },
+            PositionRiskReq {
+                position_address: "".to_string(),
+                index: unknown_index,
+            },
+        ])
+        .await;
+    assert_eq!(response.results.len(), 3);
+
+    let risk_a = response.results[0].risk.as_ref().unwrap();
+    assert_eq!(risk_a.mark_price, 32_500 * 10u64.pow(6));
+    assert_eq!(risk_a.unrealized_pnl_mark, usdc(250.0) as i64);
+
+    let risk_b = response.results[1].risk.as_ref().unwrap();
+    assert_eq!(
+        response.results[1].position.as_ref().unwrap().index,
+        position_b.index
+    );
+    // ? The taker fee is folded into bob's entry price (30045), so the pnl is measured from there
+    assert_eq!(position_b.entry_price, 30_045 * 10u64.pow(6));
+    assert_eq!(risk_b.unrealized_pnl_index, -(usdc(195.5) as i64));
+    assert_eq!(risk_b.unrealized_pnl_mark, -(usdc(245.5) as i64));
+
+    assert!(!response.results[2].successful);
+    assert_eq!(response.results[2].error_message, "Position not found");
+
+    // ? Closed positions are no longer found
+    let close = exchange.close_order_message(&alice, &position_a, btc(0.1), usdc(3100.0));
+    let response = exchange.submit_perp_order(&alice, close).await;
+    assert!(response.successful, "{}", response.error_message);
+
+    let response = exchange.position_risk(&alice.stark_key()).await;
+    assert!(!response.successful);
+    assert_eq!(response.error_message, "Position not found");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn simulated_perp_orders_match_the_execution_without_changing_the_state() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+    let carol = TestUser::new(3);
+
+    let note_a = exchange.deposit(&alice, USDC, usdc(1000.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(1000.0)).await;
+    let note_c = exchange.deposit(&carol, USDC, usdc(1000.0)).await;
+
+    let ask = exchange.open_order_message(
+        &bob,
+        OrderSide::Short,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_b,
+    );
+    exchange.submit_perp_order(&bob, ask).await;
+
+    // ? The simulated order doesn't need a signature
+    let order_a = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_a,
+    );
+    let simulation = exchange.simulate_perp_order(order_a.clone()).await;
+    assert!(simulation.successful, "{}", simulation.error_message);
+    assert_eq!(simulation.fills.len(), 1);
+    assert_eq!(simulation.fills[0].price, BTC_PRICE);
+    assert_eq!(simulation.synthetic_filled, btc(0.1));
+    assert_eq!(simulation.collateral_filled, usdc(3000.0));
+    assert_eq!(simulation.average_price, BTC_PRICE);
+    // ? The taker fee is 5 bps
+    assert_eq!(simulation.fee_taken, usdc(1.5));
+    assert_eq!(simulation.unfilled_amount, 0);
+    assert_eq!(exchange.open_interest(BTC).await, 0);
+
+    // ? Over the max leverage of the risk tier (bob's ask is still in the book)
+    let order_c = exchange.open_order_message(
+        &carol,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(100.0),
+        &note_c,
+    );
+    let rejected = exchange.simulate_perp_order(order_c).await;
+    assert!(!rejected.successful);
+    assert_eq!(rejected.error_message, "Leverage is too high");
+
+    let response = exchange.submit_perp_order(&alice, order_a).await;
+    assert!(response.successful, "{}", response.error_message);
+
+    let position_a = exchange.position(&alice, BTC).expect("alice has no position");
+    let simulated_position = simulation.position.unwrap();
+    assert_eq!(simulated_position.position_size, position_a.position_size);
+    assert_eq!(simulated_position.margin, position_a.margin);
+    assert_eq!(simulated_position.entry_price, position_a.entry_price);
+    assert_eq!(
+        simulated_position.liquidation_price,
+        position_a.liquidation_price
+    );
+    assert_eq!(
+        simulated_position.bankruptcy_price,
+        position_a.bankruptcy_price
+    );
+
+    // ? With nothing to match against the order would only rest in the book
+    let bid = exchange.open_order_message(
+        &carol,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3100.0),
+        usdc(1000.0),
+        &note_c,
+    );
+    let simulation = exchange.simulate_perp_order(bid.clone()).await;
+    assert!(simulation.successful, "{}", simulation.error_message);
+    assert!(simulation.fills.is_empty());
+    assert_eq!(simulation.unfilled_amount, btc(0.1));
+    assert!(simulation.position.is_none());
+    exchange.submit_perp_order(&carol, bid).await;
+
+    // ? Closing into carol's bid at 31k returns the margin and the pnl minus the fee
+    // ? (the pnl is measured from alice's fee-adjusted entry price of 30045)
+    let close = exchange.close_order_message(&alice, &position_a, btc(0.1), usdc(3000.0));
+    let simulation = exchange.simulate_perp_order(close).await;
+    assert!(simulation.successful, "{}", simulation.error_message);
+    assert_eq!(simulation.average_price, 31_000 * 10u64.pow(6));
+    assert!(simulation.position.is_none());
+    assert_eq!(simulation.fee_taken, usdc(1.55));
+    let pnl = position_a.get_pnl(31_000 * 10u64.pow(6));
+    assert_eq!(pnl, usdc(95.5) as i64);
+    assert_eq!(
+        simulation.collateral_returned as i64,
+        position_a.margin as i64 + pnl - simulation.fee_taken as i64
+    );
+
+    // ? The position is still open
+    let response = exchange.position_risk(&alice.stark_key()).await;
+    assert!(response.successful, "{}", response.error_message);
+    assert_eq!(response.position.unwrap().hash, position_a.hash.to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn underwater_position_gets_liquidated() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+    let maker_bid = TestUser::new(3);
+    let maker_ask = TestUser::new(4);
+    let liquidator = TestUser::new(5);
+
+    // ? Alice opens a 10x long against bob
+    let note_a = exchange.deposit(&alice, USDC, usdc(300.0)).await;
+    let note_b = exchange.deposit(&bob, USDC, usdc(1000.0)).await;
+
+    let order_a = exchange.open_order_message(
+        &alice,
+        OrderSide::Long,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(300.0),
+        &note_a,
+    );
+    exchange.submit_perp_order(&alice, order_a).await;
+    let order_b = exchange.open_order_message(
+        &bob,
+        OrderSide::Short,
+        BTC,
+        btc(0.1),
+        usdc(3000.0),
+        usdc(1000.0),
+        &note_b,
+    );
+    exchange.submit_perp_order(&bob, order_b).await;
+
+    let position_a = exchange.position(&alice, BTC).expect("alice has no position");
+
+    // ? Resting orders around 26k set the market price used for the liquidation
+    let note_bid = exchange.deposit(&maker_bid, USDC, usdc(1000.0)).await;
+    let note_ask = exchange.deposit(&maker_ask, USDC, usdc(1000.0)).await;
+    let bid = exchange.open_order_message(
+        &maker_bid,
+        OrderSide::Long,
+        BTC,
+        btc(0.01),
+        usdc(250.0),
+        usdc(100.0),
+        &note_bid,
+    );
+    exchange.submit_perp_order(&maker_bid, bid).await;
+    let ask = exchange.open_order_message(
+        &maker_ask,
+        OrderSide::Short,
+        BTC,
+        btc(0.01),
+        usdc(270.0),
+        usdc(100.0),
+        &note_ask,
+    );
+    exchange.submit_perp_order(&maker_ask, ask).await;
+
+    exchange.update_index_price(BTC, 26_000 * 10u64.pow(6)).await;
+
+    let note_l = exchange.deposit(&liquidator, USDC, usdc(1500.0)).await;
+    let response = exchange
+        .liquidate(
+            &liquidator,
+            &position_a,
+            usdc(2610.0),
+            usdc(1000.0),
+            &note_l,
+        )
+        .await;
+    assert!(response.successful, "{}", response.error_message);
+
+    assert!(exchange.leaf_hash(position_a.index).await.is_zero());
+    let new_position = exchange
+        .position(&liquidator, BTC)
+        .expect("liquidator has no position");
+    assert_eq!(new_position.position_size, position_a.position_size);
+    assert_eq!(exchange.leaf_hash(new_position.index).await, new_position.hash);
+}
+
+#[tokio::test]
+async fn requests_over_the_rate_limit_are_rejected() {
+    let exchange = TestExchange::builder().build().await;
+    let service = &exchange.service;
+    let alice = TestUser::new(1);
+
+    service.rate_limiter.lock().set_quota(
+        AccountTier::Default,
+        Quota {
+            burst: 3,
+            per_second: 1,
+        },
+    );
+
+    let orders_req = || OrdersReq {
+        user_id: alice.user_id,
+        ..Default::default()
+    };
+
+    for _ in 0..3 {
+        let response = service.get_orders(Request::new(orders_req())).await;
+        assert!(response.is_ok());
+    }
+
+    let status = service
+        .get_orders(Request::new(orders_req()))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert!(status.message().contains("user 1"), "{}", status.message());
+
+    // ? Other users have their own buckets
+    let response = service
+        .get_orders(Request::new(OrdersReq {
+            user_id: 2,
+            ..Default::default()
+        }))
+        .await;
+    assert!(response.is_ok());
+
+    // ? Moving alice to a bigger tier lifts the limit
+    let tier_req = SetAccountTierReq {
+        user_id: alice.user_id,
+        tier: 1,
+    };
+    let response = service
+        .set_account_tier(local_request(tier_req))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(response.successful, "{}", response.error_message);
+
+    let response = service.get_orders(Request::new(orders_req())).await;
+    assert!(response.is_ok());
+}
@@ -0,0 +1,570 @@
+//! Property tests for the matching engine.
+//!
+//! Random flows of perpetual limit orders and cancellations are fed into a BTC order book and
+//! the book is checked after every request. Failing inputs are saved next to this file in
+//! `orderbook_props.regressions` and replayed before any new case, so a committed seed acts
+//! as a regression test.
+
+mod common;
+
+use std::{cell::RefCell, collections::HashMap, time::SystemTime};
+
+use num_bigint::BigUint;
+use proptest::prelude::*;
+use proptest::test_runner::FileFailurePersistence;
+
+use common::{TestUser, BTC, USDC};
+use invisible_backend::{
+    matching_engine::{
+        domain::{Order, OrderSide},
+        mm_protection::{MmpConfig, MmpTrigger},
+        order_queues::PriceLevel,
+        orderbook::{Failed, OrderBook, Success},
+        orders::{limit_order_cancel_request, new_amend_order, new_limit_order_request},
+    },
+    perpetual::{
+        get_collateral_amount,
+        perp_order::{OpenOrderFields, PerpOrder},
+        OrderSide as PerpOrderSide,
+    },
+    server::server_helpers::PERP_MARKET_IDS,
+    utils::{crypto_utils::Signature, notes::Note},
+};
+
+const N_USERS: u64 = 3;
+const LOT_SIZE: u64 = 1_000_000; // 0.01 BTC
+const MIN_PRICE: u64 = 29_950; // USD
+const TICK_SIZE: u64 = 5; // USD
+
+fn config() -> ProptestConfig {
+    ProptestConfig {
+        cases: 64,
+        failure_persistence: Some(Box::new(FileFailurePersistence::WithSource("regressions"))),
+        ..ProptestConfig::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+    Place {
+        user: u64,
+        is_bid: bool,
+        tick: u64,
+        lots: u64,
+    },
+    Cancel {
+        pick: usize,
+        wrong_user: bool,
+    },
+}
+
+fn action() -> impl Strategy<Value = Action> {
+    prop_oneof![
+        4 => (0..N_USERS, any::<bool>(), 0..20u64, 1..=10u64).prop_map(
+            |(user, is_bid, tick, lots)| Action::Place {
+                user,
+                is_bid,
+                tick,
+                lots,
+            }
+        ),
+        1 => (any::<usize>(), any::<bool>())
+            .prop_map(|(pick, wrong_user)| Action::Cancel { pick, wrong_user }),
+    ]
+}
+
+// * ORDERS ========================================================================================
+
+thread_local! {
+    // ? Signing is slow and the same orders come up in many cases, so signatures are cached by order hash
+    static SIGNATURES: RefCell<HashMap<BigUint, Signature>> = RefCell::new(HashMap::new());
+}
+
+/// Builds a signed open order, spending a fixed margin note of the user.
+fn open_order(user: &TestUser, side: OrderSide, price: u64, qty: u64) -> (Order, Signature) {
+    let margin = 10_000 * 10u64.pow(6);
+    let margin_note = Note::new(
+        user.user_id,
+        user.address.clone(),
+        USDC,
+        margin,
+        BigUint::from(user.user_id),
+    );
+
+    let order_side = match side {
+        OrderSide::Bid => PerpOrderSide::Long,
+        OrderSide::Ask => PerpOrderSide::Short,
+    };
+    let collateral_amount = get_collateral_amount(BTC, qty, price);
+
+    let order = PerpOrder::new_open_order(
+        0,
+        4_000_000_000,
+        order_side,
+        BTC,
+        qty,
+        collateral_amount,
+        collateral_amount / 100,
+        OpenOrderFields {
+            initial_margin: margin,
+            collateral_token: USDC,
+            notes_in: vec![margin_note],
+            refund_note: None,
+            position_address: user.stark_key(),
+            allow_partial_liquidations: false,
+        },
+    );
+
+    let signature = SIGNATURES.with(|sigs| {
+        sigs.borrow_mut()
+            .entry(order.hash.clone())
+            .or_insert_with(|| user.sign(&order.hash))
+            .clone()
+    });
+
+    (Order::Perp(order), signature)
+}
+
+// * INVARIANTS ====================================================================================
+
+fn top_price(book: &mut OrderBook, side: OrderSide) -> Option<f64> {
+    let queue = match side {
+        OrderSide::Bid => &mut book.bid_queue,
+        OrderSide::Ask => &mut book.ask_queue,
+    };
+
+    queue
+        .peek()
+        .map(|wrapper| wrapper.order.get_price(side, None))
+}
+
+fn resting_qty(book: &OrderBook) -> u64 {
+    let bids = book.bid_queue.visualize();
+    let asks = book.ask_queue.visualize();
+
+    bids.iter()
+        .chain(asks.iter())
+        .map(|(_, qty, _, _)| qty)
+        .sum()
+}
+
+/// After every request the best bid has to be strictly below the best ask,
+/// otherwise the two orders should have been matched.
+fn assert_not_crossed(book: &mut OrderBook) -> Result<(), TestCaseError> {
+    let best_bid = top_price(book, OrderSide::Bid);
+    let best_ask = top_price(book, OrderSide::Ask);
+
+    if let (Some(bid), Some(ask)) = (best_bid, best_ask) {
+        prop_assert!(bid < ask, "crossed book: bid {} >= ask {}", bid, ask);
+    }
+
+    Ok(())
+}
+
+/// The maintained price levels have to add up to the orders resting at each price
+fn assert_depth_matches_orders(book: &OrderBook) -> Result<(), TestCaseError> {
+    // ? Order prices are computed from the order amounts, so the same level can be off by
+    // ? rounding errors (the book keys its levels the same way)
+    let level_key = |price: f64| (price * 1e6).round() as u64;
+
+    for queue in [&book.bid_queue, &book.ask_queue] {
+        let mut expected: Vec<(u64, u64, u32)> = Vec::new();
+        for (price, qty, _, _) in queue.visualize() {
+            match expected.last_mut() {
+                Some(level) if level.0 == level_key(price) => {
+                    level.1 += qty;
+                    level.2 += 1;
+                }
+                _ => expected.push((level_key(price), qty, 1)),
+            }
+        }
+
+        let depth = queue
+            .depth(usize::MAX, 0.0)
+            .iter()
+            .map(|level| (level_key(level.price), level.qty, level.order_count))
+            .collect::<Vec<(u64, u64, u32)>>();
+        prop_assert_eq!(depth, expected);
+    }
+
+    Ok(())
+}
+
+// * PROPERTIES ====================================================================================
+
+proptest! {
+    #![proptest_config(config())]
+
+    #[test]
+    fn order_flow_keeps_the_book_consistent(actions in prop::collection::vec(action(), 1..40)) {
+        let users = (1..=N_USERS).map(TestUser::new).collect::<Vec<TestUser>>();
+        let mut book = OrderBook::new(BTC, USDC, PERP_MARKET_IDS[&BTC.to_string()]);
+
+        // (order_id, side, user_id) of every order that rested in the book at some point
+        let mut placed_orders: Vec<(u64, OrderSide, u64)> = Vec::new();
+        // (order_id, user_id, client_order_id) of every accepted order
+        let mut client_orders: Vec<(u64, u64, u64)> = Vec::new();
+
+        let mut total_placed: u64 = 0;
+        let mut total_matched: u64 = 0;
+        let mut total_cancelled: u64 = 0;
+
+        for action in actions {
+            match action {
+                Action::Place { user, is_bid, tick, lots } => {
+                    let user = &users[user as usize];
+                    let side = if is_bid { OrderSide::Bid } else { OrderSide::Ask };
+                    let price = (MIN_PRICE + tick * TICK_SIZE) * 10u64.pow(6);
+                    let qty = lots * LOT_SIZE;
+
+                    let (order, signature) = open_order(user, side, price, qty);
+                    let limit_price = order.get_price(side, None);
+                    let client_order_id = client_orders.len() as u64 + 1;
+
+                    let request = new_limit_order_request(
+                        side,
+                        order,
+                        signature,
+                        SystemTime::now(),
+                        false,
+                        user.user_id,
+                        client_order_id,
+                    );
+                    let results = book.process_order(request);
+
+                    let order_id = match &results[0] {
+                        Ok(Success::Accepted { id, .. }) => *id,
+                        res => return Err(TestCaseError::fail(format!("order not accepted: {:?}", res))),
+                    };
+                    total_placed += qty;
+                    client_orders.push((order_id, user.user_id, client_order_id));
+
+                    // ? Fills are reported in (taker, maker) pairs with the same quantity and price
+                    let fills = &results[1..];
+                    prop_assert!(fills.len() % 2 == 0, "unpaired fills: {:?}", fills);
+
+                    let mut taker_filled: u64 = 0;
+                    for pair in fills.chunks(2) {
+                        match (&pair[0], &pair[1]) {
+                            (
+                                Ok(Success::Filled { side: taker_side, price: taker_price, qty: taker_qty, .. }),
+                                Ok(Success::Filled { side: maker_side, price: maker_price, qty: maker_qty, .. }),
+                            ) => {
+                                prop_assert_eq!(*taker_side, side);
+                                prop_assert_ne!(*maker_side, side);
+                                prop_assert_eq!(taker_qty, maker_qty);
+                                prop_assert_eq!(taker_price, maker_price);
+
+                                // ? The taker is never filled at a price worse than its limit
+                                match side {
+                                    OrderSide::Bid => prop_assert!(*taker_price <= limit_price),
+                                    OrderSide::Ask => prop_assert!(*taker_price >= limit_price),
+                                }
+
+                                taker_filled += taker_qty;
+                            }
+                            _ => return Err(TestCaseError::fail(format!("unexpected results: {:?}", pair))),
+                        }
+                    }
+
+                    prop_assert!(taker_filled <= qty);
+                    total_matched += taker_filled;
+
+                    // ? Whatever was not filled is left resting in the book
+                    let resting = book.get_order(order_id).map(|o| o.qty_left).unwrap_or(0);
+                    prop_assert_eq!(resting, qty - taker_filled);
+                    if resting > 0 {
+                        placed_orders.push((order_id, side, user.user_id));
+                    }
+                }
+                Action::Cancel { pick, wrong_user } => {
+                    if placed_orders.is_empty() {
+                        continue;
+                    }
+                    let (order_id, side, user_id) = placed_orders[pick % placed_orders.len()];
+                    let qty_left = book.get_order(order_id).map(|o| o.qty_left);
+
+                    let cancel_user_id = if wrong_user { user_id % N_USERS + 1 } else { user_id };
+                    let results = book.process_order(limit_order_cancel_request(order_id, side, cancel_user_id));
+
+                    match (qty_left, wrong_user) {
+                        (Some(qty_left), false) => {
+                            let cancelled = matches!(results[0], Ok(Success::Cancelled { .. }));
+                            prop_assert!(cancelled, "cancel failed: {:?}", results[0]);
+                            prop_assert!(book.get_order(order_id).is_none());
+                            total_cancelled += qty_left;
+                        }
+                        (_, _) => {
+                            // ? Orders can only be cancelled by their owner
+                            let not_found = matches!(results[0], Err(Failed::OrderNotFound(_)));
+                            prop_assert!(not_found, "unexpected cancel result: {:?}", results[0]);
+                            prop_assert_eq!(book.get_order(order_id).map(|o| o.qty_left), qty_left);
+                        }
+                    }
+                }
+            }
+
+            assert_not_crossed(&mut book)?;
+            assert_depth_matches_orders(&book)?;
+
+            // ? Client order ids resolve to exactly the orders still resting in the book
+            for (order_id, user_id, client_order_id) in client_orders.iter() {
+                let by_client_id = book
+                    .get_order_by_client_id(*user_id, *client_order_id)
+                    .map(|o| o.order_id);
+                prop_assert_eq!(by_client_id, book.get_order(*order_id).map(|o| o.order_id));
+            }
+
+            // ? Every matched unit of base leaves the book twice (once for the taker and once for the maker)
+            prop_assert_eq!(
+                resting_qty(&book),
+                total_placed - 2 * total_matched - total_cancelled
+            );
+        }
+    }
+}
+
+// * DEPTH =========================================================================================
+
+#[test]
+fn depth_groups_price_levels_away_from_the_spread() {
+    let users = (1..=N_USERS).map(TestUser::new).collect::<Vec<TestUser>>();
+    let mut book = OrderBook::new(BTC, USDC, PERP_MARKET_IDS[&BTC.to_string()]);
+
+    place(&mut book, &users[0], OrderSide::Bid, 29_995, 1);
+    place(&mut book, &users[1], OrderSide::Bid, 29_995, 2);
+    place(&mut book, &users[0], OrderSide::Bid, 29_980, 3);
+    place(&mut book, &users[1], OrderSide::Ask, 30_005, 1);
+    place(&mut book, &users[2], OrderSide::Ask, 30_020, 4);
+
+    let levels = |depth: Vec<PriceLevel>| {
+        depth
+            .iter()
+            .map(|level| (level.price, level.qty / LOT_SIZE, level.order_count))
+            .collect::<Vec<(f64, u64, u32)>>()
+    };
+
+    assert_eq!(
+        levels(book.bid_queue.depth(10, 0.0)),
+        vec![(29_995.0, 3, 2), (29_980.0, 3, 1)]
+    );
+    assert_eq!(levels(book.bid_queue.depth(1, 0.0)), vec![(29_995.0, 3, 2)]);
+
+    // ? Asking for more levels than the book has returns all of them
+    assert_eq!(
+        levels(book.bid_queue.depth(usize::MAX, 0.0)),
+        vec![(29_995.0, 3, 2), (29_980.0, 3, 1)]
+    );
+    let empty_book = OrderBook::new(BTC, USDC, PERP_MARKET_IDS[&BTC.to_string()]);
+    assert!(empty_book.ask_queue.depth(usize::MAX, 20.0).is_empty());
+
+    // ? Bids are rounded down and asks up, so grouped levels never look better than they are
+    assert_eq!(
+        levels(book.bid_queue.depth(10, 20.0)),
+        vec![(29_980.0, 6, 3)]
+    );
+    assert_eq!(
+        levels(book.ask_queue.depth(10, 20.0)),
+        vec![(30_020.0, 5, 2)]
+    );
+    assert_eq!(
+        levels(book.ask_queue.depth(10, 10.0)),
+        vec![(30_010.0, 1, 1), (30_020.0, 4, 1)]
+    );
+}
+
+// * AMENDS ========================================================================================
+
+fn place(book: &mut OrderBook, user: &TestUser, side: OrderSide, price: u64, lots: u64) -> u64 {
+    let (order, signature) = open_order(user, side, price * 10u64.pow(6), lots * LOT_SIZE);
+    let request = new_limit_order_request(
+        side,
+        order,
+        signature,
+        SystemTime::now(),
+        false,
+        user.user_id,
+        0,
+    );
+
+    match &book.process_order(request)[0] {
+        Ok(Success::Accepted { id, .. }) => *id,
+        res => panic!("order not accepted: {:?}", res),
+    }
+}
+
+/// Amends the quantity of an order, keeping its price
+fn amend_qty(book: &mut OrderBook, order_id: u64, user_id: u64, lots: u64) -> Result<(), String> {
+    return amend(book, order_id, user_id, 0.0, lots);
+}
+
+/// Amends the price and quantity of an order (a price of 0 keeps the current price)
+fn amend(
+    book: &mut OrderBook,
+    order_id: u64,
+    user_id: u64,
+    price: f64,
+    lots: u64,
+) -> Result<(), String> {
+    let wrapper = book.get_order(order_id).unwrap();
+    let expiration = match &wrapper.order {
+        Order::Perp(order) => order.expiration_timestamp,
+        Order::Spot(order) => order.expiration_timestamp,
+    };
+
+    let request = new_amend_order(
+        order_id,
+        wrapper.order_side,
+        user_id,
+        price,
+        expiration,
+        lots * LOT_SIZE,
+        wrapper.signature,
+        false,
+    );
+
+    match &book.process_order(request)[0] {
+        Ok(Success::Amended { .. }) => Ok(()),
+        Err(Failed::ValidationFailed(err)) => Err(err.clone()),
+        res => panic!("unexpected amend result: {:?}", res),
+    }
+}
+
+#[test]
+fn reducing_an_order_keeps_its_priority() {
+    let users = (1..=N_USERS).map(TestUser::new).collect::<Vec<TestUser>>();
+    let mut book = OrderBook::new(BTC, USDC, PERP_MARKET_IDS[&BTC.to_string()]);
+
+    let price = MIN_PRICE;
+    let first = place(&mut book, &users[0], OrderSide::Bid, price, 5);
+    let second = place(&mut book, &users[1], OrderSide::Bid, price, 5);
+
+    amend_qty(&mut book, first, users[0].user_id, 3).unwrap();
+    assert_eq!(book.get_order(first).unwrap().qty_left, 3 * LOT_SIZE);
+    assert_eq!(book.bid_queue.peek().unwrap().order_id, first);
+
+    // ? Restating the current price keeps the priority as well
+    let current_price = book
+        .get_order(first)
+        .unwrap()
+        .order
+        .get_price(OrderSide::Bid, None);
+    amend(&mut book, first, users[0].user_id, current_price, 2).unwrap();
+    assert_eq!(book.get_order(first).unwrap().qty_left, 2 * LOT_SIZE);
+    assert_eq!(book.bid_queue.peek().unwrap().order_id, first);
+
+    // ? Growing the order sends it to the back of the queue
+    amend_qty(&mut book, first, users[0].user_id, 6).unwrap();
+    assert_eq!(book.get_order(first).unwrap().qty_left, 6 * LOT_SIZE);
+    assert_eq!(book.bid_queue.peek().unwrap().order_id, second);
+
+    // ? Fill one lot of the second order, amends have to leave something to fill
+    place(&mut book, &users[2], OrderSide::Ask, price, 1);
+    assert_eq!(book.get_order(second).unwrap().qty_left, 4 * LOT_SIZE);
+
+    assert!(amend_qty(&mut book, second, users[1].user_id, 1).is_err());
+    amend_qty(&mut book, second, users[1].user_id, 2).unwrap();
+    assert_eq!(book.get_order(second).unwrap().qty_left, LOT_SIZE);
+    assert_eq!(book.bid_queue.peek().unwrap().order_id, second);
+}
+
+// * MARKET MAKER PROTECTION =======================================================================
+
+#[test]
+fn fills_over_the_mmp_limit_pull_the_makers_quotes() {
+    let users = (1..=N_USERS).map(TestUser::new).collect::<Vec<TestUser>>();
+    let (maker, taker) = (&users[0], &users[1]);
+    let mut book = OrderBook::new(BTC, USDC, PERP_MARKET_IDS[&BTC.to_string()]);
+
+    book.mm_protection.set_config(
+        maker.user_id,
+        MmpConfig {
+            window_ms: 60_000,
+            qty_limit: 3 * LOT_SIZE,
+            delta_limit: 0,
+        },
+    );
+
+    place(&mut book, maker, OrderSide::Bid, MIN_PRICE, 2);
+    place(&mut book, maker, OrderSide::Bid, MIN_PRICE - TICK_SIZE, 2);
+    let ask_price = MIN_PRICE + 20 * TICK_SIZE;
+    let ask = place(&mut book, maker, OrderSide::Ask, ask_price, 2);
+
+    // ? Sweeping both bids goes over the limit, so the ask is pulled with them
+    place(&mut book, taker, OrderSide::Ask, MIN_PRICE - TICK_SIZE, 4);
+    assert!(book.get_user_orders(maker.user_id).is_empty());
+    assert_eq!(
+        book.mm_protection.take_triggers(),
+        vec![MmpTrigger {
+            user_id: maker.user_id,
+            cancelled_order_ids: vec![ask],
+        }]
+    );
+
+    // ? No new quotes until the maker resets the protection
+    let (order, signature) = open_order(maker, OrderSide::Bid, MIN_PRICE * 10u64.pow(6), LOT_SIZE);
+    let request = new_limit_order_request(
+        OrderSide::Bid,
+        order,
+        signature,
+        SystemTime::now(),
+        false,
+        maker.user_id,
+        0,
+    );
+    assert!(matches!(
+        book.process_order(request)[0],
+        Err(Failed::ValidationFailed(_))
+    ));
+
+    assert!(book.mm_protection.reset(maker.user_id));
+    place(&mut book, maker, OrderSide::Bid, MIN_PRICE, 1);
+}
+
+// * MARKET PARAMETERS =============================================================================
+
+#[test]
+fn orders_off_the_market_params_are_rejected() {
+    let user = TestUser::new(1);
+    let mut book = OrderBook::new(BTC, USDC, PERP_MARKET_IDS[&BTC.to_string()]);
+    let params = book.market_params();
+
+    let mut submit = |price: u64, qty: u64| {
+        let (order, signature) = open_order(&user, OrderSide::Bid, price, qty);
+        let request = new_limit_order_request(
+            OrderSide::Bid,
+            order,
+            signature,
+            SystemTime::now(),
+            false,
+            user.user_id,
+            0,
+        );
+
+        match &book.process_order(request)[0] {
+            Ok(Success::Accepted { .. }) => Ok(()),
+            Err(Failed::ValidationFailed(err)) => Err(err.clone()),
+            res => panic!("unexpected order result: {:?}", res),
+        }
+    };
+
+    let price = MIN_PRICE * 10u64.pow(6);
+    assert_eq!(
+        submit(price + params.tick_size / 2, LOT_SIZE),
+        Err("price must be a multiple of the tick size".to_string())
+    );
+    assert_eq!(
+        submit(price, LOT_SIZE + params.lot_size / 2),
+        Err("order size must be a multiple of the lot size".to_string())
+    );
+    assert_eq!(
+        submit(price, params.lot_size),
+        Err("order value is below the minimum notional".to_string())
+    );
+    assert_eq!(
+        submit(price, params.max_order_size + params.lot_size),
+        Err("order size is above the maximum order size".to_string())
+    );
+
+    assert_eq!(submit(price + params.tick_size, LOT_SIZE), Ok(()));
+}
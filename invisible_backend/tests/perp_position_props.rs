@@ -0,0 +1,290 @@
+//! Property tests for the position arithmetic in `PerpPosition`.
+//!
+//! Positions are opened with random sizes, prices and leverage (up to 15x, capped by the max
+//! leverage of their risk tier) and then increased, reduced, flipped or liquidated along random
+//! price moves.
+//! Failing inputs are saved to `perp_position_props.regressions` next to this file and
+//! replayed first on every run.
+
+use num_bigint::BigUint;
+use proptest::prelude::*;
+use proptest::test_runner::FileFailurePersistence;
+
+use invisible_backend::perpetual::{
+    get_collateral_amount, get_risk_tier, perp_helpers::perp_swap_helpers::get_max_leverage,
+    perp_position::PerpPosition, OrderSide, COLLATERAL_TOKEN,
+};
+
+const BTC: u32 = 3592681469;
+
+// multiplier between (size * price) and collateral amounts for BTC
+const MULTIPLIER: i128 = 10_i128.pow(8);
+
+fn config() -> ProptestConfig {
+    ProptestConfig {
+        cases: 256,
+        failure_persistence: Some(Box::new(FileFailurePersistence::WithSource("regressions"))),
+        ..ProptestConfig::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PositionParams {
+    is_long: bool,
+    size: u64,
+    price: u64,
+    leverage: u64, // 4 decimals
+    allow_partial_liquidations: bool,
+}
+
+fn position_params(max_leverage: u64) -> impl Strategy<Value = PositionParams> {
+    (
+        any::<bool>(),
+        100_000..=1_000_000_000u64, // 0.001 - 10 BTC
+        (1_000..=100_000u64).prop_map(|p| p * 10u64.pow(6)), // 1k - 100k USD
+        10_000..=max_leverage * 10_000,
+        any::<bool>(),
+    )
+        .prop_map(
+            |(is_long, size, price, leverage, allow_partial_liquidations)| PositionParams {
+                is_long,
+                size,
+                price,
+                leverage: leverage.min(get_max_leverage(
+                    BTC,
+                    get_collateral_amount(BTC, size, price),
+                )),
+                allow_partial_liquidations,
+            },
+        )
+}
+
+fn open_position(params: &PositionParams) -> PerpPosition {
+    let order_side = if params.is_long {
+        OrderSide::Long
+    } else {
+        OrderSide::Short
+    };
+
+    let notional = get_collateral_amount(BTC, params.size, params.price);
+    let margin = notional * 10_000 / params.leverage;
+
+    PerpPosition::new(
+        order_side,
+        params.size,
+        BTC,
+        COLLATERAL_TOKEN,
+        margin,
+        params.leverage,
+        params.allow_partial_liquidations,
+        BigUint::from(1u32),
+        0,
+        0,
+        notional / 1000,
+    )
+}
+
+/// A price `permille`/1000 of the way from the entry price to the liquidation price.
+fn price_towards_liquidation(position: &PerpPosition, permille: u64) -> u64 {
+    let entry = position.entry_price as i128;
+    let liquidation = position.liquidation_price as i128;
+
+    (entry + (liquidation - entry) * permille as i128 / 1000) as u64
+}
+
+fn realized_pnl(position: &PerpPosition, size: u64, price: u64) -> i128 {
+    let price_delta = match position.order_side {
+        OrderSide::Long => price as i128 - position.entry_price as i128,
+        OrderSide::Short => position.entry_price as i128 - price as i128,
+    };
+
+    size as i128 * price_delta / MULTIPLIER
+}
+
+/// Bankruptcy price <= liquidation price <= entry price for longs, the other way around for shorts.
+fn assert_prices_ordered(position: &PerpPosition) -> Result<(), TestCaseError> {
+    if position.position_size == 0 {
+        return Ok(());
+    }
+
+    match position.order_side {
+        OrderSide::Long => {
+            prop_assert!(position.liquidation_price <= position.entry_price);
+            prop_assert!(position.bankruptcy_price <= position.liquidation_price);
+        }
+        OrderSide::Short => {
+            prop_assert!(position.liquidation_price >= position.entry_price);
+            prop_assert!(position.bankruptcy_price >= position.liquidation_price);
+        }
+    }
+    prop_assert_eq!(&position.hash, &position.hash_position());
+
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(config())]
+
+    #[test]
+    fn new_positions_are_liquidated_before_bankruptcy(params in position_params(15)) {
+        let position = open_position(&params);
+
+        assert_prices_ordered(&position)?;
+        match position.order_side {
+            OrderSide::Long => prop_assert!(position.liquidation_price < position.entry_price),
+            OrderSide::Short => prop_assert!(position.liquidation_price > position.entry_price),
+        }
+
+        // ? A fresh position is not liquidatable at its entry price
+        let (is_liquidatable, _) = position.is_position_liquidatable(position.entry_price, position.entry_price);
+        prop_assert!(!is_liquidatable);
+    }
+
+    #[test]
+    fn larger_positions_are_liquidated_closer_to_entry(
+        params in position_params(2),
+        scale in 2..=50u64,
+    ) {
+        let position = open_position(&params);
+        let larger = open_position(&PositionParams { size: params.size * scale, ..params.clone() });
+
+        // ? Same leverage, but the larger notional falls in a tier with a higher maintenance margin
+        let notional = |p: &PerpPosition| get_collateral_amount(BTC, p.position_size, p.entry_price);
+        prop_assume!(get_risk_tier(BTC, notional(&position)) != get_risk_tier(BTC, notional(&larger)));
+
+        let distance = |p: &PerpPosition| p.entry_price.abs_diff(p.liquidation_price);
+        prop_assert!(distance(&larger) < distance(&position));
+    }
+
+    #[test]
+    fn increasing_averages_the_entry_price(
+        params in position_params(5),
+        added_fraction in 1..=1000u64,
+        price_permille in 800..=1200u64,
+    ) {
+        let mut position = open_position(&params);
+        let prev = position.clone();
+
+        let added_size = (prev.position_size * added_fraction / 1000).max(1);
+        let added_price = prev.entry_price * price_permille / 1000;
+        let fee = get_collateral_amount(BTC, added_size, added_price) / 1000;
+
+        position.increase_position_size(added_size, added_price, fee, vec![], vec![], 0);
+
+        prop_assert_eq!(position.position_size, prev.position_size + added_size);
+        prop_assert_eq!(position.margin, prev.margin - fee);
+        prop_assert!(position.entry_price >= prev.entry_price.min(added_price));
+        prop_assert!(position.entry_price <= prev.entry_price.max(added_price));
+        assert_prices_ordered(&position)?;
+    }
+
+    #[test]
+    fn reducing_realizes_pnl_without_margin_underflow(
+        params in position_params(15),
+        reduced_fraction in 1..1000u64,
+        price_permille in 0..1000u64,
+        favorable in any::<bool>(),
+    ) {
+        let mut position = open_position(&params);
+        let prev = position.clone();
+
+        // ? Any price the position can still be traded at (not beyond the liquidation price)
+        let price = if favorable {
+            2 * prev.entry_price - price_towards_liquidation(&prev, price_permille)
+        } else {
+            price_towards_liquidation(&prev, price_permille)
+        };
+        let reduction_size = (prev.position_size * reduced_fraction / 1000).max(1);
+        prop_assume!(reduction_size < prev.position_size);
+        let fee = get_collateral_amount(BTC, reduction_size, price) / 1000;
+
+        position.reduce_position_size(reduction_size, price, fee, vec![], vec![], 0);
+
+        let expected_margin = prev.margin as i128 + realized_pnl(&prev, reduction_size, price) - fee as i128;
+        prop_assert!(expected_margin > 0, "margin underflow: {}", expected_margin);
+        prop_assert_eq!(position.margin as i128, expected_margin);
+        prop_assert_eq!(position.position_size, prev.position_size - reduction_size);
+        prop_assert_eq!(position.entry_price, prev.entry_price);
+        assert_prices_ordered(&position)?;
+    }
+
+    #[test]
+    fn flipping_opens_the_opposite_side_at_the_trade_price(
+        params in position_params(15),
+        extra_fraction in 1..=500u64,
+        price_permille in 0..1000u64,
+    ) {
+        let mut position = open_position(&params);
+        let prev = position.clone();
+
+        let price = price_towards_liquidation(&prev, price_permille);
+        let reduction_size = prev.position_size + (prev.position_size * extra_fraction / 1000).max(1);
+        let fee = get_collateral_amount(BTC, reduction_size, price) / 1000;
+
+        position.flip_position_side(reduction_size, price, fee, vec![], vec![], 0);
+
+        let expected_margin = prev.margin as i128 + realized_pnl(&prev, prev.position_size, price) - fee as i128;
+        prop_assert!(expected_margin > 0, "margin underflow: {}", expected_margin);
+        prop_assert_eq!(position.margin as i128, expected_margin);
+        prop_assert_ne!(&position.order_side, &prev.order_side);
+        prop_assert_eq!(position.position_size, reduction_size - prev.position_size);
+        prop_assert_eq!(position.entry_price, price);
+        assert_prices_ordered(&position)?;
+    }
+
+    #[test]
+    fn liquidations_never_pay_out_more_than_the_margin(
+        params in position_params(15),
+        overshoot_permille in 0..=100u64,
+    ) {
+        prop_assume!(params.leverage >= 20_000);
+
+        let mut position = open_position(&params);
+        let prev = position.clone();
+
+        // ? Up to 10% past the liquidation price, possibly past the bankruptcy price as well
+        let overshoot = prev.liquidation_price * overshoot_permille / 1000;
+        let price = match prev.order_side {
+            OrderSide::Long => prev.liquidation_price - overshoot,
+            OrderSide::Short => prev.liquidation_price + overshoot,
+        };
+        prop_assume!(price > 0);
+
+        let (is_liquidatable, liquidatable_size) = prev.is_position_liquidatable(price, price);
+        prop_assert!(is_liquidatable);
+
+        let (liquidated_size, liquidator_fee, leftover_collateral, is_partial) = position
+            .liquidate_position(price, price, vec![], vec![], 0)
+            .map_err(|e| TestCaseError::fail(format!("{:?}", e)))?;
+
+        prop_assert_eq!(liquidated_size, liquidatable_size);
+        prop_assert!(liquidated_size <= prev.position_size);
+
+        if is_partial {
+            prop_assert!(prev.position_header.allow_partial_liquidations);
+            prop_assert_eq!(leftover_collateral, 0);
+            prop_assert_eq!(position.position_size, prev.position_size - liquidated_size);
+            prop_assert_eq!(position.margin, prev.margin - liquidator_fee);
+            assert_prices_ordered(&position)?;
+        } else {
+            prop_assert_eq!(liquidated_size, prev.position_size);
+            prop_assert_eq!(position.position_size, 0);
+            prop_assert_eq!(position.margin, 0);
+            prop_assert!(leftover_collateral <= prev.margin as i64);
+        }
+    }
+
+    #[test]
+    fn healthy_positions_cannot_be_liquidated(
+        params in position_params(15),
+        price_permille in 0..1000u64,
+    ) {
+        let mut position = open_position(&params);
+
+        let price = price_towards_liquidation(&position, price_permille);
+        prop_assume!(price != position.liquidation_price);
+
+        let result = position.liquidate_position(price, price, vec![], vec![], 0);
+        prop_assert!(result.is_err());
+    }
+}
@@ -1,258 +1,177 @@
-use invisible_backend::perpetual::perp_helpers::perp_rollback::PerpRollbackInfo;
-use parking_lot::Mutex;
-use std::collections::HashMap;
-use std::str::FromStr;
-use std::sync::Arc;
-use std::thread::ThreadId;
-use std::time::Instant;
+use num_bigint::BigUint;
 
 use invisible_backend::perpetual::perp_order::OpenOrderFields;
-use invisible_backend::perpetual::{perp_order::PerpOrder, OrderSide};
-use invisible_backend::transactions::transaction_helpers::rollbacks::RollbackInfo;
-use invisible_backend::transactions::{deposit::Deposit, limit_order::LimitOrder, swap::Swap};
+use invisible_backend::perpetual::{perp_order::PerpOrder, perp_swap::PerpSwap, OrderSide};
+use invisible_backend::transaction_batch::restore_state::replay::replay_batch;
+use invisible_backend::transactions::limit_order::{LimitOrder, SpotNotesInfo};
+use invisible_backend::transactions::swap::Swap;
 use invisible_backend::utils::notes::Note;
-use num_bigint::{BigInt, BigUint};
-use num_traits::{One, Zero};
 
-use invisible_backend::starkware_crypto::EcPoint;
+use crate::common::{TestExchange, TestUser, BTC, USDC};
 
-use invisible_backend::{
-    perpetual::perp_swap::PerpSwap, transaction_batch::transaction_batch::TransactionBatch,
-};
+const BTC_PRICE: u64 = 30_000 * 10u64.pow(6);
+const EXPIRATION_TIMESTAMP: u64 = 4_000_000_000;
 
 //
 
-#[test]
-fn test_sequential_perp_fills() {
-    let _n: u64 = 1;
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sequential_perp_fills() {
+    let mut exchange = TestExchange::builder()
+        .with_index_price(BTC, BTC_PRICE)
+        .build()
+        .await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+    let carol = TestUser::new(3);
 
-    let rollback_safeguard: Arc<Mutex<HashMap<ThreadId, RollbackInfo>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let perp_rollback_safeguard: Arc<Mutex<HashMap<ThreadId, PerpRollbackInfo>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let mut tx_batch = TransactionBatch::new(4, 4, rollback_safeguard, perp_rollback_safeguard);
-
-    let (deposit1, deposit2, deposit3) = get_perp_deposits();
-
-    let note1 = deposit1.notes[0].clone();
-
-    let z_idx1 = tx_batch
-        .execute_transaction(deposit1)
-        .join()
-        .unwrap()
-        .unwrap()
-        .1
-        .unwrap()[0];
-    let z_idx2 = tx_batch
-        .execute_transaction(deposit2)
-        .join()
-        .unwrap()
-        .unwrap()
-        .1
-        .unwrap()[0];
-    let z_idx3 = tx_batch
-        .execute_transaction(deposit3)
-        .join()
-        .unwrap()
-        .unwrap()
-        .1
-        .unwrap()[0];
+    let note1 = exchange.deposit(&alice, USDC, 1000_000_000).await;
+    let note2 = exchange.deposit(&bob, USDC, 1000_000_000).await;
+    let note3 = exchange.deposit(&carol, USDC, 1000_000_000).await;
 
     // ---------------------------------------------------------------------------------------
 
-    let (perp_swap1, perp_swap2) = get_dummy_open_order_swaps(note1, vec![z_idx1, z_idx2, z_idx3]);
-
-    let now = Instant::now();
-    let handle1 = tx_batch.execute_perpetual_transaction(perp_swap1);
-    let handle2 = tx_batch.execute_perpetual_transaction(perp_swap2);
-
-    let _res1 = handle1.join().unwrap();
-    let _res2 = handle2.join().unwrap();
-
-    //
-
-    println!("time for swap: {:?}", now.elapsed());
-
-    println!("state tree: {:#?}", tx_batch.state_tree.lock().leaf_nodes);
-
-    let now = Instant::now();
-    tx_batch.finalize_batch().expect("err");
-    println!("time for finalization: {:?}", now.elapsed());
-
-    println!("All good: ");
-
-    //
-
-    //
-}
-
-//
-
-#[test]
-fn test_sequential_spot_fills() {
-    let rollback_safeguard: Arc<Mutex<HashMap<ThreadId, RollbackInfo>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let perp_rollback_safeguard: Arc<Mutex<HashMap<ThreadId, PerpRollbackInfo>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let mut tx_batch = TransactionBatch::new(4, 4, rollback_safeguard, perp_rollback_safeguard);
-
-    let (deposit1, deposit2, deposit3) = get_spot_deposits();
-
-    let note1 = deposit1.notes[0].clone();
-    let note2 = deposit2.notes[0].clone();
+    let (perp_swap1, perp_swap2) = get_dummy_open_order_swaps(
+        &mut exchange,
+        (&alice, note1),
+        (&bob, note2),
+        (&carol, note3),
+    );
 
-    let z_idx1 = tx_batch
-        .execute_transaction(deposit1)
+    // ? The long order is filled by both shorts, the second fill adds to the opened position
+    let mut tx_batch = exchange.service.transaction_batch.lock().await;
+    let res1 = tx_batch
+        .execute_perpetual_transaction(perp_swap1)
         .join()
         .unwrap()
-        .unwrap()
-        .1
-        .unwrap()[0];
-    let z_idx2 = tx_batch
-        .execute_transaction(deposit2)
-        .join()
-        .unwrap()
-        .unwrap()
-        .1
-        .unwrap()[0];
-    let z_idx3 = tx_batch
-        .execute_transaction(deposit3)
+        .unwrap();
+    let res2 = tx_batch
+        .execute_perpetual_transaction(perp_swap2)
         .join()
         .unwrap()
-        .unwrap()
-        .1
-        .unwrap()[0];
-
-    let (swap1, swap2) = get_dummy_spot_swaps(note1, note2, vec![z_idx1, z_idx2, z_idx3]);
-
-    let now = Instant::now();
-    let handle1 = tx_batch.execute_transaction(swap1);
-    let handle2 = tx_batch.execute_transaction(swap2);
-
-    let res1 = handle1.join().unwrap();
-    let res2 = handle2.join().unwrap();
-
-    println!("swap1: {:#?}\n", res1.unwrap().0.unwrap());
-    println!("swap2: {:#?}\n", res2.unwrap().0.unwrap());
-
-    //
-
-    println!("time for swap: {:?}", now.elapsed());
-
-    println!("state {:#?}", tx_batch.state_tree.lock().leaf_nodes);
-    tx_batch.finalize_batch().expect("err");
+        .unwrap();
+    drop(tx_batch);
+
+    let position1 = res1.position_a.unwrap();
+    let position2 = res2.position_a.unwrap();
+    assert_eq!(position1.position_size, 7_000_000);
+    assert_eq!(position2.position_size, 10_000_000);
+    assert_eq!(position1.index, position2.index);
+    assert_eq!(res2.position_b.unwrap().position_size, 3_000_000);
+
+    assert_eq!(exchange.leaf_hash(position2.index).await, position2.hash);
+
+    let batch = exchange.finalize_batch().await;
+    assert_ne!(batch.prev_state_root, batch.new_state_root);
+
+    // ? Re-executing the recorded swaps should reproduce the same state
+    let report = replay_batch(
+        batch.batch_index,
+        batch.prev_state,
+        batch.transactions,
+        &batch.transition_info,
+        0,
+    );
+    assert!(report.is_consistent(), "{:?}", report.diffs);
+    assert!(report.restored.is_empty());
 }
 
 //
 
-//
-
-fn get_perp_deposits() -> (Deposit, Deposit, Deposit) {
-    let pk1 = BigInt::from_str("89327498632463297463274632493286132").unwrap();
-    let address1 = EcPoint::from_priv_key(&pk1);
-
-    let note = Note::new(
-        0,
-        address1.clone(),
-        55555,
-        100_000_000,
-        BigUint::from_str("1").unwrap(),
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sequential_spot_fills() {
+    let mut exchange = TestExchange::builder().build().await;
+    let alice = TestUser::new(1);
+    let bob = TestUser::new(2);
+    let carol = TestUser::new(3);
+
+    let note1 = exchange.deposit(&alice, USDC, 5000_000_000).await;
+    let note2 = exchange.deposit(&bob, BTC, 10_000_000).await;
+    let note3 = exchange.deposit(&carol, BTC, 10_000_000).await;
+
+    let (swap1, swap2) = get_dummy_spot_swaps(
+        &mut exchange,
+        (&alice, note1.clone()),
+        (&bob, note2),
+        (&carol, note3),
     );
 
-    let deposit1 = Deposit::new(
-        1,
-        55555,
-        100_000_000,
-        address1.x.to_biguint().unwrap(),
-        vec![note.clone()],
-        &pk1.to_biguint().unwrap(),
+    let mut tx_batch = exchange.service.transaction_batch.lock().await;
+    let res1 = tx_batch.execute_transaction(swap1).join().unwrap().unwrap();
+    let res2 = tx_batch.execute_transaction(swap2).join().unwrap().unwrap();
+    drop(tx_batch);
+
+    let res1 = res1.0.unwrap().note_info_swap_response_a.unwrap();
+    let res2 = res2.0.unwrap().note_info_swap_response_a.unwrap();
+
+    // ? The first fill leaves a partial fill refund note with the rest of the order amount
+    let pfr_note = res1.new_pfr_note.unwrap();
+    assert_eq!(pfr_note.token, USDC);
+    assert_eq!(pfr_note.amount, 3000_000_000 - 1500_000_000);
+    assert_eq!(res1.swap_note.amount, 5_000_000);
+
+    // ? The second fill completes the order
+    assert!(res2.new_pfr_note.is_none());
+    assert_eq!(res2.new_amount_filled, 10_000_000);
+    assert_eq!(
+        exchange.leaf_hash(res2.swap_note.index).await,
+        res2.swap_note.hash
     );
 
-    let deposit2 = Deposit::new(
-        2,
-        55555,
-        100_000_000,
-        address1.x.to_biguint().unwrap(),
-        vec![note.clone()],
-        &pk1.to_biguint().unwrap(),
-    );
+    let batch = exchange.finalize_batch().await;
+    assert_ne!(batch.prev_state_root, batch.new_state_root);
 
-    let deposit3 = Deposit::new(
-        3,
-        55555,
-        100_000_000,
-        address1.x.to_biguint().unwrap(),
-        vec![note.clone()],
-        &pk1.to_biguint().unwrap(),
+    // ? Re-executing the recorded swaps should reproduce the same state
+    let report = replay_batch(
+        batch.batch_index,
+        batch.prev_state,
+        batch.transactions,
+        &batch.transition_info,
+        0,
     );
-
-    return (deposit1, deposit2, deposit3);
+    assert!(report.is_consistent(), "{:?}", report.diffs);
+    assert!(report.restored.is_empty());
 }
 
-fn get_dummy_open_order_swaps(note_: Note, z_idxs: Vec<u64>) -> (PerpSwap, PerpSwap) {
-    let pk1 = BigInt::from_str("89327498632463297463274632493286132").unwrap();
-    let address1 = EcPoint::from_priv_key(&pk1).x.to_biguint().unwrap();
-    let pk2 = BigInt::from_str("32875426429834347923748248234235723").unwrap();
-    let address2 = EcPoint::from_priv_key(&pk2).x.to_biguint().unwrap();
-    let pk3 = BigInt::from_str("72562893579235823562367487236523532").unwrap();
-    let address3 = EcPoint::from_priv_key(&pk3).x.to_biguint().unwrap();
-
-    let mut note1 = note_.clone();
-    note1.index = z_idxs[0];
-    let refund_note1 = Note::new(
-        z_idxs[0],
-        note1.address.clone(),
-        note1.token,
-        0,
-        note1.blinding.clone(),
-    );
+//
 
-    let mut note2 = note_.clone();
-    note2.index = z_idxs[1];
-    let refund_note2 = Note::new(
-        z_idxs[1],
-        note2.address.clone(),
-        note2.token,
-        30_000_000,
-        note2.blinding.clone(),
-    );
+//
 
-    let mut note3 = note_.clone();
-    note3.index = z_idxs[2];
-    let refund_note3 = Note::new(
-        z_idxs[2],
-        note3.address.clone(),
-        note3.token,
-        70_000_000,
-        note3.blinding.clone(),
-    );
+fn get_dummy_open_order_swaps(
+    exchange: &mut TestExchange,
+    (user1, note1): (&TestUser, Note),
+    (user2, note2): (&TestUser, Note),
+    (user3, note3): (&TestUser, Note),
+) -> (PerpSwap, PerpSwap) {
+    let collateral_token = USDC;
+    let synthetic_token = BTC;
 
-    let expiration_timestamp = 10000;
-    let synthetic_token = 54321;
-    let collateral_token = 55555;
     // --- ---- --- ---- ----
 
     let order_id = 1;
     let order_side = OrderSide::Long;
-    let synthetic_amount = 1_000_000;
-    let collateral_amount = 100_000_000;
-    let initial_margin = 100_000_000;
+    let synthetic_amount = 10_000_000;
+    let collateral_amount = 3000_000_000;
+    let initial_margin = 700_000_000;
     let fee_limit = 0;
-    let notes_in = vec![note1];
-    let refund_note = Some(refund_note1);
+    let refund_note = exchange.new_note(
+        user1,
+        collateral_token,
+        note1.amount - initial_margin,
+        note1.index,
+    );
 
     let open_order_fields = OpenOrderFields {
         initial_margin,
         collateral_token,
-        notes_in,
-        refund_note,
-        position_address: address1.clone(),
-        blinding: BigUint::one(),
+        notes_in: vec![note1],
+        refund_note: Some(refund_note),
+        position_address: user1.stark_key(),
+        allow_partial_liquidations: false,
     };
 
     let perp_order1 = PerpOrder::new_open_order(
         order_id,
-        expiration_timestamp,
+        EXPIRATION_TIMESTAMP,
         order_side,
         synthetic_token,
         synthetic_amount,
@@ -265,25 +184,28 @@ fn get_dummy_open_order_swaps(note_: Note, z_idxs: Vec<u64>) -> (PerpSwap, PerpS
 
     let order_id = 2;
     let order_side = OrderSide::Short;
-    let synthetic_amount = 700_000;
-    let collateral_amount = 70_000_000;
-    let initial_margin = 70_000_000;
-    let fee_limit = 0;
-    let notes_in = vec![note2];
-    let refund_note = Some(refund_note2);
+    let synthetic_amount = 7_000_000;
+    let collateral_amount = 2100_000_000;
+    let initial_margin = 500_000_000;
+    let refund_note = exchange.new_note(
+        user2,
+        collateral_token,
+        note2.amount - initial_margin,
+        note2.index,
+    );
 
     let open_order_fields = OpenOrderFields {
         initial_margin,
         collateral_token,
-        notes_in,
-        refund_note,
-        position_address: address2.clone(),
-        blinding: BigUint::one(),
+        notes_in: vec![note2],
+        refund_note: Some(refund_note),
+        position_address: user2.stark_key(),
+        allow_partial_liquidations: false,
     };
 
     let perp_order2 = PerpOrder::new_open_order(
         order_id,
-        expiration_timestamp,
+        EXPIRATION_TIMESTAMP,
         order_side,
         synthetic_token,
         synthetic_amount,
@@ -296,25 +218,28 @@ fn get_dummy_open_order_swaps(note_: Note, z_idxs: Vec<u64>) -> (PerpSwap, PerpS
 
     let order_id = 3;
     let order_side = OrderSide::Short;
-    let synthetic_amount = 300_000;
-    let collateral_amount = 30_000_000;
-    let initial_margin = 30_000_000;
-    let fee_limit = 0;
-    let notes_in = vec![note3];
-    let refund_note = Some(refund_note3);
+    let synthetic_amount = 3_000_000;
+    let collateral_amount = 900_000_000;
+    let initial_margin = 300_000_000;
+    let refund_note = exchange.new_note(
+        user3,
+        collateral_token,
+        note3.amount - initial_margin,
+        note3.index,
+    );
 
     let open_order_fields = OpenOrderFields {
         initial_margin,
         collateral_token,
-        notes_in,
-        refund_note,
-        position_address: address3.clone(),
-        blinding: BigUint::one(),
+        notes_in: vec![note3],
+        refund_note: Some(refund_note),
+        position_address: user3.stark_key(),
+        allow_partial_liquidations: false,
     };
 
     let perp_order3 = PerpOrder::new_open_order(
         order_id,
-        expiration_timestamp,
+        EXPIRATION_TIMESTAMP,
         order_side,
         synthetic_token,
         synthetic_amount,
@@ -325,40 +250,28 @@ fn get_dummy_open_order_swaps(note_: Note, z_idxs: Vec<u64>) -> (PerpSwap, PerpS
 
     // --- ---- --- ---- ----  ----- --- ---- ---- ---- -----
 
-    let signature1 = perp_order1.sign_order(
-        //
-        Some(vec![&pk1.to_biguint().unwrap()]),
-        None,
-    );
-    let signature2 = perp_order2.sign_order(
-        //
-        Some(vec![&pk1.to_biguint().unwrap()]),
-        None,
-    );
-    let signature3 = perp_order3.sign_order(
-        //
-        Some(vec![&pk1.to_biguint().unwrap()]),
-        None,
-    );
+    let signature1 = user1.sign(&perp_order1.hash);
+    let signature2 = user2.sign(&perp_order2.hash);
+    let signature3 = user3.sign(&perp_order3.hash);
 
     let swap1 = PerpSwap::new(
         perp_order1.clone(),
         perp_order2,
         Some(signature1.clone()),
         Some(signature2),
-        70_000_000,
-        700_000,
+        2100_000_000,
+        7_000_000,
         0,
         0,
     );
 
     let swap2 = PerpSwap::new(
-        perp_order1.clone(),
+        perp_order1,
         perp_order3,
         Some(signature1),
         Some(signature3),
-        30_000_000,
-        300_000,
+        900_000_000,
+        3_000_000,
         0,
         0,
     );
@@ -366,163 +279,87 @@ fn get_dummy_open_order_swaps(note_: Note, z_idxs: Vec<u64>) -> (PerpSwap, PerpS
     return (swap1, swap2);
 }
 
-fn get_spot_deposits() -> (Deposit, Deposit, Deposit) {
-    let pk1 = BigInt::from_str("89327498632463297463274632493286132").unwrap();
-    let address1 = EcPoint::from_priv_key(&pk1);
-
-    let note = Note::new(
-        0,
-        address1.clone(),
-        12345,
-        100_000_000,
-        BigUint::from_str("1").unwrap(),
-    );
-
-    let note2 = Note::new(
-        0,
-        address1.clone(),
-        54321,
-        5_000_000,
-        BigUint::from_str("1").unwrap(),
-    );
-
-    let deposit1 = Deposit::new(
-        1,
-        12345,
-        100_000_000,
-        address1.x.to_biguint().unwrap(),
-        vec![note],
-        &pk1.to_biguint().unwrap(),
-    );
-
-    let deposit2 = Deposit::new(
-        2,
-        54321,
-        5_000_000,
-        address1.x.to_biguint().unwrap(),
-        vec![note2.clone()],
-        &pk1.to_biguint().unwrap(),
-    );
-
-    let deposit3 = Deposit::new(
-        3,
-        54321,
-        5_000_000,
-        address1.x.to_biguint().unwrap(),
-        vec![note2.clone()],
-        &pk1.to_biguint().unwrap(),
-    );
-
-    return (deposit1, deposit2, deposit3);
-}
-
-fn get_dummy_spot_swaps(note1_: Note, note2_: Note, z_idxs: Vec<u64>) -> (Swap, Swap) {
-    let pk1 = BigInt::from_str("89327498632463297463274632493286132").unwrap();
-    let address1 = EcPoint::from_priv_key(&pk1);
-
-    let mut note1 = note1_.clone();
-    note1.index = z_idxs[0];
-    let refund_note1 = Note::new(
-        z_idxs[0],
-        note1.address.clone(),
-        note1.token,
-        50_000_000,
-        note1.blinding.clone(),
-    );
-
-    let mut note2 = note2_.clone();
-    note2.index = z_idxs[1];
-    let refund_note2 = Note::new(
-        z_idxs[1],
-        note2.address.clone(),
-        note2.token,
-        2_500_000,
-        note2.blinding.clone(),
-    );
-
-    let mut note3 = note2_.clone();
-    note3.index = z_idxs[2];
-    let refund_note3 = Note::new(
-        z_idxs[2],
-        note3.address.clone(),
-        note3.token,
-        2_500_000,
-        note3.blinding.clone(),
-    );
+fn get_dummy_spot_swaps(
+    exchange: &mut TestExchange,
+    (user1, note1): (&TestUser, Note),
+    (user2, note2): (&TestUser, Note),
+    (user3, note3): (&TestUser, Note),
+) -> (Swap, Swap) {
+    // ? Buys 0.1 BTC for 3000 USDC, filled by two sellers of 0.05 BTC
+    let refund_note1 = exchange.new_note(user1, USDC, note1.amount - 3000_000_000, note1.index);
+    let refund_note2 = exchange.new_note(user2, BTC, note2.amount - 5_000_000, note2.index);
+    let refund_note3 = exchange.new_note(user3, BTC, note3.amount - 5_000_000, note3.index);
 
     let limit_order1 = LimitOrder::new(
         1,
-        10000,
-        12345,
-        54321,
-        50_000_000,
-        5_000_000,
+        EXPIRATION_TIMESTAMP,
+        USDC,
+        BTC,
+        3000_000_000,
+        10_000_000,
         0,
-        address1.clone(),
-        address1.clone(),
-        BigUint::zero(),
-        BigUint::zero(),
-        vec![note1],
-        Some(refund_note1),
+        Some(spot_notes_info(user1, note1, refund_note1)),
+        None,
     );
 
     let limit_order2 = LimitOrder::new(
         2,
-        10000,
-        54321,
-        12345,
-        2_500_000,
-        25_000_000,
+        EXPIRATION_TIMESTAMP,
+        BTC,
+        USDC,
+        5_000_000,
+        1500_000_000,
         0,
-        address1.clone(),
-        address1.clone(),
-        BigUint::zero(),
-        BigUint::zero(),
-        vec![note2],
-        Some(refund_note2),
+        Some(spot_notes_info(user2, note2, refund_note2)),
+        None,
     );
 
     let limit_order3 = LimitOrder::new(
         3,
-        10000,
-        54321,
-        12345,
-        2_500_000,
-        25_000_000,
+        EXPIRATION_TIMESTAMP,
+        BTC,
+        USDC,
+        5_000_000,
+        1500_000_000,
         0,
-        address1.clone(),
-        address1.clone(),
-        BigUint::zero(),
-        BigUint::zero(),
-        vec![note3],
-        Some(refund_note3),
+        Some(spot_notes_info(user3, note3, refund_note3)),
+        None,
     );
 
-    let sig1 = limit_order1.sign_order(vec![&pk1.to_biguint().unwrap()]);
-    let sig2 = limit_order2.sign_order(vec![&pk1.to_biguint().unwrap()]);
-    let sig3 = limit_order3.sign_order(vec![&pk1.to_biguint().unwrap()]);
+    let sig1 = user1.sign(&limit_order1.hash);
+    let sig2 = user2.sign(&limit_order2.hash);
+    let sig3 = user3.sign(&limit_order3.hash);
 
     let swap1 = Swap::new(
         limit_order1.clone(),
         limit_order2,
         sig1.clone(),
         sig2,
-        25_000_000,
-        2_500_000,
+        1500_000_000,
+        5_000_000,
         0,
         0,
     );
 
     let swap2 = Swap::new(
-        limit_order1.clone(),
+        limit_order1,
         limit_order3,
         sig1,
         sig3,
-        25_000_000,
-        2_500_000,
+        1500_000_000,
+        5_000_000,
         0,
         0,
     );
 
     return (swap1, swap2);
 }
+
+fn spot_notes_info(user: &TestUser, note_in: Note, refund_note: Note) -> SpotNotesInfo {
+    SpotNotesInfo {
+        dest_received_address: user.address.clone(),
+        dest_received_blinding: BigUint::from(user.user_id),
+        notes_in: vec![note_in],
+        refund_note: Some(refund_note),
+    }
+}
@@ -0,0 +1,10 @@
+//! Transaction level tests.
+//!
+//! Swaps are built by hand and executed directly on the `TransactionBatch` of a test exchange,
+//! without going through the order books. Deposits and batch finalization use the harness.
+
+#[path = "../common/mod.rs"]
+mod common;
+
+mod integration_test;
+mod order_tests;
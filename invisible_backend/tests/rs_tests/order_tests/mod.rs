@@ -0,0 +1,3 @@
+mod close_order_test;
+mod perp_transaction_test;
+mod spot_transaction_test;
@@ -230,9 +230,85 @@ app.post("/amend_order", (req, res) => {
   });
 });
 
-// *  SPLIT NOTES -----------------------------------------------------------
-app.post("/split_notes", (req, res) => {
-  client.split_notes(req.body, function (err, response) {
+// * SUBMIT ORDERS BATCH ------------------------------------------------------------------
+app.post("/submit_orders_batch", (req, res) => {
+  client.submit_orders_batch(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// * CANCEL ORDERS BATCH ------------------------------------------------------------------
+app.post("/cancel_orders_batch", (req, res) => {
+  client.cancel_orders_batch(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// * CANCEL ALL ---------------------------------------------------------------------------
+app.post("/cancel_all", (req, res) => {
+  client.cancel_all(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// *  DEAD MAN'S SWITCH -----------------------------------------------------------
+app.post("/set_dead_mans_switch", (req, res) => {
+  client.set_dead_mans_switch(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// *  MARKET MAKER PROTECTION -----------------------------------------------------
+app.post("/set_mm_protection", (req, res) => {
+  client.set_mm_protection(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+app.post("/reset_mm_protection", (req, res) => {
+  client.reset_mm_protection(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// *  RESTRUCTURE NOTES -----------------------------------------------------------
+app.post("/restructure_notes", (req, res) => {
+  client.restructure_notes(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// *  TRANSFERS -----------------------------------------------------------
+app.post("/execute_transfer", (req, res) => {
+  client.execute_transfer(req.body, function (err, response) {
     if (err) {
       console.log(err);
     } else {
@@ -252,6 +328,17 @@ app.post("/change_position_margin", (req, res) => {
   });
 });
 
+// *  SET POSITION LEVERAGE -----------------------------------------------------------
+app.post("/set_leverage", (req, res) => {
+  client.set_leverage(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
 // *  OPEN NEW ORDER TAB -----------------------------------------------------------
 app.post("/open_order_tab", (req, res) => {
   client.open_order_tab(req.body, function (err, response) {
@@ -296,6 +383,83 @@ app.post("/get_liquidity", (req, res) => {
   });
 });
 
+// * GET MARKET INFO ---------------------------------------------------------------------
+app.post("/get_market_info", (req, res) => {
+  client.get_market_info(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// * GET PREDICTED FUNDING -----------------------------------------------------------
+app.post("/get_predicted_funding", (req, res) => {
+  client.get_predicted_funding(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// * GET POSITION LEDGER ------------------------------------------------------------
+app.post("/get_position_ledger", (req, res) => {
+  client.get_position_ledger(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// * GET POSITION RISK -------------------------------------------------------------
+app.post("/get_position_risk", (req, res) => {
+  client.get_position_risk(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// * GET POSITIONS RISK BATCH ------------------------------------------------------
+app.post("/get_positions_risk_batch", (req, res) => {
+  client.get_positions_risk_batch(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// * SIMULATE PERP ORDER -----------------------------------------------------------
+app.post("/simulate_perp_order", (req, res) => {
+  client.simulate_perp_order(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
+// * GET DEPTH ---------------------------------------------------------------------
+app.post("/get_depth", (req, res) => {
+  client.get_depth(req.body, function (err, response) {
+    if (err) {
+      console.log(err);
+    } else {
+      res.send({ response: response });
+    }
+  });
+});
+
 // * GET ORDERS ---------------------------------------------------------------------
 app.post("/get_orders", (req, res) => {
   client.get_orders(req.body, function (err, response) {
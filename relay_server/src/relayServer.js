@@ -234,11 +234,94 @@ amqp.connect(rabbitmqConfig, (error0, connection) => {
       );
     });
 
-    // *  SPLIT NOTES -----------------------------------------------------------
-    app.post("/split_notes", (req, res) => {
+    // * SUBMIT ORDERS BATCH ------------------------------------------------------------------
+    app.post("/submit_orders_batch", (req, res) => {
       delegateRequest(
         req.body,
-        "split_notes",
+        "orders_batch",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // * CANCEL ORDERS BATCH ------------------------------------------------------------------
+    app.post("/cancel_orders_batch", (req, res) => {
+      delegateRequest(
+        req.body,
+        "batch_cancel",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // * CANCEL ALL ---------------------------------------------------------------------------
+    app.post("/cancel_all", (req, res) => {
+      delegateRequest(
+        req.body,
+        "cancel_all",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // *  DEAD MAN'S SWITCH -----------------------------------------------------------
+    app.post("/set_dead_mans_switch", (req, res) => {
+      delegateRequest(
+        req.body,
+        "dead_mans_switch",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // *  MARKET MAKER PROTECTION -----------------------------------------------------
+    app.post("/set_mm_protection", (req, res) => {
+      delegateRequest(
+        req.body,
+        "set_mm_protection",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    app.post("/reset_mm_protection", (req, res) => {
+      delegateRequest(
+        req.body,
+        "reset_mm_protection",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // *  RESTRUCTURE NOTES -----------------------------------------------------------
+    app.post("/restructure_notes", (req, res) => {
+      delegateRequest(
+        req.body,
+        "restructure_notes",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // *  TRANSFERS -----------------------------------------------------------
+    app.post("/execute_transfer", (req, res) => {
+      delegateRequest(
+        req.body,
+        "transfer",
         channel,
         res,
         queue,
@@ -258,6 +341,18 @@ amqp.connect(rabbitmqConfig, (error0, connection) => {
       );
     });
 
+    // *  SET POSITION LEVERAGE -----------------------------------------------------------
+    app.post("/set_leverage", (req, res) => {
+      delegateRequest(
+        req.body,
+        "set_leverage",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
     // ! ORDER TABS ======================================================================
     // *  OPEN ORDER TAB -----------------------------------------------------------
     app.post("/open_order_tab", (req, res) => {
@@ -344,6 +439,90 @@ amqp.connect(rabbitmqConfig, (error0, connection) => {
       );
     });
 
+    // * GET MARKET INFO -------------------------------------------------------------------
+    app.post("/get_market_info", (req, res) => {
+      delegateRequest(
+        req.body,
+        "get_market_info",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // * GET PREDICTED FUNDING -------------------------------------------------------------
+    app.post("/get_predicted_funding", (req, res) => {
+      delegateRequest(
+        req.body,
+        "get_predicted_funding",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // * GET POSITION LEDGER ---------------------------------------------------------------
+    app.post("/get_position_ledger", (req, res) => {
+      delegateRequest(
+        req.body,
+        "get_position_ledger",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // * GET POSITION RISK -----------------------------------------------------------------
+    app.post("/get_position_risk", (req, res) => {
+      delegateRequest(
+        req.body,
+        "get_position_risk",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // * GET POSITIONS RISK BATCH ----------------------------------------------------------
+    app.post("/get_positions_risk_batch", (req, res) => {
+      delegateRequest(
+        req.body,
+        "get_positions_risk_batch",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // * SIMULATE PERP ORDER ---------------------------------------------------------------
+    app.post("/simulate_perp_order", (req, res) => {
+      delegateRequest(
+        req.body,
+        "simulate_perp_order",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
+    // * GET DEPTH -------------------------------------------------------------------------
+    app.post("/get_depth", (req, res) => {
+      delegateRequest(
+        req.body,
+        "get_depth",
+        channel,
+        res,
+        queue,
+        correlationIdToResolve
+      );
+    });
+
     // * GET ORDERS ------------------------------------------------------------------------
     app.post("/get_orders", (req, res) => {
       delegateRequest(
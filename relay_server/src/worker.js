@@ -119,6 +119,36 @@ async function processOrder(correlationId, message) {
     // Execute order in the backend engine
     let res = await callLiquidationOrderRpcWithPromise(message);
 
+    return res;
+  } else if (correlationId.startsWith("orders_batch")) {
+    // Executes a batch of orders and cancels in the backend engine
+    let res = await callOrdersBatchRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("batch_cancel")) {
+    // Cancels a batch of orders in the backend engine
+    let res = await callCancelOrdersBatchRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("cancel_all")) {
+    // Cancels all the orders of a user in the backend engine
+    let res = await callCancelAllRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("dead_mans_switch")) {
+    // Arms or refreshes the dead man's switch of a user in the backend engine
+    let res = await callDeadMansSwitchRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("set_mm_protection")) {
+    // Sets the market maker protection of a user in a market
+    let res = await callSetMmProtectionRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("reset_mm_protection")) {
+    // Unfreezes a user after their market maker protection was triggered
+    let res = await callResetMmProtectionRpcWithPromise(message);
+
     return res;
   } else if (correlationId.startsWith("cancel")) {
     // Cancels order in the backend engine
@@ -130,15 +160,25 @@ async function processOrder(correlationId, message) {
     let res = await callAmendRpcWithPromise(message);
 
     return res;
-  } else if (correlationId.startsWith("split_notes")) {
+  } else if (correlationId.startsWith("restructure_notes")) {
     // restructures notes in the backend engine
-    let res = await callSplitNotesRpcWithPromise(message);
+    let res = await callRestructureNotesRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("transfer")) {
+    // transfers notes to another user in the backend engine
+    let res = await callTransferRpcWithPromise(message);
 
     return res;
   } else if (correlationId.startsWith("change_margin")) {
     // changes the margin for a position in the backend engine
     let res = await callChangeMarginRpcWithPromise(message);
 
+    return res;
+  } else if (correlationId.startsWith("set_leverage")) {
+    // sets the leverage of a position (or previews it) in the backend engine
+    let res = await callSetLeverageRpcWithPromise(message);
+
     return res;
   } else if (correlationId.startsWith("open_order_tab")) {
     let res = await callOpenOrderTabRpcWithPromise(message);
@@ -173,6 +213,41 @@ async function processOrder(correlationId, message) {
     // gets all liquidity for a user in the backend engine
     let res = await callGetLiquidityRpcWithPromise(message);
 
+    return res;
+  } else if (correlationId.startsWith("get_market_info")) {
+    // gets the tick size, lot size and order size limits of the markets
+    let res = await callGetMarketInfoRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("get_depth")) {
+    // gets the aggregated price levels of an orderbook
+    let res = await callGetDepthRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("get_predicted_funding")) {
+    // gets the predicted rate of the next funding settlement
+    let res = await callGetPredictedFundingRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("get_position_ledger")) {
+    // gets the funding, pnl, fee and liquidation history of a position
+    let res = await callGetPositionLedgerRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("get_position_risk")) {
+    // gets the margin health of a position
+    let res = await callGetPositionRiskRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("get_positions_risk_batch")) {
+    // gets the margin health of many positions at once
+    let res = await callGetPositionsRiskBatchRpcWithPromise(message);
+
+    return res;
+  } else if (correlationId.startsWith("simulate_perp_order")) {
+    // dry runs a perp order against the book without executing it
+    let res = await callSimulatePerpOrderRpcWithPromise(message);
+
     return res;
   } else if (correlationId.startsWith("get_funding_info")) {
     // gets all liquidity for a user in the backend engine
@@ -280,6 +355,78 @@ function callCancelRpcWithPromise(cancelReq) {
   });
 }
 
+function callOrdersBatchRpcWithPromise(batchReq) {
+  return new Promise((resolve, reject) => {
+    client.submit_orders_batch(batchReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callCancelOrdersBatchRpcWithPromise(batchCancelReq) {
+  return new Promise((resolve, reject) => {
+    client.cancel_orders_batch(batchCancelReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callCancelAllRpcWithPromise(cancelAllReq) {
+  return new Promise((resolve, reject) => {
+    client.cancel_all(cancelAllReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callDeadMansSwitchRpcWithPromise(deadMansSwitchReq) {
+  return new Promise((resolve, reject) => {
+    client.set_dead_mans_switch(deadMansSwitchReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callSetMmProtectionRpcWithPromise(mmProtectionReq) {
+  return new Promise((resolve, reject) => {
+    client.set_mm_protection(mmProtectionReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callResetMmProtectionRpcWithPromise(mmProtectionResetReq) {
+  return new Promise((resolve, reject) => {
+    client.reset_mm_protection(mmProtectionResetReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
 function callAmendRpcWithPromise(amendReq) {
   return new Promise((resolve, reject) => {
     client.amend_order(amendReq, function (err, response) {
@@ -292,9 +439,21 @@ function callAmendRpcWithPromise(amendReq) {
   });
 }
 
-function callSplitNotesRpcWithPromise(splitReq) {
+function callRestructureNotesRpcWithPromise(restructureReq) {
   return new Promise((resolve, reject) => {
-    client.split_notes(splitReq, function (err, response) {
+    client.restructure_notes(restructureReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callTransferRpcWithPromise(transferReq) {
+  return new Promise((resolve, reject) => {
+    client.execute_transfer(transferReq, function (err, response) {
       if (err) {
         reject(err);
       } else {
@@ -316,6 +475,18 @@ function callChangeMarginRpcWithPromise(marginReq) {
   });
 }
 
+function callSetLeverageRpcWithPromise(leverageReq) {
+  return new Promise((resolve, reject) => {
+    client.set_leverage(leverageReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
 // -------------------------------------------------
 
 function callOpenOrderTabRpcWithPromise(marginReq) {
@@ -416,6 +587,90 @@ function callGetLiquidityRpcWithPromise(liquidityReq) {
   });
 }
 
+function callGetMarketInfoRpcWithPromise(marketInfoReq) {
+  return new Promise((resolve, reject) => {
+    client.get_market_info(marketInfoReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callGetDepthRpcWithPromise(depthReq) {
+  return new Promise((resolve, reject) => {
+    client.get_depth(depthReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callGetPredictedFundingRpcWithPromise(fundingReq) {
+  return new Promise((resolve, reject) => {
+    client.get_predicted_funding(fundingReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callGetPositionLedgerRpcWithPromise(positionLedgerReq) {
+  return new Promise((resolve, reject) => {
+    client.get_position_ledger(positionLedgerReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callGetPositionRiskRpcWithPromise(positionRiskReq) {
+  return new Promise((resolve, reject) => {
+    client.get_position_risk(positionRiskReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callGetPositionsRiskBatchRpcWithPromise(riskBatchReq) {
+  return new Promise((resolve, reject) => {
+    client.get_positions_risk_batch(riskBatchReq, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
+function callSimulatePerpOrderRpcWithPromise(perpOrderMessage) {
+  return new Promise((resolve, reject) => {
+    client.simulate_perp_order(perpOrderMessage, function (err, response) {
+      if (err) {
+        reject(err);
+      } else {
+        resolve(response);
+      }
+    });
+  });
+}
+
 function callGetFundingInfoRpcWithPromise() {
   return new Promise((resolve, reject) => {
     client.get_funding_info({}, function (err, response) {